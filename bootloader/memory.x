@@ -5,3 +5,12 @@ MEMORY
   FLASH : ORIGIN = 0x00000000, LENGTH = 8K
   RAM : ORIGIN = 0x10000000, LENGTH = 8K
 }
+
+/* The IAP routines (flash, EEPROM, see crash_log.rs) use the top 32 bytes of
+   RAM, so the stack has to start below them. */
+_stack_start = ORIGIN(RAM) + LENGTH(RAM) - 32;
+
+/* The bootloader must fit in its 8K, program2 starts right after. .data is
+   stored in flash after everything else, see cortex-m-rt's link.x. */
+ASSERT(__sidata + (__edata - __sdata) <= ORIGIN(FLASH) + LENGTH(FLASH),
+       "the bootloader doesn't fit in its 8K of flash");
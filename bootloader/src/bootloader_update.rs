@@ -0,0 +1,238 @@
+//! Self-update of the bootloader.
+//!
+//! The bootloader lives in sectors 0-1, which also hold the vector table the
+//! chip boots from. Replacing it is done in three steps:
+//!
+//! 1. The new image is uploaded to a scratch area at the end of the flash
+//!    (sectors 30-31), using the same write pipeline as program2.
+//! 2. The staged image is checked: its FMC signature must match the one the
+//!    host computed, its vector table must carry a valid LPC checksum, and the
+//!    initial stack pointer and reset vector must make sense.
+//! 3. Once the host has read the result of the check, it asks for the update
+//!    to be applied. A small routine running from RAM erases sectors 0-1 and
+//!    copies the scratch area over them, then resets the chip.
+//!
+//! Step 3 writes the first 256 bytes of sector 0 last. Until they are written,
+//! the vector table is blank, its checksum is invalid, and the ROM falls back
+//! to ISP mode on reset. Losing power in the middle of the copy therefore
+//! leaves a controller that can be recovered over USB instead of a brick.
+
+use lpc11uxx_rom::iap;
+
+use crate::MAIN_CLOCK_FREQ;
+use crate::programming_mode::{FlashWriter, set_program2_boot_magic};
+
+const SCRATCH_START_SECTOR: u32 = 0x1e;
+const SCRATCH_END_SECTOR: u32 = 0x1f;
+const SCRATCH_START: usize = 0x1e_000;
+const SCRATCH_END: usize = 0x20_000;
+const BOOTLOADER_SIZE: usize = 0x2000;
+
+/// Chunk size used when copying the scratch area over the bootloader. This
+/// is the smallest size the IAP copy command accepts.
+const COPY_CHUNK_SIZE: usize = 256;
+
+/// How many CT32B1 ticks (12ms each) to wait between the apply command and
+/// the actual update. The chip resets at the end of the update, and the host
+/// should get the status of the apply command before it does.
+const APPLY_DELAY_TICKS: u8 = 10;
+
+/// Where the update stands. Lives in the feature reports resource, along with
+/// the flash write pipeline.
+pub struct Update {
+    verified: bool,
+    /// Ticks left before applying the update, once the host asked for it.
+    apply_in: Option<u8>,
+}
+
+impl Update {
+    pub const fn new() -> Self {
+        Update { verified: false, apply_in: None }
+    }
+}
+
+/// Buffer the RAM routine copies each chunk into before handing it to the
+/// IAP. It has to be in RAM, and has to be a static: the routine can't rely
+/// on anything that lives in flash.
+static mut COPY_BUFFER: [u32; COPY_CHUNK_SIZE / 4] = [0; COPY_CHUNK_SIZE / 4];
+
+/// Prepares the scratch area to receive a new bootloader image.
+///
+/// If the scratch area isn't blank, program2 is most likely using it. Unless
+/// `force` is set, this returns 9 and leaves everything alone. With `force`,
+/// program2 gets invalidated (it wouldn't survive having its tail erased) and
+/// will have to be flashed again.
+pub fn begin(update: &mut Update, flash: &mut FlashWriter, force: bool) -> u32 {
+    cancel(update, flash);
+
+    let err = iap::blank_check_sectors(SCRATCH_START_SECTOR, SCRATCH_END_SECTOR);
+    if err != 0 {
+        if !force {
+            return 9;
+        }
+        let err = set_program2_boot_magic(0xffff_ffff);
+        if err != 0 {
+            return err;
+        }
+    }
+
+    let err = iap::prepare_sector_for_write(SCRATCH_START_SECTOR, SCRATCH_END_SECTOR);
+    if err != 0 {
+        return 1;
+    }
+    let err = iap::erase_sectors(SCRATCH_START_SECTOR, SCRATCH_END_SECTOR, unsafe { MAIN_CLOCK_FREQ } / 1024);
+    if err != 0 {
+        return 1;
+    }
+
+    flash.start(SCRATCH_START, SCRATCH_END);
+    0
+}
+
+/// Whether the flash write pipeline currently writes to the scratch area,
+/// between `begin` and `verify`.
+pub fn is_staging(flash: &FlashWriter) -> bool {
+    flash.base() == SCRATCH_START
+}
+
+/// Whether an update is being staged, or waits to be applied.
+pub fn in_progress(update: &Update, flash: &FlashWriter) -> bool {
+    is_staging(flash) || update.verified
+}
+
+/// Drops whatever update is in progress, and points the flash write pipeline
+/// back at program2.
+pub fn cancel(update: &mut Update, flash: &mut FlashWriter) {
+    *update = Update::new();
+    flash.reset();
+}
+
+/// Checks the staged image against the signature sent by the host. If
+/// everything checks out, the update is armed, and `request_apply` may be
+/// called.
+///
+/// Either way, this ends the staging session: the flash write pipeline goes
+/// back to program2.
+pub fn verify(update: &mut Update, flash: &mut FlashWriter, sig: &[u8]) -> u32 {
+    if !is_staging(flash) {
+        cancel(update, flash);
+        return 1;
+    }
+
+    let err = check_staged_image(flash, sig);
+    cancel(update, flash);
+    update.verified = err == 0;
+    err
+}
+
+fn check_staged_image(flash: &mut FlashWriter, sig: &[u8]) -> u32 {
+    let flash_end = match flash.flush() {
+        Ok(flash_end) => flash_end,
+        Err(err) => return err,
+    };
+
+    if flash_end <= SCRATCH_START || flash_end > SCRATCH_START + BOOTLOADER_SIZE {
+        return 10;
+    }
+
+    if !flash.check_signature(SCRATCH_START, flash_end, sig) {
+        return 4;
+    }
+
+    let vector_table = unsafe { core::slice::from_raw_parts(SCRATCH_START as *const u32, 8) };
+
+    // The ROM only boots an image whose first 8 vectors sum up to 0.
+    let checksum = vector_table.iter().fold(0u32, |acc, v| acc.wrapping_add(*v));
+    if checksum != 0 {
+        return 11;
+    }
+
+    let initial_sp = vector_table[0];
+    if initial_sp < 0x1000_0000 || initial_sp > 0x1000_2000 || initial_sp % 4 != 0 {
+        return 12;
+    }
+
+    let reset_vector = vector_table[1];
+    if reset_vector & 1 == 0 || reset_vector as usize >= BOOTLOADER_SIZE {
+        return 13;
+    }
+
+    0
+}
+
+/// Arms the update `verify` accepted. It gets applied by `apply_if_requested`
+/// from the main loop, a little later.
+pub fn request_apply(update: &mut Update) -> u32 {
+    if !update.verified {
+        return 14;
+    }
+    update.apply_in = Some(APPLY_DELAY_TICKS);
+    0
+}
+
+/// Replaces the bootloader with the staged image once it has been requested
+/// for long enough. Never returns in that case: the chip resets into the new
+/// bootloader. `timer_elapsed` tells whether CT32B1 ticked since the last
+/// call.
+pub fn apply_if_requested(update: &mut Update, timer_elapsed: bool) {
+    match update.apply_in {
+        Some(0) => (),
+        Some(ticks) => {
+            if timer_elapsed {
+                update.apply_in = Some(ticks - 1);
+            }
+            return;
+        },
+        None => return,
+    }
+
+    cortex_m::interrupt::disable();
+
+    // Force a call through a register. The routine lives in RAM, way out of
+    // reach of a BL from flash.
+    let copy_fn = unsafe {
+        core::ptr::read_volatile(&(copy_scratch_to_bootloader as unsafe extern "C" fn(u32) -> !))
+    };
+    unsafe { copy_fn(MAIN_CLOCK_FREQ / 1024) }
+}
+
+/// Copies the scratch area over sectors 0-1, and resets.
+///
+/// This erases the code it was loaded from, so it must run entirely from RAM
+/// and ROM: no calls into flash (including compiler intrinsics like memcpy),
+/// no constants from .rodata.
+#[inline(never)]
+#[link_section = ".data.bootloader_update"]
+unsafe extern "C" fn copy_scratch_to_bootloader(clock_khz: u32) -> ! {
+    let iap_entry: extern "C" fn(&[u32; 5], &mut [u32; 4]) =
+        core::mem::transmute(0x1fff_1ff1_usize);
+    let mut cmd_out = [clock_khz; 4];
+
+    // Prepare and erase sectors 0-1.
+    iap_entry(&[50, 0, 1, clock_khz, 0], &mut cmd_out);
+    iap_entry(&[52, 0, 1, clock_khz, 0], &mut cmd_out);
+
+    // Copy from the top down, so the chunk holding the vector table is the
+    // last one written.
+    let mut chunk = BOOTLOADER_SIZE / COPY_CHUNK_SIZE;
+    while chunk > 0 {
+        chunk -= 1;
+
+        let dst = chunk * COPY_CHUNK_SIZE;
+        let src = (SCRATCH_START + dst) as *const u32;
+        let buf = &mut COPY_BUFFER as *mut _ as *mut u32;
+        let mut i = 0;
+        while i < COPY_CHUNK_SIZE / 4 {
+            core::ptr::write_volatile(buf.add(i), core::ptr::read_volatile(src.add(i)));
+            i += 1;
+        }
+
+        let sector = (dst / 0x1000) as u32;
+        iap_entry(&[50, sector, sector, clock_khz, 0], &mut cmd_out);
+        iap_entry(&[51, dst as u32, buf as u32, COPY_CHUNK_SIZE as u32, clock_khz], &mut cmd_out);
+    }
+
+    // SYSRESETREQ
+    core::ptr::write_volatile(0xe000_ed0c as *mut u32, 0x05fa_0004);
+    loop {}
+}
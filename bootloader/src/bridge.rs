@@ -0,0 +1,78 @@
+//! Raw bridge between the CDC port and the nRF UART.
+//!
+//! Lets host tools talk to the nRF directly, e.g. to its own bootloader.
+//! Entered with the `nrf bridge` console command, and lasts until the next
+//! reset. While it's active:
+//!
+//! - Bytes go through untouched in both directions. The console, the logs and
+//!   the sniffer are all turned off, so nothing else ends up on the CDC port.
+//! - The bootloader stops sending its own packets to the nRF.
+//! - Changing the line coding of the CDC port changes the one of the UART.
+//!   The UART keeps its current settings until the host sets one.
+//!
+//! The control lines (DTR, RTS) are ignored: there's no known GPIO wired to
+//! the nRF reset pin, so tools can't reset the nRF through them, e.g. to get
+//! into its bootloader. That isn't supported.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use lpc11uxx::Peripherals;
+use lpc11uxx_rom::usbd::CdcLineCoding;
+
+use crate::nrf_comms::{usart_send_raw_str, usart_tx_ready};
+use crate::usb_debug_uart::{usb_getc, usb_putc};
+
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+pub fn active() -> bool {
+    ACTIVE.load(Ordering::Relaxed)
+}
+
+pub fn enter() {
+    crate::log::set_max_level(crate::log::Level::Off);
+    crate::sniffer::set_enabled(false);
+    ACTIVE.store(true, Ordering::Relaxed);
+}
+
+/// Forwards what the host sent to the nRF. Called from the main loop.
+pub fn poll() {
+    while usart_tx_ready() {
+        match usb_getc() {
+            Some(byte) => { usart_send_raw_str(&[byte]); },
+            None => break,
+        }
+    }
+}
+
+/// Forwards a byte from the nRF to the host. The UART has no flow control, so
+/// the byte is lost if the CDC TX FIFO is full.
+pub fn handle_usart_byte(byte: u8) {
+    usb_putc(byte);
+}
+
+pub fn set_line_coding(line_coding: &CdcLineCoding) {
+    if !active() {
+        return;
+    }
+
+    // LCR: word length in bits 0-1, extra stop bit in bit 2, parity enable
+    // in bit 3 and parity select in bits 4-5.
+    let word_length = line_coding.data_bits.max(5).min(8) - 5;
+    let stop_bits = if line_coding.char_format != 0 { 1 << 2 } else { 0 };
+    let parity = match line_coding.parity_type {
+        // Odd
+        1 => 0b00_1000,
+        // Even
+        2 => 0b01_1000,
+        // Mark
+        3 => 0b10_1000,
+        // Space
+        4 => 0b11_1000,
+        // None
+        _ => 0,
+    };
+
+    let peripherals = unsafe { Peripherals::steal() };
+    crate::nrf_comms::configure_usart_line(&peripherals.USART, line_coding.dte_rate,
+        word_length | stop_bits | parity);
+}
@@ -0,0 +1,233 @@
+//! Line-oriented debug shell over the CDC port.
+//!
+//! Polled from the programming mode main loop. Characters are echoed back, so
+//! any serial terminal will do. Type `help` for the list of commands.
+
+use core::fmt::Write;
+
+use lpc11uxx::{Peripherals, PMU};
+use lpc11uxx_rom::iap;
+
+use crate::usb_debug_uart::{usb_getc, UartDebug};
+
+const LINE_MAX_LEN: usize = 64;
+
+/// Addresses above this are reserved by the ROM.
+const EEPROM_SIZE: u32 = 0xfc0;
+
+static mut LINE: [u8; LINE_MAX_LEN] = [0; LINE_MAX_LEN];
+static mut LINE_LEN: usize = 0;
+
+pub fn poll() {
+    while let Some(byte) = usb_getc() {
+        handle_byte(byte);
+    }
+}
+
+fn handle_byte(byte: u8) {
+    let mut uart = UartDebug;
+
+    unsafe {
+        match byte {
+            b'\r' | b'\n' => {
+                let _ = uart.write_str("\r\n");
+                if LINE_LEN != 0 {
+                    let len = LINE_LEN;
+                    LINE_LEN = 0;
+                    handle_line(&LINE[..len]);
+                }
+                let _ = uart.write_str("> ");
+            },
+            // Backspace, and DEL which most terminals send instead.
+            0x08 | 0x7f => {
+                if LINE_LEN != 0 {
+                    LINE_LEN -= 1;
+                    let _ = uart.write_str("\x08 \x08");
+                }
+            },
+            byte => {
+                if let Some(place) = LINE.get_mut(LINE_LEN) {
+                    *place = byte;
+                    LINE_LEN += 1;
+                    crate::usb_debug_uart::usb_putc(byte);
+                }
+            }
+        }
+    }
+}
+
+fn handle_line(line: &[u8]) {
+    // The log module parses its own command.
+    if crate::log::handle_command(line) {
+        return;
+    }
+
+    let mut args = line.split(|c| *c == b' ').filter(|arg| !arg.is_empty());
+    let mut uart = UartDebug;
+
+    match (args.next(), args.next()) {
+        (Some(b"help"), _) => {
+            let _ = uart.write_str(
+                "info\r\n\
+                 reboot [bootloader]\r\n\
+                 eeprom get <addr> [len]\r\n\
+                 eeprom set <addr> <byte>...\r\n\
+                 log level [off|error|warn|info|debug|trace]\r\n\
+                 nrf reset\r\n\
+                 nrf bridge\r\n\
+                 sniff [on|off]\r\n");
+        },
+        (Some(b"info"), _) => cmd_info(),
+        (Some(b"reboot"), stay) => cmd_reboot(stay == Some(&b"bootloader"[..])),
+        (Some(b"eeprom"), Some(b"get")) => cmd_eeprom_get(args.next(), args.next()),
+        (Some(b"eeprom"), Some(b"set")) => cmd_eeprom_set(args.next(), args),
+        (Some(b"nrf"), Some(b"reset")) => {
+            let _ = uart.write_str(match crate::nrf_comms::usart_send_reset() {
+                Ok(()) => "ok\r\n",
+                Err(_) => "nrf tx queue full, try again\r\n",
+            });
+        },
+        (Some(b"nrf"), Some(b"bridge")) => {
+            let _ = uart.write_str("bridging to the nRF UART, reset the controller to leave\r\n");
+            crate::bridge::enter();
+        },
+        (Some(b"sniff"), state) => {
+            match state {
+                Some(b"on") => crate::sniffer::set_enabled(true),
+                Some(b"off") => crate::sniffer::set_enabled(false),
+                _ => (),
+            }
+            let _ = uart.write_line(format_args!("sniff {}", if crate::sniffer::enabled() { "on" } else { "off" }));
+        },
+        _ => {
+            let _ = uart.write_str("unknown command, try help\r\n");
+        }
+    }
+}
+
+fn cmd_info() {
+    let peripherals = unsafe { Peripherals::steal() };
+    let mut uart = UartDebug;
+
+    // Both in slot 9 of the vector tables, reserved by the Cortex-M0.
+    let bootloader_version = unsafe { core::ptr::read_volatile(0x24 as *const u32) };
+    let program2_magic = unsafe { core::ptr::read_volatile((crate::program2::PROGRAM2_BASE + 0x24) as *const u32) };
+    let program2_valid = program2_magic == 0xecaabac0;
+
+    let _ = uart.write_line(format_args!("bootloader version: {:#x}", bootloader_version));
+    let _ = uart.write_line(format_args!("hardware version: {}", unsafe { crate::EEPROM_CACHE.version }));
+    let _ = uart.write_line(format_args!("program2: {}", if program2_valid { "valid" } else { "invalid" }));
+    let _ = uart.write_line(format_args!("main clock: {}Hz", unsafe { crate::MAIN_CLOCK_FREQ }));
+    let _ = uart.write_line(format_args!("reset reason: {:#x}", peripherals.SYSCON.sysrststat.read().bits()));
+    let _ = uart.write_line(format_args!("log level: {}", crate::log::max_level().name()));
+}
+
+fn cmd_reboot(stay_in_bootloader: bool) -> ! {
+    let peripherals = unsafe { Peripherals::steal() };
+    let pmu: &PMU = &peripherals.PMU;
+    if stay_in_bootloader {
+        pmu.gpreg[0].write(|v| unsafe { v.gpdata().bits(0xecaabac0) });
+    }
+
+    let _ = UartDebug.write_str("rebooting\r\n");
+    crate::usb_debug_uart::usb_flush();
+    cortex_m::peripheral::SCB::sys_reset()
+}
+
+fn cmd_eeprom_get(addr: Option<&[u8]>, len: Option<&[u8]>) {
+    let mut uart = UartDebug;
+
+    let addr = match addr.and_then(parse_u32) {
+        Some(addr) => addr,
+        None => {
+            let _ = uart.write_str("usage: eeprom get <addr> [len]\r\n");
+            return;
+        }
+    };
+    let len = match len {
+        Some(len) => parse_u32(len).unwrap_or(0),
+        None => 4,
+    };
+
+    let mut data = [0u8; 16];
+    if len == 0 || len as usize > data.len() || addr.saturating_add(len) > EEPROM_SIZE {
+        let _ = uart.write_str("invalid range, at most 16 bytes below 0xfc0\r\n");
+        return;
+    }
+
+    let data = &mut data[..len as usize];
+    let err = iap::eeprom_read(addr, data, unsafe { crate::MAIN_CLOCK_FREQ } / 1024);
+    if err != 0 {
+        let _ = uart.write_line(format_args!("eeprom read failed: {}", err));
+        return;
+    }
+    let _ = uart.write_line(format_args!("{:#05x}: {}", addr, crate::log::Hex(data)));
+}
+
+fn cmd_eeprom_set<'a>(addr: Option<&[u8]>, bytes: impl Iterator<Item = &'a [u8]>) {
+    let mut uart = UartDebug;
+
+    let addr = match addr.and_then(parse_u32) {
+        Some(addr) => addr,
+        None => {
+            let _ = uart.write_str("usage: eeprom set <addr> <byte>...\r\n");
+            return;
+        }
+    };
+
+    let mut data = [0u8; 16];
+    let mut len = 0;
+    for byte in bytes {
+        let byte = match parse_u32(byte) {
+            Some(byte) if byte <= 0xff => byte as u8,
+            _ => {
+                let _ = uart.write_str("invalid byte\r\n");
+                return;
+            }
+        };
+        match data.get_mut(len) {
+            Some(place) => *place = byte,
+            None => {
+                let _ = uart.write_str("at most 16 bytes at a time\r\n");
+                return;
+            }
+        }
+        len += 1;
+    }
+
+    if len == 0 || addr.saturating_add(len as u32) > EEPROM_SIZE {
+        let _ = uart.write_str("invalid range\r\n");
+        return;
+    }
+
+    let err = iap::eeprom_write(addr, &data[..len], unsafe { crate::MAIN_CLOCK_FREQ } / 1024);
+    if err != 0 {
+        let _ = uart.write_line(format_args!("eeprom write failed: {}", err));
+        return;
+    }
+
+    // Keep the cached copy of the header in sync, with what was written:
+    // check_eeprom_magic would fix up a header the user broke on purpose.
+    if (addr as usize) < core::mem::size_of::<crate::EepromData>() {
+        crate::read_eeprom_cache();
+    }
+    let _ = uart.write_str("ok\r\n");
+}
+
+/// Parses a decimal, or 0x-prefixed hexadecimal number.
+fn parse_u32(s: &[u8]) -> Option<u32> {
+    let (digits, radix) = match s {
+        [b'0', b'x', digits @ ..] => (digits, 16),
+        digits => (digits, 10),
+    };
+    if digits.is_empty() {
+        return None;
+    }
+
+    let mut n: u32 = 0;
+    for c in digits {
+        let digit = (*c as char).to_digit(radix)?;
+        n = n.checked_mul(radix)?.checked_add(digit)?;
+    }
+    Some(n)
+}
@@ -0,0 +1,194 @@
+//! Crash records, kept in EEPROM so they survive the reset that usually
+//! follows a crash.
+//!
+//! Only the last crash is kept. It can be read back and cleared by the host
+//! through feature reports 0xa4 and 0xa5. A crash identical to the one
+//! already recorded isn't written again, so a crash loop doesn't wear the
+//! EEPROM out.
+//!
+//! HardFaults are recorded wherever they come from, program2 included: the
+//! bootloader's vector table is the one in use. Program2 panics go through
+//! program2's own panic handler, which leaves the details in the PMU general
+//! purpose registers (see `record_program2_panic`) and resets. The bootloader
+//! picks them up when it starts again.
+
+use core::fmt::Write;
+use core::mem::size_of;
+use core::panic::PanicInfo;
+use core::slice;
+
+use cortex_m::peripheral::SCB;
+use cortex_m_rt::ExceptionFrame;
+use lpc11uxx::{Peripherals, PMU};
+use lpc11uxx_rom::iap;
+use nrf_protocol::digest::Fnv1a;
+use static_assertions::const_assert;
+
+/// The last 64 usable bytes of the EEPROM. The ROM reserves the 64 bytes
+/// after them.
+const CRASH_LOG_EEPROM_ADDR: u32 = 0xf80;
+const CRASH_LOG_MAGIC: u32 = 0xdead_c0de;
+
+pub const CRASH_KIND_HARDFAULT: u32 = 1;
+pub const CRASH_KIND_PANIC: u32 = 2;
+pub const CRASH_KIND_PROGRAM2_PANIC: u32 = 3;
+
+/// Top half of GPREG2 when program2 panicked. The bottom half holds the line,
+/// and GPREG3 the FNV-1a digest of the file name.
+const PROGRAM2_PANIC_MAGIC: u32 = 0xfa11_0000;
+
+/// The IAP routines use the top 32 bytes of RAM as scratch space.
+const IAP_RAM_START: usize = 0x1000_1fe0;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CrashRecord {
+    magic: u32,
+    kind: u32,
+    /// SYSRSTSTAT at the time of the crash, i.e. why the crashing program was
+    /// started in the first place.
+    reset_reason: u32,
+    /// The Cortex-M0 has no CFSR. ICSR is the closest we have: it tells which
+    /// exception was active, and which one was pending.
+    icsr: u32,
+    r0: u32,
+    r1: u32,
+    r2: u32,
+    r3: u32,
+    r12: u32,
+    lr: u32,
+    pc: u32,
+    xpsr: u32,
+    /// FNV-1a hash of the panic location and message, for panics.
+    panic_digest: u32,
+    panic_line: u32,
+}
+
+const_assert!(size_of::<CrashRecord>() <= 0x3e);
+
+impl CrashRecord {
+    fn new(kind: u32) -> CrashRecord {
+        let peripherals = unsafe { Peripherals::steal() };
+
+        CrashRecord {
+            magic: CRASH_LOG_MAGIC,
+            kind,
+            reset_reason: peripherals.SYSCON.sysrststat.read().bits(),
+            icsr: unsafe { (*SCB::ptr()).icsr.read() },
+            ..CrashRecord::default()
+        }
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self as *const _ as *const u8, size_of::<CrashRecord>()) }
+    }
+}
+
+/// The crash handlers can't trust MAIN_CLOCK_FREQ: by the time program2
+/// crashes, it has long overwritten the bootloader's RAM.
+fn clock_freq_khz() -> u32 {
+    let peripherals = unsafe { Peripherals::steal() };
+    crate::lpc11uxx_misc::get_main_clock_rate(&peripherals.SYSCON) / 1024
+}
+
+fn read_record() -> CrashRecord {
+    let mut record = CrashRecord::default();
+    let bytes = unsafe { slice::from_raw_parts_mut(&mut record as *mut _ as *mut u8, size_of::<CrashRecord>()) };
+    iap::eeprom_read(CRASH_LOG_EEPROM_ADDR, bytes, clock_freq_khz());
+    record
+}
+
+extern "C" fn write_record(record: &CrashRecord) -> i32 {
+    // The IAP overwrites the top 32 bytes of RAM, where the record itself can
+    // be. This copy is on the stack, which is below them by now. The read is
+    // volatile so the copy can't be optimized into a use of the original.
+    let record = unsafe { core::ptr::read_volatile(record) };
+    if read_record() == record {
+        return 0;
+    }
+    iap::eeprom_write(CRASH_LOG_EEPROM_ADDR, record.as_bytes(), clock_freq_khz())
+}
+
+/// Same as write_record, for the crash handlers. The stack they run on can be
+/// anywhere, including in the RAM the IAP uses, and gets moved below it for
+/// the duration of the write if needed, so write_record's copy of the record
+/// is out of the IAP's way. Whatever is above the stack pointer then still
+/// gets overwritten, but it belongs to the crashed program.
+fn write_crash_record(record: &CrashRecord) -> i32 {
+    let err: i32;
+    unsafe {
+        asm!("
+            mov r4, sp
+            cmp r4, r1
+            bls 1f
+            mov sp, r1
+        1:
+            blx r2
+            mov sp, r4
+            ",
+            inout("r0") record => err,
+            inout("r1") IAP_RAM_START => _,
+            inout("r2") write_record as extern "C" fn(&CrashRecord) -> i32 => _,
+            out("r3") _, out("r4") _, out("r12") _, out("lr") _);
+    }
+    err
+}
+
+pub fn record_hardfault(frame: &ExceptionFrame) {
+    let mut record = CrashRecord::new(CRASH_KIND_HARDFAULT);
+    record.r0 = frame.r0;
+    record.r1 = frame.r1;
+    record.r2 = frame.r2;
+    record.r3 = frame.r3;
+    record.r12 = frame.r12;
+    record.lr = frame.lr;
+    record.pc = frame.pc;
+    record.xpsr = frame.xpsr;
+    write_crash_record(&record);
+}
+
+pub fn record_panic(info: &PanicInfo) {
+    let mut record = CrashRecord::new(CRASH_KIND_PANIC);
+    let mut digest = Fnv1a::new();
+
+    if let Some(location) = info.location() {
+        let _ = digest.write_str(location.file());
+        record.panic_line = location.line();
+    }
+    if let Some(message) = info.message() {
+        let _ = digest.write_fmt(*message);
+    }
+    record.panic_digest = digest.finish();
+    write_crash_record(&record);
+}
+
+/// Records the panic program2 left in the PMU before resetting, if any.
+/// Called by the bootloader when it starts.
+pub fn record_program2_panic(pmu: &PMU) {
+    let marker = pmu.gpreg[2].read().bits();
+    if marker & 0xffff_0000 != PROGRAM2_PANIC_MAGIC {
+        return;
+    }
+    pmu.gpreg[2].write(|v| unsafe { v.gpdata().bits(0) });
+
+    let mut record = CrashRecord::new(CRASH_KIND_PROGRAM2_PANIC);
+    record.panic_line = marker & 0xffff;
+    record.panic_digest = pmu.gpreg[3].read().bits();
+    write_record(&record);
+}
+
+/// Copies the current crash record into `data`. A record with a zero magic
+/// means nothing crashed since the log was last cleared.
+pub fn read(data: &mut [u8]) -> usize {
+    let mut record = read_record();
+    if record.magic != CRASH_LOG_MAGIC {
+        record = CrashRecord::default();
+    }
+    let bytes = record.as_bytes();
+    data[..bytes.len()].copy_from_slice(bytes);
+    bytes.len()
+}
+
+pub fn clear() -> i32 {
+    write_record(&CrashRecord::default())
+}
@@ -0,0 +1,209 @@
+//! Leveled logging to the CDC debug UART.
+//!
+//! Works like a tiny version of the `log` crate: `error!`, `warn!`, `info!`,
+//! `debug!` and `trace!` format their arguments into the TX FIFO of
+//! `usb_debug_uart`, prefixed by the level and the module they come from.
+//!
+//! Filtering happens twice. Statements above `STATIC_MAX_LEVEL` are compiled
+//! out entirely, format strings included, which is what keeps logging
+//! affordable in 8K. What's left can then be filtered at runtime, through the
+//! `log level <level>` console command.
+
+use core::fmt::{Arguments, Write};
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use crate::usb_debug_uart::UartDebug;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum Level {
+    Off = 0,
+    Error = 1,
+    Warn = 2,
+    Info = 3,
+    Debug = 4,
+    Trace = 5,
+}
+
+impl Level {
+    fn from_u8(level: u8) -> Level {
+        match level {
+            0 => Level::Off,
+            1 => Level::Error,
+            2 => Level::Warn,
+            3 => Level::Info,
+            4 => Level::Debug,
+            _ => Level::Trace,
+        }
+    }
+
+    pub fn parse(name: &[u8]) -> Option<Level> {
+        match name {
+            b"off" => Some(Level::Off),
+            b"error" => Some(Level::Error),
+            b"warn" => Some(Level::Warn),
+            b"info" => Some(Level::Info),
+            b"debug" => Some(Level::Debug),
+            b"trace" => Some(Level::Trace),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Level::Off => "off",
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+            Level::Trace => "trace",
+        }
+    }
+
+    fn tag(self) -> &'static str {
+        match self {
+            Level::Off => "",
+            Level::Error => "E",
+            Level::Warn => "W",
+            Level::Info => "I",
+            Level::Debug => "D",
+            Level::Trace => "T",
+        }
+    }
+}
+
+/// The most verbose level compiled in. Picked with the `max_level_*`
+/// features. Defaults to Info in debug builds, and Warn in release builds.
+pub const STATIC_MAX_LEVEL: Level = if cfg!(feature = "max_level_off") {
+    Level::Off
+} else if cfg!(feature = "max_level_error") {
+    Level::Error
+} else if cfg!(feature = "max_level_warn") {
+    Level::Warn
+} else if cfg!(feature = "max_level_info") {
+    Level::Info
+} else if cfg!(feature = "max_level_debug") {
+    Level::Debug
+} else if cfg!(feature = "max_level_trace") {
+    Level::Trace
+} else if cfg!(debug_assertions) {
+    Level::Info
+} else {
+    Level::Warn
+};
+
+static MAX_LEVEL: AtomicU8 = AtomicU8::new(STATIC_MAX_LEVEL as u8);
+
+pub fn max_level() -> Level {
+    Level::from_u8(MAX_LEVEL.load(Ordering::Relaxed))
+}
+
+/// Sets the runtime level. Can't go above STATIC_MAX_LEVEL: those statements
+/// aren't in the binary.
+pub fn set_max_level(level: Level) -> Level {
+    let level = core::cmp::min(level, STATIC_MAX_LEVEL);
+    MAX_LEVEL.store(level as u8, Ordering::Relaxed);
+    level
+}
+
+#[doc(hidden)]
+pub fn log(level: Level, module_path: &'static str, args: Arguments) {
+    let module = module_path.trim_start_matches("bootloader::");
+
+    // Messages may come from interrupt handlers. Keep them from getting
+    // mixed up in the FIFO.
+    cortex_m::interrupt::free(|_| {
+        let mut uart = UartDebug;
+        let _ = uart.write_str(level.tag());
+        let _ = uart.write_str(" [");
+        let _ = uart.write_str(module);
+        let _ = uart.write_str("] ");
+        let _ = uart.write_line(args);
+    });
+}
+
+/// Formats bytes as hex digits, without the `{:x?}` Debug machinery.
+pub struct Hex<'a>(pub &'a [u8]);
+
+impl core::fmt::Display for Hex<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        for byte in self.0 {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+/// Handles the `log level [<level>]` console command. Returns false if `line`
+/// isn't a log command.
+pub fn handle_command(line: &[u8]) -> bool {
+    const PREFIX: &[u8] = b"log level";
+    if !line.starts_with(PREFIX) {
+        return false;
+    }
+
+    let mut uart = UartDebug;
+    let name = trim(&line[PREFIX.len()..]);
+    if !name.is_empty() {
+        match Level::parse(name) {
+            Some(level) => { set_max_level(level); },
+            None => {
+                let _ = uart.write_str("unknown level, expected off/error/warn/info/debug/trace\r\n");
+                return true;
+            }
+        }
+    }
+    let _ = uart.write_line(format_args!("log level {}", max_level().name()));
+    true
+}
+
+fn trim(mut s: &[u8]) -> &[u8] {
+    while let [first, rest @ ..] = s {
+        if !first.is_ascii_whitespace() {
+            break;
+        }
+        s = rest;
+    }
+    while let [rest @ .., last] = s {
+        if !last.is_ascii_whitespace() {
+            break;
+        }
+        s = rest;
+    }
+    s
+}
+
+#[macro_export]
+macro_rules! log {
+    ($level:expr, $($arg:tt)+) => {{
+        let level = $level;
+        if level <= $crate::log::STATIC_MAX_LEVEL && level <= $crate::log::max_level() {
+            $crate::log::log(level, module_path!(), format_args!($($arg)+));
+        }
+    }};
+}
+
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)+) => { $crate::log!($crate::log::Level::Error, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)+) => { $crate::log!($crate::log::Level::Warn, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)+) => { $crate::log!($crate::log::Level::Info, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)+) => { $crate::log!($crate::log::Level::Debug, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! trace {
+    ($($arg:tt)+) => { $crate::log!($crate::log::Level::Trace, $($arg)+) };
+}
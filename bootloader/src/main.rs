@@ -3,6 +3,8 @@
 #![feature(asm, const_loop, const_if_match, const_panic, const_fn)]
 #![feature(panic_info_message)]
 
+#[macro_use]
+mod log;
 mod usb_descriptors;
 mod system;
 mod lpc11uxx_misc;
@@ -11,11 +13,18 @@ mod led;
 mod programming_mode;
 mod usb_debug_uart;
 mod nrf_comms;
+mod bootloader_update;
+mod crash_log;
+mod console;
+mod sniffer;
+mod bridge;
+mod program2;
 
 use core::slice;
 use core::mem::size_of;
 
-use cortex_m_rt::{entry, exception};
+use cortex_m::peripheral::scb::SystemHandler;
+use cortex_m_rt::exception;
 use lpc11uxx_rom::iap;
 use lpc11uxx::*;
 
@@ -44,10 +53,17 @@ static mut EEPROM_CACHE: EepromData = EepromData {
     version: 0,
 };
 
-fn check_eeprom_magic() {
+/// Reads the EEPROM header into EEPROM_CACHE, as is.
+fn read_eeprom_cache() {
     unsafe {
         let eeprom_magic_ptr = slice::from_raw_parts_mut(&mut EEPROM_CACHE as *mut _ as *mut u8, size_of::<EepromData>());
         iap::eeprom_read(0, eeprom_magic_ptr, MAIN_CLOCK_FREQ / 1024);
+    }
+}
+
+fn check_eeprom_magic() {
+    read_eeprom_cache();
+    unsafe {
         if EEPROM_CACHE.magic != 0xa55a {
             EEPROM_CACHE.magic = 0xa55a;
             EEPROM_CACHE.unknown = 0;
@@ -174,12 +190,26 @@ fn setup_watchdog(syscon: &SYSCON, watchdog: &WWDT, timeout: u32) {
     watchdog_feed(watchdog);
 }
 
+/// Undoes what RTIC did to the interrupts before init, so program2 gets them
+/// the way they are out of reset.
+fn release_interrupts(nvic: &mut NVIC, scb: &mut SCB) {
+    for &interrupt in &[Interrupt::USART, Interrupt::USB_IRQ, Interrupt::CT32B1] {
+        NVIC::mask(interrupt);
+        NVIC::unpend(interrupt);
+        unsafe { nvic.set_priority(interrupt, 0) };
+    }
+    unsafe { scb.set_priority(SystemHandler::PendSV, 0) };
+    SCB::clear_pendsv();
+
+    // RTIC runs init with interrupts disabled.
+    unsafe { cortex_m::interrupt::enable() };
+}
+
 fn start_program2() -> ! {
     // ASM is slightly different for efficiency's sake.
     unsafe {
         asm!("
-            // Get program2 vector table
-            ldr r0, =0x2000
+            // r0 holds program2's vector table
 
             // Load MSP with program2 master stack pointer
             ldr r1, [r0]
@@ -192,7 +222,7 @@ fn start_program2() -> ! {
             test:
             wfi
             b test
-        ");
+        ", in("r0") program2::PROGRAM2_BASE);
     }
     // We shouldn't end up here
     loop {
@@ -200,11 +230,9 @@ fn start_program2() -> ! {
     }
 }
 
-#[entry]
-fn main() -> ! {
-    let mut peripherals = Peripherals::take().unwrap();
-    let core_peripherals = CorePeripherals::take().unwrap();
-
+/// Decides what to boot. Only returns if we should stay in programming mode,
+/// otherwise starts program2.
+fn boot(nvic: &mut NVIC, scb: &mut SCB, peripherals: &mut Peripherals) {
     // Clock setup, and flashctrl init.
     system::initialize(&mut peripherals.SYSCON, &mut peripherals.FLASHCTRL);
 
@@ -214,6 +242,9 @@ fn main() -> ! {
     // Check that the EEPROM Magic is correct, set it to the right value otherwise.
     check_eeprom_magic();
 
+    // Program2 leaves its panics in the PMU, and resets.
+    crash_log::record_program2_panic(&peripherals.PMU);
+
     // Enable GPIO clock
     peripherals
         .SYSCON
@@ -226,6 +257,7 @@ fn main() -> ! {
     if !usb_disconnected && peripherals.SYSCON.sysrststat.read().bod().bit_is_set() {
         peripherals.SYSCON.sysrststat.write_with_zero(|f| f.bod().reset_clear());
         set_battery_power(&mut peripherals.GPIO_PORT, false);
+        release_interrupts(nvic, scb);
         loop {
             cortex_m::asm::wfi();
         }
@@ -265,7 +297,8 @@ fn main() -> ! {
 
     if peripherals.PMU.gpreg[0].read().bits() == 0xecaabac0 {
         peripherals.PMU.gpreg[0].write(|v| unsafe { v.gpdata().bits(0) });
-    } else if unsafe { *(0x2024 as *const u32) == 0xecaabac0 && EEPROM_CACHE.version != 0 } {
+    } else if unsafe { *((program2::PROGRAM2_BASE + 9 * 4) as *const u32) == 0xecaabac0 && EEPROM_CACHE.version != 0 }
+        && program2::entry_points_valid() {
         enter_programming_mode_on_reboot(&mut peripherals.PMU, false);
 
         // Enable RAM1 clock before jumping to program2.
@@ -274,219 +307,114 @@ fn main() -> ! {
             .sysahbclkctrl
             .modify(|_, writer| writer.ram1().enabled());
 
+        release_interrupts(nvic, scb);
         start_program2();
     }
-
-    programming_mode::enter_programming_mode(core_peripherals, peripherals);
-}
-
-#[exception]
-fn NonMaskableInt() {
-    let program2_hdlr = unsafe { *(0x2008 as *const extern fn()) };
-    program2_hdlr();
 }
 
-#[exception]
-fn HardFault(_frame: &cortex_m_rt::ExceptionFrame) -> ! {
-    let program2_hdlr = unsafe { *(0x200c as *const extern fn()) };
-    program2_hdlr();
-    loop {
-        cortex_m::asm::wfi();
+/// Programming mode runs on RTIC. Higher priorities preempt lower ones:
+///
+/// - USART (4): moves bytes between the nRF and the USART queues. Nothing
+///   should delay it, the RX FIFO is only 16 bytes deep.
+/// - USB_IRQ (3): the ROM USB stack, and the HID and CDC callbacks.
+/// - CT32B1 (2): the 12ms tick.
+/// - PendSV (1): handles the packets received from the nRF.
+/// - idle: everything else, see programming_mode::poll.
+///
+/// The bound interrupts still belong to program2 once it runs, so each task
+/// first checks whether it should forward to program2's handler.
+#[rtic::app(device = lpc11uxx, peripherals = true)]
+const APP: () = {
+    struct Resources {
+        usart: USART,
+        ct32b1: CT32B1,
+        usb: USB,
+        syst: SYST,
+        reports: programming_mode::FeatureReports,
+        #[init(false)]
+        timer_elapsed: bool,
     }
-}
-
-#[exception]
-fn SVCall() {
-    let program2_hdlr = unsafe { *(0x202c as *const extern fn()) };
-    program2_hdlr();
-}
 
-#[exception]
-fn PendSV() {
-    // TODO
-    let peripherals = unsafe { Peripherals::steal() };
-
-    if peripherals.PMU.gpreg[1].read().bits() == 0 {
-        programming_mode::PendSV();
-    } else {
-        let program2_hdlr = unsafe { *(0x2038 as *const extern fn()) };
-        program2_hdlr();
+    #[init]
+    fn init(cx: init::Context) -> init::LateResources {
+        let mut core = cx.core;
+        let mut device = cx.device;
+
+        boot(&mut core.NVIC, &mut core.SCB, &mut device);
+        programming_mode::init(&mut device);
+
+        init::LateResources {
+            usart: device.USART,
+            ct32b1: device.CT32B1,
+            usb: device.USB,
+            syst: core.SYST,
+            reports: programming_mode::FeatureReports::new(
+                device.SYSCON, device.WWDT, device.FLASHCTRL, device.CT16B1),
+        }
     }
-}
 
-#[exception]
-fn SysTick() {
-    let program2_hdlr = unsafe { *(0x203c as *const extern fn()) };
-    program2_hdlr();
-}
-
-#[interrupt]
-fn PIN_INT0() {
-    let program2_hdlr = unsafe { *(0x2040 as *const extern fn()) };
-    program2_hdlr();
-}
-
-#[interrupt]
-fn PIN_INT1() {
-    let program2_hdlr = unsafe { *(0x2044 as *const extern fn()) };
-    program2_hdlr();
-}
-
-#[interrupt]
-fn PIN_INT2() {
-    let program2_hdlr = unsafe { *(0x2048 as *const extern fn()) };
-    program2_hdlr();
-}
-
-#[interrupt]
-fn PIN_INT3() {
-    let program2_hdlr = unsafe { *(0x204c as *const extern fn()) };
-    program2_hdlr();
-}
-
-#[interrupt]
-fn PIN_INT4() {
-    let program2_hdlr = unsafe { *(0x2050 as *const extern fn()) };
-    program2_hdlr();
-}
-
-#[interrupt]
-fn PIN_INT5() {
-    let program2_hdlr = unsafe { *(0x2054 as *const extern fn()) };
-    program2_hdlr();
-}
+    #[idle(resources = [timer_elapsed, reports, syst])]
+    fn idle(mut cx: idle::Context) -> ! {
+        static mut HEARTBEAT_PACKET: [u8; 0x10] = programming_mode::HEARTBEAT_PACKET;
 
-#[interrupt]
-fn PIN_INT6() {
-    let program2_hdlr = unsafe { *(0x2058 as *const extern fn()) };
-    program2_hdlr();
-}
-
-#[interrupt]
-fn PIN_INT7() {
-    let program2_hdlr = unsafe { *(0x205c as *const extern fn()) };
-    program2_hdlr();
-}
-
-#[interrupt]
-fn GINT0() {
-    let program2_hdlr = unsafe { *(0x2060 as *const extern fn()) };
-    program2_hdlr();
-}
-
-#[interrupt]
-fn GINT1() {
-    let program2_hdlr = unsafe { *(0x2064 as *const extern fn()) };
-    program2_hdlr();
-}
-
-#[interrupt]
-fn SSP1() {
-    let program2_hdlr = unsafe { *(0x2078 as *const extern fn()) };
-    program2_hdlr();
-}
-
-#[interrupt]
-fn I2C() {
-    let program2_hdlr = unsafe { *(0x207c as *const extern fn()) };
-    program2_hdlr();
-}
-
-#[interrupt]
-fn CT16B0() {
-    let program2_hdlr = unsafe { *(0x2080 as *const extern fn()) };
-    program2_hdlr();
-}
-
-#[interrupt]
-fn CT16B1() {
-    let program2_hdlr = unsafe { *(0x2084 as *const extern fn()) };
-    program2_hdlr();
-}
-
-#[interrupt]
-fn CT32B0() {
-    let program2_hdlr = unsafe { *(0x2088 as *const extern fn()) };
-    program2_hdlr();
-}
+        loop {
+            let timer_elapsed = cx.resources.timer_elapsed.lock(|elapsed| core::mem::replace(elapsed, false));
+            programming_mode::poll(&mut cx.resources.reports, cx.resources.syst, HEARTBEAT_PACKET, timer_elapsed);
+            cortex_m::asm::wfi();
+        }
+    }
 
-#[interrupt]
-fn CT32B1() {
-    // TODO
-    let peripherals = unsafe { Peripherals::steal() };
+    #[task(binds = PendSV, priority = 1, resources = [reports])]
+    fn pendsv(mut cx: pendsv::Context) {
+        if program2::owns_interrupts() {
+            return program2::forward(program2::PENDSV);
+        }
 
-    if peripherals.PMU.gpreg[1].read().bits() == 0 {
-        programming_mode::CT32B1();
-    } else {
-        let program2_hdlr = unsafe { *(0x208c as *const extern fn()) };
-        program2_hdlr();
+        programming_mode::PendSV(&mut cx.resources.reports);
     }
-}
 
-#[interrupt]
-fn SSP0() {
-    let program2_hdlr = unsafe { *(0x2090 as *const extern fn()) };
-    program2_hdlr();
-}
-
-#[interrupt]
-fn USART() {
-    // TODO
-    let peripherals = unsafe { Peripherals::steal() };
+    #[task(binds = CT32B1, priority = 2, resources = [ct32b1, timer_elapsed])]
+    fn ct32b1(cx: ct32b1::Context) {
+        if program2::owns_interrupts() {
+            return program2::forward_interrupt(Interrupt::CT32B1);
+        }
 
-    if peripherals.PMU.gpreg[1].read().bits() == 0 {
-        programming_mode::USART();
-    } else {
-        let program2_hdlr = unsafe { *(0x2094 as *const extern fn()) };
-        program2_hdlr();
+        programming_mode::CT32B1(cx.resources.ct32b1);
+        *cx.resources.timer_elapsed = true;
     }
-}
 
-#[interrupt]
-fn USB_IRQ() {
-    // TODO
-    let peripherals = unsafe { Peripherals::steal() };
+    #[task(binds = USB_IRQ, priority = 3, resources = [usb, reports])]
+    fn usb_irq(cx: usb_irq::Context) {
+        if program2::owns_interrupts() {
+            return program2::forward_interrupt(Interrupt::USB_IRQ);
+        }
 
-    if peripherals.PMU.gpreg[1].read().bits() == 0 {
-        programming_mode::USB_IRQ();
-    } else {
-        let program2_hdlr = unsafe { *(0x2098 as *const extern fn()) };
-        program2_hdlr();
+        programming_mode::USB_IRQ(cx.resources.usb, Some(cx.resources.reports));
     }
-}
 
-#[interrupt]
-fn USB_FIQ() {
-    let program2_hdlr = unsafe { *(0x209c as *const extern fn()) };
-    program2_hdlr();
-}
+    #[task(binds = USART, priority = 4, resources = [usart])]
+    fn usart(cx: usart::Context) {
+        if program2::owns_interrupts() {
+            return program2::forward_interrupt(Interrupt::USART);
+        }
 
-#[interrupt]
-fn ADC() {
-    let program2_hdlr = unsafe { *(0x20a0 as *const extern fn()) };
-    program2_hdlr();
-}
+        nrf_comms::handle_interrupt(cx.resources.usart);
+    }
+};
 
-#[interrupt]
-fn WDT() {
-    let program2_hdlr = unsafe { *(0x20a4 as *const extern fn()) };
-    program2_hdlr();
+/// Everything without a handler of its own ends up here, and belongs to
+/// program2.
+#[exception]
+fn DefaultHandler(irqn: i16) {
+    program2::forward((irqn + 16) as usize);
 }
 
-#[interrupt]
-fn BOD_IRQ() {
-    let program2_hdlr = unsafe { *(0x20a8 as *const extern fn()) };
-    program2_hdlr();
-}
+#[exception]
+fn HardFault(frame: &cortex_m_rt::ExceptionFrame) -> ! {
+    crash_log::record_hardfault(frame);
 
-#[interrupt]
-fn FLASH_IRQ() {
-    let program2_hdlr = unsafe { *(0x20ac as *const extern fn()) };
-    program2_hdlr();
+    program2::forward(program2::HARDFAULT);
+    loop {
+        cortex_m::asm::wfi();
+    }
 }
-
-#[interrupt]
-fn USBWAKEUP() {
-    let program2_hdlr = unsafe { *(0x20b8 as *const extern fn()) };
-    program2_hdlr();
-}
\ No newline at end of file
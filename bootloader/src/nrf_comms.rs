@@ -1,32 +1,57 @@
-use heapless::spsc::Queue;
-use lpc11uxx::{Peripherals, Interrupt, SCB, USART, SYSCON, WWDT, NVIC};
-//use cortex_m::peripheral::NVIC;
-use cortex_m::peripheral::scb::SystemHandler;
-
-static mut USART_WRITE_RING_BUFFER: Queue<u8, heapless::consts::U256> = Queue(heapless::i::Queue::new());
-static mut USART_READ_STATE: UsartReadState = UsartReadState::NoData;
-static mut USART_READ_PACKET_LEN: u8 = 0;
-static mut USART_READ_PACKET: [u8; 256] = [0; 256];
+use heapless::consts::{U4, U64, U256};
+use heapless::spsc::{Consumer, Producer, Queue};
+use heapless::Vec;
+use lpc11uxx::{Peripherals, SCB, USART, SYSCON};
+use nrf_protocol::framing::{self, DecodeError, Decoder, MAX_PACKET_LEN};
+use nrf_protocol::message::Message;
+use nrf_protocol::UART_BAUD_RATE;
+use rtic::Mutex;
+use static_assertions::{const_assert, const_assert_eq};
+
+use crate::programming_mode::FeatureReports;
+use crate::sniffer::Direction;
+
+type RxPacket = Vec<u8, U256>;
+const_assert_eq!(MAX_PACKET_LEN, 256);
+
+// Bytes to send to the nRF, and packets received from it. Both are single
+// producer, single consumer queues, split in init_usart. Each endpoint belongs
+// to a single context:
+//
+// - TX producer: usart_send_raw_str, which runs with interrupts disabled.
+// - TX consumer: the USART interrupt. usart_send_raw_str borrows it to start
+//   the transmission, with the THRE interrupt disabled.
+// - RX producer: the USART interrupt.
+// - RX consumer: PendSV.
+//
+// The RX queue holds several packets, so a packet arriving while PendSV still
+// handles the previous one isn't lost.
+static mut USART_TX_QUEUE: Queue<u8, U256> = Queue(heapless::i::Queue::new());
+static mut USART_TX_PRODUCER: Option<Producer<'static, u8, U256>> = None;
+static mut USART_TX_CONSUMER: Option<Consumer<'static, u8, U256>> = None;
+
+static mut USART_RX_QUEUE: Queue<RxPacket, U4> = Queue(heapless::i::Queue::new());
+static mut USART_RX_PRODUCER: Option<Producer<'static, RxPacket, U4>> = None;
+static mut USART_RX_CONSUMER: Option<Consumer<'static, RxPacket, U4>> = None;
+
+/// Only used from the USART interrupt.
+static mut USART_DECODER: Decoder = Decoder::new();
 static mut SHOULD_SEND_USART_PACKET: bool = false;
 
-static mut USART_READ_PENDSV_PACKET_LEN: u8 = 0;
-static mut USART_READ_PENDSV_PACKET: [u8; 256] = [0; 256];
+/// Sets up the USART. Its interrupt, and PendSV, are unmasked by RTIC.
+pub fn init_usart(syscon: &SYSCON, usart: &USART) {
+    unsafe {
+        USART_DECODER.reset();
 
-#[derive(Debug, Clone, Copy)]
-enum UsartReadState {
-    NoData,
-    HandlingFrame,
-    EscapedCharacter,
-    PacketSubmitted,
-}
+        let (producer, consumer) = USART_TX_QUEUE.split();
+        USART_TX_PRODUCER = Some(producer);
+        USART_TX_CONSUMER = Some(consumer);
 
-pub fn init_usart(syscon: &SYSCON, usart: &USART, nvic: &mut NVIC, scb: &mut SCB) {
-    unsafe {
-        USART_READ_STATE = UsartReadState::NoData;
+        let (producer, consumer) = USART_RX_QUEUE.split();
+        USART_RX_PRODUCER = Some(producer);
+        USART_RX_CONSUMER = Some(consumer);
     }
 
-    // RingBuffer_Init is unnecessary - we're using ArrayDeque
-
     // Chip_UART_Init
     syscon.sysahbclkctrl.modify(|_, writer| writer.usart().enabled());
     syscon.uartclkdiv.write(|v| unsafe { v.div().bits(1) });
@@ -44,30 +69,50 @@ pub fn init_usart(syscon: &SYSCON, usart: &USART, nvic: &mut NVIC, scb: &mut SCB
         .fifoen().enabled()
         .rxtl().level2());
 
+    let pclk = crate::lpc11uxx_misc::get_main_clock_rate(syscon);
+    match fractional_baud::solve(pclk, UART_BAUD_RATE) {
+        Some(solution) => {
+            debug!("nrf uart at {} baud, {} ppm off", solution.baud_rate, solution.error_ppm);
+            write_divisors(usart, &solution.divisors);
+        },
+        None => error!("can't reach {} baud from a {} Hz clock", UART_BAUD_RATE, pclk),
+    }
+
+    usart.ier_mut().modify(|_, v| v
+        .rbrinten().enable_the_rda_inter()
+        .rlsinten().enable_the_rls_inter());
+}
+
+/// Programs the baud rate divisors, keeping the rest of LCR as is. The UART
+/// clock divider is set to 1 in init_usart, so PCLK is the main clock.
+fn write_divisors(usart: &USART, divisors: &fractional_baud::Divisors) {
     // Enable access to the divisor registers.
     usart.lcr.modify(|_, v| v.dlab().enable_access_to_div());
 
-    // Set the USART divisor latch to 3
-    usart.dll().write(|v| unsafe { v.dllsb().bits(3) });
-
+    usart.dll().write(|v| unsafe { v.bits(u32::from(divisors.dll())) });
+    usart.dlm().write(|v| unsafe { v.bits(u32::from(divisors.dlm())) });
     usart.fdr.write(|v| unsafe {
         v
-            .divaddval().bits(1)
-            .mulval().bits(11)
+            .divaddval().bits(divisors.divaddval)
+            .mulval().bits(divisors.mulval)
     });
 
     // Disable access to the divisor registers, restore access to USART read/write registers.
     usart.lcr.modify(|_, v| v.dlab().disable_access_to_di());
+}
 
-    unsafe { nvic.enable(Interrupt::USART) };
-    usart.ier_mut().modify(|_, v| v
-        .rbrinten().enable_the_rda_inter()
-        .rlsinten().enable_the_rls_inter());
-
-    // TODO: Make sure those priority numbers are correct. NVIC_SetPriority does
-    // fancy bit shifting I don't fully understand at 3AM.
-    unsafe { nvic.set_priority(Interrupt::USART, 0) };
-    unsafe { scb.set_priority(SystemHandler::PendSV, 1) };
+/// Reprograms the USART line settings. `lcr` holds the word length, stop bits
+/// and parity fields of the LCR register. Rates that can't be reached at all
+/// leave the divisors untouched.
+pub fn configure_usart_line(usart: &USART, baud: u32, lcr: u8) {
+    let pclk = unsafe { crate::MAIN_CLOCK_FREQ };
+    let solution = match fractional_baud::solve(pclk, baud) {
+        Some(solution) => solution,
+        None => return,
+    };
+
+    usart.lcr.write(|v| unsafe { v.bits(u32::from(lcr)) });
+    write_divisors(usart, &solution.divisors);
 }
 
 pub fn handle_interrupt(mut usart: &mut USART) {
@@ -94,78 +139,106 @@ pub fn handle_interrupt(mut usart: &mut USART) {
     }
 
     if usart.ier().read().threinten().bit_is_set() {
-        while usart.lsr.read().thre().bit_is_set() {
-            if let Some(val) = unsafe { USART_WRITE_RING_BUFFER.dequeue() } {
-                usart.thr_mut().write(|v| unsafe { v.thr().bits(val) });
-            } else {
-                break;
+        if let Some(consumer) = unsafe { USART_TX_CONSUMER.as_mut() } {
+            while usart.lsr.read().thre().bit_is_set() {
+                if let Some(val) = consumer.dequeue() {
+                    usart.thr_mut().write(|v| unsafe { v.thr().bits(val) });
+                } else {
+                    break;
+                }
+            }
+            if !consumer.ready() {
+                usart.ier_mut().modify(|_, v| v.threinten().clear_bit());
             }
         }
-        if unsafe { USART_WRITE_RING_BUFFER.is_empty() } {
-            usart.ier_mut().modify(|_, v| v.threinten().clear_bit());
+    }
+}
+
+/// A feature report to send back to the nRF.
+type Reply = Vec<u8, U64>;
+// Even with every byte escaped, a reply fits in the empty TX queue.
+const_assert!(2 * (1 + 64) + 2 <= 256);
+
+/// A reply the TX queue had no room for yet. Set by PendSV, sent by whichever
+/// of PendSV and the main loop finds room for it first.
+static mut PENDING_REPLY: Option<Reply> = None;
+
+/// Handles the packets received from the nRF. The feature reports are only
+/// locked one packet at a time, to keep USB going in between.
+///
+/// On the Cortex-M0, locking masks every interrupt, the USART one included.
+/// The reply is copied out of the feature reports and sent once the lock is
+/// released, so the USART can drain the TX queue for it.
+pub fn handle_pendsv(reports: &mut impl Mutex<T = FeatureReports>) {
+    let consumer = match unsafe { USART_RX_CONSUMER.as_mut() } {
+        Some(consumer) => consumer,
+        None => return,
+    };
+
+    while let Some(packet) = consumer.dequeue() {
+        if let Some(reply) = reports.lock(|reports| handle_packet(reports, &packet)) {
+            usart_send_reply(reply);
         }
     }
 }
 
-pub fn handle_pendsv(wwdt: &WWDT, syscon: &SYSCON) {
-    match unsafe { USART_READ_PENDSV_PACKET[0] } {
-        b'P' => unsafe {
-            let size = crate::programming_mode::hid_handle_set_feature_report(wwdt, syscon, &USART_READ_PENDSV_PACKET[1..USART_READ_PENDSV_PACKET_LEN as usize]);
+fn handle_packet(reports: &mut FeatureReports, packet: &[u8]) -> Option<Reply> {
+    match Message::parse(packet) {
+        Message::HidReport(report) => {
+            let size = crate::programming_mode::hid_handle_set_feature_report(reports, report);
             if size != 0 {
-                usart_send_hid_report(size);
+                return Reply::from_slice(&reports.packet()[..size]).ok();
             }
         },
-        b'R' => unsafe {
+        Message::Start => unsafe {
             SHOULD_SEND_USART_PACKET = true;
         },
-        b'S' => unsafe {
+        Message::Stop => unsafe {
             SHOULD_SEND_USART_PACKET = false;
         },
-        b'[' => unsafe {
-            let size = crate::programming_mode::write_report_0x94(USART_READ_PENDSV_PACKET[1] as u16);
-            usart_send_hid_report(size);
+        Message::Signature(status) => {
+            let size = crate::programming_mode::write_report_0x94(reports, status.get(0).copied().unwrap_or(0) as u16);
+            return Reply::from_slice(&reports.packet()[..size]).ok();
+        },
+        // Figure out what's up with packet W? Why is it ignored?
+        Message::Unexplained(_) => (),
+        Message::Unknown(packet) => {
+            warn!("unknown nRF packet: {}", crate::log::Hex(packet));
+        },
+        message => {
+            warn!("unexpected nRF packet: {}", crate::log::Hex(message.tag()));
         },
-        _ => ()
     }
-    unsafe { USART_READ_STATE = UsartReadState::NoData; }
+    None
 }
 
 pub fn handle_usart_byte(data: u8) {
-    unsafe {
-        match (USART_READ_STATE, data) {
-            (UsartReadState::PacketSubmitted, _) => (),
-            (UsartReadState::NoData, 0x02) => {
-                USART_READ_PACKET_LEN = 0;
-                USART_READ_STATE = UsartReadState::HandlingFrame;
-            },
-            (UsartReadState::NoData, _) => (),
-            (UsartReadState::EscapedCharacter, _) => {
-                USART_READ_PACKET[USART_READ_PACKET_LEN as usize] = data ^ 0x20;
-                USART_READ_PACKET_LEN = USART_READ_PACKET_LEN.wrapping_add(1);
-            },
-            (UsartReadState::HandlingFrame, 0x1f) => {
-                USART_READ_STATE = UsartReadState::EscapedCharacter;
-            },
-            (UsartReadState::HandlingFrame, 0x03) => {
-                // Figure out what's up with packet W? Why is it ignored?
-                if USART_READ_PACKET_LEN != 0 {
-                    if USART_READ_PACKET[0] != 0x57 {
-                        let packet_len = USART_READ_PACKET_LEN as usize;
-                        USART_READ_PENDSV_PACKET[..packet_len].copy_from_slice(&USART_READ_PACKET[..packet_len]);
-                        USART_READ_PENDSV_PACKET_LEN = USART_READ_PACKET_LEN;
-                        SCB::set_pendsv();
-                        USART_READ_PACKET_LEN = 0;
-                        USART_READ_STATE = UsartReadState::PacketSubmitted;
-                        return;
-                    }
-                    USART_READ_PACKET_LEN = 0;
-                    USART_READ_STATE = UsartReadState::NoData;
-                }
-            }
-            (UsartReadState::HandlingFrame, _) => {
-                USART_READ_PACKET[USART_READ_PACKET_LEN as usize] = data;
-                USART_READ_PACKET_LEN = USART_READ_PACKET_LEN.wrapping_add(1);
+    if crate::bridge::active() {
+        crate::bridge::handle_usart_byte(data);
+        return;
+    }
+
+    let producer = match unsafe { USART_RX_PRODUCER.as_mut() } {
+        Some(producer) => producer,
+        None => return,
+    };
+
+    match unsafe { USART_DECODER.push(data) } {
+        None => (),
+        Some(Ok(packet)) => {
+            crate::sniffer::mirror(Direction::FromNrf, &[packet]);
+            // Can't fail, packets are at most MAX_PACKET_LEN long.
+            let packet = RxPacket::from_slice(packet).unwrap_or_default();
+            if producer.enqueue(packet).is_err() {
+                warn!("dropped nRF packet: queue full");
             }
+            SCB::set_pendsv();
+        },
+        Some(Err(err)) => {
+            warn!("dropped nRF frame: {}", match err {
+                DecodeError::Overflow => "too long",
+                DecodeError::DanglingEscape => "ends with an escape",
+            });
         }
     }
 }
@@ -176,122 +249,140 @@ fn handle_usart_data(usart: &mut USART) {
 }
 
 
-fn ring_buffer_insert_mult(ring_buffer: &mut Queue<u8, heapless::consts::U256>, data: &[u8]) -> usize {
-    let old_len = ring_buffer.len();
+fn ring_buffer_insert_mult(producer: &mut Producer<'static, u8, U256>, data: &[u8]) -> usize {
     // TODO: Please tell me this turns into a simple memcpy...
-    for item in data {
-        if ring_buffer.enqueue(*item).is_err() {
-            break;
-        }
-    }
-    ring_buffer.len() - old_len
+    data.iter().take_while(|&&item| producer.enqueue(item).is_ok()).count()
 }
 
 
-fn usart_send_raw_str(data: &[u8]) -> usize {
-    let peripherals = unsafe { Peripherals::steal() };
-
-    // First, disable send interrupts.
-    peripherals.USART.ier_mut().modify(|_, v| v.threinten().disable_the_thre_int());
+/// The TX ring buffer didn't have room for a whole frame, and nothing was
+/// sent. Try again once the USART drained it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TxQueueFull;
 
-    // Insert data to the ring buffer
-    let mut inserted_len = ring_buffer_insert_mult(unsafe { &mut USART_WRITE_RING_BUFFER }, data);
+/// Whether the TX ring buffer has room for at least one more byte.
+pub fn usart_tx_ready() -> bool {
+    cortex_m::interrupt::free(|_| unsafe { USART_TX_PRODUCER.as_ref() }
+        .map_or(false, |producer| producer.ready()))
+}
 
-    // Send the contents of the ring buffer
-    while peripherals.USART.lsr.read().thre().is_empty() {
-        if let Some(val) = unsafe { USART_WRITE_RING_BUFFER .dequeue() } {
-            peripherals.USART.thr_mut().write(|v| unsafe { v.thr().bits(val) });
-        } else {
-            break;
+/// Sends bytes as-is, without framing. Returns how many fit in the TX ring
+/// buffer.
+pub fn usart_send_raw_str(data: &[u8]) -> usize {
+    cortex_m::interrupt::free(|_| {
+        let peripherals = unsafe { Peripherals::steal() };
+        let (producer, consumer) = match unsafe { (USART_TX_PRODUCER.as_mut(), USART_TX_CONSUMER.as_mut()) } {
+            (Some(producer), Some(consumer)) => (producer, consumer),
+            _ => return 0,
+        };
+
+        // First, disable send interrupts.
+        peripherals.USART.ier_mut().modify(|_, v| v.threinten().disable_the_thre_int());
+
+        // Insert data to the ring buffer
+        let mut inserted_len = ring_buffer_insert_mult(producer, data);
+
+        // Send the contents of the ring buffer
+        while peripherals.USART.lsr.read().thre().is_empty() {
+            if let Some(val) = consumer.dequeue() {
+                peripherals.USART.thr_mut().write(|v| unsafe { v.thr().bits(val) });
+            } else {
+                break;
+            }
         }
-    }
 
-    // Try to insert some more contents in the ring buffer.
-    inserted_len += ring_buffer_insert_mult(unsafe { &mut USART_WRITE_RING_BUFFER }, &data[inserted_len..]);
+        // Try to insert some more contents in the ring buffer.
+        inserted_len += ring_buffer_insert_mult(producer, &data[inserted_len..]);
 
-    // Re-enable send interrupts
-    peripherals.USART.ier_mut().modify(|_, v| v.threinten().enable_the_thre_inte());
+        // Re-enable send interrupts
+        peripherals.USART.ier_mut().modify(|_, v| v.threinten().enable_the_thre_inte());
 
-    inserted_len
-}
-
-fn usart_send_02() {
-    usart_send_raw_str(b"\x02");
+        inserted_len
+    })
 }
 
-// TODO: Write unit tests for this function cuz it's almost guaranteed to be wrong lol.
-fn usart_send_escaped_str(mut data: &[u8]) {
-    while let Some(pos) = data.iter().position(|&v| v == 0x02 || v == 0x03 || v == 0x1f) {
-        if pos != 0 {
-            usart_send_raw_str(&data[..pos]);
-        }
-        usart_send_raw_str(&[0x1fu8, data[pos] ^ 0x20]);
-        data = &data[pos + 1..];
-    }
-
-    if !data.is_empty() {
-        usart_send_raw_str(data);
+/// Frames and sends a message. Does nothing in bridge mode: the host owns the
+/// link then.
+///
+/// The frame is queued whole or not at all: half a frame would make the nRF
+/// drop the next one too.
+pub fn usart_send_message(message: &Message) -> Result<(), TxQueueFull> {
+    if crate::bridge::active() {
+        return Ok(());
     }
-}
-
-fn usart_send_03() {
-    usart_send_raw_str(b"\x03");
-}
 
-pub fn usart_send_text_transmission(data: &[u8]) {
-    // Replace disable_irq/enable_irq pairs with cortex_m::interrupt::free
+    let parts = [message.tag(), message.payload()];
     cortex_m::interrupt::free(|_| {
-        usart_send_02();
-        usart_send_escaped_str(data);
-        usart_send_03();
-    })
+        let free = match unsafe { USART_TX_PRODUCER.as_ref() } {
+            Some(producer) => producer.capacity() - producer.len(),
+            None => 0,
+        };
+        if framing::encoded_len(&parts) > free {
+            return Err(TxQueueFull);
+        }
+
+        message.encode(|data| { usart_send_raw_str(data); });
+        Ok(())
+    })?;
+    crate::sniffer::mirror(Direction::ToNrf, &parts);
+    Ok(())
 }
 
 #[allow(non_snake_case)]
-pub fn usart_send_R() {
-    usart_send_text_transmission(b"R");
+pub fn usart_send_R() -> Result<(), TxQueueFull> {
+    usart_send_message(&Message::Start)
 }
 
 /// Ends an NRF firmware upload, and sends a checksum to verify there wasn't any
 /// corruption on the wire.
-pub fn usart_send_sig_packet(data: &[u8]) {
-    cortex_m::interrupt::free(|_| {
-        usart_send_02();
-        usart_send_escaped_str(b"[");
-        usart_send_escaped_str(&data[..0x10]);
-        usart_send_03();
-    })
+pub fn usart_send_sig_packet(data: &[u8]) -> Result<(), TxQueueFull> {
+    usart_send_message(&Message::Signature(&data[..0x10]))
 }
 
-pub fn usart_send_z_packet(data: &[u8]) {
-    cortex_m::interrupt::free(|_| {
-        usart_send_02();
-        usart_send_escaped_str(b"Z");
-        usart_send_escaped_str(data);
-        usart_send_03();
-    })
+pub fn usart_send_z_packet(data: &[u8]) -> Result<(), TxQueueFull> {
+    usart_send_message(&Message::FirmwareChunk(data))
 }
 
-pub fn usart_send_reset() {
-    usart_send_text_transmission(b"\\RESET");
+pub fn usart_send_reset() -> Result<(), TxQueueFull> {
+    usart_send_message(&Message::Reset)
 }
 
+/// Heartbeats are sent again on the next tick, one that doesn't fit is
+/// dropped.
 #[allow(non_snake_case)]
 pub fn usart_send_V_packet(data: &[u8]) {
     if unsafe { SHOULD_SEND_USART_PACKET } {
-        cortex_m::interrupt::free(|_v| {
-            usart_send_02();
-            usart_send_escaped_str(b"V");
-            let len = usize::from(data[3]);
-            usart_send_escaped_str(&data[2..2 + len]);
-            usart_send_03();
-        });
+        let len = usize::from(data[3]);
+        let _ = usart_send_message(&Message::Heartbeat(&data[2..2 + len]));
     }
 }
 
-pub fn usart_send_hid_report(report_size: usize) {
-    let mut data = [0; 0x48];
-    data[0] = b'P';
-    crate::programming_mode::copy_hid_report(&mut data[1..]);
-    usart_send_text_transmission(&data[..1 + report_size])
+pub fn usart_send_hid_report(report: &[u8]) -> Result<(), TxQueueFull> {
+    usart_send_message(&Message::HidReport(report))
+}
+
+/// Answers the nRF. If the TX ring buffer is full, the reply waits for
+/// flush_reply. The nRF only waits for its latest request, so a reply still
+/// pending by then is dropped.
+fn usart_send_reply(reply: Reply) {
+    flush_reply();
+    cortex_m::interrupt::free(|_| unsafe {
+        if PENDING_REPLY.is_some() {
+            warn!("dropped nRF reply: TX queue full");
+        }
+        PENDING_REPLY = Some(reply);
+    });
+    flush_reply();
+}
+
+/// Sends the pending reply to the nRF, if the TX ring buffer has room for it
+/// now. Called from the main loop.
+pub fn flush_reply() {
+    cortex_m::interrupt::free(|_| unsafe {
+        if let Some(reply) = PENDING_REPLY.as_ref() {
+            if usart_send_hid_report(reply).is_ok() {
+                PENDING_REPLY = None;
+            }
+        }
+    })
 }
\ No newline at end of file
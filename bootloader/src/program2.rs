@@ -0,0 +1,101 @@
+//! Forwarding of exceptions and interrupts to program2.
+//!
+//! The Cortex-M0 has no VTOR, so the vector table at address 0 is always the
+//! bootloader's. Once program2 runs, every handler of the bootloader instead
+//! looks up program2's vector table at PROGRAM2_BASE, and calls the handler
+//! found there.
+//!
+//! A program2 linked for another address would crash in confusing ways, so
+//! its entry points and handlers are checked before use.
+//!
+//! Exceptions are numbered like in IPSR: 16 and up are interrupts, 16 being
+//! IRQ 0.
+
+use cortex_m::interrupt::Nr;
+use lpc11uxx::{CorePeripherals, Peripherals, NVIC, SCB};
+
+/// Where program2's vector table, and program2 itself, start.
+pub const PROGRAM2_BASE: usize = 0x2000;
+/// End of the flash program2 lives in.
+pub const PROGRAM2_END: usize = 0x2_0000;
+/// The main SRAM, where program2's initial stack pointer must point.
+const RAM_START: usize = 0x1000_0000;
+const RAM_END: usize = 0x1000_2000;
+
+pub const HARDFAULT: usize = 3;
+pub const PENDSV: usize = 14;
+const SYSTICK: usize = 15;
+const IRQ0: usize = 16;
+/// The NVIC of the Cortex-M0 has 32 interrupts.
+const IRQ_LAST: usize = IRQ0 + 31;
+
+/// Whether program2 is running, and should get the interrupts.
+///
+/// Must not touch RAM: it belongs to program2 then.
+pub fn owns_interrupts() -> bool {
+    let peripherals = unsafe { Peripherals::steal() };
+    peripherals.PMU.gpreg[1].read().bits() != 0
+}
+
+fn vector(index: usize) -> usize {
+    unsafe { *((PROGRAM2_BASE + index * 4) as *const usize) }
+}
+
+fn is_program2_code(vector: usize) -> bool {
+    let address = vector & !1;
+    vector & 1 != 0 && address >= PROGRAM2_BASE && address < PROGRAM2_END
+}
+
+/// Checks the initial stack pointer and reset vector of program2, so a
+/// program2 linked for another address doesn't get started.
+pub fn entry_points_valid() -> bool {
+    let sp = vector(0);
+    let reset = vector(1);
+
+    if sp % 4 != 0 || sp <= RAM_START || sp > RAM_END {
+        warn!("program2 initial stack pointer {:#x} isn't in RAM", sp);
+        return false;
+    }
+    if !is_program2_code(reset) {
+        warn!("program2 reset vector {:#x} isn't in program2", reset);
+        return false;
+    }
+    true
+}
+
+/// Program2's handler for `exception`. None if the vector doesn't point to
+/// Thumb code inside program2's flash, e.g. because program2 isn't flashed and
+/// reads as 0xffffffff.
+pub fn handler(exception: usize) -> Option<extern fn()> {
+    let vector = vector(exception);
+    if !is_program2_code(vector) {
+        return None;
+    }
+    Some(unsafe { core::mem::transmute::<usize, extern fn()>(vector) })
+}
+
+/// Calls program2's handler for `exception`. Without a valid one, makes sure
+/// the exception doesn't fire again right away, where that's possible.
+pub fn forward(exception: usize) {
+    match handler(exception) {
+        Some(program2_hdlr) => program2_hdlr(),
+        None => silence(exception),
+    }
+}
+
+pub fn forward_interrupt<I: Nr>(interrupt: I) {
+    forward(IRQ0 + usize::from(interrupt.nr()));
+}
+
+fn silence(exception: usize) {
+    match exception {
+        PENDSV => SCB::clear_pendsv(),
+        SYSTICK => {
+            let mut core_peripherals = unsafe { CorePeripherals::steal() };
+            core_peripherals.SYST.disable_interrupt();
+        },
+        IRQ0..=IRQ_LAST => unsafe { (*NVIC::ptr()).icer[0].write(1 << (exception - IRQ0)) },
+        // Can't be masked, or won't come back on their own.
+        _ => (),
+    }
+}
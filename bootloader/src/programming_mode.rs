@@ -4,23 +4,74 @@ use lpc11uxx_rom::usbd::{HidInitParameter, InitParameter, CoreDescriptors,
 use lpc11uxx::*;
 use crate::lpc11uxx_misc::*;
 use cortex_m::peripheral::NVIC;
+use rtic::Mutex;
 use core::convert::TryInto;
 
 use crate::MAIN_CLOCK_FREQ;
 
 use core::ptr::NonNull;
 use core::slice;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use crate::bootloader_update::Update;
 
 pub static mut USBD_HANDLE: UsbHandle = UsbHandle::null();
-static mut TIMER_ENABLED: bool = false;
-static mut TIMER_ELAPSED: bool = false;
-static mut SHOULD_REINVOKE_ISP: bool = false;
-static mut CUR_LED_BLINK_TICK: u8 = 0;
-static mut USART_PACKET: [u8; 0x10] = [0; 0x10];
-static mut HID_REPORT_PACKET: [u8; 0x40] = [0; 0x40];
-static mut FLASH_BUFFER_LEN: usize = 0;
-static mut FLASH_BUFFER: [u8; 512] = [0; 512];
-static mut FLASH_CUR_IDX: usize = 0;
+/// Number of CT32B1 periods elapsed since the timer was started. Only written
+/// by the CT32B1 task.
+static TIMER_TICKS: AtomicU32 = AtomicU32::new(0);
+/// The feature reports, while USB_IRQ runs the ROM USB stack. The ROM calls
+/// the HID callbacks without any context, this is how they get to them.
+static mut USB_IRQ_REPORTS: Option<NonNull<FeatureReports>> = None;
+
+/// Everything the feature reports work on. This is an RTIC resource, shared
+/// by USB_IRQ, where the ROM USB stack calls the HID callbacks, PendSV, which
+/// handles the reports forwarded by the nRF, and the main loop.
+pub struct FeatureReports {
+    /// Answer to the next GET_FEATURE.
+    packet: [u8; 0x40],
+    flash: FlashWriter,
+    update: Update,
+    led: BlinkingLed,
+    /// Set by report 0x90, acted upon by the main loop.
+    reinvoke_isp: bool,
+    syscon: SYSCON,
+    wwdt: WWDT,
+}
+
+impl FeatureReports {
+    pub fn new(syscon: SYSCON, wwdt: WWDT, flashctrl: FLASHCTRL, ct16b1: CT16B1) -> Self {
+        FeatureReports {
+            packet: [0; 0x40],
+            flash: FlashWriter::new(flashctrl),
+            update: Update::new(),
+            led: BlinkingLed::new(ct16b1),
+            reinvoke_isp: false,
+            syscon,
+            wwdt,
+        }
+    }
+
+    pub fn packet(&self) -> &[u8; 0x40] {
+        &self.packet
+    }
+}
+
+/// Buffers data for the flash, and writes it out 512 bytes at a time.
+pub struct FlashWriter {
+    flashctrl: FLASHCTRL,
+    buffer: [u8; 512],
+    buffer_len: usize,
+    cur_idx: usize,
+    base: usize,
+    end: usize,
+}
+
+/// The LED, which takes a step through LED_BLINK_INTENSITY_LOOP every time
+/// some data gets flashed.
+pub struct BlinkingLed {
+    ct16b1: CT16B1,
+    tick: u8,
+}
 
 // TODO: Generate the descriptors with const fns.
 static USB_HID_REPORT_DATA_DESC: &[u8] = &[
@@ -259,122 +310,162 @@ extern fn hid_get_report_handler(_handle: HidHandle, setup_packet: *const SetupP
                 // TOTALLY SAAAAAFE. I sure hope so at least.
                 core::slice::from_raw_parts_mut(*buffer, 0x40)
             };
-            buffer.copy_from_slice(unsafe { &HID_REPORT_PACKET });
-            unsafe { *length = 0x40 };
+            // Only missing when polled from the panic handler.
+            if let Some(packet) = with_usb_irq_reports(|reports| reports.packet) {
+                buffer.copy_from_slice(&packet);
+                unsafe { *length = 0x40 };
+            }
         },
         _ => (),
     }
     0
 }
 
-pub fn copy_hid_report(data: &mut [u8]) {
-    unsafe {
-        let max_len = core::cmp::min(data.len(), HID_REPORT_PACKET.len());
-        data[..max_len].copy_from_slice(&HID_REPORT_PACKET[..max_len]);
-    }
+fn with_usb_irq_reports<R>(f: impl FnOnce(&mut FeatureReports) -> R) -> Option<R> {
+    unsafe { USB_IRQ_REPORTS }.map(|mut reports| f(unsafe { reports.as_mut() }))
 }
 
-fn write_data_to_program2_flash(data: &[u8]) -> i32 {
-    unsafe {
-        let mut buffer_cap = FLASH_BUFFER.len() - FLASH_BUFFER_LEN;
+impl FlashWriter {
+    /// Starts out pointed at program2.
+    fn new(flashctrl: FLASHCTRL) -> Self {
+        FlashWriter {
+            flashctrl,
+            buffer: [0; 512],
+            buffer_len: 0,
+            cur_idx: 0,
+            base: crate::program2::PROGRAM2_BASE,
+            end: crate::program2::PROGRAM2_END,
+        }
+    }
+
+    /// Points the flash write pipeline at a new area of flash, and resets it.
+    ///
+    /// Data written through `write` ends up at `flash_base`, and the pipeline
+    /// refuses to write past `flash_end`.
+    pub fn start(&mut self, flash_base: usize, flash_end: usize) {
+        self.base = flash_base;
+        self.end = flash_end;
+        self.cur_idx = 0;
+        self.buffer_len = 0;
+    }
+
+    /// Points the flash write pipeline back at program2, where it starts out.
+    pub fn reset(&mut self) {
+        self.start(crate::program2::PROGRAM2_BASE, crate::program2::PROGRAM2_END);
+    }
+
+    /// Where the flash write pipeline currently writes to.
+    pub fn base(&self) -> usize {
+        self.base
+    }
+
+    pub fn write(&mut self, data: &[u8]) -> i32 {
+        let mut buffer_cap = self.buffer.len() - self.buffer_len;
 
         if data.len() <= buffer_cap {
             buffer_cap = data.len();
         }
 
-        FLASH_BUFFER[FLASH_BUFFER_LEN..FLASH_BUFFER_LEN + buffer_cap].copy_from_slice(&data[..buffer_cap]);
+        self.buffer[self.buffer_len..self.buffer_len + buffer_cap].copy_from_slice(&data[..buffer_cap]);
 
         if buffer_cap < data.len() {
-            if FLASH_CUR_IDX == 0 {
+            if self.base == crate::program2::PROGRAM2_BASE && self.cur_idx == 0 {
                 // Put -1 in Reserved3 of vector table, to prevent accidentally
                 // booting a partially flashed
-                FLASH_BUFFER[9 * 4..10 * 4].copy_from_slice(&(-1_i32).to_le_bytes());
+                self.buffer[9 * 4..10 * 4].copy_from_slice(&(-1_i32).to_le_bytes());
             }
-            // We start from 0x2000, since that's where program2 starts.
-            let flash_dst = FLASH_CUR_IDX + 0x2000;
+            let flash_dst = self.cur_idx + self.base;
 
-            if flash_dst + FLASH_BUFFER.len() >= 0x20_000 {
+            if flash_dst + self.buffer.len() > self.end {
                 return 1;
             }
 
-            let err = iap::prepare_sector_for_write(2, 0x1f);
+            let sector = (flash_dst / 0x1000) as u32;
+            let err = iap::prepare_sector_for_write(sector, sector);
             if err != 0 {
                 return 1;
             }
-            let err = iap::copy_ram_to_flash(flash_dst as u32, FLASH_BUFFER.as_ptr() as usize, FLASH_BUFFER.len(), MAIN_CLOCK_FREQ / 1024);
+            let err = iap::copy_ram_to_flash(flash_dst as u32, self.buffer.as_ptr() as usize, self.buffer.len(), unsafe { MAIN_CLOCK_FREQ } / 1024);
             if err != 0 {
                 return 1;
             }
-            FLASH_CUR_IDX += 0x200;
-            FLASH_BUFFER[..data.len() - buffer_cap].copy_from_slice(&data[buffer_cap..]);
-            FLASH_BUFFER_LEN = data.len() - buffer_cap;
+            self.cur_idx += 0x200;
+            self.buffer[..data.len() - buffer_cap].copy_from_slice(&data[buffer_cap..]);
+            self.buffer_len = data.len() - buffer_cap;
         } else {
-            FLASH_BUFFER_LEN += buffer_cap;
+            self.buffer_len += buffer_cap;
         }
+        0
     }
-    0
-}
-
-fn check_fmc_signature(expected_sig: &[u8]) -> bool {
-    let peripherals = unsafe { Peripherals::steal() };
 
-    peripherals.FLASHCTRL.fmsstart.write(|v| unsafe { v.start().bits(0x2030 / 16) });
-    peripherals.FLASHCTRL.fmstatclr.write(|v| v.sig_done_clr().set_bit());
-    let mut flash_stop = unsafe { (FLASH_CUR_IDX + 0x2000) / 16 };
-    if unsafe { FLASH_CUR_IDX } % 16 == 0 {
-        flash_stop -= 1;
-    }
-    peripherals.FLASHCTRL.fmsstop.write(|v| unsafe { v
-        .stop().bits(flash_stop as u32)
-        .sig_start().set_bit()
-    });
-
-    while peripherals.FLASHCTRL.fmstat.read().sig_done().bit_is_clear() {}
-
-    let mut sig = [0u8; 0x10];
-    sig[0x0..0x04].copy_from_slice(&peripherals.FLASHCTRL.fmsw0.read().bits().to_le_bytes());
-    sig[0x4..0x08].copy_from_slice(&peripherals.FLASHCTRL.fmsw1.read().bits().to_le_bytes());
-    sig[0x8..0x0c].copy_from_slice(&peripherals.FLASHCTRL.fmsw2.read().bits().to_le_bytes());
-    sig[0xc..0x10].copy_from_slice(&peripherals.FLASHCTRL.fmsw3.read().bits().to_le_bytes());
-
-    return sig == expected_sig;
-}
-
-fn end_flash_verify_firmware_sig(sig: &[u8]) -> u32 {
-    unsafe {
-        if FLASH_BUFFER_LEN != 0 {
-            for elem in &mut FLASH_BUFFER[FLASH_BUFFER_LEN..] {
+    /// Writes out whatever is left in the flash buffer, padded with 0xff.
+    ///
+    /// Returns the address right past the last byte written.
+    pub fn flush(&mut self) -> Result<usize, u32> {
+        if self.buffer_len != 0 {
+            for elem in &mut self.buffer[self.buffer_len..] {
                 *elem = 0xff;
             }
-            let flash_dst = FLASH_CUR_IDX + 0x2000;
+            let flash_dst = self.cur_idx + self.base;
 
-            if flash_dst + FLASH_BUFFER.len() >= 0x20_000 {
-                return 1;
+            if flash_dst + self.buffer.len() > self.end {
+                return Err(1);
             }
 
-            let err = iap::prepare_sector_for_write(2, 0x1f);
+            let sector = (flash_dst / 0x1000) as u32;
+            let err = iap::prepare_sector_for_write(sector, sector);
             if err != 0 {
-                return 2;
+                return Err(2);
             }
-            let err = iap::copy_ram_to_flash(flash_dst as u32, FLASH_BUFFER.as_ptr() as usize, FLASH_BUFFER.len(), MAIN_CLOCK_FREQ / 1024);
+            let err = iap::copy_ram_to_flash(flash_dst as u32, self.buffer.as_ptr() as usize, self.buffer.len(), unsafe { MAIN_CLOCK_FREQ } / 1024);
             if err != 0 {
-                return 3;
+                return Err(3);
             }
-            FLASH_CUR_IDX += FLASH_BUFFER_LEN;
+            self.cur_idx += self.buffer_len;
+            self.buffer_len = 0;
         }
+        Ok(self.base + self.cur_idx)
     }
 
-    if !check_fmc_signature(sig) {
-        return 4;
+    /// Runs the flash controller's signature generator over `start..end`, and
+    /// compares it to `expected_sig`.
+    pub fn check_signature(&self, start: usize, end: usize, expected_sig: &[u8]) -> bool {
+        let flashctrl = &self.flashctrl;
+
+        flashctrl.fmsstart.write(|v| unsafe { v.start().bits((start / 16) as u32) });
+        flashctrl.fmstatclr.write(|v| v.sig_done_clr().set_bit());
+        let mut flash_stop = end / 16;
+        if end % 16 == 0 {
+            flash_stop -= 1;
+        }
+        flashctrl.fmsstop.write(|v| unsafe { v
+            .stop().bits(flash_stop as u32)
+            .sig_start().set_bit()
+        });
+
+        while flashctrl.fmstat.read().sig_done().bit_is_clear() {}
+
+        let mut sig = [0u8; 0x10];
+        sig[0x0..0x04].copy_from_slice(&flashctrl.fmsw0.read().bits().to_le_bytes());
+        sig[0x4..0x08].copy_from_slice(&flashctrl.fmsw1.read().bits().to_le_bytes());
+        sig[0x8..0x0c].copy_from_slice(&flashctrl.fmsw2.read().bits().to_le_bytes());
+        sig[0xc..0x10].copy_from_slice(&flashctrl.fmsw3.read().bits().to_le_bytes());
+
+        if sig != expected_sig {
+            debug!("fmc signature mismatch over {:#x}..{:#x}: got {}", start, end, crate::log::Hex(&sig));
+            return false;
+        }
+        true
     }
+}
 
-    // If the signatures match, the flash was successful. Let's put the
-    // magic value in the Reserved3 slot of the Vector Table to allow
-    // booting.
+/// Rewrites the Reserved3 slot of program2's vector table. The bootloader
+/// only boots program2 if it contains 0xecaabac0.
+pub fn set_program2_boot_magic(magic: u32) -> u32 {
     let mut program2_vector_table_copy = [0u8; 4096];
     let program2_vector_table = unsafe { slice::from_raw_parts(0x2_000 as *const u8, program2_vector_table_copy.len()) };
     program2_vector_table_copy.copy_from_slice(program2_vector_table);
-    program2_vector_table_copy[9 * 4..10 * 4].copy_from_slice(&0xecaabac0_u32.to_le_bytes());
+    program2_vector_table_copy[9 * 4..10 * 4].copy_from_slice(&magic.to_le_bytes());
 
     let err = iap::prepare_sector_for_write(2,2);
     if err != 0 {
@@ -398,65 +489,84 @@ fn end_flash_verify_firmware_sig(sig: &[u8]) -> u32 {
     return 0;
 }
 
-pub fn write_report_0x94(err_code: u16) -> usize {
-    unsafe {
-        HID_REPORT_PACKET = [0; 0x40];
-        HID_REPORT_PACKET[0] = 0x94;
-        HID_REPORT_PACKET[1] = 2;
-        HID_REPORT_PACKET[2..4].copy_from_slice(&err_code.to_le_bytes());
-        4
+fn end_flash_verify_firmware_sig(flash: &mut FlashWriter, sig: &[u8]) -> u32 {
+    let flash_end = match flash.flush() {
+        Ok(flash_end) => flash_end,
+        Err(err) => return err,
+    };
+
+    if !flash.check_signature(0x2030, flash_end, sig) {
+        return 4;
+    }
+
+    if !crate::program2::entry_points_valid() {
+        return 10;
     }
+
+    // If the signatures match, the flash was successful. Let's put the
+    // magic value in the Reserved3 slot of the Vector Table to allow
+    // booting.
+    set_program2_boot_magic(0xecaabac0)
+}
+
+pub fn write_report_0x94(reports: &mut FeatureReports, err_code: u16) -> usize {
+    let packet = &mut reports.packet;
+    *packet = [0; 0x40];
+    packet[0] = 0x94;
+    packet[1] = 2;
+    packet[2..4].copy_from_slice(&err_code.to_le_bytes());
+    4
 }
 
-pub fn hid_handle_set_feature_report(wwdt: &WWDT, syscon: &SYSCON, buffer: &[u8]) -> usize {
+pub fn hid_handle_set_feature_report(reports: &mut FeatureReports, buffer: &[u8]) -> usize {
     match buffer.get(0) {
         // GET_HWINFO
         Some(0x83) => {
-            unsafe {
-                HID_REPORT_PACKET = [0; 0x40];
-                HID_REPORT_PACKET[0] = 0x83;
-                HID_REPORT_PACKET[1] = 0xf;
-                HID_REPORT_PACKET[2] = 1;
-                HID_REPORT_PACKET[3..7].copy_from_slice(&0x1002_u32.to_le_bytes());
-                HID_REPORT_PACKET[7] = 4;
-                // Get version from the Vector Table
-                let bootloader_version = *(0 as *const u32).offset(9);
-                HID_REPORT_PACKET[8..12].copy_from_slice(&bootloader_version.to_le_bytes());
-                HID_REPORT_PACKET[12] = 9;
-                HID_REPORT_PACKET[13..17].copy_from_slice(&super::EEPROM_CACHE.version.to_le_bytes());
-            }
+            let packet = &mut reports.packet;
+            *packet = [0; 0x40];
+            packet[0] = 0x83;
+            packet[1] = 0xf;
+            packet[2] = 1;
+            packet[3..7].copy_from_slice(&0x1002_u32.to_le_bytes());
+            packet[7] = 4;
+            // Get version from the Vector Table
+            let bootloader_version = unsafe { *(0 as *const u32).offset(9) };
+            packet[8..12].copy_from_slice(&bootloader_version.to_le_bytes());
+            packet[12] = 9;
+            packet[13..17].copy_from_slice(&unsafe { super::EEPROM_CACHE.version }.to_le_bytes());
             0
         },
         Some(0x90) => {
-            unsafe { SHOULD_REINVOKE_ISP = true; }
+            crate::bootloader_update::cancel(&mut reports.update, &mut reports.flash);
+            reports.reinvoke_isp = true;
             return 0;
         },
         Some(0x91) => {
-            write_report_0x94(2);
-            unsafe {
-                FLASH_CUR_IDX = 0;
-                FLASH_BUFFER_LEN = 0;
-            }
+            write_report_0x94(reports, 2);
+            crate::bootloader_update::cancel(&mut reports.update, &mut reports.flash);
             let err = iap::prepare_sector_for_write(2, 0x1f);
             if err != 0 {
-                return write_report_0x94(1);
+                return write_report_0x94(reports, 1);
             }
             let err = iap::erase_sectors(2, 0x1f, unsafe { MAIN_CLOCK_FREQ } / 1024);
             if err != 0 {
-                return write_report_0x94(1);
+                return write_report_0x94(reports, 1);
             }
-            return write_report_0x94(0);
+            return write_report_0x94(reports, 0);
         },
         Some(0x92) => {
             if let Some(buffer) = buffer.get(1).and_then(|size| buffer.get(2..usize::from(*size))) {
-                let _err = write_data_to_program2_flash(buffer);
-                let err = 0;
-                write_report_0x94(err as u16);
-                led_advance_blink();
-                unsafe {
-                    HID_REPORT_PACKET = [0; 0x40];
-                    HID_REPORT_PACKET[0] = 0x92;
+                // Program2 data ends any bootloader update, and must not end
+                // up in the scratch area.
+                if crate::bootloader_update::in_progress(&reports.update, &reports.flash) {
+                    crate::bootloader_update::cancel(&mut reports.update, &mut reports.flash);
                 }
+                let _err = reports.flash.write(buffer);
+                let err = 0;
+                write_report_0x94(reports, err as u16);
+                reports.led.advance();
+                reports.packet = [0; 0x40];
+                reports.packet[0] = 0x92;
                 return 2;
             } else {
                 1
@@ -464,46 +574,41 @@ pub fn hid_handle_set_feature_report(wwdt: &WWDT, syscon: &SYSCON, buffer: &[u8]
         },
         Some(0x93) => {
             if let Some(buffer) = buffer.get(2..0x12) {
-                let err = end_flash_verify_firmware_sig(buffer);
-                return write_report_0x94(err as u16);
+                let err = end_flash_verify_firmware_sig(&mut reports.flash, buffer);
+                return write_report_0x94(reports, err as u16);
             } else {
-                return write_report_0x94(1 as u16);
+                return write_report_0x94(reports, 1 as u16);
             }
-            //let peripherals = unsafe { Peripherals::steal() };
-            //crate::usb_debug_uart::usb_putnbr_hex(peripherals.FLASHCTRL.fmsw0.read().bits());
-            //crate::usb_debug_uart::usb_putb(b" ");
-            //crate::usb_debug_uart::usb_putnbr_hex(peripherals.FLASHCTRL.fmsw1.read().bits());
-            //crate::usb_debug_uart::usb_putb(b" ");
-            //crate::usb_debug_uart::usb_putnbr_hex(peripherals.FLASHCTRL.fmsw2.read().bits());
-            //crate::usb_debug_uart::usb_putb(b" ");
-            //crate::usb_debug_uart::usb_putnbr_hex(peripherals.FLASHCTRL.fmsw3.read().bits());
-            //crate::usb_debug_uart::usb_putb(b"\n");
         },
         Some(0x95) => {
             if let Some(0) = buffer.get(1) {
-                crate::nrf_comms::usart_send_reset();
-                super::setup_watchdog(syscon, wwdt, 10_000);
+                // The watchdog resets the controller even if the nRF never
+                // got the reset.
+                let _ = crate::nrf_comms::usart_send_reset();
+                super::setup_watchdog(&reports.syscon, &reports.wwdt, 10_000);
             }
             return 0;
         },
+        // These answer 2 once the packet is on its way to the nRF, or 15 if
+        // the TX queue to the nRF was full: nothing was sent, send it again.
         Some(0x97) => {
-            crate::nrf_comms::usart_send_text_transmission(b"Y");
-            return write_report_0x94(2);
+            let sent = crate::nrf_comms::usart_send_message(&nrf_protocol::message::Message::FirmwareUploadStart);
+            return write_report_0x94(reports, if sent.is_ok() { 2 } else { 15 });
         },
         Some(0x98) => {
             if let Some(buffer) = buffer.get(1).and_then(|size| buffer.get(2..usize::from(*size))) {
-                crate::nrf_comms::usart_send_z_packet(buffer);
-                return write_report_0x94(2);
+                let sent = crate::nrf_comms::usart_send_z_packet(buffer);
+                return write_report_0x94(reports, if sent.is_ok() { 2 } else { 15 });
             } else {
-                return write_report_0x94(1);
+                return write_report_0x94(reports, 1);
             }
         },
         Some(0x99) => {
             if let Some(buffer) = buffer.get(2..0x12) {
-                crate::nrf_comms::usart_send_sig_packet(buffer);
-                return write_report_0x94(2);
+                let sent = crate::nrf_comms::usart_send_sig_packet(buffer);
+                return write_report_0x94(reports, if sent.is_ok() { 2 } else { 15 });
             } else {
-                return write_report_0x94(1);
+                return write_report_0x94(reports, 1);
             }
         },
         Some(0xa0) => {
@@ -517,9 +622,51 @@ pub fn hid_handle_set_feature_report(wwdt: &WWDT, syscon: &SYSCON, buffer: &[u8]
             }
             return 0;
         },
+        Some(0xa1) => {
+            let force = buffer.get(1) == Some(&1);
+            let err = crate::bootloader_update::begin(&mut reports.update, &mut reports.flash, force);
+            return write_report_0x94(reports, err as u16);
+        },
+        Some(0xa2) => {
+            if !crate::bootloader_update::is_staging(&reports.flash) {
+                return write_report_0x94(reports, 1);
+            }
+            if let Some(buffer) = buffer.get(1).and_then(|size| buffer.get(2..usize::from(*size))) {
+                let err = reports.flash.write(buffer);
+                reports.led.advance();
+                return write_report_0x94(reports, err as u16);
+            } else {
+                return write_report_0x94(reports, 1);
+            }
+        },
+        Some(0xa3) => {
+            if let Some(buffer) = buffer.get(2..0x12) {
+                let err = crate::bootloader_update::verify(&mut reports.update, &mut reports.flash, buffer);
+                return write_report_0x94(reports, err as u16);
+            } else {
+                return write_report_0x94(reports, 1);
+            }
+        },
+        Some(0xa6) => {
+            let err = crate::bootloader_update::request_apply(&mut reports.update);
+            return write_report_0x94(reports, err as u16);
+        },
+        // GET_CRASH_LOG
+        Some(0xa4) => {
+            let packet = &mut reports.packet;
+            *packet = [0; 0x40];
+            packet[0] = 0xa4;
+            let len = crate::crash_log::read(&mut packet[2..]);
+            packet[1] = len as u8;
+            0
+        },
+        // CLEAR_CRASH_LOG
+        Some(0xa5) => {
+            let err = crate::crash_log::clear();
+            return write_report_0x94(reports, err as u16);
+        },
         Some(n) => {
-            crate::usb_debug_uart::usb_putnbr_hex(*n as u32);
-            crate::usb_debug_uart::usb_putb(b"\n");
+            warn!("unknown feature report {:#x}", n);
             return 0;
         },
         None => return 0,
@@ -534,8 +681,7 @@ extern fn hid_set_report_handler(_handle: HidHandle, setup_packet: *const SetupP
                 let buffer = unsafe {
                     core::slice::from_raw_parts(*buffer, length as usize)
                 };
-                let peripherals = unsafe { Peripherals::steal() };
-                hid_handle_set_feature_report(&peripherals.WWDT, &peripherals.SYSCON, buffer);
+                with_usb_irq_reports(|reports| hid_handle_set_feature_report(reports, buffer));
             },
             _ => ()
         }
@@ -572,19 +718,18 @@ fn init_usb_hid(handle: UsbHandle, hid_interface_descriptor: &mut [u8], mem_base
     return 0;
 }
 
-fn init_usb() -> i32 {
-    let mut core_peripherals = unsafe { CorePeripherals::steal() };
-    let peripherals = unsafe { Peripherals::steal() };
-
+/// Brings up the USB stack. The USB_IRQ interrupt itself is left alone: RTIC
+/// unmasks it, and the panic handler polls instead.
+pub fn init_usb(syscon: &SYSCON) -> i32 {
     // Set USB clock source to PLL OUT
-    peripherals.SYSCON.usbclksel.write(|v| v.sel().usb_pll_out());
-    peripherals.SYSCON.usbclkuen.write(|v| v.ena().no_change());
-    peripherals.SYSCON.usbclkuen.write(|v| v.ena().update_clock_source());
-    peripherals.SYSCON.usbclkdiv.write(|v| unsafe { v.div().bits(1) });
+    syscon.usbclksel.write(|v| v.sel().usb_pll_out());
+    syscon.usbclkuen.write(|v| v.ena().no_change());
+    syscon.usbclkuen.write(|v| v.ena().update_clock_source());
+    syscon.usbclkdiv.write(|v| unsafe { v.div().bits(1) });
 
     // Enable USB and USBRAM clocks.
-    peripherals.SYSCON.sysahbclkctrl.modify(|_, writer| writer.usb().enabled());
-    peripherals.SYSCON.sysahbclkctrl.modify(|_, writer| writer.usbram().enabled());
+    syscon.sysahbclkctrl.modify(|_, writer| writer.usb().enabled());
+    syscon.sysahbclkctrl.modify(|_, writer| writer.usbram().enabled());
 
     // Initialize USB handle
     let usb_api = RomDriver::get().usb_api();
@@ -639,14 +784,6 @@ fn init_usb() -> i32 {
         return err;
     }
 
-    // Enable USB_IRQ and set priority to 1.
-    unsafe {
-        // TODO: I don't think we use mask-based critical section, but it'd be nice to make sure of it.
-        core_peripherals.NVIC.set_priority(Interrupt::USB_IRQ, 0);
-        NVIC::unmask(Interrupt::USB_IRQ);
-        core_peripherals.NVIC.set_priority(Interrupt::USB_IRQ, 1);
-    }
-
     // Connect to host!
     (usb_api.hw().connect)(unsafe { USBD_HANDLE }, 1);
 
@@ -661,77 +798,80 @@ fn send_usart_R_if_usb_disconnected(gpio_port: &mut GPIO_PORT) {
             // Do nothing.
             // TODO: Compiler barrier to avoid the loop disappearing
         }
-        crate::nrf_comms::usart_send_R();
+        // Right after init_usart, the TX queue is empty.
+        let _ = crate::nrf_comms::usart_send_R();
     }
 }
 
 // TODO: Make this generic by clock
-fn ct16b1_timer_reset() {
-    let peripherals = unsafe { Peripherals::steal() };
-
-    let backup_tcr = peripherals.CT16B1.tcr.read().bits();
+fn ct16b1_timer_reset(ct16b1: &CT16B1) {
+    let backup_tcr = ct16b1.tcr.read().bits();
 
-    peripherals.CT16B1.tcr.write_with_zero(|v| v);
-    peripherals.CT16B1.tc.write(|v| unsafe { v.tc().bits(1) });
-    peripherals.CT16B1.tcr.write(|v| v.crst().reset());
+    ct16b1.tcr.write_with_zero(|v| v);
+    ct16b1.tc.write(|v| unsafe { v.tc().bits(1) });
+    ct16b1.tcr.write(|v| v.crst().reset());
 
-    while peripherals.CT16B1.tc.read().bits() != 0 {}
+    while ct16b1.tc.read().bits() != 0 {}
 
-    peripherals
-        .CT16B1
+    ct16b1
         .tcr
         .write(|writer| unsafe { writer.bits(backup_tcr) });
 }
 
-fn init_led_ctrl() {
-    let peripherals = unsafe { Peripherals::steal() };
-
+fn init_led_ctrl(iocon: &IOCON, syscon: &SYSCON, ct16b1: &CT16B1) {
     // Setup pinmux for ct16b1 timer.
-    peripherals.IOCON.pio0_21.write(|v| v
+    iocon.pio0_21.write(|v| v
         .func().ct16b1_mat0()
         .mode().floating());
 
     // Initialize ct16b1 timer.
-    peripherals.SYSCON.sysahbclkctrl.modify(|_, v| v.ct16b1().enabled());
+    syscon.sysahbclkctrl.modify(|_, v| v.ct16b1().enabled());
 
     // Set CT16B1 prescale to 0
-    peripherals.CT16B1.pr.write(|v| unsafe { v.pcval().bits(0) });
+    ct16b1.pr.write(|v| unsafe { v.pcval().bits(0) });
 
     // Enable PWM mode on CT16Bn_MAT0 ()
-    peripherals.CT16B1.pwmc.modify(|_, v| v.pwmen0().enabled());
+    ct16b1.pwmc.modify(|_, v| v.pwmen0().enabled());
 
-    peripherals.CT16B1.mr[3].write(|v| unsafe { v.match_().bits(0xfff) });
-    peripherals.CT16B1.mr[0].write(|v| unsafe { v.match_().bits(0x1000) });
+    ct16b1.mr[3].write(|v| unsafe { v.match_().bits(0xfff) });
+    ct16b1.mr[0].write(|v| unsafe { v.match_().bits(0x1000) });
 
-    peripherals.CT16B1.mcr.modify(|_, v| v.mr3r().enabled());
+    ct16b1.mcr.modify(|_, v| v.mr3r().enabled());
 
-    ct16b1_timer_reset();
+    ct16b1_timer_reset(ct16b1);
 
-    peripherals
-        .CT16B1
+    ct16b1
         .tcr
         .modify(|_, writer| writer.cen().the_timer_counter_an());
 }
 
-fn led_advance_blink() {
-    let led_blink_tick = unsafe { CUR_LED_BLINK_TICK };
-    tick_led_blink((led_blink_tick + 1) % LED_BLINK_INTENSITY_LOOP.len() as u8);
-}
+impl BlinkingLed {
+    /// Expects init_led_ctrl to have set up the timer. Starts out off.
+    fn new(ct16b1: CT16B1) -> Self {
+        let mut led = BlinkingLed { ct16b1, tick: 0 };
+        led.set(0xff);
+        led
+    }
 
-fn tick_led_blink(tick: u8) {
-    // This function has an out of bound in the original version (they send it
-    // 255 in enter_programming mode, but LED_BLINK_INTENSITY_LOOP only has 128
-    // values). To fix this, we default to 0. Also avoids a panic call.
-    let peripherals = unsafe { Peripherals::steal() };
+    fn advance(&mut self) {
+        self.set(self.tick.wrapping_add(1) % LED_BLINK_INTENSITY_LOOP.len() as u8);
+    }
 
-    peripherals.CT16B1.mr[0].write(|v| unsafe {
-        v.bits(u32::from(*LED_BLINK_INTENSITY_LOOP.get(usize::from(tick)).unwrap_or(&0)))
-    });
-    unsafe {
-        CUR_LED_BLINK_TICK = tick
-    };
+    fn set(&mut self, tick: u8) {
+        // This function has an out of bound in the original version (they send it
+        // 255 in enter_programming mode, but LED_BLINK_INTENSITY_LOOP only has 128
+        // values). To fix this, we default to 0. Also avoids a panic call.
+        self.ct16b1.mr[0].write(|v| unsafe {
+            v.bits(u32::from(*LED_BLINK_INTENSITY_LOOP.get(usize::from(tick)).unwrap_or(&0)))
+        });
+        self.tick = tick;
+    }
 }
 
+/// CT32B1 counts milliseconds, and wraps around (raising an interrupt) every
+/// TIMER_PERIOD_MS.
+const TIMER_PERIOD_MS: u32 = 12;
+
 fn init_timer_32_1(syscon: &SYSCON, ct32b1: &mut CT32B1) {
     // TODO: Understand exactly how the timer is configured
 
@@ -748,47 +888,36 @@ fn init_timer_32_1(syscon: &SYSCON, ct32b1: &mut CT32B1) {
     ct32b1.mcr.modify(|_, v| v.mr0i().enabled());
 
     // Make MR0 match when TC reaches the value "11"
-    ct32b1.mr[0].write(|v| unsafe { v.match_().bits(11) });
+    ct32b1.mr[0].write(|v| unsafe { v.match_().bits(TIMER_PERIOD_MS - 1) });
 
     NVIC::unpend(Interrupt::CT32B1);
-    unsafe { NVIC::unmask(Interrupt::CT32B1) };
-
-    // Setup USART packet to be sent periodically.
-    unsafe {
-        USART_PACKET[0] = 1;
-        USART_PACKET[1] = 0;
-        USART_PACKET[2] = 4;
-        USART_PACKET[3] = 0xc;
-        USART_PACKET[4..8].copy_from_slice(&0u32.to_le_bytes());
-        USART_PACKET[8] = 0;
-        USART_PACKET[9] = 0;
-        USART_PACKET[10] = 0;
-        USART_PACKET[11] = 0;
-        USART_PACKET[12..16].copy_from_slice(&0u32.to_le_bytes());
-    }
 
     ct32b1.tcr.modify(|_, v| v.cen().the_timer_counter_an());
-
-    unsafe { TIMER_ENABLED = true; }
 }
 
-fn reinvoke_isp() {
-    let mut core_peripherals = unsafe { CorePeripherals::steal() };
-    let peripherals = unsafe { Peripherals::steal() };
+/// Initial contents of the packet sent to the nRF on every tick. Bytes 4..8
+/// are a counter, incremented before each send.
+pub const HEARTBEAT_PACKET: [u8; 0x10] = [
+    1, 0, 4, 0xc,
+    0, 0, 0, 0,
+    0, 0, 0, 0,
+    0, 0, 0, 0,
+];
 
+fn reinvoke_isp(syscon: &SYSCON, syst: &mut SYST) {
     // Enable all necessary clocks for ISP operation
-    peripherals.SYSCON.sysahbclkctrl.modify(|_, writer| writer.gpio().enabled());
-    peripherals.SYSCON.sysahbclkctrl.modify(|_, writer| writer.ct32b1().enabled());
-    peripherals.SYSCON.sysahbclkctrl.modify(|_, writer| writer.usb().enabled());
-    peripherals.SYSCON.sysahbclkctrl.modify(|_, writer| writer.iocon().enabled());
+    syscon.sysahbclkctrl.modify(|_, writer| writer.gpio().enabled());
+    syscon.sysahbclkctrl.modify(|_, writer| writer.ct32b1().enabled());
+    syscon.sysahbclkctrl.modify(|_, writer| writer.usb().enabled());
+    syscon.sysahbclkctrl.modify(|_, writer| writer.iocon().enabled());
 
     // Reset clk divisor to its default value (1)
-    peripherals.SYSCON.sysahbclkdiv.reset();
+    syscon.sysahbclkdiv.reset();
 
     // Disable SysTick
     // (Done in a single pass by the real firmware)
-    core_peripherals.SYST.disable_counter();
-    core_peripherals.SYST.disable_interrupt();
+    syst.disable_counter();
+    syst.disable_interrupt();
 
     unsafe {
         asm!("
@@ -802,6 +931,8 @@ fn reinvoke_isp() {
     }
 }
 
+/// Runs on the stack the ISP expects, so it can't take anything from its
+/// caller, and has to steal the NVIC.
 extern fn reinvoke_isp_inner() {
     let core_peripherals = unsafe { CorePeripherals::steal() };
 
@@ -813,56 +944,76 @@ extern fn reinvoke_isp_inner() {
 }
 
 
-fn send_usart_packet_if_timer_elapsed() {
-    unsafe {
-        if TIMER_ENABLED && TIMER_ELAPSED {
-            TIMER_ELAPSED = false;
-            let mut counter = [0; 4];
-            counter.copy_from_slice(&USART_PACKET[4..8]);
-            let counter = u32::from_le_bytes(counter) + 1;
-            USART_PACKET[4..8].copy_from_slice(&counter.to_le_bytes());
-
-            crate::nrf_comms::usart_send_V_packet(&USART_PACKET);
-        }
-    }
+fn send_heartbeat_packet(packet: &mut [u8; 0x10]) {
+    let mut counter = [0; 4];
+    counter.copy_from_slice(&packet[4..8]);
+    let counter = u32::from_le_bytes(counter) + 1;
+    packet[4..8].copy_from_slice(&counter.to_le_bytes());
+
+    crate::nrf_comms::usart_send_V_packet(packet);
 }
 
-pub fn enter_programming_mode(mut core_peripherals: CorePeripherals, mut peripherals: Peripherals) -> ! {
-    init_usb();
-    crate::nrf_comms::init_usart(&peripherals.SYSCON, &peripherals.USART, &mut core_peripherals.NVIC, &mut core_peripherals.SCB);
+/// Sets up programming mode. Runs from RTIC's init, with interrupts disabled.
+/// The peripherals used afterwards then go to FeatureReports::new, or to RTIC.
+pub fn init(peripherals: &mut Peripherals) {
+    init_usb(&peripherals.SYSCON);
+    crate::nrf_comms::init_usart(&peripherals.SYSCON, &peripherals.USART);
     send_usart_R_if_usb_disconnected(&mut peripherals.GPIO_PORT);
-    init_led_ctrl();
-    tick_led_blink(0xff);
+    init_led_ctrl(&peripherals.IOCON, &peripherals.SYSCON, &peripherals.CT16B1);
     init_timer_32_1(&peripherals.SYSCON, &mut peripherals.CT32B1);
-    loop {
-        if unsafe { SHOULD_REINVOKE_ISP } {
-            reinvoke_isp();
+}
+
+/// One iteration of the main loop. `timer_elapsed` tells whether CT32B1 ticked
+/// since the last one.
+pub fn poll(reports: &mut impl Mutex<T = FeatureReports>, syst: &mut SYST,
+    heartbeat_packet: &mut [u8; 0x10], timer_elapsed: bool)
+{
+    reports.lock(|reports| {
+        if reports.reinvoke_isp {
+            reinvoke_isp(&reports.syscon, syst);
         }
 
-        // Extension: USB CDC
-        crate::usb_debug_uart::usb_flush();
-        send_usart_packet_if_timer_elapsed();
-        cortex_m::asm::wfi();
+        crate::bootloader_update::apply_if_requested(&mut reports.update, timer_elapsed);
+    });
+
+    // Extension: USB CDC
+    if crate::bridge::active() {
+        crate::bridge::poll();
+    } else {
+        crate::console::poll();
+    }
+    crate::nrf_comms::flush_reply();
+    crate::sniffer::poll();
+    crate::usb_debug_uart::usb_flush();
+    if timer_elapsed {
+        send_heartbeat_packet(heartbeat_packet);
     }
 }
 
 
 #[allow(non_snake_case)]
-pub fn CT32B1() {
-    let peripherals = unsafe { Peripherals::steal() };
-    peripherals.CT32B1.ir.write(|v| v.mr0int().set_bit());
+pub fn CT32B1(ct32b1: &mut CT32B1) {
+    ct32b1.ir.write(|v| v.mr0int().set_bit());
 
-    unsafe {
-        TIMER_ELAPSED = true;
-    }
+    // This is the only writer, a plain load and store is fine.
+    let ticks = TIMER_TICKS.load(Ordering::Relaxed);
+    TIMER_TICKS.store(ticks.wrapping_add(1), Ordering::Relaxed);
 }
 
-#[allow(non_snake_case)]
-pub fn USB_IRQ() {
-    let peripherals = unsafe { Peripherals::steal() };
+/// Milliseconds elapsed since programming mode started its timer, in steps of
+/// TIMER_PERIOD_MS. Usable from any context: the timer itself belongs to the
+/// CT32B1 task.
+pub fn uptime_ms() -> u32 {
+    TIMER_TICKS.load(Ordering::Relaxed).wrapping_mul(TIMER_PERIOD_MS)
+}
 
+/// Services the ROM USB stack. `reports` is what the HID callbacks work on,
+/// and is only missing when the panic handler polls the USB stack by hand:
+/// feature reports are ignored then.
+#[allow(non_snake_case)]
+pub fn USB_IRQ(usb: &USB, reports: Option<&mut FeatureReports>) {
     let ep_list = unsafe { slice::from_raw_parts_mut(
-        peripherals.USB.epliststart.read().bits() as *mut u32, 5) };
+        usb.epliststart.read().bits() as *mut u32, 5) };
 
     /*	WORKAROUND for artf32289 ROM driver BUG:
         As part of USB specification the device should respond
@@ -871,24 +1022,19 @@ pub fn USB_IRQ() {
         a clear STALL request. Current driver in ROM doesn't clear the STALL
         condition on new setup packet which should be fixed.
      */
-    if peripherals.USB.devcmdstat.read().setup().bit_is_set() { // If setup packet is received
+    if usb.devcmdstat.read().setup().bit_is_set() { // If setup packet is received
         ep_list[0] &= 0xdfff_ffff; // Clear EP0_OUT stall
         ep_list[2] &= 0xdfff_ffff; // Clear EP0_IN stall
     }
 
     let usb_api = RomDriver::get().usb_api();
+    unsafe { USB_IRQ_REPORTS = reports.map(NonNull::from); }
     (usb_api.hw().isr)(unsafe { USBD_HANDLE });
+    unsafe { USB_IRQ_REPORTS = None; }
 }
 
 #[allow(non_snake_case)]
-pub fn PendSV() {
+pub fn PendSV(reports: &mut impl Mutex<T = FeatureReports>) {
     SCB::clear_pendsv();
-    let peripherals = unsafe { Peripherals::steal() };
-    crate::nrf_comms::handle_pendsv(&peripherals.WWDT, &peripherals.SYSCON);
-}
-
-#[allow(non_snake_case)]
-pub fn USART() {
-    let mut peripherals = unsafe { Peripherals::steal() };
-    crate::nrf_comms::handle_interrupt(&mut peripherals.USART)
+    crate::nrf_comms::handle_pendsv(reports);
 }
@@ -1,6 +1,7 @@
+use core::fmt::Write;
 use core::panic::PanicInfo;
 use embedded_hal::blocking::delay::DelayMs;
-use lpc11uxx::CorePeripherals;
+use lpc11uxx::{CorePeripherals, Peripherals};
 use lpc11uxx_hal::delay::Delay;
 
 use crate::led;
@@ -8,8 +9,79 @@ use crate::system::{CRYSTAL_OSCILLATOR_CLOCK_RATE, SYSTEM_PPL_MSET};
 
 #[inline(never)]
 #[panic_handler]
-fn panic(_info: &PanicInfo) -> ! {
-    led_panic(0x1000, 1000)
+fn panic(info: &PanicInfo) -> ! {
+    // From here on, the USB stack is polled by hand. This keeps it going even
+    // if we panicked from inside an interrupt handler.
+    cortex_m::interrupt::disable();
+
+    crate::crash_log::record_panic(info);
+
+    // If program2 is running, the bootloader's RAM doesn't belong to us
+    // anymore, and neither does the USB stack.
+    let peripherals = unsafe { Peripherals::steal() };
+    if peripherals.PMU.gpreg[1].read().bits() != 0 {
+        led_panic(0x1000, 1000)
+    }
+
+    print_panic(info);
+
+    // Panicked before USB was brought up. Bring it up ourselves, so the
+    // message buffered so far gets a chance to make it out. The USB PLL is
+    // only running once the clocks are initialized.
+    if !crate::usb_debug_uart::initialized() && unsafe { crate::MAIN_CLOCK_FREQ } != 0 {
+        crate::programming_mode::init_usb(&peripherals.SYSCON);
+    }
+
+    led_panic_polling_usb(0x1000, 1000)
+}
+
+fn print_panic(info: &PanicInfo) {
+    let mut uart = crate::usb_debug_uart::UartDebug;
+
+    let _ = uart.write_str("\r\npanicked at ");
+    match info.location() {
+        Some(location) => {
+            let _ = write!(uart, "{}:{}:{}", location.file(), location.line(), location.column());
+        },
+        None => {
+            let _ = uart.write_str("<unknown location>");
+        }
+    }
+    if let Some(message) = info.message() {
+        let _ = uart.write_str(": ");
+        let _ = uart.write_fmt(*message);
+    }
+    let _ = uart.write_str("\r\n");
+}
+
+/// Same as led_panic, but keeps servicing the CDC port while blinking, so the
+/// panic message eventually makes it to the host.
+fn led_panic_polling_usb(start_intensity: u16, delay_ms: u32) -> ! {
+    const POLL_PERIOD_MS: u32 = 10;
+
+    led::initialize();
+
+    let peripherals = unsafe { CorePeripherals::steal() };
+
+    let mut delay = Delay::new(
+        peripherals.SYST,
+        CRYSTAL_OSCILLATOR_CLOCK_RATE * u32::from(SYSTEM_PPL_MSET),
+    );
+
+    let mut intensity = start_intensity;
+    loop {
+        led::set_intensity(intensity);
+
+        if intensity == 0 {
+            intensity = start_intensity;
+        } else {
+            intensity = 0;
+        }
+        for _i in 0..delay_ms / POLL_PERIOD_MS {
+            crate::usb_debug_uart::poll();
+            delay.delay_ms(POLL_PERIOD_MS);
+        }
+    }
 }
 
 pub fn led_panic(start_intensity: u16, delay_ms: u32) -> ! {
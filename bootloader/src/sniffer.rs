@@ -0,0 +1,143 @@
+//! Mirrors the packets exchanged with the nRF to the CDC port.
+//!
+//! Turned on and off with the `sniff on|off` console command. Each packet
+//! becomes a line of the form
+//!
+//! ```text
+//! #nrf <rx|tx> <timestamp in ms> <length> <up to 32 bytes in hex>
+//! ```
+//!
+//! followed by as many `#nrf+ <up to 32 bytes in hex>` lines as needed to
+//! hold the rest of the packet. rx is nRF to LPC, and tx is LPC to nRF.
+//! Packets are logged unescaped, without their framing. Packets that didn't
+//! fit in the queue are counted, and reported with a `#nrf dropped <count>`
+//! line. `driver-cli sniff` knows how to decode those lines.
+//!
+//! Packets are sent and received from interrupt handlers, where formatting
+//! them would take way too long. `mirror` only copies them to a queue, and
+//! `poll` prints them from the main loop.
+
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use heapless::consts::U512;
+use heapless::spsc::Queue;
+use nrf_protocol::framing::MAX_PACKET_LEN;
+
+use crate::log::Hex;
+use crate::usb_debug_uart::UartDebug;
+
+/// Direction, length and timestamp of a packet.
+const HEADER_LEN: usize = 7;
+const BYTES_PER_LINE: usize = 32;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Headers followed by the packets. Packets are mirrored from several
+/// contexts, so both ends are only used with interrupts disabled.
+static mut QUEUE: Queue<u8, U512> = Queue(heapless::i::Queue::new());
+static mut DROPPED: u32 = 0;
+
+#[derive(Debug, Clone, Copy)]
+pub enum Direction {
+    FromNrf,
+    ToNrf,
+}
+
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Queues a packet, made of the concatenation of `parts`, to be logged by
+/// `poll`.
+pub fn mirror(direction: Direction, parts: &[&[u8]]) {
+    if !enabled() {
+        return;
+    }
+
+    let len: usize = parts.iter().map(|part| part.len()).sum();
+    let mut header = [0; HEADER_LEN];
+    header[0] = match direction {
+        Direction::FromNrf => 0,
+        Direction::ToNrf => 1,
+    };
+    header[1..3].copy_from_slice(&(len as u16).to_le_bytes());
+    header[3..7].copy_from_slice(&crate::programming_mode::uptime_ms().to_le_bytes());
+
+    cortex_m::interrupt::free(|_| {
+        let queue = unsafe { &mut QUEUE };
+        if len > MAX_PACKET_LEN || queue.capacity() - queue.len() < HEADER_LEN + len {
+            unsafe { DROPPED += 1; }
+            return;
+        }
+        for byte in header.iter().chain(parts.iter().flat_map(|part| part.iter())) {
+            let _ = queue.enqueue(*byte);
+        }
+    });
+}
+
+/// Logs the packets queued by `mirror`. Called from the main loop.
+pub fn poll() {
+    let mut packet = [0; MAX_PACKET_LEN];
+
+    loop {
+        let header = cortex_m::interrupt::free(|_| {
+            let queue = unsafe { &mut QUEUE };
+            if queue.len() < HEADER_LEN {
+                return None;
+            }
+            let mut header = [0; HEADER_LEN];
+            for byte in header.iter_mut() {
+                *byte = queue.dequeue().unwrap_or(0);
+            }
+            let len = usize::from(u16::from_le_bytes([header[1], header[2]]));
+            for byte in &mut packet[..len] {
+                *byte = queue.dequeue().unwrap_or(0);
+            }
+            Some(header)
+        });
+        let header = match header {
+            Some(header) => header,
+            None => break,
+        };
+
+        let direction = if header[0] == 0 { "rx" } else { "tx" };
+        let len = usize::from(u16::from_le_bytes([header[1], header[2]]));
+        let timestamp = u32::from_le_bytes([header[3], header[4], header[5], header[6]]);
+        print_packet(direction, timestamp, &packet[..len]);
+    }
+
+    let dropped = cortex_m::interrupt::free(|_| unsafe { core::mem::replace(&mut DROPPED, 0) });
+    if dropped != 0 {
+        cortex_m::interrupt::free(|_| {
+            let _ = UartDebug.write_line(format_args!("#nrf dropped {}", dropped));
+        });
+    }
+}
+
+/// Prints a packet one line at a time. Log messages can come in between
+/// lines, but never in the middle of one.
+fn print_packet(direction: &str, timestamp: u32, packet: &[u8]) {
+    let mut lines = packet.chunks(BYTES_PER_LINE);
+
+    cortex_m::interrupt::free(|_| {
+        let mut uart = UartDebug;
+        let _ = write!(uart, "#nrf {} {} {} ", direction, timestamp, packet.len());
+        print_hex_line(&mut uart, lines.next().unwrap_or(&[]));
+    });
+    for line in lines {
+        cortex_m::interrupt::free(|_| {
+            let mut uart = UartDebug;
+            let _ = uart.write_str("#nrf+ ");
+            print_hex_line(&mut uart, line);
+        });
+    }
+}
+
+fn print_hex_line(uart: &mut UartDebug, bytes: &[u8]) {
+    let _ = uart.write_line(format_args!("{}", Hex(bytes)));
+}
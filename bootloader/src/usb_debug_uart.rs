@@ -5,6 +5,7 @@ use core::ptr::NonNull;
 
 const UCOM_TX_BUF_SZ: usize = 256;
 const UCOM_RX_BUF_SZ: usize = 256;
+const EARLY_BUF_SZ: usize = 128;
 
 struct UcomData<'a> {
     connected: VolatileCell<bool>,
@@ -16,16 +17,16 @@ struct UcomData<'a> {
     /// Buffer treated as a FIFO which stores character data that has been
     /// receive via USB CDC UART
     rx_fifo: &'a mut [u8],
-    //rx_rd_idx: usize,
-    //rx_wr_idx: usize,
+    rx_rd_idx: VolatileCell<usize>,
+    rx_wr_idx: VolatileCell<usize>,
 
     /// Flag that we did not have enough room for incoming data in rxFifo and
     /// did not call ReadEP. We will need to call ReadEP to get the buffered
     /// data and have interrupts start working again once room opens up in
-    /// tx_fifo. Note that even though we do not call ReadEP, incoming USB
-    /// packets may be dropped and into data lost. Still looking for solutions
-    /// to fix this.
-    //rx_stalled: bool,
+    /// rx_fifo. Not calling ReadEP leaves the endpoint inactive, so the
+    /// controller NAKs whatever the host sends next, and the host retries
+    /// until we catch up. No data gets lost.
+    rx_stalled: VolatileCell<bool>,
     /// Indicates transmission is in progress. This does not guarantee that
     /// tx_fifo will be drained (use usb_flush() for this).
     tx_busy: VolatileCell<bool>,
@@ -44,9 +45,9 @@ static mut UCOM_DATA: UcomData<'static> = UcomData {
     usb: UsbHandle::null(),
     cdc: CdcHandle::null(),
     rx_fifo: &mut [],
-    //rx_rd_idx: 0,
-    //rx_wr_idx: 0,
-    //rx_stalled: false,
+    rx_rd_idx: VolatileCell::new(0),
+    rx_wr_idx: VolatileCell::new(0),
+    rx_stalled: VolatileCell::new(false),
     tx_busy: VolatileCell::new(false),
     tx_fifo: &mut [],
     tx_rd_idx: VolatileCell::new(0),
@@ -57,8 +58,14 @@ static mut UCOM_DATA: UcomData<'static> = UcomData {
     ep_out_idx: 0,
 };
 
-extern fn set_line_code(_hnd: CdcHandle, _line_coding: &mut CdcLineCoding) -> i32 {
+/// Holds whatever gets printed before the CDC port is initialized (panics
+/// during early boot, mostly). It is moved to the TX FIFO by init_usb_cdc.
+static mut EARLY_BUFFER: [u8; EARLY_BUF_SZ] = [0; EARLY_BUF_SZ];
+static mut EARLY_BUFFER_LEN: usize = 0;
+
+extern fn set_line_code(_hnd: CdcHandle, line_coding: &mut CdcLineCoding) -> i32 {
     unsafe { UCOM_DATA.connected.set(true); }
+    crate::bridge::set_line_coding(line_coding);
     0
 }
 
@@ -96,6 +103,12 @@ pub fn init_usb_cdc(usb_handle: UsbHandle, cif_intf_desc: &mut [u8],
     init_param.mem_base += UCOM_TX_BUF_SZ as u32;
     init_param.mem_size -= UCOM_TX_BUF_SZ as u32;
 
+    // Queue up what was printed before we had a FIFO.
+    unsafe {
+        usb_putb(&EARLY_BUFFER[..EARLY_BUFFER_LEN]);
+        EARLY_BUFFER_LEN = 0;
+    }
+
     if init_param.mem_size < UCOM_RX_BUF_SZ as u32 {
         return 1;
     }
@@ -174,12 +187,37 @@ fn usb_uart_tx_start(ucom: &mut UcomData) {
     }
 }
 
+/// Free space in the RX FIFO. One slot always stays empty, to tell a full FIFO
+/// from an empty one.
+fn usb_rx_fifo_free_bytes(ucom: &UcomData) -> usize {
+    let rx_rd_idx = ucom.rx_rd_idx.get();
+    let rx_wr_idx = ucom.rx_wr_idx.get();
+    let used = (rx_wr_idx + ucom.rx_fifo.len() - rx_rd_idx) % ucom.rx_fifo.len();
+    ucom.rx_fifo.len() - used - 1
+}
+
 /// Receive data from the USB CDC UART.
+///
+/// Only reads the endpoint if a full packet is guaranteed to fit in the FIFO.
+/// Otherwise, leaves the packet in the endpoint and flags the RX side as
+/// stalled. usb_getc picks it up once enough room has been freed.
 fn usb_uart_rcv_data(ucom: &mut UcomData) {
+    if usb_rx_fifo_free_bytes(ucom) < 64 /* USB_MAX_PACKET_SZ */ {
+        ucom.rx_stalled.set(true);
+        return;
+    }
+
     let usb_api = RomDriver::get().usb_api();
-    if let Some(fifo_slice) = ucom.rx_fifo.get_mut(0..0x40) {
-        cortex_m::interrupt::free(|_| usb_api.hw().read_ep)(ucom.usb, ucom.ep_out_idx, fifo_slice.as_mut_ptr());
+    let mut packet = [0u8; 64];
+    let len = cortex_m::interrupt::free(|_| (usb_api.hw().read_ep)(ucom.usb, ucom.ep_out_idx, packet.as_mut_ptr()));
+    ucom.rx_stalled.set(false);
+
+    let mut rx_wr_idx = ucom.rx_wr_idx.get();
+    for byte in &packet[..core::cmp::min(len as usize, packet.len())] {
+        ucom.rx_fifo[rx_wr_idx] = *byte;
+        rx_wr_idx = (rx_wr_idx + 1) % ucom.rx_fifo.len();
     }
+    ucom.rx_wr_idx.set(rx_wr_idx);
 }
 
 extern fn ucom_bulk_hdlr(_usb: UsbHandle, data: *mut u8, evt: u32) -> i32 {
@@ -213,6 +251,15 @@ extern fn ucom_bulk_hdlr(_usb: UsbHandle, data: *mut u8, evt: u32) -> i32 {
 
 pub fn usb_putc(chara: u8) -> i32 {
     unsafe {
+        if UCOM_DATA.tx_fifo.is_empty() {
+            if let Some(place) = EARLY_BUFFER.get_mut(EARLY_BUFFER_LEN) {
+                *place = chara;
+                EARLY_BUFFER_LEN += 1;
+                return 0;
+            }
+            return chara as i32;
+        }
+
         let next_wr_idx = (UCOM_DATA.tx_wr_idx + 1) % UCOM_DATA.tx_fifo.len();
 
         // We're full already. Fuck.
@@ -277,6 +324,53 @@ pub fn usb_flush() {
     }
 }
 
+/// Pops a byte received from the host, if there's one.
+pub fn usb_getc() -> Option<u8> {
+    unsafe {
+        if UCOM_DATA.rx_fifo.is_empty() {
+            return None;
+        }
+
+        let rx_rd_idx = UCOM_DATA.rx_rd_idx.get();
+        if rx_rd_idx == UCOM_DATA.rx_wr_idx.get() {
+            return None;
+        }
+
+        let chara = UCOM_DATA.rx_fifo[rx_rd_idx];
+        UCOM_DATA.rx_rd_idx.set((rx_rd_idx + 1) % UCOM_DATA.rx_fifo.len());
+
+        // Now that there's room again, pick up the packet we left in the
+        // endpoint. This must not race with the USB interrupt doing the same.
+        if UCOM_DATA.rx_stalled.get() {
+            cortex_m::interrupt::free(|_| {
+                if UCOM_DATA.rx_stalled.get() {
+                    usb_uart_rcv_data(&mut UCOM_DATA);
+                }
+            });
+        }
+
+        Some(chara)
+    }
+}
+
+/// Services the USB stack by hand, and pushes out whatever is in the TX FIFO.
+/// Meant for when interrupts can't be relied on, i.e. from the panic handler.
+pub fn poll() {
+    if !initialized() {
+        return;
+    }
+
+    // The panic handler owns everything by now.
+    let peripherals = unsafe { lpc11uxx::Peripherals::steal() };
+    crate::programming_mode::USB_IRQ(&peripherals.USB, None);
+
+    unsafe {
+        if UCOM_DATA.connected.get() {
+            usb_uart_tx_start(&mut UCOM_DATA);
+        }
+    }
+}
+
 /*pub fn wait_until_sent() {
     // TODO: Figure this out
     unsafe {
@@ -289,6 +383,10 @@ pub fn enabled() -> bool {
     return unsafe { UCOM_DATA.connected.get() }
 }
 
+pub fn initialized() -> bool {
+    return unsafe { !UCOM_DATA.tx_fifo.is_empty() }
+}
+
 pub struct UartDebug;
 
 impl core::fmt::Write for UartDebug {
@@ -296,4 +394,13 @@ impl core::fmt::Write for UartDebug {
         usb_putb(s.as_bytes());
         Ok(())
     }
+}
+
+impl UartDebug {
+    /// Writes a line, ended by CRLF for serial terminals. Use with
+    /// format_args!, writeln! only ends lines with LF.
+    pub fn write_line(&mut self, args: core::fmt::Arguments) -> core::fmt::Result {
+        core::fmt::Write::write_fmt(self, args)?;
+        core::fmt::Write::write_str(self, "\r\n")
+    }
 }
\ No newline at end of file
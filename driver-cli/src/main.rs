@@ -1,7 +1,8 @@
 use std::convert::TryInto;
-use std::io::{self, Seek, SeekFrom, Read};
+use std::io::{Seek, SeekFrom, Read, Write, BufRead, BufReader};
 
 use hidapi_rs::*;
+use nrf_protocol::message::Message;
 
 fn reboot_to_bootloader(device: &HidDevice) {
     let mut data = vec![0; 0x40 + 1];
@@ -163,13 +164,359 @@ fn find_bootloader_device(hidapi: &mut HidApi) -> Box<HidDevice> {
     };
 }
 
-fn main() {
+/// Computes the signature the LPC flash controller's signature generator
+/// produces over `data`. The last flash line is padded with 0xff, just like
+/// the bootloader pads the last block it writes.
+fn fmc_signature(data: &[u8]) -> [u32; 4] {
+    let mut cur_word = [0u32; 4];
+    let mut ref_signature = [0u32; 4];
+    let mut next_signature = [0u32; 4];
+
+    for line in data.chunks(16) {
+        let mut buf = [0xff; 16];
+        buf[..line.len()].copy_from_slice(line);
+
+        cur_word[0] = u32::from_le_bytes(buf[0..4].try_into().unwrap());
+        cur_word[1] = u32::from_le_bytes(buf[4..8].try_into().unwrap());
+        cur_word[2] = u32::from_le_bytes(buf[8..12].try_into().unwrap());
+        cur_word[3] = u32::from_le_bytes(buf[12..16].try_into().unwrap());
+
+        next_signature[0] = cur_word[0] ^ ref_signature[0] >> 1 ^ ref_signature[1] << 31;
+        next_signature[1] = cur_word[1] ^ ref_signature[1] >> 1 ^ ref_signature[2] << 31;
+        next_signature[2] = cur_word[2] ^ ref_signature[2] >> 1 ^ ref_signature[3] << 31;
+        next_signature[3] = cur_word[3] ^ ref_signature[3] >> 1 ^
+            (ref_signature[0] & (1 << 29)) << 02 ^
+            (ref_signature[0] & (1 << 27)) << 04 ^
+            (ref_signature[0] & (1 << 02)) << 29 ^
+            (ref_signature[0] & (1 << 00)) << 31;
+
+        ref_signature = next_signature;
+    }
+
+    ref_signature
+}
+
+fn signature_to_bytes(signature: &[u32; 4]) -> [u8; 16] {
+    let mut bytes = [0; 16];
+    bytes[0..4].copy_from_slice(&signature[0].to_le_bytes());
+    bytes[4..8].copy_from_slice(&signature[1].to_le_bytes());
+    bytes[8..12].copy_from_slice(&signature[2].to_le_bytes());
+    bytes[12..16].copy_from_slice(&signature[3].to_le_bytes());
+    bytes
+}
+
+/// Reads the error code out of a 0x94 status report.
+fn get_report_0x94_status(device: &HidDevice) -> Option<u16> {
+    let mut data = vec![0; 0x40 + 1];
+
+    let data_len = get_feature_report_workaround(device, &mut data);
+    let data = &data[..data_len];
+
+    if data[0] != 0 || data[1] != 0x94 {
+        return None;
+    }
+
+    Some(u16::from_le_bytes(data[3..5].try_into().unwrap()))
+}
+
+fn begin_bootloader_update(device: &HidDevice, force: bool) -> Option<u16> {
+    let mut data = vec![0; 0x40 + 1];
+    data[0] = 0;
+    data[1] = 0xa1;
+    data[2] = force as u8;
+
+    device.send_feature_report(&data).unwrap();
+
+    get_report_0x94_status(device)
+}
+
+/// Data should be less than 0x3E bytes in size.
+fn write_bootloader_update_data(device: &HidDevice, to_flash: &[u8]) -> Option<u16> {
+    let mut data = vec![0; 0x40 + 1];
+    data[0] = 0;
+    data[1] = 0xa2;
+    data[2] = to_flash.len() as u8 + 2;
+    data[3..3 + to_flash.len()].copy_from_slice(to_flash);
+
+    device.send_feature_report(&data).unwrap();
+
+    get_report_0x94_status(device)
+}
+
+fn verify_bootloader_update(device: &HidDevice, signature: &[u8]) -> Option<u16> {
+    let mut data = vec![0; 0x40 + 1];
+    data[0] = 0;
+    data[1] = 0xa3;
+    data[2] = signature.len() as u8;
+    data[3..3 + signature.len()].copy_from_slice(signature);
+
+    device.send_feature_report(&data).unwrap();
+
+    get_report_0x94_status(device)
+}
+
+/// Asks the bootloader to apply the update it verified. It only resets into
+/// the new bootloader a while after answering, so failing to get the answer
+/// is a failure like any other.
+fn apply_bootloader_update(device: &HidDevice) -> Option<u16> {
+    let mut data = vec![0; 0x40 + 1];
+    data[0] = 0;
+    data[1] = 0xa6;
+
+    device.send_feature_report(&data).ok()?;
+
+    // Same workaround as get_feature_report_workaround, minus the unwrap.
+    let data_len = loop {
+        match device.get_feature_report(&mut data).ok()? {
+            1 => continue,
+            data_len => break data_len,
+        }
+    };
+    let data = &data[..data_len];
+
+    if data_len < 5 || data[0] != 0 || data[1] != 0x94 {
+        return None;
+    }
+
+    Some(u16::from_le_bytes(data[3..5].try_into().unwrap()))
+}
+
+/// Checks the things the bootloader checks before agreeing to replace itself,
+/// so we can refuse early, before touching the device.
+fn check_bootloader_image(image: &[u8]) -> Result<(), &'static str> {
+    if image.len() < 0x20 || image.len() > 0x2000 {
+        return Err("bootloader image must be between 32 bytes and 8KiB");
+    }
+
+    let vectors: Vec<u32> = image[..0x20].chunks(4)
+        .map(|v| u32::from_le_bytes(v.try_into().unwrap()))
+        .collect();
+
+    if vectors.iter().fold(0u32, |acc, v| acc.wrapping_add(*v)) != 0 {
+        return Err("invalid vector table checksum, did you run lpc_checksum on it?");
+    }
+    if vectors[0] < 0x1000_0000 || vectors[0] > 0x1000_2000 || vectors[0] % 4 != 0 {
+        return Err("initial stack pointer isn't in RAM");
+    }
+    if vectors[1] & 1 == 0 || vectors[1] >= 0x2000 {
+        return Err("reset vector isn't inside the bootloader");
+    }
+
+    Ok(())
+}
+
+/// Where program2 gets flashed, and the end of flash.
+const PROGRAM2_BASE: u32 = 0x2000;
+const FLASH_END: u32 = 0x2_0000;
+
+/// Checks that a program2 image is linked for 0x2000: the bootloader forwards
+/// the interrupts to the handlers in its vector table, so an image linked
+/// anywhere else would crash.
+fn check_program2_image(image: &[u8]) -> Result<(), String> {
+    // 16 exceptions, and the 32 interrupts of the NVIC.
+    const VECTOR_COUNT: usize = 48;
+
+    if image.len() < VECTOR_COUNT * 4 || image.len() > (FLASH_END - PROGRAM2_BASE) as usize {
+        return Err("program2 image must be between 192 bytes and 120KiB".into());
+    }
+
+    let vectors: Vec<u32> = image[..VECTOR_COUNT * 4].chunks(4)
+        .map(|v| u32::from_le_bytes(v.try_into().unwrap()))
+        .collect();
+    let image_end = PROGRAM2_BASE + image.len() as u32;
+    let is_code = |vector: u32| vector & 1 != 0 && (PROGRAM2_BASE..image_end).contains(&(vector & !1));
+
+    if vectors[0] <= 0x1000_0000 || vectors[0] > 0x1000_2000 || vectors[0] % 4 != 0 {
+        return Err(format!("initial stack pointer {:#x} isn't in RAM", vectors[0]));
+    }
+    if !is_code(vectors[1]) {
+        return Err(format!("reset vector {:#x} isn't inside the image", vectors[1]));
+    }
+    for (idx, &vector) in vectors.iter().enumerate().skip(2) {
+        // 7 holds the LPC checksum, 9 the boot magic. Handlers left at 0 are
+        // unused.
+        if idx == 7 || idx == 9 || vector == 0 {
+            continue;
+        }
+        if !is_code(vector) {
+            return Err(format!("vector {} ({:#x}) isn't inside the image", idx, vector));
+        }
+    }
+
+    Ok(())
+}
+
+fn start_radio_upload(device: &HidDevice) -> Option<u16> {
+    let mut data = vec![0; 0x40 + 1];
+    data[0] = 0;
+    data[1] = 0x97;
+
+    device.send_feature_report(&data).unwrap();
+
+    get_report_0x94_status(device)
+}
+
+/// Data should be less than 0x3E bytes in size.
+fn write_radio_chunk(device: &HidDevice, chunk: &[u8]) -> Option<u16> {
+    let mut data = vec![0; 0x40 + 1];
+    data[0] = 0;
+    data[1] = 0x98;
+    data[2] = chunk.len() as u8 + 2;
+    data[3..3 + chunk.len()].copy_from_slice(chunk);
+
+    device.send_feature_report(&data).unwrap();
+
+    get_report_0x94_status(device)
+}
+
+fn end_radio_upload(device: &HidDevice, signature: &[u8]) -> Option<u16> {
+    let mut data = vec![0; 0x40 + 1];
+    data[0] = 0;
+    data[1] = 0x99;
+    data[2] = signature.len() as u8 + 2;
+    data[3..3 + signature.len()].copy_from_slice(signature);
+
+    device.send_feature_report(&data).unwrap();
+
+    get_report_0x94_status(device)
+}
+
+fn reset_radio(device: &HidDevice) {
+    let mut data = vec![0; 0x40 + 1];
+    data[0] = 0;
+    data[1] = 0x95;
+    data[2] = 0;
+
+    device.send_feature_report(&data).unwrap();
+}
+
+/// The bootloader answers 0x97-0x99 with a status of 2 ("in progress"). It
+/// only changes once the nRF answers the '[' packet, at which point the
+/// bootloader stores the nRF's status in the 0x94 report.
+fn wait_radio_upload_status(device: &HidDevice, timeout: std::time::Duration) -> Option<u16> {
+    let start = std::time::Instant::now();
+    loop {
+        match get_report_0x94_status(device) {
+            Some(2) if start.elapsed() < timeout => (),
+            status => return status,
+        }
+        std::thread::sleep(std::time::Duration::from_millis(50));
+    }
+}
+
+fn parse_signature(s: &str) -> Option<[u8; 16]> {
+    let s = s.trim_start_matches("0x");
+    if s.len() != 32 {
+        return None;
+    }
+    let mut signature = [0; 16];
+    for (i, byte) in signature.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(s.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(signature)
+}
+
+#[derive(Debug)]
+struct CrashRecord {
+    kind: u32,
+    reset_reason: u32,
+    icsr: u32,
+    r0: u32,
+    r1: u32,
+    r2: u32,
+    r3: u32,
+    r12: u32,
+    lr: u32,
+    pc: u32,
+    xpsr: u32,
+    panic_digest: u32,
+    panic_line: u32,
+}
+
+/// Returns None if the device didn't answer, Some(None) if it has no crash
+/// recorded.
+fn get_crash_log(device: &HidDevice) -> Option<Option<CrashRecord>> {
+    let mut data = vec![0; 0x40 + 1];
+    data[0] = 0;
+    data[1] = 0xa4;
+
+    device.send_feature_report(&data).unwrap();
+
+    let mut data = vec![0; 0x40 + 1];
+
+    let data_len = get_feature_report_workaround(device, &mut data);
+    let data = &data[..data_len];
+
+    if data[0] != 0 || data[1] != 0xa4 || data[2] < 56 {
+        return None;
+    }
+
+    let words: Vec<u32> = data[3..3 + 56].chunks(4)
+        .map(|v| u32::from_le_bytes(v.try_into().unwrap()))
+        .collect();
+
+    if words[0] != 0xdead_c0de {
+        return Some(None);
+    }
+
+    Some(Some(CrashRecord {
+        kind: words[1],
+        reset_reason: words[2],
+        icsr: words[3],
+        r0: words[4],
+        r1: words[5],
+        r2: words[6],
+        r3: words[7],
+        r12: words[8],
+        lr: words[9],
+        pc: words[10],
+        xpsr: words[11],
+        panic_digest: words[12],
+        panic_line: words[13],
+    }))
+}
+
+fn clear_crash_log(device: &HidDevice) -> Option<u16> {
+    let mut data = vec![0; 0x40 + 1];
+    data[0] = 0;
+    data[1] = 0xa5;
+
+    device.send_feature_report(&data).unwrap();
+
+    get_report_0x94_status(device)
+}
+
+fn print_crash_record(record: &CrashRecord) {
+    match record.kind {
+        1 => println!("HardFault"),
+        2 => println!("Panic at line {}, digest {:08x}", record.panic_line, record.panic_digest),
+        3 => println!("Program2 panic at line {}, digest {:08x}", record.panic_line, record.panic_digest),
+        kind => println!("Unknown crash kind {}", kind),
+    }
+
+    let reasons = [(0, "POR"), (1, "EXTRST"), (2, "WDT"), (3, "BOD"), (4, "SYSRST")];
+    let reasons: Vec<&str> = reasons.iter()
+        .filter(|(bit, _)| record.reset_reason & (1 << bit) != 0)
+        .map(|(_, name)| *name)
+        .collect();
+    println!("Last reset: {:02x} {:?}", record.reset_reason, reasons);
+    println!("ICSR: {:08x} (active vector {})", record.icsr, record.icsr & 0x3f);
+
+    if record.kind == 1 {
+        println!("r0  = {:08x}  r1 = {:08x}  r2   = {:08x}  r3 = {:08x}", record.r0, record.r1, record.r2, record.r3);
+        println!("r12 = {:08x}  lr = {:08x}  pc   = {:08x}  xpsr = {:08x}", record.r12, record.lr, record.pc, record.xpsr);
+    }
+}
+
+fn cmd_signature(args: &[String]) {
+    let path = args.get(0).map(String::as_str).unwrap_or("firmware_from_flash.bin");
+
     /*let mut hidapi = HidApi::new().unwrap();
 
     let device = find_bootloader_device(&mut hidapi);
     println!("{:?}", get_hardware_info(&device));*/
 
-    let mut firmware_file = std::fs::File::open("firmware_from_flash.bin").unwrap();
+    let mut firmware_file = std::fs::File::open(path).unwrap();
     //firmware_file.seek(SeekFrom::Start(0x2000)).unwrap();
 
     //erase_program2(&device).unwrap();
@@ -185,42 +532,329 @@ fn main() {
 
     firmware_file.seek(SeekFrom::Start(0x30)).unwrap();
 
-    let mut cur_word = [0u32; 4];
-    let mut ref_signature = [0u32; 4];
-    let mut next_signature = [0u32; 4];
-    loop {
-        let mut buf = [0; 0x200];
-        match firmware_file.read_exact(&mut buf) {
-            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => break,
-            v => v.unwrap()
+    let mut firmware_data = Vec::new();
+    firmware_file.read_to_end(&mut firmware_data).unwrap();
+
+    let ref_signature = fmc_signature(&firmware_data);
+
+    println!("Expecting signature {:x?}", ref_signature);
+
+    //verify_flash_data(&device, &signature_to_bytes(&ref_signature)).unwrap();
+}
+
+fn cmd_check_image(args: &[String]) {
+    let path = args.get(0).unwrap_or_else(|| usage());
+    let image = std::fs::read(path).unwrap();
+
+    match check_program2_image(&image) {
+        Ok(()) => println!("{} looks like a valid program2 image", path),
+        Err(err) => {
+            eprintln!("{}: {}", path, err);
+            std::process::exit(1);
         }
+    }
+}
 
-        for buf in buf.chunks(16) {
-            cur_word[0] = u32::from_le_bytes(buf[0..4].try_into().unwrap());
-            cur_word[1] = u32::from_le_bytes(buf[4..8].try_into().unwrap());
-            cur_word[2] = u32::from_le_bytes(buf[8..12].try_into().unwrap());
-            cur_word[3] = u32::from_le_bytes(buf[12..16].try_into().unwrap());
+fn cmd_bootloader_update(args: &[String]) {
+    let mut path = None;
+    let mut confirmed = false;
+    for arg in args {
+        match arg.as_str() {
+            "--i-know-what-im-doing" => confirmed = true,
+            _ => path = Some(arg),
+        }
+    }
 
-            next_signature[0] = cur_word[0] ^ ref_signature[0] >> 1 ^ ref_signature[1] << 31;
-            next_signature[1] = cur_word[1] ^ ref_signature[1] >> 1 ^ ref_signature[2] << 31;
-            next_signature[2] = cur_word[2] ^ ref_signature[2] >> 1 ^ ref_signature[3] << 31;
-            next_signature[3] = cur_word[3] ^ ref_signature[3] >> 1 ^
-                (ref_signature[0] & (1 << 29)) << 02 ^
-                (ref_signature[0] & (1 << 27)) << 04 ^
-                (ref_signature[0] & (1 << 02)) << 29 ^
-                (ref_signature[0] & (1 << 00)) << 31;
+    let path = path.unwrap_or_else(|| usage());
+    let image = std::fs::read(path).unwrap();
 
-            ref_signature = next_signature;
+    if let Err(err) = check_bootloader_image(&image) {
+        eprintln!("Refusing to flash {}: {}", path, err);
+        std::process::exit(1);
+    }
+
+    if !confirmed {
+        eprintln!("Replacing the bootloader can brick your controller if the new one is broken.");
+        eprintln!("Pass --i-know-what-im-doing if you really want to do this.");
+        std::process::exit(1);
+    }
+
+    let mut hidapi = HidApi::new().unwrap();
+    let device = find_bootloader_device(&mut hidapi);
+
+    match begin_bootloader_update(&device, false) {
+        Some(0) => (),
+        Some(9) => {
+            println!("The staging area overlaps program2. It will be erased, and you will have to flash it again.");
+            match begin_bootloader_update(&device, true) {
+                Some(0) => (),
+                err => panic!("Failed to prepare the staging area: {:?}", err),
+            }
+        },
+        err => panic!("Failed to prepare the staging area: {:?}", err),
+    }
+
+    for chunk in image.chunks(0x3e) {
+        match write_bootloader_update_data(&device, chunk) {
+            Some(0) => (),
+            err => panic!("Failed to upload the new bootloader: {:?}", err),
         }
     }
 
-    println!("Expecting signature {:x?}", ref_signature);
+    let signature = signature_to_bytes(&fmc_signature(&image));
+    match verify_bootloader_update(&device, &signature) {
+        Some(0) => println!("New bootloader verified."),
+        err => panic!("The bootloader refused the new image: {:?}", err),
+    }
+
+    match apply_bootloader_update(&device) {
+        Some(0) => println!("The controller will now reboot into the new bootloader."),
+        err => {
+            eprintln!("The bootloader didn't confirm applying the new image: {:?}", err);
+            eprintln!("It may still apply it: leave the controller plugged in until it reboots.");
+            std::process::exit(1);
+        },
+    }
+}
+
+/// Sends a radio upload packet, again while the bootloader answers that its
+/// TX queue to the nRF is full.
+fn send_radio_packet(send: impl Fn() -> Option<u16>) -> Option<u16> {
+    const QUEUE_FULL: u16 = 15;
+    const RETRY_DELAY: std::time::Duration = std::time::Duration::from_millis(2);
+    const MAX_RETRIES: u32 = 500;
+
+    let mut status = send();
+    for _ in 0..MAX_RETRIES {
+        if status != Some(QUEUE_FULL) {
+            break;
+        }
+        std::thread::sleep(RETRY_DELAY);
+        status = send();
+    }
+    status
+}
+
+fn cmd_flash_radio(args: &[String]) {
+    // Chunks go out over the UART as soon as the bootloader gets them, and
+    // the bootloader refuses what doesn't fit in its 256 bytes TX buffer. Give
+    // it (and the nRF, which writes them to its flash) time to keep up.
+    const CHUNK_DELAY: std::time::Duration = std::time::Duration::from_millis(5);
+    // The nRF erases its flash when it gets the 'Y' packet.
+    const ERASE_DELAY: std::time::Duration = std::time::Duration::from_secs(1);
+
+    let mut path = None;
+    let mut signature = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--signature" => {
+                let arg = args.next().unwrap_or_else(|| usage());
+                signature = Some(parse_signature(arg).unwrap_or_else(|| {
+                    eprintln!("Invalid signature {}, expected 32 hex digits", arg);
+                    std::process::exit(1);
+                }));
+            },
+            _ => path = Some(arg),
+        }
+    }
+
+    let path = path.unwrap_or_else(|| usage());
+    let firmware = std::fs::read(path).unwrap();
+
+    // What the nRF checks the image against isn't known, so there is no
+    // default: a wrong one only shows up once the radio rejects the upload.
+    let signature = signature.unwrap_or_else(|| {
+        eprintln!("flash-radio needs the signature the radio expects, with --signature <hex>");
+        std::process::exit(1);
+    });
+
+    let mut hidapi = HidApi::new().unwrap();
+    let device = find_bootloader_device(&mut hidapi);
+
+    match send_radio_packet(|| start_radio_upload(&device)) {
+        Some(2) => (),
+        err => panic!("Failed to start the radio firmware upload: {:?}", err),
+    }
+    std::thread::sleep(ERASE_DELAY);
+
+    for (i, chunk) in firmware.chunks(0x3e).enumerate() {
+        match send_radio_packet(|| write_radio_chunk(&device, chunk)) {
+            Some(2) => (),
+            err => panic!("Failed to send chunk {}: {:?}", i, err),
+        }
+        std::thread::sleep(CHUNK_DELAY);
+    }
+
+    match send_radio_packet(|| end_radio_upload(&device, &signature)) {
+        Some(2) => (),
+        err => panic!("Failed to send the signature: {:?}", err),
+    }
+
+    match wait_radio_upload_status(&device, std::time::Duration::from_secs(10)) {
+        Some(0) => println!("Radio firmware flashed."),
+        Some(2) => {
+            eprintln!("The radio never acknowledged the upload.");
+            std::process::exit(1);
+        },
+        status => {
+            eprintln!("The radio rejected the upload: {:?}", status);
+            std::process::exit(1);
+        },
+    }
+
+    // Also makes the controller reboot, through the watchdog, once the radio
+    // is back.
+    reset_radio(&device);
+}
+
+fn cmd_crashlog(args: &[String]) {
+    let clear = args.iter().any(|arg| arg == "--clear");
+
+    let mut hidapi = HidApi::new().unwrap();
+    let device = find_bootloader_device(&mut hidapi);
+
+    match get_crash_log(&device) {
+        Some(Some(record)) => print_crash_record(&record),
+        Some(None) => println!("No crash recorded."),
+        None => panic!("Failed to read the crash log"),
+    }
+
+    if clear {
+        match clear_crash_log(&device) {
+            Some(0) => println!("Crash log cleared."),
+            err => panic!("Failed to clear the crash log: {:?}", err),
+        }
+    }
+}
+
+fn parse_hex(s: &str) -> Option<Vec<u8>> {
+    if s.len() % 2 != 0 {
+        return None;
+    }
+    (0..s.len()).step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// A packet mirrored by the bootloader, possibly still missing some of its
+/// `#nrf+` continuation lines.
+struct SniffedPacket {
+    direction: &'static str,
+    timestamp: u32,
+    len: usize,
+    data: Vec<u8>,
+}
+
+enum SniffedLine {
+    Start(SniffedPacket),
+    Continuation(Vec<u8>),
+    Dropped(u32),
+}
+
+/// Parses one of the lines the bootloader prints while sniffing:
+///
+/// - `#nrf <rx|tx> <timestamp> <length> <hex>` starts a packet,
+/// - `#nrf+ <hex>` holds more of it,
+/// - `#nrf dropped <count>` tells packets were lost.
+fn parse_sniffed_line(line: &str) -> Option<SniffedLine> {
+    let mut fields = line.trim_end().split(' ');
+    match fields.next()? {
+        "#nrf" => (),
+        "#nrf+" => return parse_hex(fields.next()?).map(SniffedLine::Continuation),
+        _ => return None,
+    }
+    let direction = match fields.next()? {
+        "rx" => "nRF -> LPC",
+        "tx" => "LPC -> nRF",
+        "dropped" => return fields.next()?.parse().ok().map(SniffedLine::Dropped),
+        _ => return None,
+    };
+    let timestamp = fields.next()?.parse().ok()?;
+    let len = fields.next()?.parse().ok()?;
+    let data = parse_hex(fields.next().unwrap_or(""))?;
 
-    //let mut signature = [0; 16];
-    //signature[0..4].copy_from_slice(&ref_signature[0].to_le_bytes());
-    //signature[4..8].copy_from_slice(&ref_signature[1].to_le_bytes());
-    //signature[8..12].copy_from_slice(&ref_signature[2].to_le_bytes());
-    //signature[12..16].copy_from_slice(&ref_signature[3].to_le_bytes());
+    Some(SniffedLine::Start(SniffedPacket { direction, timestamp, len, data }))
+}
+
+fn print_sniffed_packet(packet: &SniffedPacket) {
+    let timestamp = format!("{:>6}.{:03}", packet.timestamp / 1000, packet.timestamp % 1000);
+    if packet.data.len() != packet.len {
+        println!("{} {}: garbled packet, expected {} bytes, got {}: {:02x?}",
+            timestamp, packet.direction, packet.len, packet.data.len(), packet.data);
+        return;
+    }
 
-    //verify_flash_data(&device, &signature).unwrap();
-}
\ No newline at end of file
+    let message = Message::parse(&packet.data);
+    println!("{} {}: {:x?}", timestamp, packet.direction, message);
+}
+
+fn cmd_sniff(args: &[String]) {
+    let path = args.get(0).unwrap_or_else(|| usage());
+
+    // Put the tty in raw mode. With the default line discipline, the kernel
+    // echoes everything the bootloader prints back to its console.
+    let status = std::process::Command::new("stty")
+        .args(&["-F", path, "raw", "-echo"])
+        .status();
+    if !status.map(|s| s.success()).unwrap_or(false) {
+        eprintln!("Warning: failed to put {} in raw mode", path);
+    }
+
+    let mut tty = std::fs::OpenOptions::new().read(true).write(true).open(path).unwrap();
+    tty.write_all(b"sniff on\r").unwrap();
+
+    let reader = BufReader::new(tty);
+    let mut pending: Option<SniffedPacket> = None;
+    for line in reader.lines() {
+        let line = line.unwrap();
+        match parse_sniffed_line(&line) {
+            Some(SniffedLine::Start(packet)) => {
+                // The previous packet lost its last lines.
+                if let Some(packet) = pending.take() {
+                    print_sniffed_packet(&packet);
+                }
+                pending = Some(packet);
+            },
+            Some(SniffedLine::Continuation(data)) => match pending.as_mut() {
+                Some(packet) => packet.data.extend(data),
+                None => println!("continuation of a lost packet: {:02x?}", data),
+            },
+            Some(SniffedLine::Dropped(count)) => println!("the bootloader dropped {} packets", count),
+            // Not a sniffed packet. Probably a log message, show it as-is.
+            None if !line.is_empty() => println!("{}", line),
+            None => (),
+        }
+
+        if pending.as_ref().map_or(false, |packet| packet.data.len() >= packet.len) {
+            print_sniffed_packet(&pending.take().unwrap());
+        }
+    }
+}
+
+fn usage() -> ! {
+    eprintln!("Usage: driver-cli <command> [args...]");
+    eprintln!();
+    eprintln!("Commands:");
+    eprintln!("    signature [file]                                 Compute the FMC signature of a program2 image");
+    eprintln!("    check-image <file>                               Check that a program2 image is linked at 0x2000");
+    eprintln!("    bootloader-update <file> --i-know-what-im-doing  Replace the bootloader");
+    eprintln!("    flash-radio <file> --signature <hex>             Flash the nRF radio firmware");
+    eprintln!("    sniff <tty>                                      Decode the nRF traffic mirrored on the CDC port");
+    eprintln!("    crashlog [--clear]                               Show the last recorded crash");
+    std::process::exit(1);
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+
+    match args.get(0).map(String::as_str) {
+        Some("signature") => cmd_signature(&args[1..]),
+        Some("check-image") => cmd_check_image(&args[1..]),
+        Some("bootloader-update") => cmd_bootloader_update(&args[1..]),
+        Some("flash-radio") => cmd_flash_radio(&args[1..]),
+        Some("sniff") => cmd_sniff(&args[1..]),
+        Some("crashlog") => cmd_crashlog(&args[1..]),
+        _ => usage(),
+    }
+}
@@ -1,7 +1,12 @@
 MEMORY
 {
   /* NOTE 1 K = 1 KiBi = 1024 bytes */
-  /* These values correspond to the LPC11U37F */
-  FLASH : ORIGIN = 0x00000000, LENGTH = 128K
+  /* These values correspond to the LPC11U37F. The bootloader takes the
+     first 8K of flash, and runs the firmware from 0x2000 (program2). */
+  FLASH : ORIGIN = 0x00002000, LENGTH = 120K
   RAM : ORIGIN = 0x10000000, LENGTH = 8K
 }
+
+/* The IAP routines (EEPROM reads and writes, see valve.rs and analog.rs) use
+   the top 32 bytes of RAM, so the stack has to start below them. */
+_stack_start = ORIGIN(RAM) + LENGTH(RAM) - 32;
@@ -0,0 +1,334 @@
+//! The analog stick and the analog triggers.
+//!
+//! The ADC converts their channels over and over in burst mode, and the
+//! latest results are read when needed. CT16B0 ticks every
+//! `SAMPLE_PERIOD_MS` to wake the main loop up, so that movements get
+//! reported even when nothing else happens. The tick also starts sampling the
+//! trackpads and the IMU.
+//!
+//! Calibration (see `input_processing::calibration`) is kept in the EEPROM.
+
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use cortex_m::peripheral::NVIC;
+use input_processing::calibration::{AxisCalibration, AXIS_CALIBRATION_LEN};
+use input_processing::stick::{self, StickSettings};
+use lpc11uxx::{interrupt, Interrupt, Peripherals};
+use lpc11uxx_rom::iap;
+
+use crate::buttons;
+use crate::imu;
+use crate::system;
+use crate::trackpad;
+
+const SAMPLE_PERIOD_MS: u32 = 4;
+
+const ADC_BITS: u32 = 10;
+/// The ADC clock must stay under 4.5MHz.
+const ADC_MAX_CLOCK_RATE: u32 = 4_500_000;
+
+/// ADC channel of each axis, AD0..AD3 on PIO0_11..PIO0_14.
+const STICK_X: usize = 0;
+const STICK_Y: usize = 1;
+const LEFT_TRIGGER: usize = 2;
+const RIGHT_TRIGGER: usize = 3;
+const AXIS_COUNT: usize = 4;
+
+/// IOCON of PIO0_11..PIO0_14: FUNC = ADn, no pull resistor, analog mode.
+const IOCON_ANALOG_INPUT: u32 = 0x2;
+
+/// ADC CR: SEL (bits 0..8), CLKDIV (bits 8..16), BURST.
+const ADC_CR_SEL_SHIFT: u32 = 0;
+const ADC_CR_CLKDIV_SHIFT: u32 = 8;
+const ADC_CR_BURST: u32 = 1 << 16;
+/// ADC DRn: the result is in bits 6..16.
+const ADC_DR_RESULT_SHIFT: u32 = 6;
+
+/// Where the calibration lives in the EEPROM, after the bootloader's header.
+const CALIBRATION_EEPROM_ADDRESS: u32 = 0x100;
+const CALIBRATION_MAGIC: u16 = 0xca1b;
+const CALIBRATION_EEPROM_LEN: usize = 2 + AXIS_COUNT * AXIS_CALIBRATION_LEN;
+
+/// Calibration ends, and gets saved, once the ranges stopped growing for that
+/// long.
+const CALIBRATION_SETTLE_MS: u32 = 3000;
+
+/// Triggers rest at the bottom of their range.
+const TRIGGER_DEFAULT_CALIBRATION: AxisCalibration = AxisCalibration {
+    min: 0,
+    center: 0,
+    max: (1 << ADC_BITS) - 1,
+};
+
+static mut CALIBRATION: [AxisCalibration; AXIS_COUNT] = [
+    AxisCalibration::full_range(ADC_BITS),
+    AxisCalibration::full_range(ADC_BITS),
+    TRIGGER_DEFAULT_CALIBRATION,
+    TRIGGER_DEFAULT_CALIBRATION,
+];
+/// While calibrating, when the ranges last grew.
+static mut CALIBRATION_CHANGED_AT: Option<u32> = None;
+/// The calibration in use before calibrating, put back if the new one can't
+/// be saved.
+static mut PREVIOUS_CALIBRATION: [AxisCalibration; AXIS_COUNT] = [
+    AxisCalibration::full_range(ADC_BITS),
+    AxisCalibration::full_range(ADC_BITS),
+    TRIGGER_DEFAULT_CALIBRATION,
+    TRIGGER_DEFAULT_CALIBRATION,
+];
+static CALIBRATION_STATUS: AtomicU8 = AtomicU8::new(CalibrationStatus::Idle as u8);
+
+/// How the last calibration went, as the host reads it back.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[repr(u8)]
+pub enum CalibrationStatus {
+    Idle = 0,
+    InProgress = 1,
+    Saved = 2,
+    /// An axis didn't move away from where it rested, or didn't rest inside
+    /// its range. The previous calibration is kept.
+    Invalid = 3,
+    /// The EEPROM write failed. The new calibration is used until the next
+    /// reset.
+    WriteFailed = 4,
+}
+
+/// The analog controls, calibrated and processed.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct AnalogState {
+    pub stick_x: i16,
+    pub stick_y: i16,
+    pub left_trigger: u8,
+    pub right_trigger: u8,
+}
+
+pub fn initialize() {
+    let peripherals = unsafe { Peripherals::steal() };
+
+    peripherals
+        .SYSCON
+        .pdruncfg
+        .modify(|_, writer| writer.adc_pd().powered());
+    peripherals
+        .SYSCON
+        .sysahbclkctrl
+        .modify(|_, writer| writer.adc().enabled().ct16b0().enabled());
+
+    unsafe {
+        peripherals
+            .IOCON
+            .tdi_pio0_11
+            .write(|writer| writer.bits(IOCON_ANALOG_INPUT));
+        peripherals
+            .IOCON
+            .tms_pio0_12
+            .write(|writer| writer.bits(IOCON_ANALOG_INPUT));
+        peripherals
+            .IOCON
+            .tdo_pio0_13
+            .write(|writer| writer.bits(IOCON_ANALOG_INPUT));
+        peripherals
+            .IOCON
+            .trst_pio0_14
+            .write(|writer| writer.bits(IOCON_ANALOG_INPUT));
+
+        let clock_divider =
+            (system::main_clock_rate() + ADC_MAX_CLOCK_RATE - 1) / ADC_MAX_CLOCK_RATE - 1;
+        let channels = (1 << AXIS_COUNT) - 1;
+        peripherals.ADC.inten.write(|writer| writer.bits(0));
+        peripherals.ADC.cr.write(|writer| {
+            writer.bits(
+                channels << ADC_CR_SEL_SHIFT | clock_divider << ADC_CR_CLKDIV_SHIFT | ADC_CR_BURST,
+            )
+        });
+
+        // Millisecond ticks, interrupting and restarting every
+        // SAMPLE_PERIOD_MS.
+        peripherals.CT16B0.pr.write(|writer| {
+            writer
+                .pcval()
+                .bits((system::main_clock_rate() / 1000 - 1) as u16)
+        });
+        peripherals.CT16B0.mr[0].write(|writer| writer.bits(SAMPLE_PERIOD_MS - 1));
+    }
+    peripherals
+        .CT16B0
+        .mcr
+        .modify(|_, writer| writer.mr0i().enabled().mr0r().enabled());
+    peripherals
+        .CT16B0
+        .tcr
+        .modify(|_, writer| writer.cen().the_timer_counter_an());
+
+    NVIC::unpend(Interrupt::CT16B0);
+    unsafe { NVIC::unmask(Interrupt::CT16B0) };
+
+    load_calibration();
+}
+
+fn read_raw() -> [u16; AXIS_COUNT] {
+    let peripherals = unsafe { Peripherals::steal() };
+
+    let mut raw = [0; AXIS_COUNT];
+    for (channel, value) in raw.iter_mut().enumerate() {
+        let data = peripherals.ADC.dr[channel].read().bits();
+        *value = ((data >> ADC_DR_RESULT_SHIFT) & ((1 << ADC_BITS) - 1)) as u16;
+    }
+    raw
+}
+
+/// Reads the latest conversions. While calibrating, they also widen the
+/// ranges.
+pub fn sample(settings: &StickSettings) -> AnalogState {
+    let raw = read_raw();
+    let calibration = unsafe { &mut CALIBRATION };
+
+    unsafe {
+        if CALIBRATION_CHANGED_AT.is_some() {
+            let mut changed = false;
+            for (axis, value) in calibration.iter_mut().zip(raw.iter()) {
+                changed |= axis.track(*value);
+            }
+            if changed {
+                CALIBRATION_CHANGED_AT = Some(buttons::now());
+            }
+        }
+    }
+
+    let (stick_x, stick_y) = stick::process(
+        calibration[STICK_X].normalize(raw[STICK_X]),
+        calibration[STICK_Y].normalize(raw[STICK_Y]),
+        settings,
+    );
+
+    AnalogState {
+        stick_x,
+        stick_y,
+        left_trigger: calibration[LEFT_TRIGGER].normalize_unipolar(raw[LEFT_TRIGGER]),
+        right_trigger: calibration[RIGHT_TRIGGER].normalize_unipolar(raw[RIGHT_TRIGGER]),
+    }
+}
+
+/// Starts calibrating: where the stick and triggers are now becomes their
+/// rest position, and their ranges get learned while the user moves them
+/// all the way around.
+pub fn start_calibration() {
+    let raw = read_raw();
+
+    unsafe {
+        // Not while already calibrating, that would keep the unfinished one.
+        if CALIBRATION_CHANGED_AT.is_none() {
+            PREVIOUS_CALIBRATION = CALIBRATION;
+        }
+        for (axis, value) in CALIBRATION.iter_mut().zip(raw.iter()) {
+            *axis = AxisCalibration::centered_at(*value);
+        }
+        CALIBRATION_CHANGED_AT = Some(buttons::now());
+    }
+    CALIBRATION_STATUS.store(CalibrationStatus::InProgress as u8, Ordering::Relaxed);
+}
+
+pub fn calibration_status() -> CalibrationStatus {
+    match CALIBRATION_STATUS.load(Ordering::Relaxed) {
+        1 => CalibrationStatus::InProgress,
+        2 => CalibrationStatus::Saved,
+        3 => CalibrationStatus::Invalid,
+        4 => CalibrationStatus::WriteFailed,
+        _ => CalibrationStatus::Idle,
+    }
+}
+
+/// Ends the calibration and saves it, once it settled. Called from the main
+/// loop, as writing the EEPROM takes a while.
+pub fn finish_calibration_if_settled() {
+    let settled = unsafe {
+        match CALIBRATION_CHANGED_AT {
+            Some(changed_at) => buttons::now().wrapping_sub(changed_at) >= CALIBRATION_SETTLE_MS,
+            None => false,
+        }
+    };
+
+    if settled {
+        unsafe {
+            CALIBRATION_CHANGED_AT = None;
+        }
+        let status = save_calibration();
+        if status == CalibrationStatus::Invalid {
+            unsafe {
+                CALIBRATION = PREVIOUS_CALIBRATION;
+            }
+        }
+        CALIBRATION_STATUS.store(status as u8, Ordering::Relaxed);
+    }
+}
+
+fn load_calibration() {
+    let mut buffer = [0; CALIBRATION_EEPROM_LEN];
+    iap::eeprom_read(
+        CALIBRATION_EEPROM_ADDRESS,
+        &mut buffer,
+        system::main_clock_rate() / 1024,
+    );
+
+    if u16::from_le_bytes([buffer[0], buffer[1]]) != CALIBRATION_MAGIC {
+        return;
+    }
+
+    let mut calibration = unsafe { CALIBRATION };
+    for (axis, bytes) in calibration
+        .iter_mut()
+        .zip(buffer[2..].chunks_exact(AXIS_CALIBRATION_LEN))
+    {
+        let mut axis_bytes = [0; AXIS_CALIBRATION_LEN];
+        axis_bytes.copy_from_slice(bytes);
+        *axis = AxisCalibration::from_bytes(&axis_bytes);
+        if !axis.is_valid() {
+            return;
+        }
+    }
+
+    unsafe {
+        CALIBRATION = calibration;
+    }
+}
+
+/// Saves the calibration, unless one of its axes isn't valid: it would be
+/// thrown away by `load_calibration` anyway.
+fn save_calibration() -> CalibrationStatus {
+    let calibration = unsafe { CALIBRATION };
+    if !calibration.iter().all(AxisCalibration::is_valid) {
+        return CalibrationStatus::Invalid;
+    }
+
+    let mut buffer = [0; CALIBRATION_EEPROM_LEN];
+    buffer[0..2].copy_from_slice(&CALIBRATION_MAGIC.to_le_bytes());
+    for (axis, bytes) in calibration
+        .iter()
+        .zip(buffer[2..].chunks_exact_mut(AXIS_CALIBRATION_LEN))
+    {
+        bytes.copy_from_slice(&axis.to_bytes());
+    }
+
+    let status = iap::eeprom_write(
+        CALIBRATION_EEPROM_ADDRESS,
+        &buffer,
+        system::main_clock_rate() / 1024,
+    );
+    if status == 0 {
+        CalibrationStatus::Saved
+    } else {
+        CalibrationStatus::WriteFailed
+    }
+}
+
+#[interrupt]
+fn CT16B0() {
+    let peripherals = unsafe { Peripherals::steal() };
+    peripherals
+        .CT16B0
+        .ir
+        .write(|writer| writer.mr0int().set_bit());
+
+    trackpad::start_sampling();
+    imu::start_sampling();
+}
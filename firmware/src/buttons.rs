@@ -0,0 +1,332 @@
+//! The digital buttons, read from GPIOs.
+//!
+//! An edge on a button pin raises a pin interrupt (PIN_INT0..7) or the group
+//! interrupt GINT0, which only starts the debouncing: CT32B0 then samples all
+//! the pins every `DEBOUNCE_MS`, and a new state is published once two samples
+//! in a row agree. Sampling goes on while a button is held, since GINT0 only
+//! sees presses. The same interrupts wake the chip up from deep-sleep.
+//!
+//! CT32B0 counts milliseconds, which is also where timestamps come from. It
+//! doesn't count while in deep-sleep.
+
+use cortex_m::peripheral::NVIC;
+use lpc11uxx::{interrupt, Interrupt, Peripherals};
+
+use crate::report::Buttons;
+use crate::system;
+use crate::usbd;
+
+/// How long a button must stay in the same state to be taken into account.
+const DEBOUNCE_MS: u32 = 5;
+
+/// GPIO (port, pin) of each button, all active low. The first 8 get a pin
+/// interrupt each, the others share GINT0.
+const BUTTON_PINS: [((usize, u32), Buttons); 16] = [
+    ((0, 1), Buttons::A),
+    ((0, 2), Buttons::B),
+    ((0, 7), Buttons::X),
+    ((0, 17), Buttons::Y),
+    ((0, 18), Buttons::LEFT_BUMPER),
+    ((0, 19), Buttons::RIGHT_BUMPER),
+    ((0, 20), Buttons::LEFT_PAD),
+    ((1, 1), Buttons::RIGHT_PAD),
+    ((1, 2), Buttons::LEFT_TRIGGER),
+    ((1, 3), Buttons::RIGHT_TRIGGER),
+    ((1, 4), Buttons::BACK),
+    ((1, 5), Buttons::START),
+    ((1, 6), Buttons::STEAM),
+    ((1, 7), Buttons::LEFT_STICK),
+    ((1, 9), Buttons::LEFT_GRIP),
+    ((1, 11), Buttons::RIGHT_GRIP),
+];
+
+const PIN_INTERRUPT_COUNT: usize = 8;
+
+const PIN_INTERRUPTS: [Interrupt; PIN_INTERRUPT_COUNT] = [
+    Interrupt::PIN_INT0,
+    Interrupt::PIN_INT1,
+    Interrupt::PIN_INT2,
+    Interrupt::PIN_INT3,
+    Interrupt::PIN_INT4,
+    Interrupt::PIN_INT5,
+    Interrupt::PIN_INT6,
+    Interrupt::PIN_INT7,
+];
+
+/// SYSAHBCLKCTRL: clock of the GPIO group 0 interrupt.
+const SYSAHBCLKCTRL_GROUP0INT: u32 = 1 << 23;
+/// GINT0 CTRL: clears the pending interrupt when written with 1.
+const GINT_CTRL_INT: u32 = 1 << 0;
+/// STARTERP0: PIN_INT0..7 wake-up, one bit each.
+const STARTERP0_PINT_ALL: u32 = 0xff;
+const STARTERP1_GINT0: u32 = 1 << 20;
+
+/// The debounced buttons, and when they last changed.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct ButtonState {
+    pub buttons: Buttons,
+    /// Milliseconds, as returned by `now`.
+    pub timestamp: u32,
+}
+
+static mut STATE: ButtonState = ButtonState {
+    buttons: Buttons::empty(),
+    timestamp: 0,
+};
+/// The last sample, to compare the next one against.
+static mut LAST_SAMPLE: Buttons = Buttons::empty();
+static mut SAMPLING: bool = false;
+
+pub fn initialize() {
+    let peripherals = unsafe { Peripherals::steal() };
+
+    // GPIO and PINT clocks are already on (see system::initialize).
+    peripherals
+        .SYSCON
+        .sysahbclkctrl
+        .modify(|reader, writer| unsafe { writer.bits(reader.bits() | SYSAHBCLKCTRL_GROUP0INT) });
+    peripherals
+        .SYSCON
+        .sysahbclkctrl
+        .modify(|_, writer| writer.ct32b0().enabled());
+
+    // Inputs, keeping the pull-ups they have out of reset.
+    for ((port, pin), _) in BUTTON_PINS.iter() {
+        peripherals.GPIO_PORT.dir[*port]
+            .modify(|reader, writer| unsafe { writer.bits(reader.bits() & !(1 << pin)) });
+    }
+
+    // Pin interrupts on both edges.
+    for (channel, ((port, pin), _)) in BUTTON_PINS[..PIN_INTERRUPT_COUNT].iter().enumerate() {
+        // PINTSEL numbers PIO0 pins from 0 and PIO1 pins from 24.
+        let pin_number = *port as u32 * 24 + pin;
+        let channel_bit = 1 << channel;
+        unsafe {
+            peripherals.SYSCON.pintsel[channel].write(|writer| writer.bits(pin_number));
+            peripherals
+                .GPIO_PIN_INT
+                .isel
+                .modify(|reader, writer| writer.bits(reader.bits() & !channel_bit));
+            peripherals
+                .GPIO_PIN_INT
+                .sienr
+                .write(|writer| writer.bits(channel_bit));
+            peripherals
+                .GPIO_PIN_INT
+                .sienf
+                .write(|writer| writer.bits(channel_bit));
+            peripherals
+                .GPIO_PIN_INT
+                .ist
+                .write(|writer| writer.bits(channel_bit));
+        }
+    }
+
+    // The group interrupt fires when any of the other buttons goes low.
+    let mut group_pins = [0u32; 2];
+    for ((port, pin), _) in BUTTON_PINS[PIN_INTERRUPT_COUNT..].iter() {
+        group_pins[*port] |= 1 << pin;
+    }
+    unsafe {
+        for (port, pins) in group_pins.iter().enumerate() {
+            peripherals.GPIO_GROUP_INT0.port_pol[port].write(|writer| writer.bits(0));
+            peripherals.GPIO_GROUP_INT0.port_ena[port].write(|writer| writer.bits(*pins));
+        }
+        peripherals
+            .GPIO_GROUP_INT0
+            .ctrl
+            .write(|writer| writer.bits(GINT_CTRL_INT));
+
+        peripherals
+            .SYSCON
+            .starterp0
+            .modify(|reader, writer| writer.bits(reader.bits() | STARTERP0_PINT_ALL));
+        peripherals
+            .SYSCON
+            .starterp1
+            .modify(|reader, writer| writer.bits(reader.bits() | STARTERP1_GINT0));
+    }
+
+    // Free running millisecond counter, MR0 schedules the next sample.
+    unsafe {
+        peripherals
+            .CT32B0
+            .pr
+            .write(|writer| writer.pcval().bits(system::main_clock_rate() / 1000 - 1));
+    }
+    peripherals
+        .CT32B0
+        .tcr
+        .modify(|_, writer| writer.cen().the_timer_counter_an());
+
+    for interrupt in PIN_INTERRUPTS.iter() {
+        NVIC::unpend(*interrupt);
+        unsafe { NVIC::unmask(*interrupt) };
+    }
+    NVIC::unpend(Interrupt::GINT0);
+    NVIC::unpend(Interrupt::CT32B0);
+    unsafe {
+        NVIC::unmask(Interrupt::GINT0);
+        NVIC::unmask(Interrupt::CT32B0);
+    }
+
+    // Buttons held at boot don't raise any edge.
+    cortex_m::interrupt::free(|_| start_sampling());
+}
+
+/// Milliseconds since `initialize`, not counting deep-sleep.
+pub fn now() -> u32 {
+    let peripherals = unsafe { Peripherals::steal() };
+    peripherals.CT32B0.tc.read().bits()
+}
+
+pub fn state() -> ButtonState {
+    cortex_m::interrupt::free(|_| unsafe { STATE })
+}
+
+/// Whether debouncing is in progress, which needs CT32B0 to keep counting
+/// (so no deep-sleep).
+pub fn is_sampling() -> bool {
+    unsafe { SAMPLING }
+}
+
+fn read_pins() -> Buttons {
+    let peripherals = unsafe { Peripherals::steal() };
+    let ports = [
+        peripherals.GPIO_PORT.pin[0].read().bits(),
+        peripherals.GPIO_PORT.pin[1].read().bits(),
+    ];
+
+    let mut buttons = Buttons::empty();
+    for ((port, pin), button) in BUTTON_PINS.iter() {
+        buttons.set(*button, ports[*port] & (1 << pin) == 0);
+    }
+    buttons
+}
+
+/// Samples again in `DEBOUNCE_MS`. Called from the button interrupts, or with
+/// interrupts disabled.
+fn start_sampling() {
+    let peripherals = unsafe { Peripherals::steal() };
+
+    unsafe {
+        SAMPLING = true;
+        peripherals.CT32B0.mr[0].write(|writer| writer.bits(now().wrapping_add(DEBOUNCE_MS)));
+    }
+    peripherals
+        .CT32B0
+        .mcr
+        .modify(|_, writer| writer.mr0i().enabled());
+}
+
+fn stop_sampling() {
+    let peripherals = unsafe { Peripherals::steal() };
+
+    peripherals
+        .CT32B0
+        .mcr
+        .modify(|_, writer| writer.mr0i().disabled());
+    unsafe {
+        SAMPLING = false;
+    }
+}
+
+fn pin_interrupt(channel: usize) {
+    let peripherals = unsafe { Peripherals::steal() };
+
+    unsafe {
+        peripherals
+            .GPIO_PIN_INT
+            .ist
+            .write(|writer| writer.bits(1 << channel));
+    }
+    start_sampling();
+}
+
+#[interrupt]
+fn PIN_INT0() {
+    pin_interrupt(0);
+}
+
+#[interrupt]
+fn PIN_INT1() {
+    pin_interrupt(1);
+}
+
+#[interrupt]
+fn PIN_INT2() {
+    pin_interrupt(2);
+}
+
+#[interrupt]
+fn PIN_INT3() {
+    pin_interrupt(3);
+}
+
+#[interrupt]
+fn PIN_INT4() {
+    pin_interrupt(4);
+}
+
+#[interrupt]
+fn PIN_INT5() {
+    pin_interrupt(5);
+}
+
+#[interrupt]
+fn PIN_INT6() {
+    pin_interrupt(6);
+}
+
+#[interrupt]
+fn PIN_INT7() {
+    pin_interrupt(7);
+}
+
+#[interrupt]
+fn GINT0() {
+    let peripherals = unsafe { Peripherals::steal() };
+
+    unsafe {
+        peripherals
+            .GPIO_GROUP_INT0
+            .ctrl
+            .write(|writer| writer.bits(GINT_CTRL_INT));
+    }
+    start_sampling();
+}
+
+#[interrupt]
+fn CT32B0() {
+    let peripherals = unsafe { Peripherals::steal() };
+
+    peripherals
+        .CT32B0
+        .ir
+        .write(|writer| writer.mr0int().set_bit());
+
+    let sample = read_pins();
+    unsafe {
+        if sample == LAST_SAMPLE && sample != STATE.buttons {
+            let pressed = sample - STATE.buttons;
+            STATE = ButtonState {
+                buttons: sample,
+                timestamp: now(),
+            };
+
+            // Like the stock firmware, any press wakes a suspended host.
+            if !pressed.is_empty() {
+                usbd::request_wakeup();
+            }
+        }
+
+        let settled = sample == LAST_SAMPLE;
+        LAST_SAMPLE = sample;
+
+        if settled && sample.is_empty() {
+            stop_sampling();
+        } else {
+            start_sampling();
+        }
+    }
+}
@@ -0,0 +1,52 @@
+//! State of the controls, as read from the hardware. The USB personalities
+//! turn it into their own reports.
+
+use input_processing::gesture::Gesture;
+
+use crate::report::{Buttons, GamepadReport};
+
+/// Pressed together, selects the next gyro mode. Lizard mode ignores these
+/// buttons, so the chord doesn't also press keys.
+pub const GYRO_MODE_CHORD: Buttons =
+    Buttons::from_bits_truncate(Buttons::STEAM.bits() | Buttons::RIGHT_GRIP.bits());
+
+/// Position of a finger on a trackpad. X grows to the right and Y grows
+/// downward, the center being (0, 0).
+pub type Touch = (i16, i16);
+
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct Controls {
+    pub buttons: Buttons,
+    pub left_stick_x: i16,
+    pub left_stick_y: i16,
+    /// None while the pad isn't touched.
+    pub left_pad: Option<Touch>,
+    pub right_pad: Option<Touch>,
+    /// The gesture the latest left pad sample completed, if any.
+    pub left_pad_gesture: Option<Gesture>,
+    /// Where the gyro aim mode puts the right stick, None outside of it.
+    pub gyro_aim: Option<Touch>,
+    /// Mouse counts the gyro mouse mode moved by since the previous update.
+    #[cfg(not(feature = "xinput"))]
+    pub gyro_mouse: (i32, i32),
+    pub left_trigger: u8,
+    pub right_trigger: u8,
+}
+
+impl From<&Controls> for GamepadReport {
+    fn from(controls: &Controls) -> Self {
+        // The finger on the pad wins over the gyro.
+        let (right_stick_x, right_stick_y) =
+            controls.right_pad.or(controls.gyro_aim).unwrap_or_default();
+
+        GamepadReport {
+            buttons: controls.buttons,
+            left_stick_x: controls.left_stick_x,
+            left_stick_y: controls.left_stick_y,
+            right_stick_x,
+            right_stick_y,
+            left_trigger: controls.left_trigger,
+            right_trigger: controls.right_trigger,
+        }
+    }
+}
@@ -0,0 +1,19 @@
+//! Haptic feedback, through the actuators under the two trackpads.
+//!
+//! Hosts ask for rumble the Xbox 360 way: a strength for the big, low
+//! frequency motor on the left, and one for the small motor on the right. Each
+//! one maps to the actuator on the same side.
+//!
+//! Driving the actuators isn't implemented yet, so for now this only keeps
+//! track of what the host asked for.
+
+static mut LEFT_STRENGTH: u8 = 0;
+static mut RIGHT_STRENGTH: u8 = 0;
+
+/// Sets the rumble strength of each side, 0 being off.
+pub fn set_rumble(left: u8, right: u8) {
+    unsafe {
+        LEFT_STRENGTH = left;
+        RIGHT_STRENGTH = right;
+    }
+}
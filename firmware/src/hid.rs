@@ -0,0 +1,187 @@
+use core::marker::PhantomData;
+
+use usb_device::class_prelude::*;
+use usb_device::control::{Recipient, Request, RequestType};
+use usb_device::Result;
+
+use crate::report::{FeatureReports, InputReport, MAX_REPORT_LEN};
+
+const INTERFACE_CLASS_HID: u8 = 0x03;
+const INTERFACE_SUBCLASS_BOOT: u8 = 0x01;
+
+const DESCRIPTOR_TYPE_HID: u8 = 0x21;
+const DESCRIPTOR_TYPE_REPORT: u8 = 0x22;
+
+const REQUEST_GET_REPORT: u8 = 0x01;
+const REQUEST_GET_IDLE: u8 = 0x02;
+const REQUEST_GET_PROTOCOL: u8 = 0x03;
+const REQUEST_SET_REPORT: u8 = 0x09;
+const REQUEST_SET_IDLE: u8 = 0x0a;
+const REQUEST_SET_PROTOCOL: u8 = 0x0b;
+
+/// Upper byte of wValue in GET_REPORT and SET_REPORT.
+const REPORT_TYPE_FEATURE: u8 = 0x03;
+
+/// A HID interface sending input reports of type `R` on an interrupt IN
+/// endpoint. Feature reports go through `R`'s `FeatureReports` impl.
+pub struct HidClass<'a, B: UsbBus, R: InputReport + FeatureReports> {
+    interface: InterfaceNumber,
+    endpoint_in: EndpointIn<'a, B>,
+    last_report: [u8; MAX_REPORT_LEN],
+    last_report_len: usize,
+    feature_report: [u8; MAX_REPORT_LEN],
+    idle_rate: u8,
+    protocol: u8,
+    _report: PhantomData<R>,
+}
+
+impl<'a, B: UsbBus, R: InputReport + FeatureReports> HidClass<'a, B, R> {
+    pub fn new(alloc: &'a UsbBusAllocator<B>, poll_interval_ms: u8) -> Self {
+        HidClass {
+            interface: alloc.interface(),
+            endpoint_in: alloc.interrupt(MAX_REPORT_LEN as u16, poll_interval_ms),
+            last_report: [0; MAX_REPORT_LEN],
+            last_report_len: 0,
+            feature_report: [0; MAX_REPORT_LEN],
+            idle_rate: 0,
+            // Report protocol. Boot keyboard and mouse reports already have the
+            // boot layout, so SET_PROTOCOL changes nothing.
+            protocol: 1,
+            _report: PhantomData,
+        }
+    }
+
+    /// Sends `report` to the host, unless it didn't change since the last one,
+    /// has no movement, and the host only wants changes (idle rate of 0).
+    ///
+    /// Fails with `UsbError::WouldBlock` while the previous report is still
+    /// waiting for the host.
+    pub fn push_report(&mut self, report: &R) -> Result<()> {
+        let mut buffer = [0; MAX_REPORT_LEN];
+        let len = report.serialize(&mut buffer);
+
+        if self.idle_rate == 0
+            && !report.has_movement()
+            && self.last_report_len != 0
+            && buffer[..len] == self.last_report[..self.last_report_len]
+        {
+            return Ok(());
+        }
+
+        self.endpoint_in.write(&buffer[..len])?;
+
+        self.last_report = buffer;
+        self.last_report_len = len;
+        Ok(())
+    }
+
+    fn hid_descriptor(&self) -> [u8; 7] {
+        let report_descriptor_len = (R::DESCRIPTOR.len() as u16).to_le_bytes();
+
+        [
+            // HID 1.11, no country code.
+            0x11,
+            0x01,
+            0x00,
+            // One report descriptor.
+            0x01,
+            DESCRIPTOR_TYPE_REPORT,
+            report_descriptor_len[0],
+            report_descriptor_len[1],
+        ]
+    }
+
+    fn is_for_us(&self, request: &Request) -> bool {
+        request.recipient == Recipient::Interface
+            && request.index == u16::from(u8::from(self.interface))
+    }
+}
+
+impl<B: UsbBus, R: InputReport + FeatureReports> UsbClass<B> for HidClass<'_, B, R> {
+    fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> Result<()> {
+        let subclass = if R::BOOT_PROTOCOL != 0 {
+            INTERFACE_SUBCLASS_BOOT
+        } else {
+            0
+        };
+
+        writer.interface(
+            self.interface,
+            INTERFACE_CLASS_HID,
+            subclass,
+            R::BOOT_PROTOCOL,
+        )?;
+        writer.write(DESCRIPTOR_TYPE_HID, &self.hid_descriptor())?;
+        writer.endpoint(&self.endpoint_in)?;
+
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        self.last_report_len = 0;
+        self.feature_report = [0; MAX_REPORT_LEN];
+        self.idle_rate = 0;
+        self.protocol = 1;
+    }
+
+    fn control_in(&mut self, xfer: ControlIn<B>) {
+        let request = *xfer.request();
+
+        if !self.is_for_us(&request) {
+            return;
+        }
+
+        match (request.request_type, request.request) {
+            (RequestType::Standard, Request::GET_DESCRIPTOR) => {
+                match (request.value >> 8) as u8 {
+                    DESCRIPTOR_TYPE_REPORT => xfer.accept_with_static(R::DESCRIPTOR).ok(),
+                    DESCRIPTOR_TYPE_HID => xfer.accept_with(&self.hid_descriptor()).ok(),
+                    _ => xfer.reject().ok(),
+                };
+            }
+            (RequestType::Class, REQUEST_GET_REPORT) => {
+                if (request.value >> 8) as u8 == REPORT_TYPE_FEATURE {
+                    xfer.accept_with(&self.feature_report).ok();
+                } else {
+                    xfer.accept_with(&self.last_report[..self.last_report_len])
+                        .ok();
+                }
+            }
+            (RequestType::Class, REQUEST_GET_IDLE) => {
+                xfer.accept_with(&[self.idle_rate]).ok();
+            }
+            (RequestType::Class, REQUEST_GET_PROTOCOL) => {
+                xfer.accept_with(&[self.protocol]).ok();
+            }
+            _ => (),
+        }
+    }
+
+    fn control_out(&mut self, xfer: ControlOut<B>) {
+        let request = *xfer.request();
+
+        if !self.is_for_us(&request) || request.request_type != RequestType::Class {
+            return;
+        }
+
+        match request.request {
+            REQUEST_SET_IDLE => {
+                // The upper byte is the duration, the lower one the report ID.
+                self.idle_rate = (request.value >> 8) as u8;
+                xfer.accept().ok();
+            }
+            REQUEST_SET_PROTOCOL => {
+                self.protocol = request.value as u8;
+                xfer.accept().ok();
+            }
+            REQUEST_SET_REPORT => {
+                if (request.value >> 8) as u8 == REPORT_TYPE_FEATURE {
+                    R::set_feature_report(xfer.data(), &mut self.feature_report);
+                }
+                // Output reports are ignored.
+                xfer.accept().ok();
+            }
+            _ => (),
+        }
+    }
+}
@@ -0,0 +1,448 @@
+//! The IMU, an MPU-6500 on the I2C bus, and what its orientation drives.
+//!
+//! The sampling tick (see `analog`) starts reading the accelerometer and
+//! gyroscope registers, and the I2C interrupt walks the transfer through, then
+//! feeds the fusion filter (see `input_processing::fusion`).
+//!
+//! The IMU axes are taken as the controller's.
+
+use core::ptr;
+
+use cortex_m::asm;
+use cortex_m::peripheral::NVIC;
+use input_processing::fusion::{
+    Angle, ComplementaryFilter, FusionSettings, ImuSample, Orientation,
+};
+use lpc11uxx::{interrupt, Interrupt, Peripherals};
+
+use crate::buttons;
+use crate::controls::Touch;
+use crate::system;
+
+const IMU_ADDRESS: u8 = 0x68;
+
+const REGISTER_SMPLRT_DIV: u8 = 0x19;
+const REGISTER_CONFIG: u8 = 0x1a;
+const REGISTER_GYRO_CONFIG: u8 = 0x1b;
+const REGISTER_ACCEL_CONFIG: u8 = 0x1c;
+/// ACCEL_XOUT_H, followed by the other accelerometer axes, the temperature,
+/// and the gyroscope axes, all big endian.
+const REGISTER_ACCEL_XOUT_H: u8 = 0x3b;
+const REGISTER_PWR_MGMT_1: u8 = 0x6b;
+
+const PWR_MGMT_1_RESET: u8 = 0x80;
+/// The gyroscope's PLL as clock, when ready.
+const PWR_MGMT_1_AUTO_CLOCK: u8 = 0x01;
+/// Gyroscope low pass filter at 41Hz, sampling at 1kHz.
+const CONFIG_DLPF_41HZ: u8 = 0x03;
+/// 1kHz / (1 + 3): 250Hz, the sampling tick's rate.
+const SMPLRT_DIV_250HZ: u8 = 3;
+const GYRO_CONFIG_2000DPS: u8 = 0x18;
+const ACCEL_CONFIG_2G: u8 = 0x00;
+
+const SAMPLE_LEN: usize = 14;
+
+/// A register write takes about 75us at 400kHz. Needs the button timer
+/// running.
+const TRANSFER_TIMEOUT_MS: u32 = 5;
+
+/// I2C CONSET and CONCLR bits.
+const I2C_AA: u32 = 1 << 2;
+const I2C_SI: u32 = 1 << 3;
+const I2C_STO: u32 = 1 << 4;
+const I2C_STA: u32 = 1 << 5;
+const I2C_EN: u32 = 1 << 6;
+
+// I2C STAT codes, master modes.
+const STAT_START: u32 = 0x08;
+const STAT_REPEATED_START: u32 = 0x10;
+const STAT_ADDRESS_W_ACK: u32 = 0x18;
+const STAT_DATA_SENT_ACK: u32 = 0x28;
+const STAT_ADDRESS_R_ACK: u32 = 0x40;
+const STAT_DATA_RECEIVED_ACK: u32 = 0x50;
+const STAT_DATA_RECEIVED_NACK: u32 = 0x58;
+
+/// 48MHz / (60 + 60): 400kHz.
+const I2C_SCL_HALF_PERIOD: u32 = 60;
+
+/// PRESETCTRL: I2C out of reset.
+const PRESETCTRL_I2C_RST_N: u32 = 1 << 1;
+/// IOCON of PIO0_4 and PIO0_5: SCL and SDA, standard I2C.
+const IOCON_I2C: u32 = 0x1;
+
+/// Rotation for one mouse count in the gyro mouse mode, about 1/20 degree.
+#[cfg(not(feature = "xinput"))]
+const MOUSE_ANGLE_PER_COUNT: i64 = (1 << 32) / 7200;
+/// Rotation from the reference for a full deflection of the stick in the
+/// gyro aim mode, an eighth of a turn.
+const AIM_FULL_DEFLECTION: i64 = 1 << 29;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum GyroMode {
+    Off,
+    /// Turning the controller moves the mouse (lizard mode only).
+    #[cfg(not(feature = "xinput"))]
+    Mouse,
+    /// Turning the controller moves the right stick, away from where it
+    /// pointed when the mode got selected.
+    Aim,
+}
+
+impl GyroMode {
+    /// The mouse mode is skipped in the XInput personality, which has no
+    /// mouse.
+    pub fn next(self) -> Self {
+        match self {
+            #[cfg(not(feature = "xinput"))]
+            GyroMode::Off => GyroMode::Mouse,
+            #[cfg(feature = "xinput")]
+            GyroMode::Off => GyroMode::Aim,
+            #[cfg(not(feature = "xinput"))]
+            GyroMode::Mouse => GyroMode::Aim,
+            GyroMode::Aim => GyroMode::Off,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum TransferState {
+    Idle,
+    Busy,
+    Failed,
+}
+
+struct Transfer {
+    state: TransferState,
+    /// Register address, then what to write there if anything.
+    write: [u8; 2],
+    write_len: usize,
+    written: usize,
+    read: [u8; SAMPLE_LEN],
+    read_len: usize,
+    received: usize,
+}
+
+static mut TRANSFER: Transfer = Transfer {
+    state: TransferState::Idle,
+    write: [0; 2],
+    write_len: 0,
+    written: 0,
+    read: [0; SAMPLE_LEN],
+    read_len: 0,
+    received: 0,
+};
+
+/// Set once the IMU answered its configuration.
+static mut FILTER: Option<ComplementaryFilter> = None;
+static mut ORIENTATION: Orientation = Orientation {
+    pitch: 0,
+    roll: 0,
+    yaw: 0,
+};
+/// Yaw and pitch turned since `take_rotation`.
+#[cfg(not(feature = "xinput"))]
+static mut ROTATION: (i64, i64) = (0, 0);
+
+pub fn initialize() {
+    let peripherals = unsafe { Peripherals::steal() };
+
+    peripherals
+        .SYSCON
+        .sysahbclkctrl
+        .modify(|_, writer| writer.i2c().enabled());
+    unsafe {
+        peripherals
+            .SYSCON
+            .presetctrl
+            .modify(|reader, writer| writer.bits(reader.bits() | PRESETCTRL_I2C_RST_N));
+
+        peripherals
+            .IOCON
+            .pio0_4
+            .write(|writer| writer.bits(IOCON_I2C));
+        peripherals
+            .IOCON
+            .pio0_5
+            .write(|writer| writer.bits(IOCON_I2C));
+
+        peripherals
+            .I2C
+            .sclh
+            .write(|writer| writer.bits(I2C_SCL_HALF_PERIOD));
+        peripherals
+            .I2C
+            .scll
+            .write(|writer| writer.bits(I2C_SCL_HALF_PERIOD));
+        peripherals
+            .I2C
+            .conclr
+            .write(|writer| writer.bits(I2C_AA | I2C_SI | I2C_STA | I2C_EN));
+        peripherals.I2C.conset.write(|writer| writer.bits(I2C_EN));
+    }
+
+    NVIC::unpend(Interrupt::I2C);
+    unsafe { NVIC::unmask(Interrupt::I2C) };
+
+    if configure() {
+        unsafe {
+            FILTER = Some(ComplementaryFilter::new(FusionSettings::default()));
+        }
+    } else {
+        shut_down();
+    }
+}
+
+/// Returns false if the IMU didn't answer.
+fn configure() -> bool {
+    // The reset takes up to 100ms.
+    if !write_register_blocking(REGISTER_PWR_MGMT_1, PWR_MGMT_1_RESET) {
+        return false;
+    }
+    asm::delay(system::main_clock_rate() / 10);
+
+    let configuration = [
+        (REGISTER_PWR_MGMT_1, PWR_MGMT_1_AUTO_CLOCK),
+        (REGISTER_CONFIG, CONFIG_DLPF_41HZ),
+        (REGISTER_SMPLRT_DIV, SMPLRT_DIV_250HZ),
+        (REGISTER_GYRO_CONFIG, GYRO_CONFIG_2000DPS),
+        (REGISTER_ACCEL_CONFIG, ACCEL_CONFIG_2G),
+    ];
+    configuration
+        .iter()
+        .all(|(register, value)| write_register_blocking(*register, *value))
+}
+
+/// Leaves the I2C in reset, when the IMU is missing or the bus is stuck.
+fn shut_down() {
+    let peripherals = unsafe { Peripherals::steal() };
+
+    NVIC::mask(Interrupt::I2C);
+    unsafe {
+        peripherals
+            .SYSCON
+            .presetctrl
+            .modify(|reader, writer| writer.bits(reader.bits() & !PRESETCTRL_I2C_RST_N));
+        TRANSFER.state = TransferState::Idle;
+    }
+}
+
+/// Starts a transfer: writes `write`, then reads `read_len` bytes if not 0.
+/// Only called with the I2C idle.
+fn start_transfer(write: &[u8], read_len: usize) {
+    let peripherals = unsafe { Peripherals::steal() };
+
+    unsafe {
+        TRANSFER.state = TransferState::Busy;
+        TRANSFER.write[..write.len()].copy_from_slice(write);
+        TRANSFER.write_len = write.len();
+        TRANSFER.written = 0;
+        TRANSFER.read_len = read_len;
+        TRANSFER.received = 0;
+
+        peripherals.I2C.conset.write(|writer| writer.bits(I2C_STA));
+    }
+}
+
+/// Only used while nothing else uses the I2C. Returns false if the IMU didn't
+/// answer, or if the transfer didn't end in time (with SDA or SCL held low).
+fn write_register_blocking(register: u8, value: u8) -> bool {
+    start_transfer(&[register, value], 0);
+    // Set by the interrupt.
+    let state = || unsafe { ptr::read_volatile(&TRANSFER.state) };
+    let started_at = buttons::now();
+    while state() == TransferState::Busy {
+        if buttons::now().wrapping_sub(started_at) > TRANSFER_TIMEOUT_MS {
+            return false;
+        }
+    }
+
+    let succeeded = state() == TransferState::Idle;
+    unsafe {
+        TRANSFER.state = TransferState::Idle;
+    }
+    succeeded
+}
+
+/// Starts reading a sample, unless the IMU is missing or the previous reading
+/// isn't over yet.
+pub fn start_sampling() {
+    cortex_m::interrupt::free(|_| unsafe {
+        if FILTER.is_some() && TRANSFER.state != TransferState::Busy {
+            start_transfer(&[REGISTER_ACCEL_XOUT_H], SAMPLE_LEN);
+        }
+    });
+}
+
+pub fn orientation() -> Orientation {
+    cortex_m::interrupt::free(|_| unsafe { ORIENTATION })
+}
+
+/// How much yaw and pitch turned since the last call.
+#[cfg(not(feature = "xinput"))]
+pub fn take_rotation() -> (i64, i64) {
+    cortex_m::interrupt::free(|_| unsafe {
+        let rotation = ROTATION;
+        ROTATION = (0, 0);
+        rotation
+    })
+}
+
+fn sample_received(bytes: &[u8; SAMPLE_LEN]) {
+    let word = |index: usize| i16::from_be_bytes([bytes[index], bytes[index + 1]]);
+    let sample = ImuSample {
+        accel: [word(0), word(2), word(4)],
+        // Bytes 6 and 7 are the temperature.
+        gyro: [word(8), word(10), word(12)],
+    };
+
+    unsafe {
+        if let Some(filter) = &mut FILTER {
+            #[cfg(not(feature = "xinput"))]
+            {
+                let [pitch, _, yaw] = filter.rotation(&sample);
+                ROTATION.0 += i64::from(yaw);
+                ROTATION.1 += i64::from(pitch);
+            }
+            ORIENTATION = filter.update(&sample);
+        }
+    }
+}
+
+fn finish_transfer(state: TransferState) {
+    let peripherals = unsafe { Peripherals::steal() };
+
+    unsafe {
+        peripherals.I2C.conset.write(|writer| writer.bits(I2C_STO));
+        TRANSFER.state = state;
+    }
+}
+
+#[interrupt]
+fn I2C() {
+    let peripherals = unsafe { Peripherals::steal() };
+    let i2c = &peripherals.I2C;
+    let transfer = unsafe { &mut TRANSFER };
+
+    let status = i2c.stat.read().bits() & 0xf8;
+    unsafe {
+        match status {
+            STAT_START => {
+                i2c.dat
+                    .write(|writer| writer.bits(u32::from(IMU_ADDRESS << 1)));
+                i2c.conclr.write(|writer| writer.bits(I2C_STA));
+            }
+            STAT_REPEATED_START => {
+                i2c.dat
+                    .write(|writer| writer.bits(u32::from(IMU_ADDRESS << 1 | 1)));
+                i2c.conclr.write(|writer| writer.bits(I2C_STA));
+            }
+            STAT_ADDRESS_W_ACK | STAT_DATA_SENT_ACK => {
+                if transfer.written < transfer.write_len {
+                    i2c.dat
+                        .write(|writer| writer.bits(u32::from(transfer.write[transfer.written])));
+                    transfer.written += 1;
+                } else if transfer.read_len != 0 {
+                    i2c.conset.write(|writer| writer.bits(I2C_STA));
+                } else {
+                    finish_transfer(TransferState::Idle);
+                }
+            }
+            STAT_ADDRESS_R_ACK => {
+                // Acknowledge all but the last byte.
+                if transfer.read_len > 1 {
+                    i2c.conset.write(|writer| writer.bits(I2C_AA));
+                } else {
+                    i2c.conclr.write(|writer| writer.bits(I2C_AA));
+                }
+            }
+            STAT_DATA_RECEIVED_ACK | STAT_DATA_RECEIVED_NACK => {
+                if transfer.received < transfer.read_len {
+                    transfer.read[transfer.received] = i2c.dat.read().bits() as u8;
+                    transfer.received += 1;
+                }
+
+                if status == STAT_DATA_RECEIVED_NACK {
+                    finish_transfer(TransferState::Idle);
+                    if transfer.received == SAMPLE_LEN {
+                        sample_received(&transfer.read);
+                    }
+                } else if transfer.received + 1 >= transfer.read_len {
+                    i2c.conclr.write(|writer| writer.bits(I2C_AA));
+                }
+            }
+            // Not acknowledged, or arbitration lost.
+            _ => finish_transfer(TransferState::Failed),
+        }
+
+        i2c.conclr.write(|writer| writer.bits(I2C_SI));
+    }
+}
+
+/// Gyro mode, and what it needs to turn the orientation into controls.
+pub struct Gyro {
+    mode: GyroMode,
+    /// Orientation when the aim mode got selected.
+    reference: Orientation,
+    /// Rotation left under a mouse count, for the next time.
+    #[cfg(not(feature = "xinput"))]
+    remainder: (i64, i64),
+}
+
+impl Gyro {
+    pub fn new() -> Self {
+        Gyro {
+            mode: GyroMode::Off,
+            reference: Orientation::default(),
+            #[cfg(not(feature = "xinput"))]
+            remainder: (0, 0),
+        }
+    }
+
+    pub fn select_next_mode(&mut self) {
+        self.mode = self.mode.next();
+        self.reference = orientation();
+        #[cfg(not(feature = "xinput"))]
+        {
+            self.remainder = (0, 0);
+            take_rotation();
+        }
+    }
+
+    /// Where the right stick goes in the aim mode: relative to the reference,
+    /// turning right is +X and raising the front is up, so -Y.
+    pub fn aim(&self) -> Option<Touch> {
+        if self.mode != GyroMode::Aim {
+            return None;
+        }
+
+        let orientation = orientation();
+        let deflection = |from: Angle, to: Angle| {
+            let turned = i64::from(to.wrapping_sub(from) as i32);
+            let max = i64::from(i16::MAX);
+            (turned * max / AIM_FULL_DEFLECTION).max(-max).min(max) as i16
+        };
+
+        Some((
+            -deflection(self.reference.yaw, orientation.yaw),
+            -deflection(self.reference.pitch, orientation.pitch),
+        ))
+    }
+
+    /// Mouse counts for the rotation since the last call, in the mouse mode:
+    /// turning left or raising the front moves left or up.
+    #[cfg(not(feature = "xinput"))]
+    pub fn mouse_movement(&mut self) -> (i32, i32) {
+        let (yaw, pitch) = take_rotation();
+        if self.mode != GyroMode::Mouse {
+            return (0, 0);
+        }
+
+        let x = self.remainder.0 - yaw;
+        let y = self.remainder.1 - pitch;
+        self.remainder = (x % MOUSE_ANGLE_PER_COUNT, y % MOUSE_ANGLE_PER_COUNT);
+        (
+            (x / MOUSE_ANGLE_PER_COUNT) as i32,
+            (y / MOUSE_ANGLE_PER_COUNT) as i32,
+        )
+    }
+}
@@ -0,0 +1,96 @@
+//! Reading the controls: starts the input drivers, and turns what they read
+//! into `Controls` for the main loop.
+//!
+//! Only built with the `input-drivers` feature, see Cargo.toml.
+
+use input_processing::gesture::{GestureDetector, GestureSettings, PadSample};
+use input_processing::stick::StickSettings;
+
+use crate::analog;
+use crate::buttons;
+use crate::controls::{Controls, GYRO_MODE_CHORD};
+use crate::imu::{self, Gyro};
+use crate::report::Buttons;
+use crate::trackpad::{self, Pad};
+
+pub fn initialize() {
+    // Buttons first, the IMU times its transfers with their timer.
+    buttons::initialize();
+    trackpad::initialize();
+    // Before the sampling tick starts.
+    imu::initialize();
+    analog::initialize();
+}
+
+/// Whether the chip can go to deep-sleep: not while a button is being
+/// debounced.
+pub fn can_deep_sleep() -> bool {
+    !buttons::is_sampling()
+}
+
+/// What the main loop keeps between two updates.
+pub struct Inputs {
+    last_button_timestamp: u32,
+    stick_settings: StickSettings,
+    left_pad_gestures: GestureDetector,
+    gyro: Gyro,
+}
+
+impl Inputs {
+    pub fn new() -> Self {
+        Inputs {
+            last_button_timestamp: 0,
+            stick_settings: StickSettings::default(),
+            left_pad_gestures: GestureDetector::new(GestureSettings::default()),
+            gyro: Gyro::new(),
+        }
+    }
+
+    pub fn update(&mut self, controls: &mut Controls) {
+        let previous_buttons = controls.buttons;
+        let button_state = buttons::state();
+        if button_state.timestamp != self.last_button_timestamp {
+            controls.buttons = button_state.buttons;
+            self.last_button_timestamp = button_state.timestamp;
+        }
+
+        self.update_analog_controls(controls);
+        self.update_trackpad_controls(controls);
+        self.update_gyro_controls(controls, previous_buttons);
+    }
+
+    fn update_analog_controls(&self, controls: &mut Controls) {
+        let analog = analog::sample(&self.stick_settings);
+        controls.left_stick_x = analog.stick_x;
+        controls.left_stick_y = analog.stick_y;
+        controls.left_trigger = analog.left_trigger;
+        controls.right_trigger = analog.right_trigger;
+
+        analog::finish_calibration_if_settled();
+    }
+
+    fn update_trackpad_controls(&mut self, controls: &mut Controls) {
+        controls.left_pad = trackpad::touch(Pad::Left);
+        controls.right_pad = trackpad::touch(Pad::Right);
+
+        controls.left_pad_gesture = self.left_pad_gestures.update(&PadSample {
+            timestamp: buttons::now(),
+            position: controls.left_pad,
+            clicked: controls.buttons.contains(Buttons::LEFT_PAD),
+        });
+    }
+
+    /// Pressing STEAM and the right grip together selects the next gyro mode.
+    fn update_gyro_controls(&mut self, controls: &mut Controls, previous_buttons: Buttons) {
+        let chord = GYRO_MODE_CHORD;
+        if controls.buttons.contains(chord) && !previous_buttons.contains(chord) {
+            self.gyro.select_next_mode();
+        }
+
+        controls.gyro_aim = self.gyro.aim();
+        #[cfg(not(feature = "xinput"))]
+        {
+            controls.gyro_mouse = self.gyro.mouse_movement();
+        }
+    }
+}
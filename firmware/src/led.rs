@@ -1,5 +1,8 @@
 use lpc11uxx::Peripherals;
 
+static mut INTENSITY: u16 = 0;
+static mut DIMMED: bool = false;
+
 pub fn initialize() {
     let peripherals = unsafe { Peripherals::steal() };
 
@@ -66,9 +69,41 @@ pub fn initialize() {
 }
 
 pub fn set_intensity(intensity: u16) {
+    unsafe {
+        INTENSITY = intensity;
+    }
+    apply_intensity();
+}
+
+/// Dims the LED (e.g. while the USB bus is suspended), without forgetting the
+/// intensity it should go back to.
+pub fn set_dimmed(dimmed: bool) {
+    unsafe {
+        DIMMED = dimmed;
+    }
+    apply_intensity();
+}
+
+/// Whether the LED is on at all. It then needs CT16B1, which stops in
+/// deep-sleep.
+pub fn is_lit() -> bool {
+    intensity() != 0
+}
+
+fn intensity() -> u16 {
+    unsafe {
+        if DIMMED {
+            INTENSITY / 16
+        } else {
+            INTENSITY
+        }
+    }
+}
+
+fn apply_intensity() {
     let peripherals = unsafe { Peripherals::steal() };
 
     unsafe {
-        peripherals.CT16B1.mr[0].write(|writer| writer.bits(u32::from(intensity)));
+        peripherals.CT16B1.mr[0].write(|writer| writer.bits(u32::from(intensity())));
     }
 }
@@ -0,0 +1,279 @@
+//! Lizard mode: the controller as a keyboard and a mouse, for when Steam isn't
+//! running.
+//!
+//! What each control does comes from a `Mapping`, one of `MAPPINGS` the host
+//! selects through the vendor interface. Steam turns lizard mode off when it
+//! takes over the controller, and back on when it lets go, the same way.
+
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+use input_processing::gesture::{Direction, Gesture};
+
+use crate::controls::{Controls, Touch, GYRO_MODE_CHORD};
+use crate::report::{Buttons, KeyModifiers, KeyboardReport, MouseButtons, MouseReport};
+
+// HID usages of the keys the mappings use.
+pub const KEY_ENTER: u8 = 0x28;
+pub const KEY_ESCAPE: u8 = 0x29;
+pub const KEY_TAB: u8 = 0x2b;
+pub const KEY_F5: u8 = 0x3e;
+pub const KEY_HOME: u8 = 0x4a;
+pub const KEY_PAGE_UP: u8 = 0x4b;
+pub const KEY_END: u8 = 0x4d;
+pub const KEY_PAGE_DOWN: u8 = 0x4e;
+pub const KEY_RIGHT: u8 = 0x4f;
+pub const KEY_LEFT: u8 = 0x50;
+pub const KEY_DOWN: u8 = 0x51;
+pub const KEY_UP: u8 = 0x52;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Action {
+    Key(u8),
+    Modifier(KeyModifiers),
+    MouseButton(MouseButtons),
+}
+
+pub struct Mapping {
+    /// What each button does while held. The buttons of `GYRO_MODE_CHORD`
+    /// are ignored.
+    pub buttons: &'static [(Buttons, Action)],
+    /// Keys of the up, down, left and right quadrants of the left pad, pressed
+    /// while it is clicked.
+    pub left_pad_keys: [u8; 4],
+    /// Keys tapped by swipes up, down, left and right on the left pad, 0 for
+    /// none.
+    pub left_pad_swipe_keys: [u8; 4],
+    /// Pad units per mouse count of the right pad. 0 disables the mouse.
+    pub right_pad_divider: i16,
+}
+
+/// Close to the desktop mode of Valve's firmware.
+pub const DEFAULT_MAPPING: Mapping = Mapping {
+    buttons: &[
+        (Buttons::A, Action::Key(KEY_ENTER)),
+        (Buttons::B, Action::Key(KEY_ESCAPE)),
+        (Buttons::BACK, Action::Key(KEY_TAB)),
+        (Buttons::START, Action::Key(KEY_ESCAPE)),
+        (Buttons::RIGHT_PAD, Action::MouseButton(MouseButtons::LEFT)),
+        (
+            Buttons::RIGHT_TRIGGER,
+            Action::MouseButton(MouseButtons::LEFT),
+        ),
+        (
+            Buttons::LEFT_TRIGGER,
+            Action::MouseButton(MouseButtons::RIGHT),
+        ),
+        (
+            Buttons::LEFT_GRIP,
+            Action::Modifier(KeyModifiers::LEFT_CTRL),
+        ),
+    ],
+    left_pad_keys: [KEY_UP, KEY_DOWN, KEY_LEFT, KEY_RIGHT],
+    left_pad_swipe_keys: [KEY_PAGE_UP, KEY_PAGE_DOWN, KEY_HOME, KEY_END],
+    right_pad_divider: 64,
+};
+
+/// For slides: A and swipes to the right go forward, B and swipes to the
+/// left go back, START starts the show.
+pub const PRESENTATION_MAPPING: Mapping = Mapping {
+    buttons: &[
+        (Buttons::A, Action::Key(KEY_PAGE_DOWN)),
+        (Buttons::B, Action::Key(KEY_PAGE_UP)),
+        (Buttons::BACK, Action::Key(KEY_ESCAPE)),
+        (Buttons::START, Action::Key(KEY_F5)),
+        (Buttons::RIGHT_PAD, Action::MouseButton(MouseButtons::LEFT)),
+        (
+            Buttons::RIGHT_TRIGGER,
+            Action::MouseButton(MouseButtons::LEFT),
+        ),
+    ],
+    left_pad_keys: [KEY_UP, KEY_DOWN, KEY_LEFT, KEY_RIGHT],
+    left_pad_swipe_keys: [0, 0, KEY_PAGE_UP, KEY_PAGE_DOWN],
+    right_pad_divider: 64,
+};
+
+/// The mappings the host can select, by index. The first one is used until
+/// it does.
+pub static MAPPINGS: [&Mapping; 2] = [&DEFAULT_MAPPING, &PRESENTATION_MAPPING];
+
+static ENABLED: AtomicBool = AtomicBool::new(true);
+static SELECTED_MAPPING: AtomicU8 = AtomicU8::new(0);
+
+/// Turns lizard mode on or off. While off, all keys and buttons are released
+/// and the mouse stays still.
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Switches to `MAPPINGS[index]`, releasing what the previous mapping
+/// pressed. Does nothing if there's no such mapping.
+pub fn select_mapping(index: u8) {
+    if usize::from(index) < MAPPINGS.len() {
+        SELECTED_MAPPING.store(index, Ordering::Relaxed);
+    }
+}
+
+pub fn selected_mapping() -> u8 {
+    SELECTED_MAPPING.load(Ordering::Relaxed)
+}
+
+/// Turns `Controls` into keyboard and mouse reports.
+pub struct Lizard {
+    /// Index of the mapping in `MAPPINGS`.
+    mapping: u8,
+    keyboard: KeyboardReport,
+    buttons: MouseButtons,
+    /// Key tapped by a swipe, pressed until a report with it got sent.
+    tapped: Option<u8>,
+    last_right_pad: Option<Touch>,
+    /// Movement in pad units not sent to the host yet.
+    pending_x: i32,
+    pending_y: i32,
+}
+
+impl Lizard {
+    pub fn new() -> Self {
+        Lizard {
+            mapping: selected_mapping(),
+            keyboard: KeyboardReport::default(),
+            buttons: MouseButtons::empty(),
+            tapped: None,
+            last_right_pad: None,
+            pending_x: 0,
+            pending_y: 0,
+        }
+    }
+
+    fn mapping(&self) -> &'static Mapping {
+        MAPPINGS[usize::from(self.mapping)]
+    }
+
+    pub fn update(&mut self, controls: &Controls) {
+        if !is_enabled() {
+            *self = Lizard::new();
+            return;
+        }
+        if self.mapping != selected_mapping() {
+            *self = Lizard::new();
+        }
+
+        let mapping = self.mapping();
+
+        self.keyboard = KeyboardReport::default();
+        self.buttons = MouseButtons::empty();
+        let mut key_count = 0;
+        let mut press = |keyboard: &mut KeyboardReport, key| {
+            // Past 6 keys, the others are dropped.
+            if key_count < keyboard.keys.len() {
+                keyboard.keys[key_count] = key;
+                key_count += 1;
+            }
+        };
+
+        for (button, action) in mapping.buttons.iter() {
+            if !controls.buttons.contains(*button) || GYRO_MODE_CHORD.intersects(*button) {
+                continue;
+            }
+            match *action {
+                Action::Key(key) => press(&mut self.keyboard, key),
+                Action::Modifier(modifier) => self.keyboard.modifiers |= modifier,
+                Action::MouseButton(mouse_button) => self.buttons |= mouse_button,
+            }
+        }
+
+        let left_pad = controls
+            .left_pad
+            .filter(|_| controls.buttons.contains(Buttons::LEFT_PAD));
+        if let Some((x, y)) = left_pad {
+            let [up, down, left, right] = mapping.left_pad_keys;
+            let key = if x.abs() > y.abs() {
+                if x < 0 {
+                    left
+                } else {
+                    right
+                }
+            } else if y < 0 {
+                up
+            } else {
+                down
+            };
+            press(&mut self.keyboard, key);
+        }
+
+        if let Some(Gesture::Swipe(direction)) = controls.left_pad_gesture {
+            let [up, down, left, right] = mapping.left_pad_swipe_keys;
+            let key = match direction {
+                Direction::Up => up,
+                Direction::Down => down,
+                Direction::Left => left,
+                Direction::Right => right,
+            };
+            if key != 0 {
+                self.tapped = Some(key);
+            }
+        }
+        if let Some(key) = self.tapped {
+            press(&mut self.keyboard, key);
+        }
+
+        // Only finger movements move the mouse, touching the pad doesn't.
+        if let (Some(last), Some(current)) = (self.last_right_pad, controls.right_pad) {
+            if mapping.right_pad_divider != 0 {
+                self.pending_x += i32::from(current.0) - i32::from(last.0);
+                self.pending_y += i32::from(current.1) - i32::from(last.1);
+            }
+        }
+        self.last_right_pad = controls.right_pad;
+
+        // The gyro moves in mouse counts already.
+        let divider = i32::from(mapping.right_pad_divider);
+        let (x, y) = controls.gyro_mouse;
+        self.pending_x += x * divider;
+        self.pending_y += y * divider;
+    }
+
+    pub fn keyboard_report(&self) -> KeyboardReport {
+        self.keyboard
+    }
+
+    /// Releases the tapped key, once the host got it pressed.
+    pub fn keyboard_report_sent(&mut self, report: &KeyboardReport) {
+        if let Some(key) = self.tapped {
+            if report.keys.contains(&key) {
+                self.tapped = None;
+            }
+        }
+    }
+
+    /// The mouse report to send, moving by as much of the pending movement as
+    /// a report can carry.
+    pub fn mouse_report(&self) -> MouseReport {
+        let divider = i32::from(self.mapping().right_pad_divider);
+        let counts = |pending: i32| {
+            if divider == 0 {
+                0
+            } else {
+                (pending / divider).max(-127).min(127) as i8
+            }
+        };
+
+        MouseReport {
+            buttons: self.buttons,
+            x: counts(self.pending_x),
+            y: counts(self.pending_y),
+            wheel: 0,
+        }
+    }
+
+    /// Removes the movement of `report` from the pending one, once the host
+    /// got it.
+    pub fn mouse_report_sent(&mut self, report: &MouseReport) {
+        let divider = i32::from(self.mapping().right_pad_divider);
+        self.pending_x -= i32::from(report.x) * divider;
+        self.pending_y -= i32::from(report.y) * divider;
+    }
+}
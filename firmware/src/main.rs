@@ -10,10 +10,36 @@ extern crate enum_primitive;
 use cortex_m::asm;
 use cortex_m_rt::{entry, exception};
 
+// The keyboard, mouse, vendor and gamepad interfaces of lizard mode take all 4
+// interrupt IN endpoints of the LPC11Uxx, and the CDC port needs 2 more.
+#[cfg(all(feature = "debug-cdc", not(feature = "xinput")))]
+compile_error!("debug-cdc needs the xinput feature: lizard mode leaves no endpoints for it");
+
+#[cfg(feature = "input-drivers")]
+mod analog;
+#[cfg(feature = "input-drivers")]
+mod buttons;
+mod controls;
+#[cfg(feature = "xinput")]
+mod haptics;
+mod hid;
+#[cfg(feature = "input-drivers")]
+mod imu;
+#[cfg(feature = "input-drivers")]
+mod inputs;
 mod led;
+#[cfg(not(feature = "xinput"))]
+mod lizard;
+mod report;
 mod rt;
 mod system;
+#[cfg(feature = "input-drivers")]
+mod trackpad;
+mod usart;
 mod usbd;
+mod valve;
+#[cfg(feature = "xinput")]
+mod xinput;
 
 use lpc11uxx_rom::usbd::{CoreDescriptors, DeviceDescriptor, InitParameter};
 use lpc11uxx_rom::RomDriver;
@@ -22,7 +48,21 @@ use lpc11uxx::interrupt;
 
 use usb_device::prelude::*;
 
-use usbd_serial::*;
+#[cfg(feature = "debug-cdc")]
+use usbd_serial::SerialPort;
+
+use controls::Controls;
+use hid::HidClass;
+#[cfg(feature = "input-drivers")]
+use inputs::Inputs;
+#[cfg(not(feature = "xinput"))]
+use lizard::Lizard;
+use report::GamepadReport;
+#[cfg(not(feature = "xinput"))]
+use report::{KeyboardReport, MouseReport};
+use valve::VendorReport;
+#[cfg(feature = "xinput")]
+use xinput::{XInputClass, XInputReport};
 
 #[exception]
 fn DefaultHandler(_irq: i16) {
@@ -74,49 +114,237 @@ fn DefaultHandler(_irq: i16) {
     }
 }*/
 
+/// Called when polling found nothing to do, with whether the host suspended
+/// the bus. While it stays suspended, the LED is dimmed, and the chip goes to
+/// deep-sleep unless something needs the clocks: a button being debounced, or
+/// the LED, as long as it's lit. The main loop's WFI is then all the sleep
+/// there is.
+fn sleep_while_suspended(suspended: bool) {
+    led::set_dimmed(suspended);
+    if !suspended {
+        return;
+    }
+
+    #[cfg(feature = "input-drivers")]
+    let can_deep_sleep = inputs::can_deep_sleep() && !led::is_lit();
+    #[cfg(not(feature = "input-drivers"))]
+    let can_deep_sleep = !led::is_lit();
+
+    if can_deep_sleep {
+        system::deep_sleep();
+    }
+}
+
+fn reboot_if_requested() {
+    if valve::reboot_requested() {
+        // Give the host the time to see the request complete.
+        asm::delay(system::main_clock_rate() / 100);
+        valve::reboot_to_bootloader();
+    }
+}
+
+#[cfg(feature = "debug-cdc")]
+fn echo_debug_port(serial: &mut SerialPort<usbd::UsbBus>) {
+    let mut buf = [0u8; 64];
+
+    if let Ok(count) = serial.read(&mut buf[..]) {
+        serial.write(&buf[..count]).ok();
+    }
+}
+
+type UsbBusAllocator = usb_device::bus::UsbBusAllocator<usbd::UsbBus>;
+
+/// What the controller enumerates as: its USB classes, and the reports it
+/// makes out of the controls.
+trait Personality {
+    fn new(usb_bus: &'static UsbBusAllocator) -> Self;
+
+    /// Called once the classes got created.
+    fn build_device(usb_bus: &'static UsbBusAllocator) -> UsbDevice<'static, usbd::UsbBus>;
+
+    /// Polls `usb_device` with all the classes, returns whether any has events.
+    fn poll(&mut self, usb_device: &mut UsbDevice<'static, usbd::UsbBus>) -> bool;
+
+    /// Only called while configured. Reports that would block until the host
+    /// took the previous ones are skipped.
+    fn push_reports(&mut self, controls: &Controls);
+
+    #[cfg(feature = "debug-cdc")]
+    fn serial(&mut self) -> &mut SerialPort<'static, usbd::UsbBus>;
+}
+
+/// A Steam Controller, with lizard mode.
+#[cfg(not(feature = "xinput"))]
+struct SteamController {
+    keyboard: HidClass<'static, usbd::UsbBus, KeyboardReport>,
+    mouse: HidClass<'static, usbd::UsbBus, MouseReport>,
+    vendor: HidClass<'static, usbd::UsbBus, VendorReport>,
+    gamepad: HidClass<'static, usbd::UsbBus, GamepadReport>,
+    #[cfg(feature = "debug-cdc")]
+    serial: SerialPort<'static, usbd::UsbBus>,
+    lizard: Lizard,
+}
+
+#[cfg(not(feature = "xinput"))]
+impl Personality for SteamController {
+    fn new(usb_bus: &'static UsbBusAllocator) -> Self {
+        // Same interface numbers as Valve's firmware, Steam and driver-cli
+        // look for the vendor one at 2.
+        SteamController {
+            keyboard: HidClass::new(usb_bus, 1),
+            mouse: HidClass::new(usb_bus, 1),
+            vendor: HidClass::new(usb_bus, 1),
+            gamepad: HidClass::new(usb_bus, 1),
+            #[cfg(feature = "debug-cdc")]
+            serial: SerialPort::new(usb_bus),
+            lizard: Lizard::new(),
+        }
+    }
+
+    fn build_device(usb_bus: &'static UsbBusAllocator) -> UsbDevice<'static, usbd::UsbBus> {
+        UsbDeviceBuilder::new(usb_bus, UsbVidPid(valve::VENDOR_ID, valve::PRODUCT_ID))
+            .manufacturer("Valve Software")
+            .product("Steam Controller")
+            .supports_remote_wakeup(true)
+            .build()
+    }
+
+    fn poll(&mut self, usb_device: &mut UsbDevice<'static, usbd::UsbBus>) -> bool {
+        usb_device.poll(&mut [
+            &mut self.keyboard,
+            &mut self.mouse,
+            &mut self.vendor,
+            &mut self.gamepad,
+            #[cfg(feature = "debug-cdc")]
+            &mut self.serial,
+        ])
+    }
+
+    fn push_reports(&mut self, controls: &Controls) {
+        self.gamepad
+            .push_report(&GamepadReport::from(controls))
+            .ok();
+
+        let lizard = &mut self.lizard;
+        lizard.update(controls);
+        let keyboard_report = lizard.keyboard_report();
+        if self.keyboard.push_report(&keyboard_report).is_ok() {
+            lizard.keyboard_report_sent(&keyboard_report);
+        }
+        let mouse_report = lizard.mouse_report();
+        if self.mouse.push_report(&mouse_report).is_ok() {
+            lizard.mouse_report_sent(&mouse_report);
+        }
+    }
+
+    #[cfg(feature = "debug-cdc")]
+    fn serial(&mut self) -> &mut SerialPort<'static, usbd::UsbBus> {
+        &mut self.serial
+    }
+}
+
+/// An Xbox 360 controller. The vendor interface stays, to reboot to the
+/// bootloader, but Valve's tools won't look for it under these IDs.
+#[cfg(feature = "xinput")]
+struct Xbox360Controller {
+    xinput: XInputClass<'static, usbd::UsbBus>,
+    vendor: HidClass<'static, usbd::UsbBus, VendorReport>,
+    #[cfg(feature = "debug-cdc")]
+    serial: SerialPort<'static, usbd::UsbBus>,
+}
+
+#[cfg(feature = "xinput")]
+impl Personality for Xbox360Controller {
+    fn new(usb_bus: &'static UsbBusAllocator) -> Self {
+        // Drivers expect the XInput interface first.
+        Xbox360Controller {
+            xinput: XInputClass::new(usb_bus),
+            vendor: HidClass::new(usb_bus, 1),
+            #[cfg(feature = "debug-cdc")]
+            serial: SerialPort::new(usb_bus),
+        }
+    }
+
+    fn build_device(usb_bus: &'static UsbBusAllocator) -> UsbDevice<'static, usbd::UsbBus> {
+        UsbDeviceBuilder::new(usb_bus, UsbVidPid(xinput::VENDOR_ID, xinput::PRODUCT_ID))
+            .manufacturer("Microsoft")
+            .product("Controller")
+            .device_class(0xff)
+            .device_sub_class(0xff)
+            .device_protocol(0xff)
+            .device_release(xinput::DEVICE_RELEASE)
+            .supports_remote_wakeup(true)
+            .build()
+    }
+
+    fn poll(&mut self, usb_device: &mut UsbDevice<'static, usbd::UsbBus>) -> bool {
+        usb_device.poll(&mut [
+            &mut self.xinput,
+            &mut self.vendor,
+            #[cfg(feature = "debug-cdc")]
+            &mut self.serial,
+        ])
+    }
+
+    fn push_reports(&mut self, controls: &Controls) {
+        let report = GamepadReport::from(controls);
+        self.xinput.push_report(&XInputReport::from(&report)).ok();
+    }
+
+    #[cfg(feature = "debug-cdc")]
+    fn serial(&mut self) -> &mut SerialPort<'static, usbd::UsbBus> {
+        &mut self.serial
+    }
+}
+
+#[cfg(not(feature = "xinput"))]
+type SelectedPersonality = SteamController;
+#[cfg(feature = "xinput")]
+type SelectedPersonality = Xbox360Controller;
+
+/// The main loop: reads the controls, and reports them through `personality`.
+fn run<P: Personality>(
+    usb_device: &mut UsbDevice<'static, usbd::UsbBus>,
+    personality: &mut P,
+) -> ! {
+    let mut controls = Controls::default();
+    #[cfg(feature = "input-drivers")]
+    let mut inputs = Inputs::new();
+
+    loop {
+        let has_events = personality.poll(usb_device);
+
+        reboot_if_requested();
+        usbd::wake_host_if_requested(usb_device);
+
+        if !has_events {
+            sleep_while_suspended(usb_device.state() == UsbDeviceState::Suspend);
+        }
+
+        #[cfg(feature = "input-drivers")]
+        inputs.update(&mut controls);
+
+        if usb_device.state() == UsbDeviceState::Configured {
+            personality.push_reports(&controls);
+        }
+
+        #[cfg(feature = "debug-cdc")]
+        echo_debug_port(personality.serial());
+
+        // Wait for interruption (as polling is handled in the IRQ)
+        asm::wfi();
+    }
+}
+
 unsafe fn usb_init() {
-    usbd::USB_BUS_ALLOCATOR = Some(usbd::UsbBus::new());
+    usbd::USB_BUS_ALLOCATOR = Some(usbd::UsbBus::with_hardware(usbd::Lpc11uxxUsb));
 
     if let Some(ref usb_bus) = usbd::USB_BUS_ALLOCATOR {
-        let mut serial = SerialPort::new(usb_bus);
-        usbd::USB_DEVICE = Some(
-            UsbDeviceBuilder::new(usb_bus, UsbVidPid(0x16c0, 0x27dd))
-                .product("Serial port")
-                .device_class(USB_CLASS_CDC)
-                .build(),
-        );
+        let mut personality = SelectedPersonality::new(usb_bus);
+        usbd::USB_DEVICE = Some(SelectedPersonality::build_device(usb_bus));
 
         if let Some(ref mut usb_device) = usbd::USB_DEVICE {
-            loop {
-                if !usb_device.poll(&mut [&mut serial]) {
-                    continue;
-                }
-
-                let mut buf = [0u8; 64];
-
-                match serial.read(&mut buf[..]) {
-                    Ok(count) => {
-                        // count bytes were read to &buf[..count]
-                    }
-                    //Err(UsbError::WouldBlock) => {}
-                    Err(err) => {
-                        //panic!()
-                    }
-                };
-
-                match serial.write(&[0x3a, 0x29]) {
-                    Ok(count) => {
-                        // count bytes were written
-                    }
-                    //Err(UsbError::WouldBlock) => {}
-                    Err(err) => {
-                        //panic!()
-                    }
-                };
-
-                // Wait for interruption (as polling is handled in the IRQ)
-                asm::wfi();
-            }
+            run(usb_device, &mut personality);
         }
     }
 }
@@ -128,6 +356,10 @@ fn main() -> ! {
 
     led::set_intensity(0x1000);
 
+    usart::initialize();
+    #[cfg(feature = "input-drivers")]
+    inputs::initialize();
+
     unsafe {
         usb_init();
     }
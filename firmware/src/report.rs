@@ -0,0 +1,257 @@
+use bitflags::bitflags;
+
+/// Largest input report any of our HID interfaces sends.
+pub const MAX_REPORT_LEN: usize = 64;
+
+/// An input report, as sent on the interrupt IN endpoint of a `HidClass`.
+pub trait InputReport {
+    /// The HID report descriptor describing the serialized report.
+    const DESCRIPTOR: &'static [u8];
+    /// Boot protocol of the interface: 1 for a keyboard, 2 for a mouse, 0 if
+    /// the report isn't a boot one.
+    const BOOT_PROTOCOL: u8 = 0;
+
+    /// Writes the report to `buffer`, and returns its length.
+    fn serialize(&self, buffer: &mut [u8; MAX_REPORT_LEN]) -> usize;
+
+    /// Whether the report has relative axes not at 0. Sending such a report
+    /// again moves again, so it is never skipped as a repeat.
+    fn has_movement(&self) -> bool {
+        false
+    }
+}
+
+/// Feature reports of a `HidClass`, exchanged on the control endpoint.
+pub trait FeatureReports {
+    /// Handles a feature report sent by the host. `response` is what the host
+    /// gets when it reads the feature report back, and is kept as is between
+    /// calls.
+    fn set_feature_report(_data: &[u8], _response: &mut [u8; MAX_REPORT_LEN]) {}
+}
+
+bitflags! {
+    /// Gamepad buttons, in the order of the HID button usages 1 to 16.
+    #[derive(Default)]
+    pub struct Buttons: u16 {
+        const A              = 1 << 0;
+        const B              = 1 << 1;
+        const X              = 1 << 2;
+        const Y              = 1 << 3;
+        const LEFT_BUMPER    = 1 << 4;
+        const RIGHT_BUMPER   = 1 << 5;
+        const LEFT_TRIGGER   = 1 << 6;
+        const RIGHT_TRIGGER  = 1 << 7;
+        const BACK           = 1 << 8;
+        const START          = 1 << 9;
+        const STEAM          = 1 << 10;
+        const LEFT_STICK     = 1 << 11;
+        const LEFT_PAD       = 1 << 12;
+        const RIGHT_PAD      = 1 << 13;
+        const LEFT_GRIP      = 1 << 14;
+        const RIGHT_GRIP     = 1 << 15;
+    }
+}
+
+/// State of the standard HID gamepad.
+///
+/// Axes follow HID: X grows to the right and Y grows downward. The right stick
+/// is the right trackpad until it gets its own processing.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct GamepadReport {
+    pub buttons: Buttons,
+    pub left_stick_x: i16,
+    pub left_stick_y: i16,
+    pub right_stick_x: i16,
+    pub right_stick_y: i16,
+    pub left_trigger: u8,
+    pub right_trigger: u8,
+}
+
+const GAMEPAD_REPORT_DESCRIPTOR: &[u8] = &[
+    0x05, 0x01, // Usage Page (Generic Desktop)
+    0x09, 0x05, // Usage (Game Pad)
+    0xa1, 0x01, // Collection (Application)
+    0x05, 0x09, //   Usage Page (Button)
+    0x19, 0x01, //   Usage Minimum (1)
+    0x29, 0x10, //   Usage Maximum (16)
+    0x15, 0x00, //   Logical Minimum (0)
+    0x25, 0x01, //   Logical Maximum (1)
+    0x75, 0x01, //   Report Size (1)
+    0x95, 0x10, //   Report Count (16)
+    0x81, 0x02, //   Input (Data, Variable, Absolute)
+    0x05, 0x01, //   Usage Page (Generic Desktop)
+    0x09, 0x30, //   Usage (X)
+    0x09, 0x31, //   Usage (Y)
+    0x09, 0x33, //   Usage (Rx)
+    0x09, 0x34, //   Usage (Ry)
+    0x16, 0x00, 0x80, //   Logical Minimum (-32768)
+    0x26, 0xff, 0x7f, //   Logical Maximum (32767)
+    0x75, 0x10, //   Report Size (16)
+    0x95, 0x04, //   Report Count (4)
+    0x81, 0x02, //   Input (Data, Variable, Absolute)
+    0x09, 0x32, //   Usage (Z)
+    0x09, 0x35, //   Usage (Rz)
+    0x15, 0x00, //   Logical Minimum (0)
+    0x26, 0xff, 0x00, //   Logical Maximum (255)
+    0x75, 0x08, //   Report Size (8)
+    0x95, 0x02, //   Report Count (2)
+    0x81, 0x02, //   Input (Data, Variable, Absolute)
+    0xc0, // End Collection
+];
+
+impl FeatureReports for GamepadReport {}
+
+impl InputReport for GamepadReport {
+    const DESCRIPTOR: &'static [u8] = GAMEPAD_REPORT_DESCRIPTOR;
+
+    fn serialize(&self, buffer: &mut [u8; MAX_REPORT_LEN]) -> usize {
+        buffer[0..2].copy_from_slice(&self.buttons.bits().to_le_bytes());
+        buffer[2..4].copy_from_slice(&self.left_stick_x.to_le_bytes());
+        buffer[4..6].copy_from_slice(&self.left_stick_y.to_le_bytes());
+        buffer[6..8].copy_from_slice(&self.right_stick_x.to_le_bytes());
+        buffer[8..10].copy_from_slice(&self.right_stick_y.to_le_bytes());
+        buffer[10] = self.left_trigger;
+        buffer[11] = self.right_trigger;
+        12
+    }
+}
+
+#[cfg(not(feature = "xinput"))]
+bitflags! {
+    #[derive(Default)]
+    pub struct KeyModifiers: u8 {
+        const LEFT_CTRL   = 1 << 0;
+        const LEFT_SHIFT  = 1 << 1;
+        const LEFT_ALT    = 1 << 2;
+        const LEFT_GUI    = 1 << 3;
+        const RIGHT_CTRL  = 1 << 4;
+        const RIGHT_SHIFT = 1 << 5;
+        const RIGHT_ALT   = 1 << 6;
+        const RIGHT_GUI   = 1 << 7;
+    }
+}
+
+/// Boot keyboard report: modifiers, and up to 6 pressed keys as HID usages.
+#[cfg(not(feature = "xinput"))]
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct KeyboardReport {
+    pub modifiers: KeyModifiers,
+    pub keys: [u8; 6],
+}
+
+// The boot keyboard descriptor of HID 1.11 appendix B.1, without the LEDs.
+#[cfg(not(feature = "xinput"))]
+const KEYBOARD_REPORT_DESCRIPTOR: &[u8] = &[
+    0x05, 0x01, // Usage Page (Generic Desktop)
+    0x09, 0x06, // Usage (Keyboard)
+    0xa1, 0x01, // Collection (Application)
+    0x05, 0x07, //   Usage Page (Key Codes)
+    0x19, 0xe0, //   Usage Minimum (224)
+    0x29, 0xe7, //   Usage Maximum (231)
+    0x15, 0x00, //   Logical Minimum (0)
+    0x25, 0x01, //   Logical Maximum (1)
+    0x75, 0x01, //   Report Size (1)
+    0x95, 0x08, //   Report Count (8)
+    0x81, 0x02, //   Input (Data, Variable, Absolute)
+    0x95, 0x01, //   Report Count (1)
+    0x75, 0x08, //   Report Size (8)
+    0x81, 0x01, //   Input (Constant)
+    0x95, 0x06, //   Report Count (6)
+    0x75, 0x08, //   Report Size (8)
+    0x15, 0x00, //   Logical Minimum (0)
+    0x25, 0x65, //   Logical Maximum (101)
+    0x05, 0x07, //   Usage Page (Key Codes)
+    0x19, 0x00, //   Usage Minimum (0)
+    0x29, 0x65, //   Usage Maximum (101)
+    0x81, 0x00, //   Input (Data, Array)
+    0xc0, // End Collection
+];
+
+#[cfg(not(feature = "xinput"))]
+impl FeatureReports for KeyboardReport {}
+
+#[cfg(not(feature = "xinput"))]
+impl InputReport for KeyboardReport {
+    const DESCRIPTOR: &'static [u8] = KEYBOARD_REPORT_DESCRIPTOR;
+    const BOOT_PROTOCOL: u8 = 1;
+
+    fn serialize(&self, buffer: &mut [u8; MAX_REPORT_LEN]) -> usize {
+        buffer[0] = self.modifiers.bits();
+        buffer[1] = 0;
+        buffer[2..8].copy_from_slice(&self.keys);
+        8
+    }
+}
+
+#[cfg(not(feature = "xinput"))]
+bitflags! {
+    #[derive(Default)]
+    pub struct MouseButtons: u8 {
+        const LEFT   = 1 << 0;
+        const RIGHT  = 1 << 1;
+        const MIDDLE = 1 << 2;
+    }
+}
+
+/// Boot mouse report, with a wheel. Movements are relative.
+#[cfg(not(feature = "xinput"))]
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct MouseReport {
+    pub buttons: MouseButtons,
+    pub x: i8,
+    pub y: i8,
+    pub wheel: i8,
+}
+
+// The boot mouse descriptor of HID 1.11 appendix B.2, plus a wheel.
+#[cfg(not(feature = "xinput"))]
+const MOUSE_REPORT_DESCRIPTOR: &[u8] = &[
+    0x05, 0x01, // Usage Page (Generic Desktop)
+    0x09, 0x02, // Usage (Mouse)
+    0xa1, 0x01, // Collection (Application)
+    0x09, 0x01, //   Usage (Pointer)
+    0xa1, 0x00, //   Collection (Physical)
+    0x05, 0x09, //     Usage Page (Button)
+    0x19, 0x01, //     Usage Minimum (1)
+    0x29, 0x03, //     Usage Maximum (3)
+    0x15, 0x00, //     Logical Minimum (0)
+    0x25, 0x01, //     Logical Maximum (1)
+    0x95, 0x03, //     Report Count (3)
+    0x75, 0x01, //     Report Size (1)
+    0x81, 0x02, //     Input (Data, Variable, Absolute)
+    0x95, 0x01, //     Report Count (1)
+    0x75, 0x05, //     Report Size (5)
+    0x81, 0x01, //     Input (Constant)
+    0x05, 0x01, //     Usage Page (Generic Desktop)
+    0x09, 0x30, //     Usage (X)
+    0x09, 0x31, //     Usage (Y)
+    0x09, 0x38, //     Usage (Wheel)
+    0x15, 0x81, //     Logical Minimum (-127)
+    0x25, 0x7f, //     Logical Maximum (127)
+    0x75, 0x08, //     Report Size (8)
+    0x95, 0x03, //     Report Count (3)
+    0x81, 0x06, //     Input (Data, Variable, Relative)
+    0xc0, //   End Collection
+    0xc0, // End Collection
+];
+
+#[cfg(not(feature = "xinput"))]
+impl FeatureReports for MouseReport {}
+
+#[cfg(not(feature = "xinput"))]
+impl InputReport for MouseReport {
+    const DESCRIPTOR: &'static [u8] = MOUSE_REPORT_DESCRIPTOR;
+    const BOOT_PROTOCOL: u8 = 2;
+
+    fn serialize(&self, buffer: &mut [u8; MAX_REPORT_LEN]) -> usize {
+        buffer[0] = self.buttons.bits();
+        buffer[1] = self.x as u8;
+        buffer[2] = self.y as u8;
+        buffer[3] = self.wheel as u8;
+        4
+    }
+
+    fn has_movement(&self) -> bool {
+        self.x != 0 || self.y != 0 || self.wheel != 0
+    }
+}
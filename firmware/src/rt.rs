@@ -1,18 +1,44 @@
+use core::fmt::Write;
 use core::panic::PanicInfo;
+use cortex_m::peripheral::SCB;
 use embedded_hal::blocking::delay::DelayMs;
-use lpc11uxx::CorePeripherals;
+use lpc11uxx::{CorePeripherals, Peripherals};
 use lpc11uxx_hal::delay::Delay;
+use nrf_protocol::digest::Fnv1a;
 
 use crate::led;
 use crate::system::{CRYSTAL_OSCILLATOR_CLOCK_RATE, SYSTEM_PPL_MSET};
 
+/// Top half of GPREG2 after a panic, see the bootloader's crash_log.
+const PANIC_MAGIC: u32 = 0xfa11_0000;
+
+/// Hands the panic over to the bootloader, which records it in its crash log,
+/// through the PMU general purpose registers. They survive the reset.
 #[inline(never)]
 #[panic_handler]
-fn panic(_info: &PanicInfo) -> ! {
-    led_panic()
+fn panic(info: &PanicInfo) -> ! {
+    cortex_m::interrupt::disable();
+
+    // The message would need the panic_info_message feature, only the
+    // location goes in.
+    let mut digest = Fnv1a::new();
+    let mut line = 0;
+    if let Some(location) = info.location() {
+        let _ = digest.write_str(location.file());
+        line = location.line();
+    }
+
+    let peripherals = unsafe { Peripherals::steal() };
+    peripherals.PMU.gpreg[3].write(|writer| unsafe { writer.gpdata().bits(digest.finish()) });
+    peripherals.PMU.gpreg[2]
+        .write(|writer| unsafe { writer.gpdata().bits(PANIC_MAGIC | (line & 0xffff)) });
+
+    // Give whoever is looking a chance to notice.
+    led_blink(3);
+    SCB::sys_reset()
 }
 
-fn led_panic() -> ! {
+fn led_blink(times: u32) {
     let peripherals = unsafe { CorePeripherals::steal() };
 
     let mut delay = Delay::new(
@@ -20,15 +46,10 @@ fn led_panic() -> ! {
         CRYSTAL_OSCILLATOR_CLOCK_RATE * u32::from(SYSTEM_PPL_MSET),
     );
 
-    let mut intensity = 0x1000;
-    loop {
-        led::set_intensity(intensity);
-
-        if intensity == 0 {
-            intensity = 0x1000;
-        } else {
-            intensity = 0;
-        }
-        delay.delay_ms(1000);
+    for _ in 0..times {
+        led::set_intensity(0x1000);
+        delay.delay_ms(500_u32);
+        led::set_intensity(0);
+        delay.delay_ms(500_u32);
     }
 }
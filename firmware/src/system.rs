@@ -1,9 +1,25 @@
-use lpc11uxx::{CorePeripherals, Peripherals};
+use cortex_m::asm;
+use cortex_m::peripheral::NVIC;
+use lpc11uxx::{CorePeripherals, Interrupt, Peripherals};
 
 pub static CRYSTAL_OSCILLATOR_CLOCK_RATE: u32 = 12_000_000;
 pub static SYSTEM_PPL_MSET: u8 = 3;
 pub static SYSTEM_PPL_PSET: u8 = 1;
 
+/// PDSLEEPCFG with the BOD and the watchdog oscillator powered down. The other
+/// bits are reserved, and must be written with these values.
+const PDSLEEPCFG_BOD_WDTOSC_OFF: u32 = 0x0000_18FF;
+/// USBCLKCTRL: USB_NEED_CLK under hardware control, wake-up on its rising edge.
+const USBCLKCTRL_POL_CLK: u32 = 1 << 1;
+const STARTERP1_USB_WAKEUP: u32 = 1 << 19;
+/// USBCLKST: USB_NEED_CLK is high.
+const USBCLKST_NEED_CLKST: u32 = 1 << 0;
+
+/// Rate of the main clock once `initialize` switched it to the system PLL.
+pub fn main_clock_rate() -> u32 {
+    CRYSTAL_OSCILLATOR_CLOCK_RATE * (u32::from(SYSTEM_PPL_MSET) + 1)
+}
+
 pub fn initialize() {
     let peripherals = unsafe { Peripherals::steal() };
     let core_peripherals = unsafe { CorePeripherals::steal() };
@@ -188,3 +204,93 @@ pub fn initialize() {
         .sysahbclkctrl
         .modify(|_, writer| writer.usbram().enabled());
 }
+
+/// Puts the chip in deep-sleep until the USB bus resumes (or any other enabled
+/// wake-up source fires), then brings the clocks back up. Returns right away if
+/// the USB controller still needs its clock.
+///
+/// Only the IRC keeps running in deep-sleep, so the main clock is switched to
+/// it for the duration, and the timers stop. Everything else powered in
+/// PDRUNCFG comes back on wake-up.
+pub fn deep_sleep() {
+    let peripherals = unsafe { Peripherals::steal() };
+    let mut core_peripherals = unsafe { CorePeripherals::steal() };
+
+    unsafe {
+        // USB_NEED_CLK going high (bus activity) raises USB_WAKEUP.
+        peripherals
+            .SYSCON
+            .usbclkctrl
+            .write(|writer| writer.bits(USBCLKCTRL_POL_CLK));
+        peripherals
+            .SYSCON
+            .starterp1
+            .modify(|reader, writer| writer.bits(reader.bits() | STARTERP1_USB_WAKEUP));
+    }
+
+    NVIC::unpend(Interrupt::USBWAKEUP);
+
+    // USB_NEED_CLK only drops a few ms after the bus got suspended. Sleeping
+    // while it's still high would wait for a rising edge that never comes. If
+    // it rises after this, USB_WAKEUP is pending and WFI returns right away.
+    if peripherals.SYSCON.usbclkst.read().bits() & USBCLKST_NEED_CLKST != 0 {
+        return;
+    }
+
+    // Run from the IRC while the PLLs are down.
+    peripherals
+        .SYSCON
+        .mainclksel
+        .write(|writer| writer.sel().irc_oscillator());
+    peripherals
+        .SYSCON
+        .mainclkuen
+        .write(|writer| writer.ena().no_change());
+    peripherals
+        .SYSCON
+        .mainclkuen
+        .write(|writer| writer.ena().update_clock_source());
+
+    unsafe {
+        // Keep the BOD and watchdog oscillator off while sleeping, and power
+        // back up whatever runs now on wake-up.
+        peripherals
+            .SYSCON
+            .pdsleepcfg
+            .write(|writer| writer.bits(PDSLEEPCFG_BOD_WDTOSC_OFF));
+        let pdruncfg = peripherals.SYSCON.pdruncfg.read().bits();
+        peripherals
+            .SYSCON
+            .pdawakecfg
+            .write(|writer| writer.bits(pdruncfg));
+
+        // PM = 1: deep-sleep on WFI with SLEEPDEEP set.
+        peripherals
+            .PMU
+            .pcon
+            .modify(|reader, writer| writer.bits((reader.bits() & !0x7) | 0x1));
+    }
+
+    unsafe { NVIC::unmask(Interrupt::USBWAKEUP) };
+
+    core_peripherals.SCB.set_sleepdeep();
+    asm::wfi();
+    core_peripherals.SCB.clear_sleepdeep();
+
+    // Wait for both PLLs to lock again, then switch back to the system PLL.
+    while peripherals.SYSCON.syspllstat.read().lock().bit_is_clear() {}
+    while peripherals.SYSCON.usbpllstat.read().lock().bit_is_clear() {}
+
+    peripherals
+        .SYSCON
+        .mainclksel
+        .write(|writer| writer.sel().pll_output());
+    peripherals
+        .SYSCON
+        .mainclkuen
+        .write(|writer| writer.ena().no_change());
+    peripherals
+        .SYSCON
+        .mainclkuen
+        .write(|writer| writer.ena().update_clock_source());
+}
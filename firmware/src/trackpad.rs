@@ -0,0 +1,335 @@
+//! The two trackpads, each with its own controller: the left one on SSP0, the
+//! right one on SSP1.
+//!
+//! The controllers are driven as Cirque Pinnacle ASICs in absolute mode, which
+//! is what they look like.
+//!
+//! Sampling doesn't block: `start_sampling` fills the SSP FIFO with a read of
+//! the position registers, and the SSP interrupt takes the answer once it is
+//! all there, then clears the controller's data ready flag the same way.
+
+use cortex_m::peripheral::NVIC;
+use lpc11uxx::{interrupt, Interrupt, Peripherals};
+
+use crate::controls::Touch;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Pad {
+    Left = 0,
+    Right = 1,
+}
+
+/// A raw reading of a pad, in the controller's units.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct PadSample {
+    pub x: u16,
+    pub y: u16,
+    /// Roughly how much of the finger touches the pad, 0 when nothing does.
+    pub pressure: u8,
+}
+
+/// Pressure under which the pad doesn't count as touched.
+const TOUCH_MIN_PRESSURE: u8 = 2;
+
+/// Range of the absolute coordinates.
+const X_MIN: i32 = 128;
+const X_MAX: i32 = 1920;
+const Y_MIN: i32 = 64;
+const Y_MAX: i32 = 1472;
+
+// Register access protocol: a read is the address with READ, then two filler
+// bytes, then one filler byte per register read.
+const RAP_READ: u8 = 0xa0;
+const RAP_WRITE: u8 = 0x80;
+const RAP_FILLER: u8 = 0xfc;
+const RAP_READ_HEADER_LEN: usize = 3;
+
+const REGISTER_STATUS1: u8 = 0x02;
+const REGISTER_SYS_CONFIG1: u8 = 0x03;
+const REGISTER_FEED_CONFIG1: u8 = 0x04;
+const REGISTER_FEED_CONFIG2: u8 = 0x05;
+/// PacketByte0..4: buttons, X low, Y low, X and Y high nibbles, Z.
+const REGISTER_PACKET_BYTE0: u8 = 0x12;
+const PACKET_LEN: usize = 5;
+
+/// FeedConfig1: feed enabled, absolute mode.
+const FEED_CONFIG1_ABSOLUTE: u8 = 0x03;
+/// FeedConfig2: no taps, no secondary taps, no scrolling, no glide extend.
+const FEED_CONFIG2_NO_GESTURES: u8 = 0x1e;
+
+/// The whole read fits in the 8 frames SSP FIFO.
+const READ_LEN: usize = RAP_READ_HEADER_LEN + PACKET_LEN;
+const_assert!(READ_LEN <= 8);
+
+/// SSP CR0: 8 bits frames, SPI, CPOL = 0, CPHA = 1.
+const SSP_CR0_8BIT_MODE1: u32 = 0x7 | 1 << 7;
+const SSP_CR1_SSE: u32 = 1 << 1;
+const SSP_SR_RNE: u32 = 1 << 2;
+const SSP_SR_BSY: u32 = 1 << 4;
+/// IMSC, ICR: receive timeout, i.e. the transfer is over and the RX FIFO
+/// holds all of it.
+const SSP_RT: u32 = 1 << 1;
+/// 48MHz / 48: 1MHz.
+const SSP_CLOCK_PRESCALER: u32 = 48;
+
+/// PRESETCTRL: SSP0 and SSP1 out of reset.
+const PRESETCTRL_SSP0_RST_N: u32 = 1 << 0;
+const PRESETCTRL_SSP1_RST_N: u32 = 1 << 2;
+/// SYSAHBCLKCTRL: SSP0 and SSP1 clocks.
+const SYSAHBCLKCTRL_SSP0: u32 = 1 << 11;
+const SYSAHBCLKCTRL_SSP1: u32 = 1 << 18;
+
+/// IOCON values for the pins of each bus: MISO, MOSI and SCK functions, no
+/// pull resistor.
+const IOCON_FUNC1: u32 = 0x1;
+const IOCON_FUNC2: u32 = 0x2;
+
+/// GPIO (port, pin) of the chip select of each pad, active low.
+const CHIP_SELECT: [(usize, u32); 2] = [(1, 19), (1, 23)];
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Transfer {
+    Idle,
+    Reading,
+    ClearingStatus,
+}
+
+static mut TRANSFERS: [Transfer; 2] = [Transfer::Idle; 2];
+static mut SAMPLES: [PadSample; 2] = [PadSample {
+    x: 0,
+    y: 0,
+    pressure: 0,
+}; 2];
+
+fn ssp(pad: Pad) -> &'static lpc11uxx::ssp0::RegisterBlock {
+    unsafe {
+        match pad {
+            Pad::Left => &*lpc11uxx::SSP0::ptr(),
+            Pad::Right => &*lpc11uxx::SSP1::ptr(),
+        }
+    }
+}
+
+fn select(pad: Pad, selected: bool) {
+    let peripherals = unsafe { Peripherals::steal() };
+    let (port, pin) = CHIP_SELECT[pad as usize];
+
+    unsafe {
+        if selected {
+            peripherals.GPIO_PORT.clr[port].write(|writer| writer.bits(1 << pin));
+        } else {
+            peripherals.GPIO_PORT.set[port].write(|writer| writer.bits(1 << pin));
+        }
+    }
+}
+
+pub fn initialize() {
+    let peripherals = unsafe { Peripherals::steal() };
+
+    unsafe {
+        peripherals.SYSCON.sysahbclkctrl.modify(|reader, writer| {
+            writer.bits(reader.bits() | SYSAHBCLKCTRL_SSP0 | SYSAHBCLKCTRL_SSP1)
+        });
+        peripherals
+            .SYSCON
+            .ssp0clkdiv
+            .write(|writer| writer.div().bits(1));
+        peripherals
+            .SYSCON
+            .ssp1clkdiv
+            .write(|writer| writer.div().bits(1));
+        peripherals.SYSCON.presetctrl.modify(|reader, writer| {
+            writer.bits(reader.bits() | PRESETCTRL_SSP0_RST_N | PRESETCTRL_SSP1_RST_N)
+        });
+
+        // SSP0: MISO0, MOSI0, SCK0.
+        peripherals
+            .IOCON
+            .pio0_8
+            .write(|writer| writer.bits(IOCON_FUNC1));
+        peripherals
+            .IOCON
+            .pio0_9
+            .write(|writer| writer.bits(IOCON_FUNC1));
+        peripherals
+            .IOCON
+            .pio1_29
+            .write(|writer| writer.bits(IOCON_FUNC1));
+        // SSP1: MISO1, MOSI1, SCK1.
+        peripherals
+            .IOCON
+            .pio1_21
+            .write(|writer| writer.bits(IOCON_FUNC2));
+        peripherals
+            .IOCON
+            .pio1_22
+            .write(|writer| writer.bits(IOCON_FUNC2));
+        peripherals
+            .IOCON
+            .pio1_20
+            .write(|writer| writer.bits(IOCON_FUNC2));
+    }
+
+    for &pad in [Pad::Left, Pad::Right].iter() {
+        let (port, pin) = CHIP_SELECT[pad as usize];
+        select(pad, false);
+        peripherals.GPIO_PORT.dir[port]
+            .modify(|reader, writer| unsafe { writer.bits(reader.bits() | 1 << pin) });
+
+        let ssp = ssp(pad);
+        unsafe {
+            ssp.cr0.write(|writer| writer.bits(SSP_CR0_8BIT_MODE1));
+            ssp.cpsr.write(|writer| writer.bits(SSP_CLOCK_PRESCALER));
+            ssp.cr1.write(|writer| writer.bits(SSP_CR1_SSE));
+        }
+
+        write_register_blocking(pad, REGISTER_SYS_CONFIG1, 0x00);
+        write_register_blocking(pad, REGISTER_FEED_CONFIG2, FEED_CONFIG2_NO_GESTURES);
+        write_register_blocking(pad, REGISTER_FEED_CONFIG1, FEED_CONFIG1_ABSOLUTE);
+        write_register_blocking(pad, REGISTER_STATUS1, 0x00);
+
+        unsafe {
+            ssp.icr.write(|writer| writer.bits(SSP_RT));
+            ssp.imsc.write(|writer| writer.bits(SSP_RT));
+        }
+    }
+
+    for interrupt in [Interrupt::SSP0, Interrupt::SSP1].iter() {
+        NVIC::unpend(*interrupt);
+        unsafe { NVIC::unmask(*interrupt) };
+    }
+}
+
+/// Only used while the SSP interrupts are still masked.
+fn write_register_blocking(pad: Pad, register: u8, value: u8) {
+    let ssp = ssp(pad);
+
+    select(pad, true);
+    unsafe {
+        ssp.dr
+            .write(|writer| writer.bits(u32::from(RAP_WRITE | register)));
+        ssp.dr.write(|writer| writer.bits(u32::from(value)));
+    }
+    while ssp.sr.read().bits() & SSP_SR_BSY != 0 {}
+    select(pad, false);
+
+    while ssp.sr.read().bits() & SSP_SR_RNE != 0 {
+        ssp.dr.read();
+    }
+}
+
+/// Starts reading both pads, unless they are still busy with the previous
+/// reading.
+pub fn start_sampling() {
+    for &pad in [Pad::Left, Pad::Right].iter() {
+        cortex_m::interrupt::free(|_| unsafe {
+            if TRANSFERS[pad as usize] == Transfer::Idle {
+                TRANSFERS[pad as usize] = Transfer::Reading;
+                start_read(pad);
+            }
+        });
+    }
+}
+
+fn start_read(pad: Pad) {
+    let ssp = ssp(pad);
+
+    select(pad, true);
+    unsafe {
+        ssp.dr
+            .write(|writer| writer.bits(u32::from(RAP_READ | REGISTER_PACKET_BYTE0)));
+        for _ in 1..READ_LEN {
+            ssp.dr.write(|writer| writer.bits(u32::from(RAP_FILLER)));
+        }
+    }
+}
+
+fn start_clearing_status(pad: Pad) {
+    let ssp = ssp(pad);
+
+    select(pad, true);
+    unsafe {
+        ssp.dr
+            .write(|writer| writer.bits(u32::from(RAP_WRITE | REGISTER_STATUS1)));
+        ssp.dr.write(|writer| writer.bits(0));
+    }
+}
+
+pub fn sample(pad: Pad) -> PadSample {
+    cortex_m::interrupt::free(|_| unsafe { SAMPLES[pad as usize] })
+}
+
+/// Where the finger is on `pad`, centered like the sticks, or None if it
+/// isn't touched.
+pub fn touch(pad: Pad) -> Option<Touch> {
+    let sample = sample(pad);
+    if sample.pressure < TOUCH_MIN_PRESSURE {
+        return None;
+    }
+
+    let center = |value: u16, min: i32, max: i32| {
+        let half_range = (max - min) / 2;
+        let value = (i32::from(value) - min - half_range) * i32::from(i16::MAX) / half_range;
+        value.max(-i32::from(i16::MAX)).min(i32::from(i16::MAX)) as i16
+    };
+    Some((
+        center(sample.x, X_MIN, X_MAX),
+        center(sample.y, Y_MIN, Y_MAX),
+    ))
+}
+
+fn parse_packet(packet: &[u8; PACKET_LEN]) -> PadSample {
+    PadSample {
+        x: u16::from(packet[1]) | u16::from(packet[3] & 0x0f) << 8,
+        y: u16::from(packet[2]) | u16::from(packet[3] & 0xf0) << 4,
+        pressure: packet[4] & 0x3f,
+    }
+}
+
+fn transfer_done(pad: Pad) {
+    let ssp = ssp(pad);
+
+    unsafe {
+        ssp.icr.write(|writer| writer.bits(SSP_RT));
+    }
+    // The RX FIFO fills a bit after the data is clocked out.
+    while ssp.sr.read().bits() & SSP_SR_BSY != 0 {}
+    select(pad, false);
+
+    let mut received = [0u8; READ_LEN];
+    let mut len = 0;
+    while ssp.sr.read().bits() & SSP_SR_RNE != 0 {
+        let byte = ssp.dr.read().bits() as u8;
+        if len < READ_LEN {
+            received[len] = byte;
+            len += 1;
+        }
+    }
+
+    unsafe {
+        match TRANSFERS[pad as usize] {
+            Transfer::Reading => {
+                if len == READ_LEN {
+                    let mut packet = [0; PACKET_LEN];
+                    packet.copy_from_slice(&received[RAP_READ_HEADER_LEN..]);
+                    SAMPLES[pad as usize] = parse_packet(&packet);
+                }
+                TRANSFERS[pad as usize] = Transfer::ClearingStatus;
+                start_clearing_status(pad);
+            }
+            Transfer::ClearingStatus | Transfer::Idle => {
+                TRANSFERS[pad as usize] = Transfer::Idle;
+            }
+        }
+    }
+}
+
+#[interrupt]
+fn SSP0() {
+    transfer_done(Pad::Left);
+}
+
+#[interrupt]
+fn SSP1() {
+    transfer_done(Pad::Right);
+}
@@ -0,0 +1,71 @@
+use lpc11uxx::Peripherals;
+use nrf_protocol::UART_BAUD_RATE;
+
+use crate::system;
+
+/// Sets up the USART connected to the nRF: 8N1, FIFOs on, no interrupts yet.
+pub fn initialize() {
+    let peripherals = unsafe { Peripherals::steal() };
+    let usart = &peripherals.USART;
+
+    // Enable USART clock, and divide it by 1 so PCLK is the main clock.
+    peripherals
+        .SYSCON
+        .sysahbclkctrl
+        .modify(|_, writer| writer.usart().enabled());
+    peripherals
+        .SYSCON
+        .uartclkdiv
+        .write(|writer| unsafe { writer.div().bits(1) });
+
+    // PIO1_17 is RXD, PIO1_18 is TXD
+    peripherals
+        .IOCON
+        .pio1_17
+        .write(|writer| writer.func().rxd().mode().floating());
+    peripherals
+        .IOCON
+        .pio1_18
+        .write(|writer| writer.func().txd().mode().floating());
+
+    usart.fcr_mut().write(|writer| {
+        writer
+            .fifoen()
+            .enabled()
+            .rxfifores()
+            .clear()
+            .txfifores()
+            .clear()
+    });
+    usart
+        .lcr
+        .write(|writer| writer.wls()._8_bit_character_leng().sbs()._1_stop_bit());
+
+    // The PLL setup is fixed, so failing here is a bug.
+    let solution = fractional_baud::solve(system::main_clock_rate(), UART_BAUD_RATE)
+        .expect("nRF baud rate unreachable");
+    let divisors = solution.divisors;
+
+    // Enable access to the divisor registers.
+    usart
+        .lcr
+        .modify(|_, writer| writer.dlab().enable_access_to_div());
+    unsafe {
+        usart
+            .dll()
+            .write(|writer| writer.bits(u32::from(divisors.dll())));
+        usart
+            .dlm()
+            .write(|writer| writer.bits(u32::from(divisors.dlm())));
+        usart.fdr.write(|writer| {
+            writer
+                .divaddval()
+                .bits(divisors.divaddval)
+                .mulval()
+                .bits(divisors.mulval)
+        });
+    }
+    usart
+        .lcr
+        .modify(|_, writer| writer.dlab().disable_access_to_di());
+}
@@ -0,0 +1,102 @@
+use cortex_m::peripheral::NVIC;
+use lpc11uxx::{CorePeripherals, Interrupt};
+use lpc11uxx_usbd::UsbHardware;
+
+/// The USB device controller of the LPC11U37.
+#[derive(Clone, Copy)]
+pub struct Lpc11uxxUsb;
+
+impl Lpc11uxxUsb {
+    const RAM_BASE: usize = 0x2000_4000;
+
+    fn registers(&self) -> &lpc11uxx::usb::RegisterBlock {
+        unsafe { &*lpc11uxx::USB::ptr() }
+    }
+}
+
+impl UsbHardware for Lpc11uxxUsb {
+    fn ram(&self) -> *mut u8 {
+        Self::RAM_BASE as *mut u8
+    }
+
+    fn buffer_address_offset(&self, buffer: *mut u8) -> u32 {
+        // Bits 21:6 of the address, the upper ones come from DATABUFSTART.
+        ((buffer as u32) >> 6) & 0xFFFF
+    }
+
+    fn enable_interrupt(&self) {
+        unsafe {
+            let mut peripheral = CorePeripherals::steal();
+            peripheral.NVIC.set_priority(Interrupt::USB_IRQ, 1);
+            NVIC::unmask(Interrupt::USB_IRQ);
+        }
+    }
+
+    fn devcmdstat(&self) -> u32 {
+        self.registers().devcmdstat.read().bits()
+    }
+
+    fn write_devcmdstat(&self, bits: u32) {
+        self.registers()
+            .devcmdstat
+            .write(|writer| unsafe { writer.bits(bits) });
+    }
+
+    fn intstat(&self) -> u32 {
+        self.registers().intstat.read().bits()
+    }
+
+    fn write_intstat(&self, bits: u32) {
+        self.registers()
+            .intstat
+            .write(|writer| unsafe { writer.bits(bits) });
+    }
+
+    fn write_inten(&self, bits: u32) {
+        self.registers()
+            .inten
+            .write(|writer| unsafe { writer.bits(bits) });
+    }
+
+    fn write_epliststart(&self, list: *mut u8) {
+        self.registers()
+            .epliststart
+            .write(|writer| unsafe { writer.bits(list as u32) });
+    }
+
+    fn write_databufstart(&self, buffers: *mut u8) {
+        self.registers()
+            .databufstart
+            .write(|writer| unsafe { writer.bits(buffers as u32) });
+    }
+
+    fn epinuse(&self) -> u32 {
+        self.registers().epinuse.read().bits()
+    }
+
+    fn write_epinuse(&self, bits: u32) {
+        self.registers()
+            .epinuse
+            .write(|writer| unsafe { writer.bits(bits) });
+    }
+
+    fn epbufcfg(&self) -> u32 {
+        self.registers().epbufcfg.read().bits()
+    }
+
+    fn write_epbufcfg(&self, bits: u32) {
+        self.registers()
+            .epbufcfg
+            .write(|writer| unsafe { writer.bits(bits) });
+    }
+
+    fn epskip(&self) -> u32 {
+        self.registers().epskip.read().bits()
+    }
+
+    fn write_epskip(&self, bits: u32) {
+        self.registers()
+            .epskip
+            .write(|writer| unsafe { writer.bits(bits) });
+    }
+}
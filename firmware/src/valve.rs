@@ -0,0 +1,159 @@
+//! The vendor HID interface of the Steam Controller, interface 2 of VID 0x28de
+//! PID 0x1102. Steam and driver-cli talk to the controller through its feature
+//! reports: the first byte is a command, the second the length of what follows.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use cortex_m::peripheral::SCB;
+use lpc11uxx::Peripherals;
+use lpc11uxx_rom::iap;
+
+#[cfg(feature = "input-drivers")]
+use crate::analog;
+#[cfg(not(feature = "xinput"))]
+use crate::lizard;
+use crate::report::{FeatureReports, InputReport, MAX_REPORT_LEN};
+use crate::system;
+
+pub const VENDOR_ID: u16 = 0x28de;
+pub const PRODUCT_ID: u16 = 0x1102;
+
+/// Reported in the attributes where Valve's firmware has its build timestamp.
+/// Ours is the crate version, as 0xMMmmpp.
+pub const FIRMWARE_VERSION: u32 = 0x00_01_00;
+
+#[cfg(not(feature = "xinput"))]
+const CLEAR_DIGITAL_MAPPINGS: u8 = 0x81;
+const GET_ATTRIBUTES: u8 = 0x83;
+#[cfg(not(feature = "xinput"))]
+const DEFAULT_DIGITAL_MAPPINGS: u8 = 0x85;
+const REBOOT_TO_BOOTLOADER: u8 = 0x95;
+#[cfg(feature = "input-drivers")]
+const CALIBRATE_JOYSTICK: u8 = 0xbf;
+/// Ours: the response is a `CalibrationStatus`, telling whether the last
+/// CALIBRATE_JOYSTICK got saved.
+#[cfg(feature = "input-drivers")]
+const GET_CALIBRATION_STATUS: u8 = 0xc0;
+/// Ours: selects the lizard mode mapping, by its index in
+/// `lizard::MAPPINGS`. The response is the mapping in use after it.
+#[cfg(not(feature = "xinput"))]
+const SELECT_DIGITAL_MAPPING: u8 = 0xc1;
+
+const ATTRIBUTE_PRODUCT_ID: u8 = 0x01;
+const ATTRIBUTE_FIRMWARE_VERSION: u8 = 0x04;
+const ATTRIBUTE_HARDWARE_VERSION: u8 = 0x09;
+const ATTRIBUTE_BOOTLOADER_VERSION: u8 = 0x0a;
+
+/// Magic the bootloader looks for in `PMU.gpreg[0]` to stay in programming
+/// mode, also the argument of REBOOT_TO_BOOTLOADER.
+const BOOTLOADER_MAGIC: u32 = 0xecaabac0;
+
+static REBOOT_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+// The same vendor report descriptor as the bootloader: 64 bytes of input,
+// output and feature report.
+const VENDOR_REPORT_DESCRIPTOR: &[u8] = &[
+    0x06, 0x00, 0xff, 0x09, 0x01, 0xa1, 0x01, 0x15, 0x00, 0x26, 0xff, 0x00, 0x75, 0x08, 0x95, 0x40,
+    0x09, 0x01, 0x81, 0x02, 0x95, 0x40, 0x09, 0x01, 0x91, 0x02, 0x95, 0x40, 0x09, 0x01, 0xb1, 0x02,
+    0xc0,
+];
+
+/// Reports of the vendor interface. Only its feature reports are used for
+/// now, Steam's controller state reports aren't sent.
+pub struct VendorReport;
+
+impl InputReport for VendorReport {
+    const DESCRIPTOR: &'static [u8] = VENDOR_REPORT_DESCRIPTOR;
+
+    fn serialize(&self, _buffer: &mut [u8; MAX_REPORT_LEN]) -> usize {
+        0
+    }
+}
+
+impl FeatureReports for VendorReport {
+    fn set_feature_report(data: &[u8], response: &mut [u8; MAX_REPORT_LEN]) {
+        match data.get(0) {
+            // Steam clears the mappings when it takes over the controller, and
+            // restores them when it lets go.
+            #[cfg(not(feature = "xinput"))]
+            Some(&CLEAR_DIGITAL_MAPPINGS) => lizard::set_enabled(false),
+            #[cfg(not(feature = "xinput"))]
+            Some(&DEFAULT_DIGITAL_MAPPINGS) => lizard::set_enabled(true),
+            #[cfg(not(feature = "xinput"))]
+            Some(&SELECT_DIGITAL_MAPPING) => {
+                if let (Some(1), Some(index)) = (data.get(1), data.get(2)) {
+                    lizard::select_mapping(*index);
+                }
+                *response = [0; MAX_REPORT_LEN];
+                response[0] = SELECT_DIGITAL_MAPPING;
+                response[1] = 1;
+                response[2] = lizard::selected_mapping();
+            }
+            Some(&GET_ATTRIBUTES) => write_attributes(response),
+            // The stick and triggers must be at rest, and then moved all the
+            // way around.
+            #[cfg(feature = "input-drivers")]
+            Some(&CALIBRATE_JOYSTICK) => analog::start_calibration(),
+            #[cfg(feature = "input-drivers")]
+            Some(&GET_CALIBRATION_STATUS) => {
+                *response = [0; MAX_REPORT_LEN];
+                response[0] = GET_CALIBRATION_STATUS;
+                response[1] = 1;
+                response[2] = analog::calibration_status() as u8;
+            }
+            Some(&REBOOT_TO_BOOTLOADER) => {
+                let magic = data
+                    .get(1)
+                    .and_then(|size| data.get(2..2 + usize::from(*size)));
+                if magic == Some(&BOOTLOADER_MAGIC.to_le_bytes()[..]) {
+                    REBOOT_REQUESTED.store(true, Ordering::Relaxed);
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+fn write_attributes(response: &mut [u8; MAX_REPORT_LEN]) {
+    let attributes = [
+        (ATTRIBUTE_PRODUCT_ID, u32::from(PRODUCT_ID)),
+        (ATTRIBUTE_FIRMWARE_VERSION, FIRMWARE_VERSION),
+        (ATTRIBUTE_HARDWARE_VERSION, hardware_version()),
+        (ATTRIBUTE_BOOTLOADER_VERSION, bootloader_version()),
+    ];
+
+    *response = [0; MAX_REPORT_LEN];
+    response[0] = GET_ATTRIBUTES;
+    response[1] = (attributes.len() * 5) as u8;
+    for (index, (tag, value)) in attributes.iter().enumerate() {
+        let offset = 2 + index * 5;
+        response[offset] = *tag;
+        response[offset + 1..offset + 5].copy_from_slice(&value.to_le_bytes());
+    }
+}
+
+/// The board revision, which the bootloader keeps in the EEPROM header.
+fn hardware_version() -> u32 {
+    let mut header = [0; 8];
+    iap::eeprom_read(0, &mut header, system::main_clock_rate() / 1024);
+    u32::from_le_bytes([header[4], header[5], header[6], header[7]])
+}
+
+/// The bootloader keeps its version in the reserved slot 9 of its vector
+/// table.
+fn bootloader_version() -> u32 {
+    unsafe { *(0x24 as *const u32) }
+}
+
+/// Whether the host asked to reboot to the bootloader. The reboot itself must
+/// wait for the control transfer to complete.
+pub fn reboot_requested() -> bool {
+    REBOOT_REQUESTED.load(Ordering::Relaxed)
+}
+
+/// Reboots to the bootloader, which stays in programming mode.
+pub fn reboot_to_bootloader() -> ! {
+    let peripherals = unsafe { Peripherals::steal() };
+    peripherals.PMU.gpreg[0].write(|writer| unsafe { writer.gpdata().bits(BOOTLOADER_MAGIC) });
+    SCB::sys_reset()
+}
@@ -0,0 +1,217 @@
+//! The Xbox 360 controller protocol, which games using XInput understand.
+//!
+//! Not HID: the interface is vendor specific (class 0xFF, subclass 0x5D), with
+//! a 20 bytes input report on an interrupt IN endpoint, and rumble and LED
+//! commands coming on an interrupt OUT endpoint.
+
+use bitflags::bitflags;
+
+use usb_device::class_prelude::*;
+use usb_device::Result;
+
+use crate::haptics;
+use crate::report::{Buttons, GamepadReport};
+
+pub const VENDOR_ID: u16 = 0x045e;
+pub const PRODUCT_ID: u16 = 0x028e;
+pub const DEVICE_RELEASE: u16 = 0x0114;
+
+const INTERFACE_CLASS_VENDOR: u8 = 0xff;
+const INTERFACE_SUBCLASS_XINPUT: u8 = 0x5d;
+const INTERFACE_PROTOCOL_XINPUT: u8 = 0x01;
+
+/// Undocumented class descriptor the Xbox 360 controller has after its
+/// interface descriptor. Drivers look for it.
+const DESCRIPTOR_TYPE_XINPUT: u8 = 0x21;
+
+const MAX_PACKET_SIZE: u16 = 32;
+
+pub const REPORT_LEN: usize = 20;
+
+const MESSAGE_INPUT: u8 = 0x00;
+const MESSAGE_RUMBLE: u8 = 0x00;
+const MESSAGE_LED: u8 = 0x01;
+
+bitflags! {
+    #[derive(Default)]
+    pub struct XInputButtons: u16 {
+        const DPAD_UP        = 1 << 0;
+        const DPAD_DOWN      = 1 << 1;
+        const DPAD_LEFT      = 1 << 2;
+        const DPAD_RIGHT     = 1 << 3;
+        const START          = 1 << 4;
+        const BACK           = 1 << 5;
+        const LEFT_THUMB     = 1 << 6;
+        const RIGHT_THUMB    = 1 << 7;
+        const LEFT_SHOULDER  = 1 << 8;
+        const RIGHT_SHOULDER = 1 << 9;
+        const GUIDE          = 1 << 10;
+        const A              = 1 << 12;
+        const B              = 1 << 13;
+        const X              = 1 << 14;
+        const Y              = 1 << 15;
+    }
+}
+
+/// Input report of the Xbox 360 controller. Unlike HID, Y axes grow upward.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct XInputReport {
+    pub buttons: XInputButtons,
+    pub left_trigger: u8,
+    pub right_trigger: u8,
+    pub left_stick_x: i16,
+    pub left_stick_y: i16,
+    pub right_stick_x: i16,
+    pub right_stick_y: i16,
+}
+
+impl XInputReport {
+    pub fn serialize(&self) -> [u8; REPORT_LEN] {
+        let mut buffer = [0; REPORT_LEN];
+        buffer[0] = MESSAGE_INPUT;
+        buffer[1] = REPORT_LEN as u8;
+        buffer[2..4].copy_from_slice(&self.buttons.bits().to_le_bytes());
+        buffer[4] = self.left_trigger;
+        buffer[5] = self.right_trigger;
+        buffer[6..8].copy_from_slice(&self.left_stick_x.to_le_bytes());
+        buffer[8..10].copy_from_slice(&self.left_stick_y.to_le_bytes());
+        buffer[10..12].copy_from_slice(&self.right_stick_x.to_le_bytes());
+        buffer[12..14].copy_from_slice(&self.right_stick_y.to_le_bytes());
+        buffer
+    }
+}
+
+impl From<&GamepadReport> for XInputReport {
+    fn from(report: &GamepadReport) -> Self {
+        const BUTTON_MAP: [(Buttons, XInputButtons); 10] = [
+            (Buttons::A, XInputButtons::A),
+            (Buttons::B, XInputButtons::B),
+            (Buttons::X, XInputButtons::X),
+            (Buttons::Y, XInputButtons::Y),
+            (Buttons::LEFT_BUMPER, XInputButtons::LEFT_SHOULDER),
+            (Buttons::RIGHT_BUMPER, XInputButtons::RIGHT_SHOULDER),
+            (Buttons::BACK, XInputButtons::BACK),
+            (Buttons::START, XInputButtons::START),
+            (Buttons::STEAM, XInputButtons::GUIDE),
+            (Buttons::LEFT_STICK, XInputButtons::LEFT_THUMB),
+        ];
+
+        let mut buttons = XInputButtons::empty();
+        for (button, xinput_button) in BUTTON_MAP.iter() {
+            buttons.set(*xinput_button, report.buttons.contains(*button));
+        }
+        // The right stick is the right pad, clicking it is the closest thing
+        // to a thumb click.
+        buttons.set(
+            XInputButtons::RIGHT_THUMB,
+            report.buttons.contains(Buttons::RIGHT_PAD),
+        );
+
+        XInputReport {
+            buttons,
+            left_trigger: report.left_trigger,
+            right_trigger: report.right_trigger,
+            left_stick_x: report.left_stick_x,
+            left_stick_y: report.left_stick_y.saturating_neg(),
+            right_stick_x: report.right_stick_x,
+            right_stick_y: report.right_stick_y.saturating_neg(),
+        }
+    }
+}
+
+pub struct XInputClass<'a, B: UsbBus> {
+    interface: InterfaceNumber,
+    endpoint_in: EndpointIn<'a, B>,
+    endpoint_out: EndpointOut<'a, B>,
+    last_report: Option<[u8; REPORT_LEN]>,
+}
+
+impl<'a, B: UsbBus> XInputClass<'a, B> {
+    pub fn new(alloc: &'a UsbBusAllocator<B>) -> Self {
+        XInputClass {
+            interface: alloc.interface(),
+            endpoint_in: alloc.interrupt(MAX_PACKET_SIZE, 4),
+            endpoint_out: alloc.interrupt(MAX_PACKET_SIZE, 8),
+            last_report: None,
+        }
+    }
+
+    /// Sends `report` to the host if it changed since the last one, like the
+    /// Xbox 360 controller does.
+    ///
+    /// Fails with `UsbError::WouldBlock` while the previous report is still
+    /// waiting for the host.
+    pub fn push_report(&mut self, report: &XInputReport) -> Result<()> {
+        let buffer = report.serialize();
+
+        if self.last_report == Some(buffer) {
+            return Ok(());
+        }
+
+        self.endpoint_in.write(&buffer)?;
+
+        self.last_report = Some(buffer);
+        Ok(())
+    }
+
+    fn handle_output(&self, data: &[u8]) {
+        match data {
+            // Strength of the big (left) and small (right) motors.
+            [MESSAGE_RUMBLE, 0x08, _, left, right, ..] => haptics::set_rumble(*left, *right),
+            // Ring of light animations, but we have no ring.
+            [MESSAGE_LED, ..] => (),
+            _ => (),
+        }
+    }
+}
+
+impl<B: UsbBus> UsbClass<B> for XInputClass<'_, B> {
+    fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> Result<()> {
+        writer.interface(
+            self.interface,
+            INTERFACE_CLASS_VENDOR,
+            INTERFACE_SUBCLASS_XINPUT,
+            INTERFACE_PROTOCOL_XINPUT,
+        )?;
+        writer.write(
+            DESCRIPTOR_TYPE_XINPUT,
+            &[
+                0x00,
+                0x01,
+                0x01,
+                0x25,
+                u8::from(self.endpoint_in.address()),
+                REPORT_LEN as u8,
+                0x00,
+                0x00,
+                0x00,
+                0x00,
+                0x13,
+                u8::from(self.endpoint_out.address()),
+                0x08,
+                0x00,
+                0x00,
+            ],
+        )?;
+        writer.endpoint(&self.endpoint_in)?;
+        writer.endpoint(&self.endpoint_out)?;
+
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        self.last_report = None;
+        haptics::set_rumble(0, 0);
+    }
+
+    fn endpoint_out(&mut self, addr: EndpointAddress) {
+        if addr != self.endpoint_out.address() {
+            return;
+        }
+
+        let mut buffer = [0; MAX_PACKET_SIZE as usize];
+        if let Ok(len) = self.endpoint_out.read(&mut buffer) {
+            self.handle_output(&buffer[..len]);
+        }
+    }
+}
@@ -0,0 +1,183 @@
+//! Baud rate divisors for the LPC11Uxx USART (and the other NXP 16550-style
+//! UARTs with a fractional divider).
+//!
+//! The USART runs at
+//!
+//! ```text
+//!                           PCLK
+//! baud = ------------------------------------------
+//!        16 * (256 * DLM + DLL) * (1 + DIVADDVAL / MULVAL)
+//! ```
+//!
+//! with 1 <= MULVAL <= 15 and 0 <= DIVADDVAL < MULVAL. When the fractional
+//! divider is in use (DIVADDVAL > 0), the divisor latch must be at least 3.
+//! See the USART chapter of UM10462.
+
+#![no_std]
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Divisors {
+    /// The divisor latch, i.e. DLM:DLL.
+    pub dl: u16,
+    pub divaddval: u8,
+    pub mulval: u8,
+}
+
+impl Divisors {
+    pub fn dll(&self) -> u8 {
+        self.dl as u8
+    }
+
+    pub fn dlm(&self) -> u8 {
+        (self.dl >> 8) as u8
+    }
+
+    /// The baud rate these divisors give, rounded to the nearest integer.
+    pub fn baud_rate(&self, pclk: u32) -> u32 {
+        let num = u64::from(pclk) * u64::from(self.mulval);
+        let den = 16 * u64::from(self.dl) * (u64::from(self.mulval) + u64::from(self.divaddval));
+        ((num + den / 2) / den) as u32
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Solution {
+    pub divisors: Divisors,
+    /// The baud rate actually achieved.
+    pub baud_rate: u32,
+    /// Relative error between the achieved and requested rate, in parts per
+    /// million. Positive if the achieved rate is too fast.
+    pub error_ppm: i32,
+}
+
+/// Finds the divisors giving the rate closest to `baud_rate` from a `pclk`
+/// UART clock. When several combinations are equally close, the one that
+/// doesn't use the fractional divider wins.
+///
+/// Returns None if `baud_rate` is 0, or can't be reached at all.
+pub fn solve(pclk: u32, baud_rate: u32) -> Option<Solution> {
+    if baud_rate == 0 {
+        return None;
+    }
+
+    let pclk = u64::from(pclk);
+    let baud = u64::from(baud_rate);
+
+    let mut best: Option<(Divisors, u64, u64)> = None;
+
+    for mulval in 1..=15u64 {
+        for divaddval in 0..mulval {
+            // dl = pclk / (16 * baud * (1 + divaddval / mulval)), rounded.
+            let num = pclk * mulval;
+            let den = 16 * baud * (mulval + divaddval);
+            let dl = (num + den / 2) / den;
+
+            let min_dl = if divaddval == 0 { 1 } else { 3 };
+            if dl < min_dl || dl > 0xffff {
+                continue;
+            }
+
+            // The achieved rate is num / achieved_den. Its distance to the
+            // requested rate, in micro-Hz. The requested rate is the same for
+            // all candidates, so this ranks them like the relative error would.
+            let achieved_den = 16 * dl * (mulval + divaddval);
+            let error = abs_diff(num, baud * achieved_den) * 1_000_000 / achieved_den;
+
+            let divisors = Divisors {
+                dl: dl as u16,
+                divaddval: divaddval as u8,
+                mulval: mulval as u8,
+            };
+            match best {
+                Some((_, best_error, _)) if best_error <= error => (),
+                _ => best = Some((divisors, error, achieved_den)),
+            }
+        }
+    }
+
+    let (divisors, _, achieved_den) = best?;
+    let num = pclk * u64::from(divisors.mulval);
+    let error_ppm = (num as i64 - (baud * achieved_den) as i64) * 1_000_000 / (baud * achieved_den) as i64;
+
+    Some(Solution {
+        divisors,
+        baud_rate: divisors.baud_rate(pclk as u32),
+        error_ppm: error_ppm as i32,
+    })
+}
+
+fn abs_diff(a: u64, b: u64) -> u64 {
+    if a > b { a - b } else { b - a }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn divisors(dl: u16, divaddval: u8, mulval: u8) -> Divisors {
+        Divisors { dl, divaddval, mulval }
+    }
+
+    #[test]
+    fn known_rates() {
+        let table = [
+            // (pclk, requested rate, divisors, achieved rate)
+            (12_000_000, 115_200, divisors(4, 5, 8), 115_385),
+            (48_000_000, 916_667, divisors(3, 1, 11), 916_667),
+            // Exact without the fractional divider, which wins the ties.
+            (12_000_000, 750_000, divisors(1, 0, 1), 750_000),
+            (48_000_000, 1_000_000, divisors(3, 0, 1), 1_000_000),
+            (48_000_000, 500_000, divisors(6, 0, 1), 500_000),
+            (48_000_000, 9_600, divisors(250, 1, 4), 9_600),
+        ];
+
+        for &(pclk, baud_rate, expected, achieved) in &table {
+            let solution = solve(pclk, baud_rate).unwrap();
+            assert_eq!(solution.divisors, expected, "{} baud from {} Hz", baud_rate, pclk);
+            assert_eq!(solution.baud_rate, achieved, "{} baud from {} Hz", baud_rate, pclk);
+            assert_eq!(solution.baud_rate, expected.baud_rate(pclk));
+        }
+    }
+
+    #[test]
+    fn error() {
+        // 115385 instead of 115200.
+        assert_eq!(solve(12_000_000, 115_200).unwrap().error_ppm, 1602);
+        // 916666.67 instead of 916667.
+        assert_eq!(solve(48_000_000, 916_667).unwrap().error_ppm, 0);
+        assert!(solve(12_000_000, 700_000).unwrap().error_ppm > 0);
+        assert!(solve(12_000_000, 800_000).unwrap().error_ppm < 0);
+    }
+
+    #[test]
+    fn unreachable_rates() {
+        assert_eq!(solve(12_000_000, 0), None);
+        // The divisor latch would round down to 0.
+        assert_eq!(solve(12_000_000, 2_000_000), None);
+        assert_eq!(solve(0, 9_600), None);
+        // It would need more than 16 bits, even with the fractional divider.
+        assert_eq!(solve(48_000_000, 1), None);
+        assert_eq!(solve(48_000_000, 23), None);
+        assert!(solve(48_000_000, 24).is_some());
+    }
+
+    #[test]
+    fn divisor_latch_bytes() {
+        let divisors = divisors(0x1234, 0, 1);
+        assert_eq!(divisors.dll(), 0x34);
+        assert_eq!(divisors.dlm(), 0x12);
+    }
+
+    #[test]
+    fn valid_divisors() {
+        // The fractional divider needs DL >= 3.
+        for baud_rate in (100_000..3_000_000).step_by(997) {
+            if let Some(solution) = solve(48_000_000, baud_rate) {
+                let divisors = solution.divisors;
+                assert!(divisors.divaddval < divisors.mulval);
+                assert!(divisors.mulval >= 1 && divisors.mulval <= 15);
+                assert!(divisors.divaddval == 0 || divisors.dl >= 3);
+            }
+        }
+    }
+}
@@ -0,0 +1,103 @@
+//! Calibration of analog axes read by an ADC.
+
+use crate::AXIS_MAX;
+
+/// Where an axis rests and how far it goes, in raw ADC units.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AxisCalibration {
+    pub min: u16,
+    pub center: u16,
+    pub max: u16,
+}
+
+/// Size of a serialized `AxisCalibration`.
+pub const AXIS_CALIBRATION_LEN: usize = 6;
+
+impl AxisCalibration {
+    /// An axis using the whole range of an ADC of `bits` bits.
+    pub const fn full_range(bits: u32) -> Self {
+        let max = (1 << bits) - 1;
+        AxisCalibration {
+            min: 0,
+            center: (max / 2) as u16,
+            max: max as u16,
+        }
+    }
+
+    /// An axis at rest at `raw`, with no range yet. The range gets learned
+    /// by `track`.
+    pub fn centered_at(raw: u16) -> Self {
+        AxisCalibration {
+            min: raw,
+            center: raw,
+            max: raw,
+        }
+    }
+
+    /// Widens the range to include `raw`. Returns whether it changed.
+    pub fn track(&mut self, raw: u16) -> bool {
+        if raw < self.min {
+            self.min = raw;
+            true
+        } else if raw > self.max {
+            self.max = raw;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Whether the range makes sense, e.g. after reading it back from
+    /// storage that was never written. One-sided axes rest at `min`.
+    pub fn is_valid(&self) -> bool {
+        self.min <= self.center && self.center < self.max
+    }
+
+    /// `raw` as a centered axis, from -`AXIS_MAX` at `min` to `AXIS_MAX` at
+    /// `max`. Each side is scaled on its own, so the center is 0 even when it
+    /// isn't halfway.
+    pub fn normalize(&self, raw: u16) -> i16 {
+        let raw = i32::from(raw);
+        let center = i32::from(self.center);
+
+        let distance = raw - center;
+        let range = if distance < 0 {
+            center - i32::from(self.min)
+        } else {
+            i32::from(self.max) - center
+        };
+        if range <= 0 {
+            return 0;
+        }
+
+        (distance * AXIS_MAX / range).max(-AXIS_MAX).min(AXIS_MAX) as i16
+    }
+
+    /// `raw` as a one-sided axis, like a trigger: 0 at `center` (where it
+    /// rests) to 255 at `max`.
+    pub fn normalize_unipolar(&self, raw: u16) -> u8 {
+        let range = i32::from(self.max) - i32::from(self.center);
+        if range <= 0 {
+            return 0;
+        }
+
+        let distance = i32::from(raw) - i32::from(self.center);
+        (distance * 255 / range).max(0).min(255) as u8
+    }
+
+    pub fn to_bytes(&self) -> [u8; AXIS_CALIBRATION_LEN] {
+        let mut bytes = [0; AXIS_CALIBRATION_LEN];
+        bytes[0..2].copy_from_slice(&self.min.to_le_bytes());
+        bytes[2..4].copy_from_slice(&self.center.to_le_bytes());
+        bytes[4..6].copy_from_slice(&self.max.to_le_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8; AXIS_CALIBRATION_LEN]) -> Self {
+        AxisCalibration {
+            min: u16::from_le_bytes([bytes[0], bytes[1]]),
+            center: u16::from_le_bytes([bytes[2], bytes[3]]),
+            max: u16::from_le_bytes([bytes[4], bytes[5]]),
+        }
+    }
+}
@@ -0,0 +1,147 @@
+//! Orientation from a gyroscope and an accelerometer, with a fixed point
+//! complementary filter.
+//!
+//! The gyroscope is integrated every sample, and pitch and roll are pulled a
+//! bit toward what gravity says they are, which cancels the gyroscope's
+//! drift. Nothing measures yaw, so it drifts.
+//!
+//! Axes follow the controller lying flat, buttons up: X to the right, Y
+//! forward, Z up. Rotations follow the right hand rule around them: pitch
+//! around X (raising the front), roll around Y, yaw around Z.
+
+use crate::math::{atan2, isqrt, TURN};
+
+/// Angles, in 1/2^32 turns, so that they wrap around on their own.
+pub type Angle = u32;
+
+/// One reading of the IMU.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ImuSample {
+    /// X, Y, Z.
+    pub accel: [i16; 3],
+    /// Around X, Y, Z.
+    pub gyro: [i16; 3],
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Orientation {
+    pub pitch: Angle,
+    pub roll: Angle,
+    pub yaw: Angle,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FusionSettings {
+    /// Gyroscope reading for a turn per second.
+    pub gyro_counts_per_turn_per_s: u32,
+    /// Accelerometer reading for 1g.
+    pub accel_counts_per_g: u16,
+    /// Time between two samples.
+    pub sample_period_us: u32,
+    /// Each sample, pitch and roll move toward gravity by 1/2^shift of the
+    /// difference. Larger is smoother, but slower to correct drift.
+    pub accel_correction_shift: u8,
+}
+
+impl Default for FusionSettings {
+    /// An MPU-6500 at +-2000dps and +-2g, sampled every 4ms.
+    fn default() -> Self {
+        FusionSettings {
+            // 16.4 counts per degree per second.
+            gyro_counts_per_turn_per_s: 5904,
+            accel_counts_per_g: 16384,
+            sample_period_us: 4000,
+            accel_correction_shift: 6,
+        }
+    }
+}
+
+pub struct ComplementaryFilter {
+    settings: FusionSettings,
+    orientation: Option<Orientation>,
+}
+
+impl ComplementaryFilter {
+    pub fn new(settings: FusionSettings) -> Self {
+        ComplementaryFilter {
+            settings,
+            orientation: None,
+        }
+    }
+
+    /// The orientation so far, all zeros before the first sample.
+    pub fn orientation(&self) -> Orientation {
+        self.orientation.unwrap_or_default()
+    }
+
+    /// How much each angle turns during one sample, according to the
+    /// gyroscope alone.
+    pub fn rotation(&self, sample: &ImuSample) -> [i32; 3] {
+        let mut rotation = [0; 3];
+        for (angle, rate) in rotation.iter_mut().zip(sample.gyro.iter()) {
+            // rate / counts_per_turn_per_s turns per second, for
+            // sample_period_us.
+            let turns = i64::from(*rate) * i64::from(self.settings.sample_period_us) * (1 << 32)
+                / (i64::from(self.settings.gyro_counts_per_turn_per_s) * 1_000_000);
+            *angle = turns as i32;
+        }
+        rotation
+    }
+
+    /// Takes the next sample, and returns the new orientation.
+    pub fn update(&mut self, sample: &ImuSample) -> Orientation {
+        let gravity = self.gravity_angles(sample);
+
+        let mut orientation = match self.orientation {
+            Some(orientation) => orientation,
+            // Nothing to integrate from yet: start from gravity.
+            None => match gravity {
+                Some((pitch, roll)) => Orientation {
+                    pitch,
+                    roll,
+                    yaw: 0,
+                },
+                None => Orientation::default(),
+            },
+        };
+
+        let [pitch_step, roll_step, yaw_step] = self.rotation(sample);
+        orientation.pitch = orientation.pitch.wrapping_add(pitch_step as u32);
+        orientation.roll = orientation.roll.wrapping_add(roll_step as u32);
+        orientation.yaw = orientation.yaw.wrapping_add(yaw_step as u32);
+
+        if let Some((pitch, roll)) = gravity {
+            let shift = self.settings.accel_correction_shift;
+            let correct = |angle: Angle, target: Angle| {
+                // The shortest way around.
+                let error = target.wrapping_sub(angle) as i32;
+                angle.wrapping_add((error >> shift) as u32)
+            };
+            orientation.pitch = correct(orientation.pitch, pitch);
+            orientation.roll = correct(orientation.roll, roll);
+        }
+
+        self.orientation = Some(orientation);
+        orientation
+    }
+
+    /// Pitch and roll according to the accelerometer, if it only feels
+    /// gravity (between 0.5g and 1.5g).
+    fn gravity_angles(&self, sample: &ImuSample) -> Option<(Angle, Angle)> {
+        let [x, y, z] = sample.accel;
+        let (x, y, z) = (i32::from(x), i32::from(y), i32::from(z));
+
+        // Each square fits in 31 bits, so the sum of two fits in 32, and the
+        // sum of three quarters too.
+        let yz = isqrt((y * y) as u32 + (z * z) as u32) as i32;
+        let square_quarter = |value: i32| (value * value) as u32 / 4;
+        let magnitude = 2 * isqrt(square_quarter(x) + square_quarter(y) + square_quarter(z)) as i32;
+        let g = i32::from(self.settings.accel_counts_per_g);
+        if magnitude < g / 2 || magnitude > g * 3 / 2 {
+            return None;
+        }
+
+        let to_angle = |angle: i32| (angle.rem_euclid(TURN) as u32) << 16;
+        Some((to_angle(atan2(y, z)), to_angle(atan2(-x, yz))))
+    }
+}
@@ -0,0 +1,180 @@
+//! Gestures on a trackpad: touches, clicks, swipes and radial menus.
+//!
+//! A `GestureDetector` is fed one `PadSample` at a time, so a recorded trace
+//! of samples can be replayed through it on the host.
+
+use crate::math::{atan2, TURN};
+use crate::AXIS_MAX;
+
+/// One reading of a trackpad.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PadSample {
+    /// Milliseconds, from any clock that doesn't go backward (but may wrap).
+    pub timestamp: u32,
+    /// Where the finger is, None when the pad isn't touched. Like the sticks,
+    /// X grows to the right and Y grows downward, from -`AXIS_MAX` to
+    /// `AXIS_MAX` with (0, 0) at the center.
+    pub position: Option<(i16, i16)>,
+    /// Whether the pad is clicked.
+    pub clicked: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Up,
+    Down,
+    Left,
+    Right,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Gesture {
+    /// The finger landed on the pad.
+    Touch,
+    /// The finger left the pad without swiping.
+    Release,
+    /// The pad got clicked, outside of a radial menu.
+    Click,
+    /// The finger moved quickly in a direction, then left the pad.
+    Swipe(Direction),
+    /// The pad got clicked in a segment of the radial menu. Segments are
+    /// numbered clockwise, from 0 centered on up.
+    RadialSelect(u8),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GestureSettings {
+    /// How far the finger must go for a swipe.
+    pub swipe_min_distance: u16,
+    /// How long a swipe can last at most, from touch to release.
+    pub swipe_max_duration_ms: u32,
+    /// Segments of the radial menu, 0 for no menu (so clicks are only
+    /// clicks).
+    pub radial_segments: u8,
+    /// How far from the center a click must be to select in the radial menu.
+    /// Closer ones are clicks.
+    pub radial_min_radius: u16,
+}
+
+impl Default for GestureSettings {
+    fn default() -> Self {
+        GestureSettings {
+            swipe_min_distance: 16000,
+            swipe_max_duration_ms: 300,
+            radial_segments: 0,
+            radial_min_radius: 12000,
+        }
+    }
+}
+
+/// Turns pad samples into gestures.
+#[derive(Debug, Clone)]
+pub struct GestureDetector {
+    settings: GestureSettings,
+    /// Where and when the current touch started.
+    touch_start: Option<((i16, i16), u32)>,
+    last_position: Option<(i16, i16)>,
+    clicked: bool,
+}
+
+impl GestureDetector {
+    pub fn new(settings: GestureSettings) -> Self {
+        GestureDetector {
+            settings,
+            touch_start: None,
+            last_position: None,
+            clicked: false,
+        }
+    }
+
+    pub fn settings(&self) -> &GestureSettings {
+        &self.settings
+    }
+
+    pub fn set_settings(&mut self, settings: GestureSettings) {
+        self.settings = settings;
+    }
+
+    /// Takes the next sample, and returns the gesture it completes if any.
+    /// When several happen at once, a click wins over a touch.
+    pub fn update(&mut self, sample: &PadSample) -> Option<Gesture> {
+        let was_clicked = self.clicked;
+        self.clicked = sample.clicked;
+
+        let gesture = match (self.touch_start, sample.position) {
+            (None, Some(position)) => {
+                self.touch_start = Some((position, sample.timestamp));
+                Some(Gesture::Touch)
+            }
+            (Some((start, started_at)), None) => {
+                self.touch_start = None;
+                let end = self.last_position.unwrap_or(start);
+                let duration = sample.timestamp.wrapping_sub(started_at);
+                let swipe = self.swipe(start, end, duration).map(Gesture::Swipe);
+                Some(swipe.unwrap_or(Gesture::Release))
+            }
+            _ => None,
+        };
+        // A click can come without any position, when the finger is at the
+        // very edge of the pad.
+        let position = sample.position.or(self.last_position);
+        self.last_position = sample.position;
+
+        if sample.clicked && !was_clicked {
+            let position = position.unwrap_or((0, 0));
+            return Some(match self.radial_segment(position) {
+                Some(segment) => Gesture::RadialSelect(segment),
+                None => Gesture::Click,
+            });
+        }
+
+        gesture
+    }
+
+    fn swipe(&self, start: (i16, i16), end: (i16, i16), duration: u32) -> Option<Direction> {
+        if duration > self.settings.swipe_max_duration_ms {
+            return None;
+        }
+
+        let dx = i32::from(end.0) - i32::from(start.0);
+        let dy = i32::from(end.1) - i32::from(start.1);
+        let min_distance = i32::from(self.settings.swipe_min_distance);
+
+        if dx.abs() >= dy.abs() && dx.abs() >= min_distance {
+            Some(if dx < 0 {
+                Direction::Left
+            } else {
+                Direction::Right
+            })
+        } else if dy.abs() > dx.abs() && dy.abs() >= min_distance {
+            Some(if dy < 0 {
+                Direction::Up
+            } else {
+                Direction::Down
+            })
+        } else {
+            None
+        }
+    }
+
+    fn radial_segment(&self, (x, y): (i16, i16)) -> Option<u8> {
+        let segments = u32::from(self.settings.radial_segments);
+        if segments == 0 {
+            return None;
+        }
+
+        let (x, y) = (i32::from(x), i32::from(y));
+        let min_radius = i64::from(self.settings.radial_min_radius).min(i64::from(AXIS_MAX));
+        if i64::from(x * x) + i64::from(y * y) < min_radius * min_radius {
+            return None;
+        }
+
+        // Clockwise from up, Y growing downward, is from up toward right.
+        // Then turns so that segment 0 is centered on up.
+        let angle = atan2(x, -y).rem_euclid(TURN) as u32;
+        let turn = TURN as u32;
+        let half_segment = turn / segments / 2;
+        let angle = (angle + half_segment) % turn;
+        Some((angle * segments / turn) as u8)
+    }
+}
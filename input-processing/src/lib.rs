@@ -0,0 +1,17 @@
+//! Turns raw readings of the controls into what gets reported to the host:
+//! calibration of the analog axes, then dead zones and response curves,
+//! trackpad gestures, and orientation from the IMU.
+//!
+//! This crate has no dependency on the hardware, so it can be tested on the
+//! host.
+
+#![no_std]
+
+pub mod calibration;
+pub mod fusion;
+pub mod gesture;
+mod math;
+pub mod stick;
+
+/// Largest magnitude of a processed axis.
+pub const AXIS_MAX: i32 = i16::MAX as i32;
@@ -0,0 +1,119 @@
+//! Integer versions of what `core` only has for floats, or not at all.
+
+/// A full turn, in the units `atan2` returns.
+pub const TURN: i32 = 0x1_0000;
+
+/// Square root, rounded down.
+pub fn isqrt(value: u32) -> u32 {
+    let mut root = 0u32;
+    let mut bit = 1u32 << 30;
+    let mut value = value;
+
+    while bit > value {
+        bit >>= 2;
+    }
+    while bit != 0 {
+        if value >= root + bit {
+            value -= root + bit;
+            root = (root >> 1) + bit;
+        } else {
+            root >>= 1;
+        }
+        bit >>= 2;
+    }
+    root
+}
+
+/// Angle from the X axis to (x, y), toward Y, in 1/`TURN` turns from
+/// -`TURN` / 2 to `TURN` / 2. Within a quarter of a degree.
+pub fn atan2(y: i32, x: i32) -> i32 {
+    const QUARTER: i64 = TURN as i64 / 4;
+
+    let (x, y) = (i64::from(x), i64::from(y));
+
+    // atan(z) for z = num / den, 0 <= num <= den, as z * (pi/4 + 0.273 *
+    // (1 - z)). 0.273 rad is 2847 / `TURN` turns. z is in 1/`TURN`, so that
+    // nothing overflows whatever the inputs.
+    let atan = |num: i64, den: i64| -> i64 {
+        if den == 0 {
+            return 0;
+        }
+        let turn = i64::from(TURN);
+        let z = num * turn / den;
+        z * (QUARTER / 2) / turn + z * 2847 * (turn - z) / (turn * turn)
+    };
+
+    // Away from X, in either direction.
+    let from_x = if x.abs() >= y.abs() {
+        let angle = atan(y.abs(), x.abs());
+        if x >= 0 {
+            angle
+        } else {
+            2 * QUARTER - angle
+        }
+    } else {
+        let angle = atan(x.abs(), y.abs());
+        if x >= 0 {
+            QUARTER - angle
+        } else {
+            QUARTER + angle
+        }
+    };
+
+    (if y < 0 { -from_x } else { from_x }) as i32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn isqrt_rounds_down() {
+        for root in 0..=0xffffu32 {
+            assert_eq!(isqrt(root * root), root);
+            if root != 0 {
+                assert_eq!(isqrt(root * root - 1), root - 1);
+            }
+        }
+        assert_eq!(isqrt(u32::MAX), 0xffff);
+    }
+
+    #[test]
+    fn atan2_axes() {
+        assert_eq!(atan2(0, 1), 0);
+        assert_eq!(atan2(1, 0), TURN / 4);
+        assert_eq!(atan2(0, -1), TURN / 2);
+        assert_eq!(atan2(-1, 0), -TURN / 4);
+        assert_eq!(atan2(1, 1), TURN / 8);
+        assert_eq!(atan2(-1, -1), -3 * TURN / 8);
+        assert_eq!(atan2(0, 0), 0);
+    }
+
+    #[test]
+    fn atan2_error_bound() {
+        // A quarter of a degree.
+        let bound = f64::from(TURN) / 360.0 / 4.0;
+        for &radius in &[100.0, 16384.0, 2.0e9] {
+            for step in 0..3600 {
+                let angle = f64::from(step) / 3600.0 * 2.0 * core::f64::consts::PI;
+                let (x, y) = (
+                    (radius * angle.cos()).round(),
+                    (radius * angle.sin()).round(),
+                );
+                let expected = y.atan2(x) / (2.0 * core::f64::consts::PI) * f64::from(TURN);
+                let actual = f64::from(atan2(y as i32, x as i32));
+                let error = (actual - expected).abs();
+                // -TURN / 2 and TURN / 2 are the same angle.
+                let error = error.min((error - f64::from(TURN)).abs());
+                assert!(error <= bound, "{} at {}: {}", radius, step, error);
+            }
+        }
+    }
+
+    #[test]
+    fn atan2_extremes() {
+        assert_eq!(atan2(i32::MAX, i32::MAX), TURN / 8);
+        assert_eq!(atan2(i32::MIN, i32::MIN), -3 * TURN / 8);
+        assert_eq!(atan2(0, i32::MIN), TURN / 2);
+    }
+}
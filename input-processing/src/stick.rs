@@ -0,0 +1,88 @@
+//! Dead zones and response curves of a thumbstick, once calibrated (see
+//! `calibration`).
+
+use crate::math::isqrt;
+use crate::AXIS_MAX;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Deadzone {
+    None,
+    /// Ignores the stick while its distance to the center is under the
+    /// given size. Keeps the direction of small movements.
+    Radial(u16),
+    /// Ignores each axis on its own while under the given size. Makes it easy
+    /// to move along one axis only.
+    Axial(u16),
+}
+
+/// How the distance to the center maps to the reported one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResponseCurve {
+    Linear,
+    /// Finer control close to the center.
+    Quadratic,
+    /// Even finer control close to the center.
+    Cubic,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StickSettings {
+    pub deadzone: Deadzone,
+    pub curve: ResponseCurve,
+}
+
+impl Default for StickSettings {
+    fn default() -> Self {
+        StickSettings {
+            deadzone: Deadzone::Radial(4000),
+            curve: ResponseCurve::Linear,
+        }
+    }
+}
+
+/// Applies the dead zone, then the response curve, to a stick position. Axes
+/// range from -`AXIS_MAX` to `AXIS_MAX`, and what is past the dead zone is
+/// scaled back to the whole range.
+pub fn process(x: i16, y: i16, settings: &StickSettings) -> (i16, i16) {
+    let (x, y) = (i32::from(x), i32::from(y));
+
+    let (x, y) = match settings.deadzone {
+        Deadzone::None => (x, y),
+        Deadzone::Axial(size) => (rescale(x, i32::from(size)), rescale(y, i32::from(size))),
+        Deadzone::Radial(size) => {
+            // Each square fits in 31 bits, so their sum fits in 32.
+            let magnitude = isqrt((x * x) as u32 + (y * y) as u32) as i32;
+            if magnitude == 0 {
+                (0, 0)
+            } else {
+                // Moves the point along its direction only.
+                let rescaled = rescale(magnitude, i32::from(size));
+                (x * rescaled / magnitude, y * rescaled / magnitude)
+            }
+        }
+    };
+
+    let curve = |value: i32| {
+        let value = value.max(-AXIS_MAX).min(AXIS_MAX);
+        match settings.curve {
+            ResponseCurve::Linear => value,
+            ResponseCurve::Quadratic => value * value.abs() / AXIS_MAX,
+            ResponseCurve::Cubic => value * value.abs() / AXIS_MAX * value.abs() / AXIS_MAX,
+        }
+    };
+
+    (curve(x) as i16, curve(y) as i16)
+}
+
+/// Maps `deadzone..AXIS_MAX` to `0..AXIS_MAX`, keeping the sign, and
+/// anything closer to 0 than `deadzone` to 0.
+fn rescale(value: i32, deadzone: i32) -> i32 {
+    let deadzone = deadzone.max(0).min(AXIS_MAX - 1);
+    let magnitude = value.abs();
+    if magnitude <= deadzone {
+        return 0;
+    }
+
+    let rescaled = ((magnitude - deadzone) * AXIS_MAX / (AXIS_MAX - deadzone)).min(AXIS_MAX);
+    rescaled * value.signum()
+}
@@ -0,0 +1,87 @@
+//! Normalization of raw ADC readings by an `AxisCalibration`.
+
+use input_processing::calibration::AxisCalibration;
+use input_processing::AXIS_MAX;
+
+const AXIS_MAX_I16: i16 = AXIS_MAX as i16;
+
+/// Rests well off the middle of its range, like a worn stick.
+const ASYMMETRIC: AxisCalibration = AxisCalibration {
+    min: 100,
+    center: 300,
+    max: 1000,
+};
+
+#[test]
+fn normalize_asymmetric_range() {
+    assert_eq!(ASYMMETRIC.normalize(300), 0);
+    assert_eq!(ASYMMETRIC.normalize(100), -AXIS_MAX_I16);
+    assert_eq!(ASYMMETRIC.normalize(1000), AXIS_MAX_I16);
+
+    // Halfway on each side, although the sides are 200 and 700 wide.
+    assert_eq!(ASYMMETRIC.normalize(200), -AXIS_MAX_I16 / 2);
+    assert_eq!(ASYMMETRIC.normalize(650), AXIS_MAX_I16 / 2);
+
+    // Past the ends of the range.
+    assert_eq!(ASYMMETRIC.normalize(0), -AXIS_MAX_I16);
+    assert_eq!(ASYMMETRIC.normalize(u16::MAX), AXIS_MAX_I16);
+}
+
+#[test]
+fn normalize_without_range() {
+    let calibration = AxisCalibration::centered_at(500);
+    assert_eq!(calibration.normalize(0), 0);
+    assert_eq!(calibration.normalize(500), 0);
+    assert_eq!(calibration.normalize(1023), 0);
+}
+
+#[test]
+fn normalize_unipolar() {
+    // Rests at the bottom of its range, like a trigger.
+    let trigger = AxisCalibration {
+        min: 40,
+        center: 40,
+        max: 1000,
+    };
+    assert_eq!(trigger.normalize_unipolar(40), 0);
+    assert_eq!(trigger.normalize_unipolar(520), 127);
+    assert_eq!(trigger.normalize_unipolar(1000), 255);
+
+    // Past the ends of the range.
+    assert_eq!(trigger.normalize_unipolar(0), 0);
+    assert_eq!(trigger.normalize_unipolar(u16::MAX), 255);
+
+    assert_eq!(AxisCalibration::centered_at(40).normalize_unipolar(1000), 0);
+}
+
+#[test]
+fn track_and_validate() {
+    let mut calibration = AxisCalibration::centered_at(500);
+    assert!(!calibration.is_valid());
+
+    assert!(calibration.track(900));
+    assert!(!calibration.track(700));
+    assert!(calibration.is_valid());
+    assert!(calibration.track(100));
+    assert_eq!(
+        calibration,
+        AxisCalibration {
+            min: 100,
+            center: 500,
+            max: 900,
+        }
+    );
+
+    // Only moved below where it rests.
+    let mut calibration = AxisCalibration::centered_at(500);
+    calibration.track(100);
+    assert!(!calibration.is_valid());
+}
+
+#[test]
+fn round_trip() {
+    assert_eq!(
+        AxisCalibration::from_bytes(&ASYMMETRIC.to_bytes()),
+        ASYMMETRIC
+    );
+}
@@ -0,0 +1,145 @@
+//! Replays the sample logs in `logs/` through the fusion filter.
+
+use input_processing::fusion::{
+    Angle, ComplementaryFilter, FusionSettings, ImuSample, Orientation,
+};
+
+fn parse_log(log: &str) -> Vec<ImuSample> {
+    log.lines()
+        .filter(|line| !line.starts_with('#'))
+        .map(|line| {
+            let values: Vec<i16> = line
+                .split(',')
+                .map(|value| value.parse().unwrap())
+                .collect();
+            ImuSample {
+                accel: [values[0], values[1], values[2]],
+                gyro: [values[3], values[4], values[5]],
+            }
+        })
+        .collect()
+}
+
+/// Every orientation the filter goes through.
+fn replay(log: &str) -> Vec<Orientation> {
+    let mut filter = ComplementaryFilter::new(FusionSettings::default());
+    parse_log(log)
+        .iter()
+        .map(|sample| filter.update(sample))
+        .collect()
+}
+
+fn degrees(angle: Angle) -> f64 {
+    f64::from(angle as i32) * 360.0 / 2f64.powi(32)
+}
+
+fn assert_near(angle: Angle, expected: f64, tolerance: f64) {
+    let actual = degrees(angle);
+    assert!(
+        (actual - expected).abs() <= tolerance,
+        "{} degrees instead of {}",
+        actual,
+        expected
+    );
+}
+
+#[test]
+fn at_rest() {
+    let orientations = replay(include_str!("logs/at_rest.csv"));
+    for orientation in &orientations {
+        assert_near(orientation.pitch, 0.0, 1.0);
+        assert_near(orientation.roll, 0.0, 1.0);
+    }
+    // Only the gyroscope's bias, 1 count or 0.06 degree per second.
+    assert_near(orientations.last().unwrap().yaw, 0.0, 0.5);
+}
+
+#[test]
+fn pitch_sweep() {
+    let orientations = replay(include_str!("logs/pitch_sweep.csv"));
+    // Halfway through the sweep.
+    assert_near(orientations[125].pitch, 22.5, 2.0);
+    let last = orientations.last().unwrap();
+    assert_near(last.pitch, 45.0, 1.0);
+    assert_near(last.roll, 0.0, 1.0);
+    assert_near(last.yaw, 0.0, 1.0);
+}
+
+#[test]
+fn roll_sweep() {
+    let orientations = replay(include_str!("logs/roll_sweep.csv"));
+    assert_near(orientations[125].roll, 15.0, 2.0);
+    let last = orientations.last().unwrap();
+    assert_near(last.roll, 30.0, 1.0);
+    assert_near(last.pitch, 0.0, 1.0);
+    assert_near(last.yaw, 0.0, 1.0);
+}
+
+#[test]
+fn yaw_spin() {
+    let orientations = replay(include_str!("logs/yaw_spin.csv"));
+    assert_near(orientations[249].yaw, 90.0, 1.0);
+    // 180 degrees, where the angle wraps around.
+    let last = orientations.last().unwrap();
+    assert!((degrees(last.yaw).abs() - 180.0).abs() <= 1.0);
+    assert_near(last.pitch, 0.0, 1.0);
+    assert_near(last.roll, 0.0, 1.0);
+}
+
+#[test]
+fn starts_from_gravity() {
+    let mut samples = parse_log(include_str!("logs/pitch_sweep.csv"));
+    // Already raised, with the gyroscope still.
+    let tilted = samples.pop().unwrap();
+    let tilted = ImuSample {
+        gyro: [0; 3],
+        ..tilted
+    };
+    let mut filter = ComplementaryFilter::new(FusionSettings::default());
+    assert_eq!(filter.orientation(), Orientation::default());
+    assert_near(filter.update(&tilted).pitch, 45.0, 1.0);
+}
+
+#[test]
+fn accelerations_are_not_gravity() {
+    let mut filter = ComplementaryFilter::new(FusionSettings::default());
+    let flat = ImuSample {
+        accel: [0, 0, 16384],
+        gyro: [0; 3],
+    };
+    filter.update(&flat);
+    // A 2g shake sideways must not tilt the orientation.
+    for _ in 0..100 {
+        filter.update(&ImuSample {
+            accel: [32767, 0, 16384],
+            gyro: [0; 3],
+        });
+    }
+    assert_eq!(filter.orientation(), filter.update(&flat));
+    assert_near(filter.orientation().roll, 0.0, 0.01);
+}
+
+#[test]
+fn rotation() {
+    let filter = ComplementaryFilter::new(FusionSettings::default());
+    // A turn per second around X, half a turn per second backward around Z,
+    // for 4ms.
+    let sample = ImuSample {
+        accel: [0, 0, 16384],
+        gyro: [5904, 0, -2952],
+    };
+    let step = (1i64 << 32) / 250;
+    assert_eq!(
+        filter.rotation(&sample),
+        [step as i32, 0, (-step / 2) as i32]
+    );
+
+    let full_scale = ImuSample {
+        accel: [0; 3],
+        gyro: [i16::MAX, i16::MIN, 0],
+    };
+    let [x, y, _] = filter.rotation(&full_scale);
+    // About 2000 degrees per second.
+    assert!((degrees(x as u32) - 8.0).abs() < 0.1);
+    assert!((degrees(y as u32) + 8.0).abs() < 0.1);
+}
@@ -0,0 +1,213 @@
+//! Replays the pad traces in `traces/` through the gesture detector.
+
+use input_processing::gesture::{Direction, Gesture, GestureDetector, GestureSettings, PadSample};
+
+fn parse_trace(trace: &str) -> Vec<PadSample> {
+    trace
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .map(|line| {
+            let fields: Vec<&str> = line.split(',').collect();
+            let position = if fields[1].is_empty() {
+                None
+            } else {
+                Some((fields[1].parse().unwrap(), fields[2].parse().unwrap()))
+            };
+            PadSample {
+                timestamp: fields[0].parse().unwrap(),
+                position,
+                clicked: fields[3] == "1",
+            }
+        })
+        .collect()
+}
+
+fn replay(settings: GestureSettings, samples: &[PadSample]) -> Vec<Gesture> {
+    let mut detector = GestureDetector::new(settings);
+    samples
+        .iter()
+        .filter_map(|sample| detector.update(sample))
+        .collect()
+}
+
+/// The same trace, with the finger's path turned a quarter turn clockwise.
+fn turned_clockwise(samples: &[PadSample]) -> Vec<PadSample> {
+    samples
+        .iter()
+        .map(|sample| PadSample {
+            // Y grows downward, so clockwise takes right to down.
+            position: sample.position.map(|(x, y)| (-y, x)),
+            ..*sample
+        })
+        .collect()
+}
+
+fn radial(segments: u8) -> GestureSettings {
+    GestureSettings {
+        radial_segments: segments,
+        ..GestureSettings::default()
+    }
+}
+
+fn click_at(position: Option<(i16, i16)>) -> [PadSample; 2] {
+    [
+        PadSample {
+            timestamp: 0,
+            position,
+            clicked: false,
+        },
+        PadSample {
+            timestamp: 4,
+            position,
+            clicked: true,
+        },
+    ]
+}
+
+#[test]
+fn swipes() {
+    let mut samples = parse_trace(include_str!("traces/swipe_right.csv"));
+    for direction in &[
+        Direction::Right,
+        Direction::Down,
+        Direction::Left,
+        Direction::Up,
+    ] {
+        assert_eq!(
+            replay(GestureSettings::default(), &samples),
+            [Gesture::Touch, Gesture::Swipe(*direction)]
+        );
+        samples = turned_clockwise(&samples);
+    }
+}
+
+#[test]
+fn slow_drag_is_not_a_swipe() {
+    let samples = parse_trace(include_str!("traces/slow_drag.csv"));
+    assert_eq!(
+        replay(GestureSettings::default(), &samples),
+        [Gesture::Touch, Gesture::Release]
+    );
+}
+
+#[test]
+fn short_swipe_is_not_a_swipe() {
+    let samples = parse_trace(include_str!("traces/swipe_right.csv"));
+    let settings = GestureSettings {
+        swipe_min_distance: 30000,
+        ..GestureSettings::default()
+    };
+    assert_eq!(
+        replay(settings, &samples),
+        [Gesture::Touch, Gesture::Release]
+    );
+}
+
+#[test]
+fn tap() {
+    let samples = parse_trace(include_str!("traces/tap.csv"));
+    assert_eq!(
+        replay(GestureSettings::default(), &samples),
+        [Gesture::Touch, Gesture::Release]
+    );
+}
+
+#[test]
+fn click_without_radial_menu() {
+    let samples = parse_trace(include_str!("traces/click_up_left.csv"));
+    assert_eq!(
+        replay(GestureSettings::default(), &samples),
+        [Gesture::Touch, Gesture::Click, Gesture::Release]
+    );
+}
+
+#[test]
+fn click_in_radial_menu() {
+    let samples = parse_trace(include_str!("traces/click_up_left.csv"));
+    // 27 degrees left of up.
+    assert_eq!(
+        replay(radial(4), &samples),
+        [Gesture::Touch, Gesture::RadialSelect(0), Gesture::Release]
+    );
+    assert_eq!(
+        replay(radial(8), &samples),
+        [Gesture::Touch, Gesture::RadialSelect(7), Gesture::Release]
+    );
+}
+
+#[test]
+fn radial_segment_boundaries() {
+    let select = |segments, position| {
+        let mut detector = GestureDetector::new(radial(segments));
+        click_at(Some(position))
+            .iter()
+            .filter_map(|sample| detector.update(sample))
+            .last()
+    };
+
+    // Up, right, down and left.
+    assert_eq!(select(4, (0, -20000)), Some(Gesture::RadialSelect(0)));
+    assert_eq!(select(4, (20000, 0)), Some(Gesture::RadialSelect(1)));
+    assert_eq!(select(4, (0, 20000)), Some(Gesture::RadialSelect(2)));
+    assert_eq!(select(4, (-20000, 0)), Some(Gesture::RadialSelect(3)));
+
+    // Either side of the boundary between up and right, at 45 degrees.
+    assert_eq!(select(4, (14000, -14300)), Some(Gesture::RadialSelect(0)));
+    assert_eq!(select(4, (14300, -14000)), Some(Gesture::RadialSelect(1)));
+    // Either side of up, where the angle wraps around.
+    assert_eq!(select(4, (-100, -20000)), Some(Gesture::RadialSelect(0)));
+    assert_eq!(select(4, (100, -20000)), Some(Gesture::RadialSelect(0)));
+    assert_eq!(select(4, (-14300, -14000)), Some(Gesture::RadialSelect(3)));
+
+    // The corners of the pad are right on boundaries, which belong to the
+    // next segment clockwise.
+    assert_eq!(
+        select(4, (i16::MIN, i16::MIN)),
+        Some(Gesture::RadialSelect(0))
+    );
+    assert_eq!(
+        select(4, (i16::MAX, i16::MAX)),
+        Some(Gesture::RadialSelect(2))
+    );
+    assert_eq!(select(1, (0, 20000)), Some(Gesture::RadialSelect(0)));
+
+    // Too close to the center.
+    assert_eq!(select(4, (8000, 8000)), Some(Gesture::Click));
+}
+
+#[test]
+fn click_without_position() {
+    // At the very edge of the pad, the position is lost.
+    let samples = click_at(None);
+    assert_eq!(
+        replay(GestureSettings::default(), &samples),
+        [Gesture::Click]
+    );
+    assert_eq!(replay(radial(4), &samples), [Gesture::Click]);
+
+    // Right after a touch, the last position is used.
+    let mut detector = GestureDetector::new(radial(4));
+    let touch = PadSample {
+        timestamp: 0,
+        position: Some((20000, 0)),
+        clicked: false,
+    };
+    let lost = PadSample {
+        timestamp: 4,
+        position: None,
+        clicked: true,
+    };
+    assert_eq!(detector.update(&touch), Some(Gesture::Touch));
+    assert_eq!(detector.update(&lost), Some(Gesture::RadialSelect(1)));
+}
+
+#[test]
+fn held_click_is_one_click() {
+    let mut detector = GestureDetector::new(GestureSettings::default());
+    let [released, clicked] = click_at(Some((0, 0)));
+    assert_eq!(detector.update(&released), Some(Gesture::Touch));
+    assert_eq!(detector.update(&clicked), Some(Gesture::Click));
+    assert_eq!(detector.update(&clicked), None);
+    assert_eq!(detector.update(&released), None);
+    assert_eq!(detector.update(&clicked), Some(Gesture::Click));
+}
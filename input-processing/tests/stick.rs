@@ -0,0 +1,94 @@
+//! Dead zones and response curves applied by `stick::process`.
+
+use input_processing::stick::{process, Deadzone, ResponseCurve, StickSettings};
+use input_processing::AXIS_MAX;
+
+const AXIS_MAX_I16: i16 = AXIS_MAX as i16;
+
+fn settings(deadzone: Deadzone, curve: ResponseCurve) -> StickSettings {
+    StickSettings { deadzone, curve }
+}
+
+#[test]
+fn deadzone_edge() {
+    for deadzone in &[Deadzone::Axial(4000), Deadzone::Radial(4000)] {
+        let settings = settings(*deadzone, ResponseCurve::Linear);
+        assert_eq!(process(4000, 0, &settings), (0, 0));
+        assert_eq!(process(-4000, 0, &settings), (0, 0));
+        assert_eq!(process(0, 4000, &settings), (0, 0));
+        // Right past the edge, the output starts from 0 again.
+        assert_eq!(process(4001, 0, &settings), (1, 0));
+        assert_eq!(process(-4001, 0, &settings), (-1, 0));
+        assert_eq!(process(0, 4001, &settings), (0, 1));
+        // The whole range is still reachable.
+        assert_eq!(process(AXIS_MAX_I16, 0, &settings), (AXIS_MAX_I16, 0));
+        assert_eq!(process(-AXIS_MAX_I16, 0, &settings), (-AXIS_MAX_I16, 0));
+    }
+}
+
+#[test]
+fn deadzone_larger_than_range() {
+    let settings = settings(Deadzone::Axial(u16::MAX), ResponseCurve::Linear);
+    assert_eq!(process(AXIS_MAX_I16 - 1, 0, &settings), (0, 0));
+    assert_eq!(process(AXIS_MAX_I16, 0, &settings), (AXIS_MAX_I16, 0));
+}
+
+#[test]
+fn radial_and_axial() {
+    let radial = settings(Deadzone::Radial(4000), ResponseCurve::Linear);
+    let axial = settings(Deadzone::Axial(4000), ResponseCurve::Linear);
+
+    // Diagonal, inside the dead zone of each axis but not inside the circle.
+    assert_eq!(process(3000, 3000, &axial), (0, 0));
+    let (x, y) = process(3000, 3000, &radial);
+    assert!(x > 0 && x == y);
+
+    // Mostly along X: the axial dead zone snaps it to the axis, the radial one
+    // keeps its direction.
+    let (x, y) = process(8000, 3000, &axial);
+    assert!(x > 0 && y == 0);
+    let (x, y) = process(8000, 3000, &radial);
+    assert!(x > 0 && y > 0);
+    assert!((i32::from(x) * 3 - i32::from(y) * 8).abs() < 8);
+}
+
+#[test]
+fn curves() {
+    let curve = |curve, x| process(x, 0, &settings(Deadzone::None, curve)).0;
+
+    for response in &[
+        ResponseCurve::Linear,
+        ResponseCurve::Quadratic,
+        ResponseCurve::Cubic,
+    ] {
+        assert_eq!(curve(*response, 0), 0);
+        assert_eq!(curve(*response, AXIS_MAX_I16), AXIS_MAX_I16);
+        assert_eq!(curve(*response, -AXIS_MAX_I16), -AXIS_MAX_I16);
+        // One past -AXIS_MAX, clamped.
+        assert_eq!(curve(*response, i16::MIN), -AXIS_MAX_I16);
+    }
+
+    assert_eq!(curve(ResponseCurve::Linear, 16384), 16384);
+    assert_eq!(curve(ResponseCurve::Quadratic, 16384), 8192);
+    assert_eq!(curve(ResponseCurve::Quadratic, -16384), -8192);
+    assert_eq!(curve(ResponseCurve::Cubic, 16384), 4096);
+}
+
+#[test]
+fn corners() {
+    // The farthest corner, where the squares of the axes add up to 2^31.
+    for curve in &[
+        ResponseCurve::Linear,
+        ResponseCurve::Quadratic,
+        ResponseCurve::Cubic,
+    ] {
+        let (x, y) = process(
+            i16::MIN,
+            i16::MIN,
+            &settings(Deadzone::Radial(4000), *curve),
+        );
+        assert!(x < 0 && x == y);
+        let (x, y) = process(i16::MIN, i16::MIN, &settings(Deadzone::None, *curve));
+        assert_eq!((x, y), (-AXIS_MAX_I16, -AXIS_MAX_I16));
+    }
+}
@@ -13,7 +13,7 @@ enum IapCmd {
     PrepareSectorForWrite = 50,
     CopyRamToFlash = 51,
     EraseSectors = 52,
-    //BlankCheckSectors = 53,
+    BlankCheckSectors = 53,
     //ReadPartID = 54,
     //ReadBootCodeVersion = 55,
     //Compare = 56,
@@ -81,7 +81,22 @@ pub fn erase_sectors(
     return cmd_out[0] as i32;
 }
 
-//pub fn blank_check_sectors() -> i32 {}
+pub fn blank_check_sectors(start_sector_number: u32, end_sector_number: u32) -> i32 {
+    let mut cmd_out = &mut [0; 4];
+    send_iap_command(
+        &[
+            IapCmd::BlankCheckSectors as u32,
+            start_sector_number,
+            end_sector_number,
+            0,
+            0,
+        ],
+        &mut cmd_out,
+    );
+
+    return cmd_out[0] as i32;
+}
+
 //pub fn read_part_id() -> i32 {}
 //pub fn read_boot_code_version() -> i32 {}
 //pub fn compare() -> i32 {}
@@ -0,0 +1,901 @@
+//! A usb-device driver for the USB device controller of the LPC11Uxx.
+//!
+//! The controller's registers and USB RAM are only reached through
+//! `UsbHardware`, so the driver runs on the chip as well as on the host, against
+//! the controller `sim` simulates.
+
+#![no_std]
+
+use arrayvec::ArrayVec;
+
+use core::cell::{Cell, RefCell};
+
+use usb_device::bus::{PollResult, UsbBusAllocator};
+use usb_device::endpoint::{EndpointAddress, EndpointType};
+use usb_device::{Result, UsbDirection, UsbError};
+
+use bitfield::bitfield;
+
+#[cfg(any(test, feature = "sim"))]
+pub mod sim;
+
+pub const MAX_IF_COUNT: usize = 8;
+pub const MAX_EP_LOGICAL_COUNT: usize = 5;
+pub const MAX_EP_PHYSICAL_COUNT: usize = 10;
+pub const MAX_PACKET0: usize = 0x40;
+pub const FS_MAX_BULK_PACKET: usize = 0x40;
+pub const HS_MAX_BULK_PACKET: usize = 0x200;
+
+const DEVCMDSTAT_DEV_ADDR: u32 = 0x7F;
+const DEVCMDSTAT_DEV_EN: u32 = 1 << 7;
+const DEVCMDSTAT_SETUP: u32 = 1 << 8;
+const DEVCMDSTAT_FORCE_NEEDCLK: u32 = 1 << 9;
+const DEVCMDSTAT_DCON: u32 = 1 << 16;
+const DEVCMDSTAT_DSUS: u32 = 1 << 17;
+const DEVCMDSTAT_DCON_C: u32 = 1 << 24;
+const DEVCMDSTAT_DSUS_C: u32 = 1 << 25;
+const DEVCMDSTAT_DRES_C: u32 = 1 << 26;
+/// Those are cleared by writing 1, so they must not be written back as read.
+const DEVCMDSTAT_WRITE_1_TO_CLEAR: u32 =
+    DEVCMDSTAT_SETUP | DEVCMDSTAT_DCON_C | DEVCMDSTAT_DSUS_C | DEVCMDSTAT_DRES_C;
+
+const INTSTAT_EP_ALL: u32 = 0x3FF;
+const INTSTAT_FRAME_INT: u32 = 1 << 30;
+const INTSTAT_DEV_INT: u32 = 1 << 31;
+
+/// Everything `UsbBus` touches outside of itself: the registers of the USB
+/// device controller, and the USB RAM holding the endpoint command/status list
+/// and the endpoint buffers.
+///
+/// Registers are accessed as raw bits, see UM10462 chapter 11 for the layouts.
+pub trait UsbHardware {
+    /// Start of the USB RAM. The endpoint command/status list takes its first
+    /// 0x100 bytes, the endpoint buffers follow.
+    fn ram(&self) -> *mut u8;
+
+    /// The "address offset" field of an endpoint list entry pointing to
+    /// `buffer`, which must be 64 bytes aligned.
+    fn buffer_address_offset(&self, buffer: *mut u8) -> u32;
+
+    fn enable_interrupt(&self);
+
+    fn devcmdstat(&self) -> u32;
+    fn write_devcmdstat(&self, bits: u32);
+    fn intstat(&self) -> u32;
+    fn write_intstat(&self, bits: u32);
+    fn write_inten(&self, bits: u32);
+    fn write_epliststart(&self, list: *mut u8);
+    fn write_databufstart(&self, buffers: *mut u8);
+    fn epinuse(&self) -> u32;
+    fn write_epinuse(&self, bits: u32);
+    fn epbufcfg(&self) -> u32;
+    fn write_epbufcfg(&self, bits: u32);
+    fn epskip(&self) -> u32;
+    fn write_epskip(&self, bits: u32);
+}
+
+pub struct EndpointBuffer {
+    endpoint_entry: *mut HardwareEndpoint,
+    buffer_address: *mut u8,
+    buffer_address_offset: u32,
+    buffer_size: u32,
+    address: EndpointAddress,
+    endpoint_type: EndpointType,
+}
+
+impl EndpointBuffer {
+    pub fn new(address: EndpointAddress) -> Self {
+        EndpointBuffer {
+            endpoint_entry: core::ptr::null_mut(),
+            buffer_address: core::ptr::null_mut(),
+            buffer_address_offset: 0,
+            buffer_size: 0,
+            address,
+            endpoint_type: EndpointType::Control,
+        }
+    }
+
+    pub fn get_hw_mut(&mut self) -> &mut HardwareEndpoint {
+        unsafe { &mut *self.endpoint_entry }
+    }
+
+    pub fn get_hw(&self) -> &HardwareEndpoint {
+        unsafe { &*self.endpoint_entry }
+    }
+
+    pub fn configure(
+        &mut self,
+        endpoint_type: EndpointType,
+        endpoint_entry: *mut HardwareEndpoint,
+        buffer_address: *mut u8,
+        buffer_address_offset: u32,
+        buffer_size: u32,
+    ) {
+        self.endpoint_type = endpoint_type;
+        self.endpoint_entry = endpoint_entry;
+        self.buffer_address = buffer_address;
+        self.buffer_address_offset = buffer_address_offset;
+        self.buffer_size = buffer_size;
+
+        let hardware_endpoint = self.get_hw_mut();
+
+        hardware_endpoint.set_size(buffer_size);
+        hardware_endpoint.set_address(buffer_address_offset);
+        hardware_endpoint.set_cs(0);
+
+        if endpoint_type == EndpointType::Isochronous {
+            hardware_endpoint.set_iso_type(true);
+        }
+
+        self.disable();
+    }
+
+    /// Makes the buffer ready to receive the next packet. The controller counts
+    /// the size down and moves the address as the packet comes in.
+    pub fn setup_for_output(&mut self) {
+        let buffer_address = self.buffer_address_offset;
+        let buffer_size = self.buffer_size;
+
+        let hardware_endpoint = self.get_hw_mut();
+
+        hardware_endpoint.set_address(buffer_address);
+        hardware_endpoint.set_size(buffer_size);
+
+        if !hardware_endpoint.is_stalled() {
+            hardware_endpoint.set_active(true);
+        }
+    }
+
+    pub fn setup_for_setup(&mut self) {
+        let buffer_address = self.buffer_address_offset;
+
+        let hardware_endpoint = self.get_hw_mut();
+
+        hardware_endpoint.set_address(buffer_address);
+    }
+
+    pub fn setup_for_input(&mut self, data: &[u8]) {
+        assert!(data.len() <= self.buffer_size as usize);
+
+        let address = self.address;
+
+        let buffer_address = self.buffer_address_offset;
+
+        unsafe {
+            data.as_ptr().copy_to(self.buffer_address, data.len());
+        }
+
+        let hardware_endpoint = self.get_hw_mut();
+
+        hardware_endpoint.set_address(buffer_address);
+        hardware_endpoint.set_size(data.len() as u32);
+
+        // FIXME: NXP seems to clear bit 26 & 27???
+        //hardware_endpoint.set_iso_type(false);
+
+        if address.index() == 0 || !hardware_endpoint.is_stalled() {
+            hardware_endpoint.set_active(true);
+        }
+    }
+
+    pub fn reset_after_stall(&mut self, is_active: bool) {
+        let address = self.address;
+        let buffer_size = self.buffer_size;
+        let hardware_endpoint = self.get_hw_mut();
+        hardware_endpoint.set_reset(true);
+
+        if address.direction() == UsbDirection::In {
+            // if the IN EP was set active, reflect it in the hardware endpoint entry.
+            if is_active {
+                hardware_endpoint.set_active(true);
+            }
+        } else {
+            // For OUT EP, we reactivate and reset the size to the previous value.
+            hardware_endpoint.set_active(true);
+            hardware_endpoint.set_size(buffer_size);
+        }
+    }
+
+    pub fn enable(&mut self) {
+        if self.address.index() != 0 {
+            let address = self.address;
+            let hardware_endpoint = self.get_hw_mut();
+
+            hardware_endpoint.set_disabled(false);
+
+            // EP OUT mut be active when enabled
+            if address.direction() == UsbDirection::Out {
+                hardware_endpoint.set_active(true);
+            }
+        }
+    }
+
+    pub fn disable(&mut self) {
+        if self.address.index() != 0 {
+            self.get_hw_mut().set_disabled(true);
+        }
+    }
+
+    pub fn write(&mut self, buf: &[u8]) -> Result<(usize, bool)> {
+        self.setup_for_input(buf);
+
+        Ok((buf.len(), self.get_hw().is_stalled()))
+    }
+
+    /// Whether the controller put a packet in the buffer that wasn't read yet.
+    pub fn has_packet(&self) -> bool {
+        let hardware_endpoint = self.get_hw();
+
+        !hardware_endpoint.is_active()
+            && !hardware_endpoint.is_stalled()
+            && !hardware_endpoint.is_disabled()
+    }
+
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if !self.has_packet() {
+            return Err(UsbError::WouldBlock);
+        }
+
+        let count = (self.buffer_size - self.get_hw().size()) as usize;
+        if count > buf.len() {
+            return Err(UsbError::BufferOverflow);
+        }
+
+        unsafe { self.read_unsafe(buf.as_mut_ptr(), count)? };
+
+        self.setup_for_output();
+
+        Ok(count)
+    }
+
+    pub fn read_setup(&self, buf: &mut [u8]) -> Result<usize> {
+        // This is the SETUP buffer of EP0 OUT, the list entries of EP0 OUT and
+        // IN are right before and after it. Disable stall if set on either.
+        unsafe {
+            let out_entry = self.endpoint_entry.offset(-1);
+            let in_entry = self.endpoint_entry.offset(1);
+            (*out_entry).set_stalled(false);
+            (*in_entry).set_stalled(false);
+        }
+
+        let count = core::cmp::min(buf.len(), 8);
+
+        unsafe { self.read_unsafe(buf.as_mut_ptr(), count) }
+    }
+
+    unsafe fn read_unsafe(&self, buf: *mut u8, count: usize) -> Result<usize> {
+        buf.copy_from(self.buffer_address, count);
+
+        Ok(count)
+    }
+}
+
+pub struct Endpoint<H: UsbHardware> {
+    address: EndpointAddress,
+    endpoint_type: Option<EndpointType>,
+    hardware: H,
+    /// An IN endpoint was written to while stalled, the packet goes out once
+    /// the stall is cleared.
+    is_active: Cell<bool>,
+    /// For a double buffered OUT endpoint, whether the next packet to read is
+    /// in the second buffer.
+    is_used: Cell<bool>,
+    /// For EP0 OUT, whether the next read is the SETUP packet.
+    is_setup: Cell<bool>,
+    first_buffer: RefCell<EndpointBuffer>,
+    second_buffer: RefCell<EndpointBuffer>,
+    max_packet_size: u32,
+}
+
+// SAFETY: We only have one core so no issues here :)
+unsafe impl<H: UsbHardware> Sync for Endpoint<H> {}
+
+impl<H: UsbHardware> Endpoint<H> {
+    pub fn new(address: EndpointAddress, hardware: H) -> Self {
+        Endpoint {
+            address,
+            endpoint_type: None,
+            hardware,
+            first_buffer: RefCell::new(EndpointBuffer::new(address)),
+            second_buffer: RefCell::new(EndpointBuffer::new(address)),
+            is_active: Cell::new(false),
+            is_used: Cell::new(false),
+            is_setup: Cell::new(false),
+            max_packet_size: MAX_PACKET0 as u32,
+        }
+    }
+
+    pub fn get_address(&self) -> EndpointAddress {
+        self.address
+    }
+
+    pub fn set_endpoint_type(&mut self, endpoint_type: EndpointType) {
+        self.endpoint_type = Some(endpoint_type);
+    }
+
+    pub fn endpoint_type(&self) -> Option<EndpointType> {
+        self.endpoint_type
+    }
+
+    fn mask(&self) -> u32 {
+        1 << get_buffer_offset_with_address(self.address)
+    }
+
+    fn is_double_buffered(&self) -> bool {
+        self.address.index() != 0 && self.hardware.epbufcfg() & self.mask() != 0
+    }
+
+    /// Whether the controller uses the second buffer next.
+    fn second_buffer_in_use(&self) -> bool {
+        self.is_double_buffered() && self.hardware.epinuse() & self.mask() != 0
+    }
+
+    pub fn initialize(&self) {
+        let usb_memory_base = self.hardware.ram();
+        let endpoint_list = usb_memory_base as *mut HardwareEndpoint;
+        let endpoint_buffer = unsafe { usb_memory_base.offset(0x100) };
+        let address = self.address;
+
+        let endpoint_index = get_buffer_offset_with_address(address);
+        let buffer_index = endpoint_index * 2;
+
+        // Each physical endpoint has two list entries, one per buffer. For EP0
+        // OUT, the second one is the SETUP buffer.
+        let target_entry = unsafe { endpoint_list.offset(buffer_index) };
+        let target_double_entry = unsafe { target_entry.offset(1) };
+
+        let target_buffer_address =
+            unsafe { endpoint_buffer.offset(self.max_packet_size as isize * buffer_index) };
+        let target_double_buffer_address =
+            unsafe { target_buffer_address.offset(self.max_packet_size as isize) };
+
+        let mut first_buffer = self.first_buffer.borrow_mut();
+        let mut second_buffer = self.second_buffer.borrow_mut();
+
+        first_buffer.configure(
+            self.endpoint_type.unwrap_or(EndpointType::Bulk),
+            target_entry,
+            target_buffer_address,
+            self.hardware.buffer_address_offset(target_buffer_address),
+            self.max_packet_size,
+        );
+        second_buffer.configure(
+            self.endpoint_type.unwrap_or(EndpointType::Bulk),
+            target_double_entry,
+            target_double_buffer_address,
+            self.hardware
+                .buffer_address_offset(target_double_buffer_address),
+            self.max_packet_size,
+        );
+
+        if address.index() == 0 {
+            // EP0 OUT stays ready for the data and status stages of control
+            // transfers, the controller stops it when a SETUP comes in.
+            if address.direction() == UsbDirection::Out {
+                first_buffer.setup_for_output();
+            }
+        } else if self.endpoint_type.is_some() {
+            first_buffer.enable();
+            second_buffer.enable();
+        }
+
+        self.is_active.set(false);
+        self.is_used.set(false);
+        self.is_setup.set(false);
+    }
+
+    fn deactivate(&self) {
+        let mask_to_apply = self.mask();
+
+        self.hardware.write_epskip(mask_to_apply);
+
+        while self.hardware.epskip() & mask_to_apply != 0 {}
+
+        // Clear ep interrupt
+        self.hardware.write_intstat(mask_to_apply);
+    }
+
+    pub fn is_stalled(&self) -> bool {
+        self.first_buffer.borrow().get_hw().is_stalled()
+    }
+
+    pub fn set_stalled(&self, stalled: bool) {
+        let mut first_buffer = self.first_buffer.borrow_mut();
+        let mut second_buffer = self.second_buffer.borrow_mut();
+
+        if !stalled {
+            if self.address.index() == 0 {
+                // If we are on EP0, we just need to clear the stall bit.
+                first_buffer.get_hw_mut().set_stalled(false);
+            } else {
+                // If we are on a non zero endpoint, we need to clear the stall bit on our double buffer and choose the appropriate one to reset.
+                first_buffer.get_hw_mut().set_stalled(false);
+                second_buffer.get_hw_mut().set_stalled(false);
+
+                let in_use = self.second_buffer_in_use();
+                let (mut target_buffer, mut other_buffer) = if in_use {
+                    // Secondary buffer in use.
+                    (second_buffer, first_buffer)
+                } else {
+                    // Primary buffer in use.
+                    (first_buffer, second_buffer)
+                };
+
+                target_buffer.reset_after_stall(self.is_active.get());
+
+                if self.address.direction() == UsbDirection::Out {
+                    // Both buffers take packets again, starting from the one
+                    // in use.
+                    other_buffer.setup_for_output();
+                    self.is_used.set(in_use);
+                } else {
+                    // Whatever was left in the other buffer was meant for
+                    // before the stall.
+                    other_buffer.get_hw_mut().set_active(false);
+                }
+
+                self.is_active.set(false);
+            }
+        } else {
+            let mut hardware_endpoint = first_buffer;
+            // If the active bit is set, we need to get ride of it before setting the stalled bit
+            let endpoint_entry = hardware_endpoint.get_hw_mut();
+
+            let is_active = endpoint_entry.is_active();
+            if is_active {
+                self.deactivate();
+            }
+
+            // Set stall bit
+            endpoint_entry.set_stalled(true);
+
+            // If we aren't on EP0 and double buffering is active, we need to set the stall bit on the double buffer.
+            if self.is_double_buffered() {
+                let mut hardware_endpoint = second_buffer;
+                let endpoint_entry = hardware_endpoint.get_hw_mut();
+
+                let is_active = endpoint_entry.is_active();
+                if is_active {
+                    self.deactivate();
+                }
+
+                // Set stall bit
+                endpoint_entry.set_stalled(true);
+            }
+        }
+    }
+
+    pub fn write(&self, buf: &[u8]) -> Result<usize> {
+        if buf.len() > self.max_packet_size as usize {
+            return Err(UsbError::BufferOverflow);
+        }
+
+        let first_buffer = self.first_buffer.borrow_mut();
+        let second_buffer = self.second_buffer.borrow_mut();
+
+        // The controller sends the buffer EPINUSE points to first, so that one
+        // gets the packet if it is free, else the other one, which goes out
+        // right after.
+        let (current_buffer, other_buffer) = if self.second_buffer_in_use() {
+            // Secondary buffer in use.
+            (second_buffer, first_buffer)
+        } else {
+            // Primary Buffer in use.
+            (first_buffer, second_buffer)
+        };
+
+        let mut target_buffer = if !current_buffer.get_hw().is_active() {
+            current_buffer
+        } else if self.is_double_buffered() && !other_buffer.get_hw().is_active() {
+            other_buffer
+        } else {
+            return Err(UsbError::WouldBlock);
+        };
+
+        let (count, is_stalled) = target_buffer.write(buf)?;
+
+        if self.address.index() != 0 && is_stalled {
+            self.is_active.set(true);
+        }
+
+        Ok(count)
+    }
+
+    pub fn read(&self, buf: &mut [u8]) -> Result<usize> {
+        if self.is_setup.get() {
+            let res = self.read_setup(buf);
+
+            self.is_setup.set(false);
+
+            res
+        } else {
+            self.read_normal(buf)
+        }
+    }
+
+    fn read_setup(&self, buf: &mut [u8]) -> Result<usize> {
+        let mut buffer = self.second_buffer.borrow_mut();
+        let res = buffer.read_setup(buf);
+
+        buffer.setup_for_setup();
+
+        // The controller stopped EP0 OUT when the SETUP came in, it's needed
+        // again for the data or status stage.
+        self.first_buffer.borrow_mut().setup_for_output();
+
+        res
+    }
+
+    /// Whether a packet is waiting to be read, on an OUT endpoint other than
+    /// EP0.
+    pub fn has_packet(&self) -> bool {
+        if self.address.index() == 0
+            || self.address.direction() != UsbDirection::Out
+            || self.endpoint_type.is_none()
+        {
+            return false;
+        }
+
+        if self.is_used.get() {
+            self.second_buffer.borrow().has_packet()
+        } else {
+            self.first_buffer.borrow().has_packet()
+        }
+    }
+
+    fn read_normal(&self, buf: &mut [u8]) -> Result<usize> {
+        // The controller fills the buffers in turn when double buffered, they
+        // are read in the same order.
+        let res = if self.is_used.get() {
+            // Secondary buffer
+            self.second_buffer.borrow_mut().read(buf)
+        } else {
+            // Primary buffer
+            self.first_buffer.borrow_mut().read(buf)
+        };
+
+        if res.is_ok() && self.is_double_buffered() {
+            self.is_used.set(!self.is_used.get());
+        }
+
+        res
+    }
+}
+
+bitfield! {
+  pub struct HardwareEndpoint(u32);
+  impl Debug;
+  pub address, set_address: 15, 0;
+  pub size, set_size : 25, 16;
+  pub is_iso_type, set_iso_type: 26;
+  // bit 27: ???
+  pub is_reset, set_reset: 28;
+  pub is_stalled, set_stalled: 29;
+  pub is_disabled, set_disabled: 30;
+  pub is_active, set_active: 31;
+  pub cs, set_cs: 31, 26;
+}
+
+pub struct UsbBus<H: UsbHardware> {
+    endpoints: ArrayVec<[Endpoint<H>; MAX_EP_PHYSICAL_COUNT]>,
+    hardware: H,
+    /// What the interrupts raised since the last poll, see `handle_irq`.
+    interrupt_status: Cell<u32>,
+}
+
+unsafe impl<H: UsbHardware> Sync for UsbBus<H> {}
+
+impl<H: UsbHardware + Clone> UsbBus<H> {
+    /// Creates a bus driving `hardware`, the chip's own controller or `sim`.
+    pub fn with_hardware(hardware: H) -> UsbBusAllocator<Self> {
+        let mut bus = UsbBus {
+            endpoints: ArrayVec::new(),
+            hardware: hardware.clone(),
+            interrupt_status: Cell::new(0),
+        };
+
+        for i in 0..MAX_EP_LOGICAL_COUNT {
+            let out_endpoint = Endpoint::new(
+                EndpointAddress::from_parts(i, UsbDirection::Out),
+                hardware.clone(),
+            );
+            bus.endpoints.push(out_endpoint);
+
+            let in_endpoint = Endpoint::new(
+                EndpointAddress::from_parts(i, UsbDirection::In),
+                hardware.clone(),
+            );
+            bus.endpoints.push(in_endpoint);
+        }
+
+        UsbBusAllocator::new(bus)
+    }
+}
+
+impl<H: UsbHardware> UsbBus<H> {
+    fn get_endpoint(&self, address: EndpointAddress) -> Result<&Endpoint<H>> {
+        for endpoint in &self.endpoints {
+            let ep_addr = endpoint.get_address();
+
+            if address.index() == ep_addr.index() && address.direction() == ep_addr.direction() {
+                return Ok(endpoint);
+            }
+        }
+
+        Err(UsbError::InvalidEndpoint)
+    }
+
+    unsafe fn init_endpoints(&self) {
+        let usb_memory_base = self.hardware.ram();
+
+        self.hardware.write_epliststart(usb_memory_base);
+        self.hardware
+            .write_databufstart(usb_memory_base.offset(0x100));
+
+        // Clear eplist
+        core::ptr::write_bytes(usb_memory_base, 0, 0x100);
+
+        for endpoint in &self.endpoints {
+            endpoint.initialize();
+        }
+    }
+
+    fn connect(&self) {
+        self.modify_devcmdstat(|bits| bits | DEVCMDSTAT_DCON);
+    }
+
+    #[allow(dead_code)]
+    fn disconnect(&self) {
+        self.modify_devcmdstat(|bits| bits & !DEVCMDSTAT_DCON);
+    }
+
+    fn modify_devcmdstat(&self, f: impl FnOnce(u32) -> u32) {
+        let bits = self.hardware.devcmdstat() & !DEVCMDSTAT_WRITE_1_TO_CLEAR;
+        self.hardware.write_devcmdstat(f(bits));
+    }
+
+    /// Clears `flag`, one of the write 1 to clear bits of DEVCMDSTAT, if set.
+    /// Returns whether it was set.
+    fn take_devcmdstat_flag(&self, flag: u32) -> bool {
+        if self.hardware.devcmdstat() & flag == 0 {
+            return false;
+        }
+
+        self.modify_devcmdstat(|bits| bits | flag);
+        true
+    }
+
+    /// Signals resume to the host. Has no effect unless the bus is suspended.
+    pub fn remote_wakeup(&self) {
+        // The USB clock may be stopped while suspended, and is needed to drive
+        // the resume signaling. Writing 0 to DSUS starts it.
+        self.modify_devcmdstat(|bits| (bits | DEVCMDSTAT_FORCE_NEEDCLK) & !DEVCMDSTAT_DSUS);
+    }
+
+    pub fn handle_irq(&self) {
+        // First we get USB interruption status and immediately clear the register.
+        let interrupt_status = self.hardware.intstat();
+        self.hardware.write_intstat(interrupt_status);
+        // Interrupts can come more than once between two polls.
+        self.interrupt_status
+            .set(self.interrupt_status.get() | interrupt_status);
+    }
+}
+
+fn get_buffer_offset_with_address(endpoint_address: EndpointAddress) -> isize {
+    (endpoint_address.index() * 2
+        + if endpoint_address.direction() == UsbDirection::In {
+            1
+        } else {
+            0
+        }) as isize
+}
+
+impl<H: UsbHardware> usb_device::bus::UsbBus for UsbBus<H> {
+    fn alloc_ep(
+        &mut self,
+        ep_dir: UsbDirection,
+        ep_addr: Option<EndpointAddress>,
+        ep_type: EndpointType,
+        max_packet_size: u16,
+        _interval: u8,
+    ) -> Result<EndpointAddress> {
+        // Every endpoint gets a buffer of that size, see Endpoint::initialize.
+        if usize::from(max_packet_size) > MAX_PACKET0 {
+            return Err(UsbError::EndpointMemoryOverflow);
+        }
+
+        for index in ep_addr
+            .map(|a| {
+                let index = get_buffer_offset_with_address(a) as usize;
+                index..index + 1
+            })
+            .unwrap_or(2..self.endpoints.len())
+        {
+            let ep = &mut self.endpoints[index];
+
+            if ep.endpoint_type().is_some() || ep.get_address().direction() != ep_dir {
+                continue;
+            }
+
+            ep.set_endpoint_type(ep_type);
+
+            return Ok(ep.get_address());
+        }
+
+        Err(match ep_addr {
+            Some(_) => UsbError::InvalidEndpoint,
+            None => UsbError::EndpointOverflow,
+        })
+    }
+
+    fn enable(&mut self) {
+        // TODO: move the usb clock init here?
+
+        // Enable USB IRQ
+        self.hardware.enable_interrupt();
+
+        // reset devcmdstat
+        self.hardware.write_devcmdstat(0x0);
+        self.reset();
+
+        self.set_device_address(0);
+        self.connect();
+    }
+
+    fn reset(&self) {
+        // Clear reset bit if it's set
+        self.take_devcmdstat_flag(DEVCMDSTAT_DRES_C);
+
+        // Clear EP usage registers
+        self.hardware.write_epinuse(0);
+        self.hardware.write_epskip(0);
+
+        unsafe {
+            self.init_endpoints();
+        }
+
+        // Enable double buffering
+        self.hardware.write_epbufcfg(0x3FF);
+
+        self.modify_devcmdstat(|bits| bits | DEVCMDSTAT_DEV_EN);
+
+        // Clear all EP interrupts, device status, and SOF interrupts.
+        self.hardware
+            .write_intstat(INTSTAT_EP_ALL | INTSTAT_FRAME_INT | INTSTAT_DEV_INT);
+        self.interrupt_status.set(0);
+
+        self.hardware
+            .write_inten(INTSTAT_EP_ALL | INTSTAT_FRAME_INT | INTSTAT_DEV_INT);
+    }
+
+    fn poll(&self) -> PollResult {
+        let interrupt_status = self.interrupt_status.replace(0);
+
+        // FIXME: maybe we should clear not enable lines? it's not clear if we can't trust the hardware..
+        //interrupt_status &= self.hardware.inten();
+
+        // Device Status interruption?
+        if interrupt_status & INTSTAT_DEV_INT != 0 {
+            if self.hardware.devcmdstat() & DEVCMDSTAT_DRES_C != 0 {
+                return PollResult::Reset;
+            }
+
+            // Clear connect bit if it's set
+            self.take_devcmdstat_flag(DEVCMDSTAT_DCON_C);
+
+            // Find suspension change bit and clear it if it's set
+            if self.take_devcmdstat_flag(DEVCMDSTAT_DSUS_C) {
+                // Endpoint events are left for the next poll.
+                self.interrupt_status.set(interrupt_status & INTSTAT_EP_ALL);
+
+                // Report the right suspension event
+                if self.hardware.devcmdstat() & DEVCMDSTAT_DSUS != 0 {
+                    return PollResult::Suspend;
+                } else {
+                    return PollResult::Resume;
+                }
+            }
+        }
+
+        // The PollResult masks have a bit per logical endpoint.
+        let mut ep_out: u16 = 0;
+        let mut ep_in_complete: u16 = 0;
+        let mut ep_setup: u16 = 0;
+
+        for endpoint_index in 0..MAX_EP_PHYSICAL_COUNT {
+            let endpoint_mask = 1 << (endpoint_index / 2);
+            let interrupted = interrupt_status & 1 << endpoint_index != 0;
+
+            if endpoint_index % 2 == 1 {
+                // This is an IN EP.
+                if interrupted {
+                    ep_in_complete |= endpoint_mask;
+                }
+            } else if endpoint_index == 0 {
+                // If we are on OUT EP0, check for possible setup packet.
+                if interrupted {
+                    // Find setup bit and clear it if it's set
+                    if self.take_devcmdstat_flag(DEVCMDSTAT_SETUP) {
+                        self.endpoints[0].is_setup.set(true);
+                        ep_setup |= endpoint_mask;
+                    } else {
+                        ep_out |= endpoint_mask;
+                    }
+                }
+            } else if self.endpoints[endpoint_index].has_packet() {
+                // This is an OUT EP. With double buffering, a second packet
+                // may be waiting after the one the interrupt was for.
+                ep_out |= endpoint_mask;
+            }
+        }
+
+        if ep_in_complete == 0 && ep_out == 0 && ep_setup == 0 {
+            return PollResult::None;
+        }
+
+        PollResult::Data {
+            ep_out,
+            ep_in_complete,
+            ep_setup,
+        }
+    }
+
+    fn set_device_address(&self, addr: u8) {
+        // Clear device address.
+        self.modify_devcmdstat(|bits| bits & !DEVCMDSTAT_DEV_ADDR);
+
+        // Set device address and enable.
+        self.modify_devcmdstat(|bits| {
+            (bits & !DEVCMDSTAT_DEV_ADDR)
+                | (u32::from(addr) & DEVCMDSTAT_DEV_ADDR)
+                | DEVCMDSTAT_DEV_EN
+        });
+    }
+
+    fn write(&self, ep_addr: EndpointAddress, buf: &[u8]) -> Result<usize> {
+        if !ep_addr.is_in() {
+            return Err(UsbError::InvalidEndpoint);
+        }
+
+        match self.get_endpoint(ep_addr) {
+            Ok(endpoint) => endpoint.write(buf),
+            Err(error) => Err(error),
+        }
+    }
+
+    fn read(&self, ep_addr: EndpointAddress, buf: &mut [u8]) -> Result<usize> {
+        if !ep_addr.is_out() {
+            return Err(UsbError::InvalidEndpoint);
+        }
+
+        match self.get_endpoint(ep_addr) {
+            Ok(endpoint) => endpoint.read(buf),
+            Err(error) => Err(error),
+        }
+    }
+
+    fn is_stalled(&self, ep_addr: EndpointAddress) -> bool {
+        match self.get_endpoint(ep_addr) {
+            Ok(endpoint) => endpoint.is_stalled(),
+            Err(_) => false,
+        }
+    }
+
+    fn set_stalled(&self, ep_addr: EndpointAddress, stalled: bool) {
+        if let Ok(endpoint) = self.get_endpoint(ep_addr) {
+            endpoint.set_stalled(stalled);
+        }
+    }
+
+    fn suspend(&self) {
+        // Let the controller drop USB_NEED_CLK, so the main loop can put the
+        // chip in deep-sleep until the bus resumes.
+        self.modify_devcmdstat(|bits| bits & !DEVCMDSTAT_FORCE_NEEDCLK);
+    }
+
+    fn resume(&self) {
+        // Undo remote_wakeup.
+        self.modify_devcmdstat(|bits| bits & !DEVCMDSTAT_FORCE_NEEDCLK);
+    }
+}
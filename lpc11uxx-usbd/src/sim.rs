@@ -0,0 +1,778 @@
+//! A simulated USB device controller, so `UsbBus` can run without the chip.
+//!
+//! The controller keeps its registers and USB RAM in memory, and follows the
+//! endpoint command/status list the same way the LPC11U37 does: the host side
+//! of a test drives it with `bus_reset`, `setup`, `transfer_out`,
+//! `transfer_in`... and the driver sees the resulting list entries, EPINUSE
+//! toggles and interrupt status, like on the real controller.
+//!
+//! Only the parts of the controller the driver uses are modeled: no
+//! isochronous endpoints, no LPM, no frame interrupts.
+
+use core::cell::{Cell, UnsafeCell};
+
+use super::*;
+
+/// The LPC11U37 has 2K of USB RAM.
+pub const RAM_SIZE: usize = 0x800;
+
+/// DEVCMDSTAT bits software can write as-is.
+const DEVCMDSTAT_READ_WRITE: u32 =
+    DEVCMDSTAT_DEV_ADDR | DEVCMDSTAT_DEV_EN | DEVCMDSTAT_FORCE_NEEDCLK | DEVCMDSTAT_DCON;
+
+/// What the device answered to a transaction of the host.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Response {
+    /// The data phase went through, with that many bytes.
+    Ack(usize),
+    Nak,
+    Stall,
+    /// The device isn't enabled or connected, or the endpoint is disabled.
+    NoResponse,
+}
+
+pub struct Controller {
+    ram: UnsafeCell<[u32; RAM_SIZE / 4]>,
+    devcmdstat: Cell<u32>,
+    intstat: Cell<u32>,
+    inten: Cell<u32>,
+    epliststart: Cell<usize>,
+    databufstart: Cell<usize>,
+    epinuse: Cell<u32>,
+    epbufcfg: Cell<u32>,
+    remote_wakeup: Cell<bool>,
+}
+
+impl Controller {
+    pub const fn new() -> Self {
+        Controller {
+            ram: UnsafeCell::new([0; RAM_SIZE / 4]),
+            devcmdstat: Cell::new(0),
+            intstat: Cell::new(0),
+            inten: Cell::new(0),
+            epliststart: Cell::new(0),
+            databufstart: Cell::new(0),
+            epinuse: Cell::new(0),
+            epbufcfg: Cell::new(0),
+            remote_wakeup: Cell::new(false),
+        }
+    }
+
+    fn ram_offset(&self, pointer: *mut u8) -> usize {
+        let offset = (pointer as usize).wrapping_sub(self.ram.get() as usize);
+        assert!(offset < RAM_SIZE, "pointer outside of the USB RAM");
+        offset
+    }
+
+    fn entry_pointer(&self, entry_index: usize) -> *mut u32 {
+        let offset = self.epliststart.get() + entry_index * 4;
+        assert!(
+            offset + 4 <= RAM_SIZE,
+            "endpoint list outside of the USB RAM"
+        );
+        unsafe { (self.ram.get() as *mut u8).add(offset) as *mut u32 }
+    }
+
+    /// Endpoint list entry `entry_index`, as the driver left it.
+    pub fn entry(&self, entry_index: usize) -> HardwareEndpoint {
+        HardwareEndpoint(unsafe { self.entry_pointer(entry_index).read_volatile() })
+    }
+
+    fn set_entry(&self, entry_index: usize, entry: HardwareEndpoint) {
+        unsafe { self.entry_pointer(entry_index).write_volatile(entry.0) }
+    }
+
+    fn buffer(&self, entry: &HardwareEndpoint, size: usize) -> *mut u8 {
+        let offset = (entry.address() as usize) << 6;
+        assert!(offset + size <= RAM_SIZE, "buffer outside of the USB RAM");
+        unsafe { (self.ram.get() as *mut u8).add(offset) }
+    }
+
+    fn raise(&self, interrupt: u32) {
+        self.intstat.set(self.intstat.get() | interrupt);
+    }
+
+    fn is_online(&self) -> bool {
+        let devcmdstat = self.devcmdstat.get();
+        devcmdstat & DEVCMDSTAT_DEV_EN != 0 && devcmdstat & DEVCMDSTAT_DCON != 0
+    }
+
+    /// The list entry of the buffer the controller uses next on
+    /// `physical_endpoint`, the one EPINUSE selects when double buffered.
+    fn current_entry_index(&self, physical_endpoint: usize) -> usize {
+        let mask = 1 << physical_endpoint;
+        let double_buffered = physical_endpoint >= 2 && self.epbufcfg.get() & mask != 0;
+
+        if double_buffered && self.epinuse.get() & mask != 0 {
+            physical_endpoint * 2 + 1
+        } else {
+            physical_endpoint * 2
+        }
+    }
+
+    /// Updates an entry after `count` bytes went through, like the controller
+    /// does, and moves on to the other buffer when double buffered.
+    fn complete(&self, physical_endpoint: usize, entry_index: usize, count: usize) {
+        let mut entry = self.entry(entry_index);
+        entry.set_size(entry.size() - count as u32);
+        entry.set_address(entry.address() + (count as u32 >> 6));
+        entry.set_active(false);
+        self.set_entry(entry_index, entry);
+
+        let mask = 1 << physical_endpoint;
+        if physical_endpoint >= 2 && self.epbufcfg.get() & mask != 0 {
+            self.epinuse.set(self.epinuse.get() ^ mask);
+        }
+
+        self.raise(mask);
+    }
+
+    /// The host resets the bus.
+    pub fn bus_reset(&self) {
+        let devcmdstat = self.devcmdstat.get() & !(DEVCMDSTAT_DEV_ADDR | DEVCMDSTAT_DSUS);
+        self.devcmdstat.set(devcmdstat | DEVCMDSTAT_DRES_C);
+        self.epinuse.set(0);
+        self.raise(INTSTAT_DEV_INT);
+    }
+
+    /// The host suspends the bus.
+    pub fn suspend(&self) {
+        self.devcmdstat
+            .set(self.devcmdstat.get() | DEVCMDSTAT_DSUS | DEVCMDSTAT_DSUS_C);
+        self.raise(INTSTAT_DEV_INT);
+    }
+
+    /// The host resumes the bus, on its own or after a remote wakeup.
+    pub fn resume(&self) {
+        let devcmdstat = self.devcmdstat.get() & !DEVCMDSTAT_DSUS;
+        self.devcmdstat.set(devcmdstat | DEVCMDSTAT_DSUS_C);
+        self.remote_wakeup.set(false);
+        self.raise(INTSTAT_DEV_INT);
+    }
+
+    /// Whether the device signaled a remote wakeup since the bus got
+    /// suspended.
+    pub fn remote_wakeup_signaled(&self) -> bool {
+        self.remote_wakeup.get()
+    }
+
+    /// Whether the device pulls up D+.
+    pub fn is_connected(&self) -> bool {
+        self.devcmdstat.get() & DEVCMDSTAT_DCON != 0
+    }
+
+    pub fn device_address(&self) -> u8 {
+        (self.devcmdstat.get() & DEVCMDSTAT_DEV_ADDR) as u8
+    }
+
+    /// Interrupts that are both pending and enabled.
+    pub fn pending_interrupts(&self) -> u32 {
+        self.intstat.get() & self.inten.get()
+    }
+
+    /// The host sends a SETUP packet to EP0. As on the real controller, it is
+    /// always acknowledged, and clears the active and stall bits of EP0.
+    pub fn setup(&self, packet: &[u8; 8]) -> Response {
+        if !self.is_online() {
+            return Response::NoResponse;
+        }
+
+        let setup_entry = self.entry(1);
+        unsafe {
+            packet
+                .as_ptr()
+                .copy_to(self.buffer(&setup_entry, packet.len()), packet.len());
+        }
+
+        for &entry_index in &[0, 2] {
+            let mut entry = self.entry(entry_index);
+            entry.set_active(false);
+            entry.set_stalled(false);
+            self.set_entry(entry_index, entry);
+        }
+
+        self.devcmdstat
+            .set(self.devcmdstat.get() | DEVCMDSTAT_SETUP);
+        self.raise(1 << 0);
+
+        Response::Ack(packet.len())
+    }
+
+    /// The host sends `data` to OUT endpoint `endpoint`. An empty `data` is a
+    /// zero-length packet.
+    pub fn transfer_out(&self, endpoint: usize, data: &[u8]) -> Response {
+        if !self.is_online() {
+            return Response::NoResponse;
+        }
+
+        let physical_endpoint = endpoint * 2;
+        let entry_index = self.current_entry_index(physical_endpoint);
+        let entry = self.entry(entry_index);
+
+        if entry.is_disabled() && endpoint != 0 {
+            return Response::NoResponse;
+        }
+        if entry.is_stalled() {
+            return Response::Stall;
+        }
+        if !entry.is_active() {
+            return Response::Nak;
+        }
+
+        let count = core::cmp::min(data.len(), entry.size() as usize);
+        unsafe {
+            data.as_ptr().copy_to(self.buffer(&entry, count), count);
+        }
+
+        self.complete(physical_endpoint, entry_index, count);
+
+        Response::Ack(count)
+    }
+
+    /// The host reads IN endpoint `endpoint` into `data`. `Ack(0)` is a
+    /// zero-length packet.
+    pub fn transfer_in(&self, endpoint: usize, data: &mut [u8]) -> Response {
+        if !self.is_online() {
+            return Response::NoResponse;
+        }
+
+        let physical_endpoint = endpoint * 2 + 1;
+        let entry_index = self.current_entry_index(physical_endpoint);
+        let entry = self.entry(entry_index);
+
+        if entry.is_disabled() && endpoint != 0 {
+            return Response::NoResponse;
+        }
+        if entry.is_stalled() {
+            return Response::Stall;
+        }
+        if !entry.is_active() {
+            return Response::Nak;
+        }
+
+        let count = core::cmp::min(data.len(), entry.size() as usize);
+        unsafe {
+            data.as_mut_ptr()
+                .copy_from(self.buffer(&entry, count), count);
+        }
+
+        self.complete(physical_endpoint, entry_index, count);
+
+        Response::Ack(count)
+    }
+}
+
+impl Default for Controller {
+    fn default() -> Self {
+        Controller::new()
+    }
+}
+
+// SAFETY: the controller is only ever used from a single thread, like the real
+// one is from a single core.
+unsafe impl Sync for Controller {}
+
+impl UsbHardware for &Controller {
+    fn ram(&self) -> *mut u8 {
+        self.ram.get() as *mut u8
+    }
+
+    fn buffer_address_offset(&self, buffer: *mut u8) -> u32 {
+        // Relative to the start of the simulated RAM, which isn't where
+        // DATABUFSTART points on the chip.
+        (self.ram_offset(buffer) >> 6) as u32
+    }
+
+    fn enable_interrupt(&self) {}
+
+    fn devcmdstat(&self) -> u32 {
+        self.devcmdstat.get()
+    }
+
+    fn write_devcmdstat(&self, bits: u32) {
+        let previous = self.devcmdstat.get();
+
+        // Writing 0 to DSUS while suspended starts a remote wakeup, the host
+        // then resumes the bus.
+        if previous & DEVCMDSTAT_DSUS != 0 && bits & DEVCMDSTAT_DSUS == 0 {
+            self.remote_wakeup.set(true);
+        }
+
+        let kept = previous & !DEVCMDSTAT_READ_WRITE & !(bits & DEVCMDSTAT_WRITE_1_TO_CLEAR);
+        self.devcmdstat.set(kept | (bits & DEVCMDSTAT_READ_WRITE));
+    }
+
+    fn intstat(&self) -> u32 {
+        self.intstat.get()
+    }
+
+    fn write_intstat(&self, bits: u32) {
+        self.intstat.set(self.intstat.get() & !bits);
+    }
+
+    fn write_inten(&self, bits: u32) {
+        self.inten.set(bits);
+    }
+
+    fn write_epliststart(&self, list: *mut u8) {
+        self.epliststart.set(self.ram_offset(list));
+    }
+
+    fn write_databufstart(&self, buffers: *mut u8) {
+        self.databufstart.set(self.ram_offset(buffers));
+    }
+
+    fn epinuse(&self) -> u32 {
+        self.epinuse.get()
+    }
+
+    fn write_epinuse(&self, bits: u32) {
+        self.epinuse.set(bits);
+    }
+
+    fn epbufcfg(&self) -> u32 {
+        self.epbufcfg.get()
+    }
+
+    fn write_epbufcfg(&self, bits: u32) {
+        self.epbufcfg.set(bits);
+    }
+
+    fn epskip(&self) -> u32 {
+        // Skipping completes right away.
+        0
+    }
+
+    fn write_epskip(&self, bits: u32) {
+        for physical_endpoint in 0..MAX_EP_PHYSICAL_COUNT {
+            let mask = 1 << physical_endpoint;
+            if bits & mask == 0 {
+                continue;
+            }
+
+            // The controller deactivates the buffer in use, without toggling
+            // EPINUSE, and raises the endpoint interrupt.
+            let entry_index = self.current_entry_index(physical_endpoint);
+            let mut entry = self.entry(entry_index);
+            if entry.is_active() {
+                entry.set_active(false);
+                self.set_entry(entry_index, entry);
+                self.raise(mask);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::vec;
+    use std::vec::Vec;
+
+    use usb_device::bus::UsbBusAllocator;
+    use usb_device::device::{UsbDevice, UsbDeviceBuilder, UsbDeviceState, UsbVidPid};
+    use usb_device::endpoint::{EndpointIn, EndpointOut};
+    use usb_device::UsbError;
+
+    use super::{Controller, Response};
+    use crate::UsbBus;
+
+    /// The default of usb-device.
+    const MAX_PACKET_SIZE_0: usize = 8;
+
+    const DESCRIPTOR_DEVICE: u8 = 1;
+    const DESCRIPTOR_CONFIGURATION: u8 = 2;
+    const DESCRIPTOR_STRING: u8 = 3;
+    const DESCRIPTOR_HID_REPORT: u8 = 0x22;
+
+    /// 7 characters, so the descriptor fills two packets exactly.
+    const SERIAL_NUMBER: &str = "0123456";
+
+    fn get_descriptor(descriptor_type: u8, index: u8, length: u16) -> [u8; 8] {
+        let [length_low, length_high] = length.to_le_bytes();
+        [
+            0x80,
+            0x06,
+            index,
+            descriptor_type,
+            0,
+            0,
+            length_low,
+            length_high,
+        ]
+    }
+
+    /// In US English.
+    fn get_string_descriptor(index: u8, length: u16) -> [u8; 8] {
+        let mut setup = get_descriptor(DESCRIPTOR_STRING, index, length);
+        setup[4..6].copy_from_slice(&0x0409u16.to_le_bytes());
+        setup
+    }
+
+    fn set_address(address: u8) -> [u8; 8] {
+        [0x00, 0x05, address, 0, 0, 0, 0, 0]
+    }
+
+    fn set_configuration(configuration: u8) -> [u8; 8] {
+        [0x00, 0x09, configuration, 0, 0, 0, 0, 0]
+    }
+
+    fn endpoint_halt(set: bool, endpoint: u8) -> [u8; 8] {
+        let request = if set { 0x03 } else { 0x01 };
+        [0x02, request, 0, 0, endpoint, 0, 0, 0]
+    }
+
+    fn get_endpoint_status(endpoint: u8) -> [u8; 8] {
+        [0x82, 0x00, 0, 0, endpoint, 0, 2, 0]
+    }
+
+    /// The host side of the bus, and the device under test.
+    struct Host<'a, 'c> {
+        controller: &'c Controller,
+        device: UsbDevice<'a, UsbBus<&'c Controller>>,
+    }
+
+    impl<'a, 'c> Host<'a, 'c> {
+        fn new(
+            controller: &'c Controller,
+            usb_bus: &'a UsbBusAllocator<UsbBus<&'c Controller>>,
+        ) -> Self {
+            let device = UsbDeviceBuilder::new(usb_bus, UsbVidPid(0x28de, 0x1102))
+                .manufacturer("Valve Software")
+                .product("Steam Controller")
+                .serial_number(SERIAL_NUMBER)
+                .build();
+
+            Host { controller, device }
+        }
+
+        /// What the main loop does, with USB_IRQ first when it would fire.
+        fn poll(&mut self) {
+            if self.controller.pending_interrupts() != 0 {
+                self.device.bus().handle_irq();
+            }
+            self.device.poll(&mut []);
+        }
+
+        fn reset(&mut self) {
+            self.controller.bus_reset();
+            self.poll();
+            assert_eq!(self.device.state(), UsbDeviceState::Default);
+        }
+
+        /// A control transfer with a data stage from the device. Fails with
+        /// what the device answered instead of data.
+        fn control_in(&mut self, setup: [u8; 8]) -> Result<Vec<u8>, Response> {
+            assert_eq!(self.controller.setup(&setup), Response::Ack(8));
+            self.poll();
+
+            let length = usize::from(u16::from_le_bytes([setup[6], setup[7]]));
+            let mut data = Vec::new();
+            loop {
+                let mut packet = [0; MAX_PACKET_SIZE_0];
+                match self.controller.transfer_in(0, &mut packet) {
+                    Response::Ack(count) => {
+                        data.extend_from_slice(&packet[..count]);
+                        self.poll();
+                        if count < MAX_PACKET_SIZE_0 || data.len() == length {
+                            break;
+                        }
+                    }
+                    response => return Err(response),
+                }
+            }
+
+            assert_eq!(self.controller.transfer_out(0, &[]), Response::Ack(0));
+            self.poll();
+
+            Ok(data)
+        }
+
+        /// A control transfer without a data stage.
+        fn control(&mut self, setup: [u8; 8]) -> Result<(), Response> {
+            assert_eq!(self.controller.setup(&setup), Response::Ack(8));
+            self.poll();
+
+            let mut status = [0; MAX_PACKET_SIZE_0];
+            match self.controller.transfer_in(0, &mut status) {
+                Response::Ack(0) => {
+                    self.poll();
+                    Ok(())
+                }
+                response => Err(response),
+            }
+        }
+
+        fn enumerate(&mut self) {
+            self.reset();
+            self.control(set_address(5)).unwrap();
+            self.control(set_configuration(1)).unwrap();
+            assert_eq!(self.device.state(), UsbDeviceState::Configured);
+        }
+    }
+
+    #[test]
+    fn enumeration() {
+        let controller = Controller::new();
+        let usb_bus = UsbBus::with_hardware(&controller);
+        let mut host = Host::new(&controller, &usb_bus);
+
+        assert!(controller.is_connected());
+        host.reset();
+
+        // Only the start of the device descriptor, as Windows does first.
+        let device_descriptor = host
+            .control_in(get_descriptor(DESCRIPTOR_DEVICE, 0, 64))
+            .unwrap();
+        assert_eq!(device_descriptor.len(), 18);
+        assert_eq!(device_descriptor[..2], [18, DESCRIPTOR_DEVICE]);
+        assert_eq!(usize::from(device_descriptor[7]), MAX_PACKET_SIZE_0);
+        assert_eq!(device_descriptor[8..12], [0xde, 0x28, 0x02, 0x11]);
+
+        host.control(set_address(5)).unwrap();
+        // Only taken once the status stage is over.
+        assert_eq!(controller.device_address(), 5);
+        assert_eq!(host.device.state(), UsbDeviceState::Addressed);
+
+        let configuration_descriptor = host
+            .control_in(get_descriptor(DESCRIPTOR_CONFIGURATION, 0, 255))
+            .unwrap();
+        assert_eq!(configuration_descriptor[..2], [9, DESCRIPTOR_CONFIGURATION]);
+
+        let serial_number = host.control_in(get_string_descriptor(3, 255)).unwrap();
+        let serial_number: Vec<u8> = serial_number[2..].iter().step_by(2).copied().collect();
+        assert_eq!(serial_number, SERIAL_NUMBER.as_bytes());
+
+        host.control(set_configuration(1)).unwrap();
+        assert_eq!(host.device.state(), UsbDeviceState::Configured);
+
+        // A reset starts it all over.
+        host.reset();
+        assert_eq!(controller.device_address(), 0);
+    }
+
+    #[test]
+    fn control_stalls() {
+        let controller = Controller::new();
+        let usb_bus = UsbBus::with_hardware(&controller);
+        let mut host = Host::new(&controller, &usb_bus);
+        host.reset();
+
+        // No HID class, so nobody answers.
+        assert_eq!(
+            host.control_in(get_descriptor(DESCRIPTOR_HID_REPORT, 0, 64)),
+            Err(Response::Stall)
+        );
+        // The host gives up on the status stage, it's stalled as well.
+        assert_eq!(controller.transfer_out(0, &[]), Response::Stall);
+
+        // The next SETUP clears the stall.
+        let device_descriptor = host
+            .control_in(get_descriptor(DESCRIPTOR_DEVICE, 0, 18))
+            .unwrap();
+        assert_eq!(device_descriptor.len(), 18);
+
+        // A vendor request nobody takes, without a data stage.
+        assert_eq!(
+            host.control([0x40, 0x01, 0, 0, 0, 0, 0, 0]),
+            Err(Response::Stall)
+        );
+        host.control(set_address(5)).unwrap();
+    }
+
+    #[test]
+    fn endpoint_halt_stalls() {
+        let controller = Controller::new();
+        let usb_bus = UsbBus::with_hardware(&controller);
+        let ep_in: EndpointIn<_> = usb_bus.bulk(64);
+        let ep_out: EndpointOut<_> = usb_bus.bulk(64);
+        let mut host = Host::new(&controller, &usb_bus);
+        host.enumerate();
+
+        let mut packet = [0; 64];
+
+        host.control(endpoint_halt(true, 0x81)).unwrap();
+        host.control(endpoint_halt(true, 0x01)).unwrap();
+        assert_eq!(host.control_in(get_endpoint_status(0x81)), Ok(vec![1, 0]));
+        assert_eq!(host.control_in(get_endpoint_status(0x01)), Ok(vec![1, 0]));
+
+        // Written while halted, it only goes out once the halt is cleared.
+        assert_eq!(ep_in.write(b"halted"), Ok(6));
+        assert_eq!(controller.transfer_in(1, &mut packet), Response::Stall);
+        assert_eq!(controller.transfer_out(1, b"halted"), Response::Stall);
+        assert_eq!(ep_out.read(&mut packet), Err(UsbError::WouldBlock));
+
+        host.control(endpoint_halt(false, 0x81)).unwrap();
+        host.control(endpoint_halt(false, 0x01)).unwrap();
+        assert_eq!(host.control_in(get_endpoint_status(0x81)), Ok(vec![0, 0]));
+
+        assert_eq!(controller.transfer_in(1, &mut packet), Response::Ack(6));
+        assert_eq!(&packet[..6], b"halted");
+        assert_eq!(controller.transfer_in(1, &mut packet), Response::Nak);
+
+        assert_eq!(controller.transfer_out(1, b"resumed"), Response::Ack(7));
+        assert_eq!(ep_out.read(&mut packet), Ok(7));
+        assert_eq!(&packet[..7], b"resumed");
+
+        // A packet waiting when the halt is set is dropped.
+        assert_eq!(ep_in.write(b"dropped"), Ok(7));
+        host.control(endpoint_halt(true, 0x81)).unwrap();
+        host.control(endpoint_halt(false, 0x81)).unwrap();
+        assert_eq!(controller.transfer_in(1, &mut packet), Response::Nak);
+    }
+
+    #[test]
+    fn in_double_buffering() {
+        let controller = Controller::new();
+        let usb_bus = UsbBus::with_hardware(&controller);
+        let ep_in: EndpointIn<_> = usb_bus.bulk(64);
+        let mut host = Host::new(&controller, &usb_bus);
+        host.enumerate();
+
+        // EP1 IN is physical endpoint 3, with list entries 6 and 7.
+        assert_eq!(ep_in.write(b"first"), Ok(5));
+        assert_eq!(ep_in.write(b"second"), Ok(6));
+        assert!(controller.entry(6).is_active());
+        assert!(controller.entry(7).is_active());
+        assert_eq!(ep_in.write(b"third"), Err(UsbError::WouldBlock));
+
+        let mut packet = [0; 64];
+        assert_eq!(controller.transfer_in(1, &mut packet), Response::Ack(5));
+        assert_eq!(&packet[..5], b"first");
+        host.poll();
+
+        // The controller moved on to the second buffer, the first one is free
+        // again and goes out after it.
+        assert!(!controller.entry(6).is_active());
+        assert_eq!(ep_in.write(b"third"), Ok(5));
+
+        assert_eq!(controller.transfer_in(1, &mut packet), Response::Ack(6));
+        assert_eq!(&packet[..6], b"second");
+        assert_eq!(controller.transfer_in(1, &mut packet), Response::Ack(5));
+        assert_eq!(&packet[..5], b"third");
+        assert_eq!(controller.transfer_in(1, &mut packet), Response::Nak);
+        host.poll();
+
+        // Back to the first buffer, one packet at a time.
+        for packet_index in 0..5u8 {
+            assert_eq!(ep_in.write(&[packet_index]), Ok(1));
+            assert_eq!(controller.transfer_in(1, &mut packet), Response::Ack(1));
+            assert_eq!(packet[0], packet_index);
+            host.poll();
+        }
+    }
+
+    #[test]
+    fn out_double_buffering() {
+        let controller = Controller::new();
+        let usb_bus = UsbBus::with_hardware(&controller);
+        let ep_out: EndpointOut<_> = usb_bus.bulk(64);
+        let mut host = Host::new(&controller, &usb_bus);
+        host.enumerate();
+
+        let mut packet = [0; 64];
+        assert_eq!(ep_out.read(&mut packet), Err(UsbError::WouldBlock));
+
+        // Both buffers take a packet before the device reads any.
+        assert_eq!(controller.transfer_out(1, b"first"), Response::Ack(5));
+        assert_eq!(controller.transfer_out(1, b"second"), Response::Ack(6));
+        assert_eq!(controller.transfer_out(1, b"third"), Response::Nak);
+        host.poll();
+
+        assert_eq!(ep_out.read(&mut packet), Ok(5));
+        assert_eq!(&packet[..5], b"first");
+
+        // Room for one more, behind the one still waiting.
+        assert_eq!(controller.transfer_out(1, b"third"), Response::Ack(5));
+        assert_eq!(controller.transfer_out(1, b"fourth"), Response::Nak);
+        host.poll();
+
+        assert_eq!(ep_out.read(&mut packet), Ok(6));
+        assert_eq!(&packet[..6], b"second");
+        assert_eq!(ep_out.read(&mut packet), Ok(5));
+        assert_eq!(&packet[..5], b"third");
+        assert_eq!(ep_out.read(&mut packet), Err(UsbError::WouldBlock));
+
+        // Too big for what the class reads into: it stays there.
+        assert_eq!(controller.transfer_out(1, b"fourth"), Response::Ack(6));
+        assert_eq!(ep_out.read(&mut packet[..4]), Err(UsbError::BufferOverflow));
+        assert_eq!(ep_out.read(&mut packet), Ok(6));
+        assert_eq!(&packet[..6], b"fourth");
+    }
+
+    #[test]
+    fn zero_length_packets() {
+        let controller = Controller::new();
+        let usb_bus = UsbBus::with_hardware(&controller);
+        let ep_in: EndpointIn<_> = usb_bus.bulk(64);
+        let ep_out: EndpointOut<_> = usb_bus.bulk(64);
+        let mut host = Host::new(&controller, &usb_bus);
+        host.enumerate();
+
+        let mut packet = [0; 64];
+
+        // The serial number descriptor is two full packets. The host asked for
+        // more, so a zero-length packet ends the data stage.
+        let setup = get_string_descriptor(3, 255);
+        assert_eq!(controller.setup(&setup), Response::Ack(8));
+        host.poll();
+        for expected in &[8, 8, 0] {
+            assert_eq!(
+                controller.transfer_in(0, &mut packet),
+                Response::Ack(*expected)
+            );
+            host.poll();
+        }
+        assert_eq!(controller.transfer_out(0, &[]), Response::Ack(0));
+        host.poll();
+
+        // When it asked for exactly that, it goes to the status stage right
+        // away, whether usb-device queued a zero-length packet or not.
+        let setup = get_string_descriptor(3, 16);
+        assert_eq!(controller.setup(&setup), Response::Ack(8));
+        host.poll();
+        for expected in &[8, 8] {
+            assert_eq!(
+                controller.transfer_in(0, &mut packet),
+                Response::Ack(*expected)
+            );
+            host.poll();
+        }
+        assert_eq!(controller.transfer_out(0, &[]), Response::Ack(0));
+        host.poll();
+        let device_descriptor = host
+            .control_in(get_descriptor(DESCRIPTOR_DEVICE, 0, 18))
+            .unwrap();
+        assert_eq!(device_descriptor.len(), 18);
+
+        // On the other endpoints, both ways.
+        assert_eq!(ep_in.write(&[]), Ok(0));
+        assert_eq!(controller.transfer_in(1, &mut packet), Response::Ack(0));
+        assert_eq!(controller.transfer_in(1, &mut packet), Response::Nak);
+
+        assert_eq!(controller.transfer_out(1, &[]), Response::Ack(0));
+        assert_eq!(ep_out.read(&mut packet), Ok(0));
+        assert_eq!(ep_out.read(&mut packet), Err(UsbError::WouldBlock));
+    }
+
+    #[test]
+    fn suspend_and_remote_wakeup() {
+        let controller = Controller::new();
+        let usb_bus = UsbBus::with_hardware(&controller);
+        let mut host = Host::new(&controller, &usb_bus);
+        host.enumerate();
+
+        controller.suspend();
+        host.poll();
+        assert_eq!(host.device.state(), UsbDeviceState::Suspend);
+
+        host.device.bus().remote_wakeup();
+        assert!(controller.remote_wakeup_signaled());
+
+        controller.resume();
+        host.poll();
+        assert_eq!(host.device.state(), UsbDeviceState::Configured);
+        assert!(!controller.remote_wakeup_signaled());
+    }
+}
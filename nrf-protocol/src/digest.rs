@@ -0,0 +1,64 @@
+//! The 32-bit FNV-1a digest the panic handlers use to identify a panic
+//! location and message.
+//!
+//! Program2 hands its panics over to the bootloader as a digest, so both
+//! sides have to agree on the hash.
+
+use core::fmt::{self, Write};
+
+const OFFSET_BASIS: u32 = 0x811c_9dc5;
+const PRIME: u32 = 0x0100_0193;
+
+/// 32-bit FNV-1a, fed through `fmt::Write` so a panic message can be hashed
+/// without formatting it into a buffer first.
+#[derive(Debug, Clone, Copy)]
+pub struct Fnv1a(u32);
+
+impl Fnv1a {
+    pub const fn new() -> Fnv1a {
+        Fnv1a(OFFSET_BASIS)
+    }
+
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.0 ^= u32::from(b);
+            self.0 = self.0.wrapping_mul(PRIME);
+        }
+    }
+
+    pub fn finish(&self) -> u32 {
+        self.0
+    }
+}
+
+impl Default for Fnv1a {
+    fn default() -> Fnv1a {
+        Fnv1a::new()
+    }
+}
+
+impl Write for Fnv1a {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write_bytes(s.as_bytes());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn known_digests() {
+        assert_eq!(Fnv1a::new().finish(), 0x811c_9dc5);
+
+        let mut digest = Fnv1a::new();
+        digest.write_bytes(b"a");
+        assert_eq!(digest.finish(), 0xe40c_292c);
+
+        let mut digest = Fnv1a::new();
+        let line = 42;
+        let _ = write!(digest, "foo{}", line);
+        assert_eq!(digest.finish(), 0x811d_5571);
+    }
+}
@@ -0,0 +1,273 @@
+//! Byte-level framing of packets on the nRF UART.
+//!
+//! A frame starts with STX and ends with ETX. Inside a frame, the STX, ETX and
+//! ESC bytes are sent as ESC followed by the byte XORed with 0x20, so they
+//! never show up raw in a packet.
+
+pub const STX: u8 = 0x02;
+pub const ETX: u8 = 0x03;
+pub const ESC: u8 = 0x1f;
+const ESC_XOR: u8 = 0x20;
+
+/// Size of the largest packet the decoder accepts, after unescaping.
+pub const MAX_PACKET_LEN: usize = 256;
+
+fn needs_escape(byte: u8) -> bool {
+    byte == STX || byte == ETX || byte == ESC
+}
+
+/// Escapes `data`, handing the result to `sink` in as few pieces as possible.
+pub fn encode_escaped<F: FnMut(&[u8])>(mut data: &[u8], sink: &mut F) {
+    while let Some(pos) = data.iter().position(|&v| needs_escape(v)) {
+        if pos != 0 {
+            sink(&data[..pos]);
+        }
+        sink(&[ESC, data[pos] ^ ESC_XOR]);
+        data = &data[pos + 1..];
+    }
+
+    if !data.is_empty() {
+        sink(data);
+    }
+}
+
+/// Frames a packet made of the concatenation of `parts`.
+pub fn encode_frame<F: FnMut(&[u8])>(parts: &[&[u8]], mut sink: F) {
+    sink(&[STX]);
+    for part in parts {
+        encode_escaped(part, &mut sink);
+    }
+    sink(&[ETX]);
+}
+
+/// Length of the frame encode_frame produces for `parts`.
+pub fn encoded_len(parts: &[&[u8]]) -> usize {
+    let escaped: usize = parts.iter()
+        .map(|part| part.iter().map(|&v| if needs_escape(v) { 2 } else { 1 }).sum::<usize>())
+        .sum();
+    escaped + 2
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The packet didn't fit in MAX_PACKET_LEN bytes. The rest of the frame is
+    /// skipped.
+    Overflow,
+    /// The frame ended right after an ESC.
+    DanglingEscape,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// Waiting for an STX.
+    Idle,
+    InFrame,
+    Escaped,
+    /// Skipping the rest of a frame that overflowed.
+    Discarding,
+}
+
+/// Incremental frame decoder. Feed it the bytes from the wire, one at a time.
+///
+/// An STX always starts a new frame, even in the middle of another one. This
+/// lets the decoder get back in sync after bytes got lost on the wire.
+pub struct Decoder {
+    state: State,
+    len: usize,
+    buf: [u8; MAX_PACKET_LEN],
+}
+
+impl Decoder {
+    pub const fn new() -> Decoder {
+        Decoder {
+            state: State::Idle,
+            len: 0,
+            buf: [0; MAX_PACKET_LEN],
+        }
+    }
+
+    /// Drops any partially received frame.
+    pub fn reset(&mut self) {
+        self.state = State::Idle;
+        self.len = 0;
+    }
+
+    /// Handles one byte. Returns the unescaped packet when `byte` completes a
+    /// frame. Empty frames are ignored.
+    pub fn push(&mut self, byte: u8) -> Option<Result<&[u8], DecodeError>> {
+        if byte == STX {
+            self.state = State::InFrame;
+            self.len = 0;
+            return None;
+        }
+
+        match (self.state, byte) {
+            (State::Idle, _) => None,
+            (State::Discarding, ETX) => {
+                self.state = State::Idle;
+                None
+            },
+            (State::Discarding, _) => None,
+            (State::Escaped, ETX) => {
+                self.reset();
+                Some(Err(DecodeError::DanglingEscape))
+            },
+            (State::Escaped, _) => {
+                self.state = State::InFrame;
+                self.append(byte ^ ESC_XOR)
+            },
+            (State::InFrame, ESC) => {
+                self.state = State::Escaped;
+                None
+            },
+            (State::InFrame, ETX) => {
+                let len = self.len;
+                self.reset();
+                if len == 0 {
+                    None
+                } else {
+                    Some(Ok(&self.buf[..len]))
+                }
+            },
+            (State::InFrame, _) => self.append(byte),
+        }
+    }
+
+    fn append(&mut self, byte: u8) -> Option<Result<&[u8], DecodeError>> {
+        match self.buf.get_mut(self.len) {
+            Some(place) => {
+                *place = byte;
+                self.len += 1;
+                None
+            },
+            None => {
+                self.state = State::Discarding;
+                self.len = 0;
+                Some(Err(DecodeError::Overflow))
+            }
+        }
+    }
+}
+
+impl Default for Decoder {
+    fn default() -> Decoder {
+        Decoder::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::vec;
+    use std::vec::Vec;
+    use super::*;
+
+    /// xorshift32, so the property tests don't need a dependency.
+    struct Rng(u32);
+
+    impl Rng {
+        fn next(&mut self) -> u32 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 17;
+            self.0 ^= self.0 << 5;
+            self.0
+        }
+
+        /// Mostly the bytes that need escaping, so they show up next to each
+        /// other and at the ends of the parts.
+        fn byte(&mut self) -> u8 {
+            match self.next() % 4 {
+                0 => STX,
+                1 => ETX,
+                2 => ESC,
+                _ => self.next() as u8,
+            }
+        }
+    }
+
+    fn encode(parts: &[&[u8]]) -> Vec<u8> {
+        let mut frame = Vec::new();
+        encode_frame(parts, |bytes| frame.extend_from_slice(bytes));
+        frame
+    }
+
+    fn decode(bytes: &[u8]) -> Vec<Result<Vec<u8>, DecodeError>> {
+        let mut decoder = Decoder::new();
+        bytes.iter()
+            .filter_map(|&byte| decoder.push(byte).map(|result| result.map(|packet| packet.to_vec())))
+            .collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut rng = Rng(0x1234_5678);
+        for _ in 0..10_000 {
+            let len = 1 + rng.next() as usize % MAX_PACKET_LEN;
+            let packet: Vec<u8> = (0..len).map(|_| rng.byte()).collect();
+            let split = rng.next() as usize % (len + 1);
+            let parts: [&[u8]; 2] = [&packet[..split], &packet[split..]];
+
+            let frame = encode(&parts);
+            assert_eq!(frame.len(), encoded_len(&parts));
+            assert_eq!(frame[0], STX);
+            assert_eq!(frame[frame.len() - 1], ETX);
+            assert!(frame[1..frame.len() - 1].iter().all(|&byte| byte != STX && byte != ETX));
+            assert_eq!(decode(&frame), [Ok(packet)]);
+        }
+    }
+
+    #[test]
+    fn every_byte_round_trips() {
+        let packet: Vec<u8> = (0..=255).collect();
+        assert_eq!(decode(&encode(&[&packet])), [Ok(packet)]);
+    }
+
+    #[test]
+    fn empty_frames_are_ignored() {
+        assert_eq!(decode(&encode(&[])), []);
+    }
+
+    #[test]
+    fn longest_packet_fits() {
+        let packet = [0x55; MAX_PACKET_LEN];
+        assert_eq!(decode(&encode(&[&packet])), [Ok(packet.to_vec())]);
+    }
+
+    #[test]
+    fn overlong_frame_is_skipped() {
+        let mut bytes = encode(&[&[0x55; MAX_PACKET_LEN + 1]]);
+        bytes.extend(encode(&[&[1, 2, 3]]));
+        assert_eq!(decode(&bytes), [Err(DecodeError::Overflow), Ok(vec![1, 2, 3])]);
+    }
+
+    #[test]
+    fn overflow_on_escaped_byte() {
+        let mut packet = [0x55; MAX_PACKET_LEN + 1];
+        packet[MAX_PACKET_LEN] = ESC;
+        assert_eq!(decode(&encode(&[&packet])), [Err(DecodeError::Overflow)]);
+    }
+
+    #[test]
+    fn dangling_escape() {
+        let mut bytes = vec![STX, 1, ESC, ETX];
+        bytes.extend(encode(&[&[4]]));
+        assert_eq!(decode(&bytes), [Err(DecodeError::DanglingEscape), Ok(vec![4])]);
+    }
+
+    #[test]
+    fn garbage_between_frames_is_ignored() {
+        let mut bytes = vec![0xff, ETX, ESC, 0x42];
+        bytes.extend(encode(&[&[1, STX]]));
+        bytes.extend_from_slice(&[ESC, ETX, 0x00, 0x99]);
+        bytes.extend(encode(&[&[ESC, 2]]));
+        assert_eq!(decode(&bytes), [Ok(vec![1, STX]), Ok(vec![ESC, 2])]);
+    }
+
+    #[test]
+    fn stx_restarts_a_frame() {
+        let mut bytes = vec![STX, 9, 9, ESC];
+        bytes.extend(encode(&[&[7]]));
+        assert_eq!(decode(&bytes), [Ok(vec![7])]);
+    }
+}
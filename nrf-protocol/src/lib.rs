@@ -0,0 +1,14 @@
+//! The protocol spoken between the LPC and the nRF radio chip over the UART.
+//!
+//! This crate has no dependency on the hardware, so it can be used from the
+//! bootloader, the firmware, and host tools alike.
+
+#![no_std]
+
+/// Baud rate of the nRF UART. It's what the original firmware's divisors
+/// (DLL = 3, DIVADDVAL = 1, MULVAL = 11) give at 48MHz.
+pub const UART_BAUD_RATE: u32 = 916_667;
+
+pub mod digest;
+pub mod framing;
+pub mod message;
@@ -0,0 +1,198 @@
+//! Packets exchanged with the nRF, as carried inside frames.
+//!
+//! The first byte of a packet tells what it is. The rest is the payload.
+
+use crate::framing;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Message<'a> {
+    /// 'P': a HID feature report tunneled over the radio. From the nRF, it's
+    /// a report to handle as if it came over USB. From the LPC, it's the
+    /// answer.
+    HidReport(&'a [u8]),
+    /// 'R': the nRF asks the LPC to start sending heartbeats.
+    Start,
+    /// 'S': the nRF asks the LPC to stop sending heartbeats.
+    Stop,
+    /// '[': ends an nRF firmware upload. From the LPC, carries the 16-byte
+    /// signature of the uploaded firmware. From the nRF, carries the status
+    /// of the upload as a single byte.
+    Signature(&'a [u8]),
+    /// 'V': sent periodically by the LPC, between a Start and a Stop.
+    Heartbeat(&'a [u8]),
+    /// 'Z': a chunk of nRF firmware.
+    FirmwareChunk(&'a [u8]),
+    /// 'Y': starts an nRF firmware upload.
+    FirmwareUploadStart,
+    /// "\RESET": resets the nRF.
+    Reset,
+    /// 'W': sent by the nRF. Nobody knows what it means, the original
+    /// firmware ignores it.
+    Unexplained(&'a [u8]),
+    /// Anything else. Holds the whole packet, type byte included.
+    Unknown(&'a [u8]),
+}
+
+const RESET: &[u8] = b"\\RESET";
+
+impl<'a> Message<'a> {
+    /// Packet types that don't carry anything are matched on their first byte
+    /// only, like the original firmware does. Trailing bytes are ignored.
+    pub fn parse(packet: &'a [u8]) -> Message<'a> {
+        if packet == RESET {
+            return Message::Reset;
+        }
+
+        match packet.split_first() {
+            Some((b'P', payload)) => Message::HidReport(payload),
+            Some((b'R', _)) => Message::Start,
+            Some((b'S', _)) => Message::Stop,
+            Some((b'[', payload)) => Message::Signature(payload),
+            Some((b'V', payload)) => Message::Heartbeat(payload),
+            Some((b'Z', payload)) => Message::FirmwareChunk(payload),
+            Some((b'Y', _)) => Message::FirmwareUploadStart,
+            Some((b'W', payload)) => Message::Unexplained(payload),
+            _ => Message::Unknown(packet),
+        }
+    }
+
+    /// The bytes identifying the packet type.
+    pub fn tag(&self) -> &'static [u8] {
+        match self {
+            Message::HidReport(_) => b"P",
+            Message::Start => b"R",
+            Message::Stop => b"S",
+            Message::Signature(_) => b"[",
+            Message::Heartbeat(_) => b"V",
+            Message::FirmwareChunk(_) => b"Z",
+            Message::FirmwareUploadStart => b"Y",
+            Message::Reset => RESET,
+            Message::Unexplained(_) => b"W",
+            Message::Unknown(_) => b"",
+        }
+    }
+
+    pub fn payload(&self) -> &'a [u8] {
+        match *self {
+            Message::HidReport(payload) |
+            Message::Signature(payload) |
+            Message::Heartbeat(payload) |
+            Message::FirmwareChunk(payload) |
+            Message::Unexplained(payload) |
+            Message::Unknown(payload) => payload,
+            Message::Start |
+            Message::Stop |
+            Message::FirmwareUploadStart |
+            Message::Reset => &[],
+        }
+    }
+
+    /// Frames the message, handing the bytes to send to `sink`.
+    pub fn encode<F: FnMut(&[u8])>(&self, sink: F) {
+        framing::encode_frame(&[self.tag(), self.payload()], sink)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::vec::Vec;
+    use super::*;
+    use crate::framing::Decoder;
+
+    fn encode(message: &Message) -> Vec<u8> {
+        let mut frame = Vec::new();
+        message.encode(|bytes| frame.extend_from_slice(bytes));
+        frame
+    }
+
+    /// The packets the decoder gets out of `frame`.
+    fn decode(frame: &[u8]) -> Vec<Vec<u8>> {
+        let mut decoder = Decoder::new();
+        frame.iter()
+            .filter_map(|&byte| decoder.push(byte).map(|result| result.unwrap().to_vec()))
+            .collect()
+    }
+
+    fn assert_round_trips(message: Message) {
+        let packets = decode(&encode(&message));
+        assert_eq!(packets.len(), 1, "{:?}", message);
+        assert_eq!(Message::parse(&packets[0]), message);
+    }
+
+    #[test]
+    fn every_message_round_trips() {
+        // Payloads with bytes that need escaping.
+        let payload: &[u8] = &[0x02, 0x03, 0x1f, b'P', 0x00, 0xff];
+        for message in &[
+            Message::HidReport(payload),
+            Message::Start,
+            Message::Stop,
+            Message::Signature(&[0x1f; 16]),
+            Message::Signature(&[0]),
+            Message::Heartbeat(payload),
+            Message::FirmwareChunk(&[0x55; 0x3e]),
+            Message::FirmwareUploadStart,
+            Message::Reset,
+            Message::Unexplained(payload),
+            Message::Unknown(b"Qwhatever"),
+            Message::Unknown(&[0x02]),
+        ] {
+            assert_round_trips(*message);
+        }
+    }
+
+    #[test]
+    fn tags() {
+        assert_eq!(Message::parse(b"P"), Message::HidReport(&[]));
+        assert_eq!(Message::parse(b"R"), Message::Start);
+        assert_eq!(Message::parse(b"S"), Message::Stop);
+        assert_eq!(Message::parse(b"["), Message::Signature(&[]));
+        assert_eq!(Message::parse(b"V"), Message::Heartbeat(&[]));
+        assert_eq!(Message::parse(b"Z"), Message::FirmwareChunk(&[]));
+        assert_eq!(Message::parse(b"Y"), Message::FirmwareUploadStart);
+        assert_eq!(Message::parse(b"W"), Message::Unexplained(&[]));
+        assert_eq!(Message::parse(b"\\RESET"), Message::Reset);
+    }
+
+    #[test]
+    fn reset_is_the_whole_packet() {
+        // Only the exact packet is a reset, anything else is unknown, even
+        // though it starts with the same byte.
+        assert_eq!(Message::parse(b"\\"), Message::Unknown(b"\\"));
+        assert_eq!(Message::parse(b"\\RES"), Message::Unknown(b"\\RES"));
+        assert_eq!(Message::parse(b"\\RESET!"), Message::Unknown(b"\\RESET!"));
+        // 'R' alone is Start, with whatever follows ignored.
+        assert_eq!(Message::parse(b"RESET"), Message::Start);
+        assert_eq!(Message::Reset.tag(), b"\\RESET");
+        assert_eq!(Message::Reset.payload(), b"");
+    }
+
+    #[test]
+    fn trailing_bytes_of_empty_messages_are_ignored() {
+        assert_eq!(Message::parse(b"Sxyz"), Message::Stop);
+        assert_eq!(Message::parse(b"Y\x00"), Message::FirmwareUploadStart);
+    }
+
+    #[test]
+    fn truncated_payloads() {
+        // A signature from the LPC is 16 bytes, but parse doesn't check.
+        assert_eq!(Message::parse(b"[\x01\x02"), Message::Signature(&[1, 2]));
+        assert_eq!(Message::parse(b"Pab"), Message::HidReport(b"ab"));
+    }
+
+    #[test]
+    fn unknown() {
+        assert_eq!(Message::parse(b""), Message::Unknown(b""));
+        assert_eq!(Message::parse(b"Q12"), Message::Unknown(b"Q12"));
+        assert_eq!(Message::parse(&[0xff]), Message::Unknown(&[0xff]));
+
+        // Sent as is, with no tag of its own.
+        let unknown = Message::Unknown(b"Q12");
+        assert_eq!(unknown.tag(), b"");
+        assert_eq!(unknown.payload(), b"Q12");
+        // An empty one makes an empty frame, which the decoder ignores.
+        assert_eq!(decode(&encode(&Message::Unknown(b""))), Vec::<Vec<u8>>::new());
+    }
+}